@@ -0,0 +1,69 @@
+//! Alice and Bob exchange a message and a file with in-memory keystores.
+//!
+//! ```text
+//! cargo run --example round_trip [FILE]
+//! ```
+//!
+//! `FILE` defaults to `.samples/bytes.jpg`; the sealed and reopened copies are
+//! written next to it as `<stem>.enc` and `<stem>.dec.<ext>`.
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use cli_rust::crypto::cipher::{Cipher, MemoryStore};
+
+fn main() {
+    let mut alice = Cipher::new(MemoryStore::new());
+    let mut bob = Cipher::new(MemoryStore::new());
+
+    alice.init(None).unwrap();
+    bob.init(None).unwrap();
+
+    let alice_pub = alice.export_public_key().unwrap();
+    let bob_pub = bob.export_public_key().unwrap();
+
+    alice.register_peer("bob", &bob_pub).unwrap();
+    bob.register_peer("alice", &alice_pub).unwrap();
+
+    let message = "Hello, Bob!";
+    let encrypted = alice.encrypt_text(message, "bob").unwrap();
+    let decrypted = bob.decrypt_text(&encrypted, "alice").unwrap();
+
+    let fingerprint = alice.export_fingerprint().unwrap();
+    println!("is ready {}", alice.is_ready());
+    println!("fingerprint {}", fingerprint);
+
+    println!("Encrypted message: {}", encrypted);
+    println!("Decrypted message: {}", decrypted);
+    assert_eq!(message, decrypted);
+
+    assert!(alice.has_peer("bob").unwrap());
+    assert!(!alice.has_peer("alice").unwrap());
+
+    // File encryption by alice
+    let input = env::args_os()
+        .nth(1)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(".samples/bytes.jpg"));
+    let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = input.extension().unwrap_or_default().to_string_lossy();
+    let sealed_path = input.with_file_name(format!("{}.enc", stem));
+    let opened_path = input.with_file_name(format!("{}.dec.{}", stem, extension));
+
+    let file_bytes = fs::read(&input).unwrap();
+    let enc_bytes = alice.encrypt_bytes(&file_bytes, "bob").unwrap();
+    fs::write(&sealed_path, &enc_bytes).unwrap();
+
+    // File decryption by bob
+    let dec_bytes = fs::read(&sealed_path).unwrap();
+    let decoded_bytes = bob.decrypt_bytes(&dec_bytes, "alice").unwrap();
+    assert_eq!(decoded_bytes, file_bytes);
+    fs::write(&opened_path, &decoded_bytes).unwrap();
+    println!("Wrote {} and {}", sealed_path.display(), opened_path.display());
+
+    alice.remove_peer("bob").unwrap();
+    assert!(!alice.has_peer("bob").unwrap());
+
+    alice.clear_cache();
+    bob.clear_cache();
+}
@@ -0,0 +1,120 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::CliError;
+
+const APP_DIR: &str = "nyx";
+const PROFILES_DIR: &str = "profiles";
+
+/// Settings shared by every profile, read from `<config-dir>/nyx/config.json`
+/// or the file passed with `--config`. A missing file means defaults.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Profile used when neither `--profile` nor `NYX_PROFILE` is given; set
+    /// by `nyx profile switch`.
+    pub profile: Option<String>,
+}
+
+/// Where a profile keeps its keystore.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[derive(clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum StoreBackend {
+    /// One file per entry; `FileStore`.
+    #[default]
+    Files,
+    /// A single SQLite database, for long-lived agents that keep history;
+    /// `SqliteStore`.
+    Sqlite,
+}
+
+/// Settings of one profile, from `<config-dir>/nyx/profiles/<profile>.json`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ProfileConfig {
+    /// Name shown in the chat prompt.
+    pub display_name: Option<String>,
+    /// Multiaddr `listen` binds to.
+    pub listen_address: String,
+    /// Keep the unlocked key in the profile's keyring entry; see `nyx unlock`.
+    pub remember_key: bool,
+    /// Keystore backend, chosen at `nyx init`.
+    pub store: StoreBackend,
+}
+
+impl Default for ProfileConfig {
+    fn default() -> Self {
+        Self {
+            display_name: None,
+            listen_address: "/ip4/0.0.0.0/tcp/0".into(),
+            remember_key: false,
+            store: StoreBackend::default(),
+        }
+    }
+}
+
+fn config_dir() -> Result<PathBuf, CliError> {
+    let base = dirs::config_dir()
+        .ok_or_else(|| CliError::Config("No config directory available".into()))?;
+
+    Ok(base.join(APP_DIR))
+}
+
+fn load<T: DeserializeOwned + Default>(path: &Path) -> Result<T, CliError> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(T::default()),
+        Err(e) => return Err(CliError::Config(format!("{}: {}", path.display(), e))),
+    };
+
+    if data.iter().all(u8::is_ascii_whitespace) {
+        return Ok(T::default());
+    }
+
+    serde_json::from_slice(&data).map_err(|e| CliError::Config(format!("{}: {}", path.display(), e)))
+}
+
+fn save<T: Serialize>(value: &T, path: &Path) -> Result<(), CliError> {
+    let data = serde_json::to_vec_pretty(value).map_err(|e| CliError::Config(e.to_string()))?;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, data)?;
+
+    Ok(())
+}
+
+impl Config {
+    pub fn default_path() -> Result<PathBuf, CliError> {
+        Ok(config_dir()?.join("config.json"))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, CliError> {
+        load(path)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), CliError> {
+        save(self, path)
+    }
+}
+
+impl ProfileConfig {
+    pub fn path(profile: &str) -> Result<PathBuf, CliError> {
+        Ok(config_dir()?
+            .join(PROFILES_DIR)
+            .join(format!("{}.json", profile)))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, CliError> {
+        load(path)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), CliError> {
+        save(self, path)
+    }
+}
@@ -0,0 +1,1348 @@
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use clap::{Args, Parser, Subcommand};
+use colored::*;
+use dialoguer::{theme::ColorfulTheme, Confirm, Editor, Password};
+use libp2p::Multiaddr;
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::mpsc;
+use zeroize::Zeroizing;
+
+use crate::console;
+use crate::crypto::cipher::{Cipher, CipherError, IdentityAnnouncement, PeerInfo, Verification};
+use crate::peer::msg::{Envelope, EnvelopeKind};
+use crate::peer::p2p::{NodeEvent, P2PNode};
+use crate::vault::manager::KeyringError;
+use crate::vault::md::{NoteSummary, NotesVault, VaultError};
+
+pub mod config;
+pub mod profile;
+
+use config::{Config, StoreBackend};
+use profile::{Profile, ProfileStore};
+
+/// Environment variable holding the keystore password, for scripts. When it
+/// is unset the password is taken from the keyring or prompted for.
+pub const PASSWORD_ENV: &str = "NYX_PASSWORD";
+/// Environment variable selecting the profile, like `--profile`.
+pub const PROFILE_ENV: &str = "NYX_PROFILE";
+/// Environment variable choosing where remembered keys are kept: `keyring`
+/// (the default), `file` for the encrypted secrets file only, or `env` to read
+/// them from `SECRET_VAR_PREFIX` variables, for CI.
+pub const SECRETS_ENV: &str = "NYX_SECRETS";
+/// Prefix of the variables read with `NYX_SECRETS=env`; the key of profile
+/// `default` is in `NYX_SECRET_NYX_PROFILE_DEFAULT`, base64url-encoded.
+pub const SECRET_VAR_PREFIX: &str = "NYX_SECRET";
+
+/// Process exit codes. Clap exits with `USAGE` on bad arguments.
+pub mod exit_code {
+    pub const FAILURE: u8 = 1;
+    pub const USAGE: u8 = 2;
+    /// Wrong password.
+    pub const AUTH: u8 = 3;
+    /// No keystore, peer or session with that name.
+    pub const NOT_FOUND: u8 = 4;
+    /// Data or a key that must not be trusted: a changed identity key, a
+    /// bad signature or a message that does not decrypt.
+    pub const UNTRUSTED: u8 = 5;
+    pub const CORRUPTED: u8 = 6;
+    /// The user declined to go on, e.g. kept a pinned key that changed.
+    pub const ABORTED: u8 = 7;
+}
+
+#[derive(Debug, Error)]
+pub enum CliError {
+    #[error(transparent)]
+    Cipher(#[from] CipherError),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Invalid config: {0}")]
+    Config(String),
+
+    #[error("No keystore for profile {0}; run `nyx init` first")]
+    NotInitialized(String),
+
+    #[error("Profile {0} already has a keystore")]
+    AlreadyInitialized(String),
+
+    #[error("Profile {0} is the active profile; switch to another one first")]
+    ProfileInUse(String),
+
+    #[error("Network error: {0}")]
+    Network(String),
+
+    #[error(transparent)]
+    Keyring(#[from] KeyringError),
+
+    #[error(transparent)]
+    Vault(#[from] VaultError),
+
+    #[error("Prompt failed: {0}")]
+    Prompt(String),
+
+    #[error("{0}")]
+    Aborted(String),
+}
+
+impl From<dialoguer::Error> for CliError {
+    fn from(err: dialoguer::Error) -> Self {
+        CliError::Prompt(err.to_string())
+    }
+}
+
+impl CliError {
+    pub fn exit_code(&self) -> u8 {
+        fn cipher_exit_code(err: &CipherError) -> u8 {
+            match err {
+                CipherError::WrongPassword => exit_code::AUTH,
+                CipherError::PeerNotFound(_)
+                | CipherError::PeerKeyUnknown(_)
+                | CipherError::SessionNotFound(_) => exit_code::NOT_FOUND,
+                CipherError::IdentityKeyChanged { .. }
+                | CipherError::InvalidSignature
+                | CipherError::DecryptionFailed(_) => exit_code::UNTRUSTED,
+                CipherError::KeystoreCorrupted(_) => exit_code::CORRUPTED,
+                _ => exit_code::FAILURE,
+            }
+        }
+
+        match self {
+            CliError::Cipher(err) | CliError::Vault(VaultError::Cipher(err)) => cipher_exit_code(err),
+            CliError::Vault(VaultError::NotFound(_)) => exit_code::NOT_FOUND,
+            CliError::Vault(VaultError::Corrupted(_)) => exit_code::CORRUPTED,
+            CliError::Vault(VaultError::Rejected { .. }) => exit_code::UNTRUSTED,
+            CliError::NotInitialized(_) => exit_code::NOT_FOUND,
+            CliError::Config(_) => exit_code::USAGE,
+            CliError::Aborted(_) => exit_code::ABORTED,
+            _ => exit_code::FAILURE,
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+#[command(name = "nyx", version, about = "End-to-end encrypted messaging in the terminal")]
+pub struct Cli {
+    /// Config file to read instead of `<config-dir>/nyx/config.json`.
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Profile to use instead of the active one.
+    #[arg(long, short, global = true, env = PROFILE_ENV, value_name = "NAME")]
+    pub profile: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Create the keystore and identity of the selected profile.
+    Init(InitArgs),
+    /// Create, list, switch and delete profiles.
+    #[command(subcommand)]
+    Profile(ProfileCommand),
+    /// Forget the remembered key; the password is asked for again.
+    Lock,
+    /// Unlock with the password and remember the key, as `init --remember`.
+    Unlock,
+    /// Our own identity.
+    #[command(subcommand)]
+    Id(IdCommand),
+    /// Known peers and their keys.
+    #[command(subcommand)]
+    Peer(PeerCommand),
+    /// Encrypt for a peer with the static peer key, to pass on by other
+    /// means. `chat` and `send` use a forward-secret session instead.
+    Encrypt(CryptArgs),
+    /// Decrypt what a peer encrypted with `encrypt`.
+    Decrypt(CryptArgs),
+    /// Encrypted markdown notes.
+    #[command(subcommand)]
+    Note(NoteCommand),
+    /// Interactive chat with a peer.
+    Chat {
+        peer: String,
+    },
+    /// Announce ourselves on the local network and print what peers send.
+    Listen {
+        /// Multiaddr to listen on; overrides `listen_address` in the config.
+        #[arg(long)]
+        address: Option<String>,
+    },
+    /// Send a message or a note to a peer on the local network.
+    Send(SendArgs),
+}
+
+#[derive(Debug, Args)]
+#[command(group(clap::ArgGroup::new("content").required(true).args(["message", "note"])))]
+pub struct SendArgs {
+    pub peer: String,
+    pub message: Option<String>,
+    /// Share this note instead of sending a message.
+    #[arg(long, value_name = "ID")]
+    pub note: Option<String>,
+    /// Seconds to wait for the peer to show up and acknowledge.
+    #[arg(long, default_value_t = 30)]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Args)]
+pub struct InitArgs {
+    /// Remember the unlocked key in the OS keyring, or a private file where
+    /// there is none, so the password is not asked for on every run.
+    #[arg(long)]
+    pub remember: bool,
+    /// Keystore backend; `sqlite` keeps everything in one database file.
+    #[arg(long, value_enum)]
+    pub store: Option<StoreBackend>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ProfileCommand {
+    /// Create a profile with a new identity.
+    Create {
+        name: String,
+        #[command(flatten)]
+        init: InitArgs,
+    },
+    /// List profiles; the active one is marked with `*`.
+    List,
+    /// Make a profile the active one.
+    Switch {
+        name: String,
+    },
+    /// Delete a profile with its keystore, config and keyring entry.
+    Delete {
+        name: String,
+        /// Do not ask for confirmation.
+        #[arg(long)]
+        yes: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum IdCommand {
+    /// Print our fingerprint and public keys.
+    Show,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum PeerCommand {
+    /// Register a peer, or check its key against the pinned one.
+    Add {
+        id: String,
+        /// Base64url public key, as printed by `nyx id show`.
+        public_key: String,
+        #[arg(long)]
+        alias: Option<String>,
+        /// Signed announcement of the peer's signing key, as printed by
+        /// `nyx id show`.
+        #[arg(long)]
+        announcement: Option<String>,
+    },
+    /// List registered peers.
+    List,
+    /// Forget a peer and its session.
+    Remove {
+        id: String,
+    },
+    /// Compare safety numbers with a peer and mark it verified.
+    Verify {
+        id: String,
+        /// Safety number read out or scanned from the peer, instead of
+        /// confirming interactively.
+        #[arg(long)]
+        code: Option<String>,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct CryptArgs {
+    /// Peer the data is for, or from.
+    pub peer: String,
+    /// Text message, or base64 ciphertext when decrypting. Without it, the
+    /// input is processed as a binary stream.
+    pub message: Option<String>,
+    /// Read the stream from this file instead of stdin.
+    #[arg(short, long)]
+    pub input: Option<PathBuf>,
+    /// Write the stream to this file instead of stdout.
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum NoteCommand {
+    /// Write a new note; without `--body` it opens `$EDITOR`.
+    New {
+        title: String,
+        #[arg(long = "tag", short, value_name = "TAG")]
+        tags: Vec<String>,
+        #[arg(long)]
+        body: Option<String>,
+    },
+    /// Change a note; without any option it opens the body in `$EDITOR`.
+    Edit {
+        id: String,
+        #[arg(long)]
+        title: Option<String>,
+        /// Replaces all tags of the note.
+        #[arg(long = "tag", short, value_name = "TAG")]
+        tags: Vec<String>,
+        #[arg(long)]
+        body: Option<String>,
+    },
+    /// Print a note.
+    Show {
+        id: String,
+    },
+    /// List notes, most recently updated first.
+    List {
+        /// Only notes with this tag.
+        #[arg(long, short)]
+        tag: Option<String>,
+    },
+    /// Find notes by title, tag or body.
+    Search {
+        query: String,
+    },
+    /// Delete a note.
+    Delete {
+        id: String,
+    },
+    /// Encrypt a note for a peer; base64 on stdout unless `--output` is given.
+    /// Share it again to send later changes.
+    Share {
+        id: String,
+        peer: String,
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Add or sync a note shared by a peer, from base64 or from `--input`.
+    /// Concurrent edits are merged; conflicts are shown and marked in the body.
+    Import {
+        peer: String,
+        data: Option<String>,
+        #[arg(short, long)]
+        input: Option<PathBuf>,
+    },
+}
+
+pub async fn run(cli: Cli) -> Result<(), CliError> {
+    let config_path = match cli.config {
+        Some(path) => path,
+        None => Config::default_path()?,
+    };
+    let mut config = Config::load(&config_path)?;
+
+    let profile = Profile::new(
+        cli.profile
+            .as_deref()
+            .or(config.profile.as_deref())
+            .unwrap_or(Profile::DEFAULT),
+    )?;
+
+    match cli.command {
+        Command::Init(args) => init(&profile, &args),
+        Command::Profile(command) => profiles(&mut config, &config_path, &profile, command),
+        Command::Lock => lock(&profile),
+        Command::Unlock => unlock(&profile),
+        Command::Id(IdCommand::Show) => id_show(&profile),
+        Command::Peer(command) => peer(&profile, command),
+        Command::Encrypt(args) => encrypt(&profile, args),
+        Command::Decrypt(args) => decrypt(&profile, args),
+        Command::Note(command) => note(&profile, command),
+        Command::Chat { peer } => chat(&profile, &peer).await,
+        Command::Listen { address } => {
+            let address = match address {
+                Some(address) => address,
+                None => profile.config()?.listen_address,
+            };
+            listen(&profile, &address).await
+        }
+        Command::Send(args) => send(&profile, args).await,
+    }
+}
+
+fn password_from_env() -> Option<Zeroizing<String>> {
+    env::var(PASSWORD_ENV).ok().map(Zeroizing::new)
+}
+
+fn prompt_password(prompt: &str, confirm: bool) -> Result<Zeroizing<String>, CliError> {
+    let theme = ColorfulTheme::default();
+    let mut input = Password::with_theme(&theme).with_prompt(prompt);
+    if confirm {
+        input = input
+            .with_confirmation("Repeat password", "Passwords do not match")
+            .allow_empty_password(true);
+    }
+
+    Ok(Zeroizing::new(input.interact()?))
+}
+
+fn new_cipher(profile: &Profile, remember: bool) -> Result<Cipher<ProfileStore>, CliError> {
+    let cipher = Cipher::new(profile.store()?);
+
+    Ok(if remember {
+        cipher.with_key_cache(profile.secret_manager()?)
+    } else {
+        cipher
+    })
+}
+
+/// Opens and unlocks the keystore of `profile`. The password comes from
+/// `PASSWORD_ENV`, else the remembered key if `remember_key` is set, else a
+/// prompt.
+fn open_cipher(profile: &Profile) -> Result<Cipher<ProfileStore>, CliError> {
+    open_cipher_with(profile, |prompt| prompt_password(prompt, false))
+}
+
+/// `open_cipher` asking for the password through `prompt`.
+fn open_cipher_with(
+    profile: &Profile,
+    prompt: impl FnOnce(&str) -> Result<Zeroizing<String>, CliError>,
+) -> Result<Cipher<ProfileStore>, CliError> {
+    if !profile.exists()? {
+        return Err(CliError::NotInitialized(profile.name().into()));
+    }
+    let remember = profile.config()?.remember_key;
+
+    if let Some(password) = password_from_env() {
+        let mut cipher = new_cipher(profile, remember)?;
+        cipher.init(Some(password.as_str()).filter(|p| !p.is_empty()))?;
+        return Ok(cipher);
+    }
+
+    let mut cipher = new_cipher(profile, remember)?;
+    match cipher.init(None) {
+        Ok(()) => return Ok(cipher),
+        Err(CipherError::WrongPassword) if remember => {
+            // The remembered key is gone or stale, e.g. after a password
+            // change elsewhere: stop relying on it until the next unlock.
+            drop(cipher);
+            set_remember_key(profile, false)?;
+            eprintln!(
+                "{}",
+                format!("No valid remembered key for profile {}.", profile.name()).yellow()
+            );
+            cipher = new_cipher(profile, false)?;
+        }
+        Err(CipherError::WrongPassword) => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    let password = prompt(&format!("Password for profile {}", profile.name()))?;
+    cipher.init(Some(&password))?;
+
+    Ok(cipher)
+}
+
+fn init(profile: &Profile, args: &InitArgs) -> Result<(), CliError> {
+    if profile.exists()? {
+        return Err(CliError::AlreadyInitialized(profile.name().into()));
+    }
+
+    let password = match password_from_env() {
+        Some(password) => password,
+        None => prompt_password("New password (empty for none)", true)?,
+    };
+    if password.is_empty() {
+        println!(
+            "{}",
+            "No password set: the keystore key is stored next to the keystore.".yellow()
+        );
+    }
+
+    let config_path = profile.config_path()?;
+    if let Some(store) = args.store {
+        let mut config = profile.config()?;
+        config.store = store;
+        config.save(&config_path)?;
+    }
+
+    let remember = args.remember && !password.is_empty();
+    let mut cipher = new_cipher(profile, remember)?;
+    cipher.init(Some(password.as_str()).filter(|p| !p.is_empty()))?;
+
+    // Leave a config to edit behind.
+    if remember || !config_path.exists() {
+        let mut config = profile.config()?;
+        config.remember_key = remember;
+        config.save(&config_path)?;
+    }
+
+    println!("Keystore created for profile {}.", profile.name().bright_yellow());
+    print_identity(&cipher)
+}
+
+fn set_remember_key(profile: &Profile, remember: bool) -> Result<(), CliError> {
+    let path = profile.config_path()?;
+    let mut config = profile.config()?;
+    config.remember_key = remember;
+    config.save(&path)
+}
+
+fn lock(profile: &Profile) -> Result<(), CliError> {
+    if !profile.exists()? {
+        return Err(CliError::NotInitialized(profile.name().into()));
+    }
+
+    set_remember_key(profile, false)?;
+    match profile.secret_manager()?.delete_secret() {
+        Ok(()) | Err(KeyringError::NotFound) => {}
+        Err(e) => return Err(e.into()),
+    }
+    println!("Profile {} is locked.", profile.name().bright_yellow());
+    Ok(())
+}
+
+fn unlock(profile: &Profile) -> Result<(), CliError> {
+    if !profile.exists()? {
+        return Err(CliError::NotInitialized(profile.name().into()));
+    }
+
+    set_remember_key(profile, true)?;
+
+    let result = open_cipher(profile);
+    if result.is_err() {
+        set_remember_key(profile, false)?;
+    }
+    let cipher = result?;
+
+    if !cipher.is_password_protected() {
+        set_remember_key(profile, false)?;
+        println!("Profile {} has no password to remember.", profile.name());
+        return Ok(());
+    }
+
+    println!("Profile {} is unlocked.", profile.name().bright_yellow());
+    Ok(())
+}
+
+fn profiles(
+    config: &mut Config,
+    config_path: &Path,
+    active: &Profile,
+    command: ProfileCommand,
+) -> Result<(), CliError> {
+    match command {
+        ProfileCommand::Create { name, init: args } => init(&Profile::new(&name)?, &args)?,
+        ProfileCommand::List => {
+            for name in Profile::list()? {
+                if name == active.name() {
+                    println!("* {}", name.bright_yellow());
+                } else {
+                    println!("  {}", name);
+                }
+            }
+        }
+        ProfileCommand::Switch { name } => {
+            let profile = Profile::new(&name)?;
+            if !profile.exists()? {
+                return Err(CliError::NotInitialized(name));
+            }
+
+            config.profile = Some(name);
+            config.save(config_path)?;
+            println!("Active profile is now {}.", profile.name().bright_yellow());
+        }
+        ProfileCommand::Delete { name, yes } => {
+            let profile = Profile::new(&name)?;
+            if profile.name() == active.name() {
+                return Err(CliError::ProfileInUse(name));
+            }
+            if !profile.data_dir()?.exists() {
+                return Err(CliError::NotInitialized(name));
+            }
+
+            let confirmed = yes
+                || Confirm::with_theme(&ColorfulTheme::default())
+                    .with_prompt(format!(
+                        "Delete profile {} and its identity? This cannot be undone",
+                        name
+                    ))
+                    .default(false)
+                    .interact()?;
+            if !confirmed {
+                return Err(CliError::Aborted(format!("Kept profile {}", name)));
+            }
+
+            profile.delete()?;
+            println!("Deleted profile {}.", name);
+        }
+    }
+
+    Ok(())
+}
+
+fn print_identity(cipher: &Cipher<ProfileStore>) -> Result<(), CliError> {
+    println!("fingerprint:  {}", cipher.export_fingerprint()?.bright_white());
+    println!("public key:   {}", cipher.export_public_key()?);
+    println!("signing key:  {}", cipher.export_signing_key()?);
+    println!("announcement: {}", cipher.identity_announcement()?.encode()?);
+    Ok(())
+}
+
+fn id_show(profile: &Profile) -> Result<(), CliError> {
+    let cipher = open_cipher(profile)?;
+    print_identity(&cipher)
+}
+
+fn peer(profile: &Profile, command: PeerCommand) -> Result<(), CliError> {
+    let mut cipher = open_cipher(profile)?;
+
+    match command {
+        PeerCommand::Add {
+            id,
+            public_key,
+            alias,
+            announcement,
+        } => {
+            match cipher.register_peer(&id, &public_key) {
+                Ok(()) => {}
+                Err(CipherError::IdentityKeyChanged {
+                    peer,
+                    pinned,
+                    offered,
+                }) => {
+                    if !console::safety::confirm_key_change(&peer, &pinned, &offered) {
+                        cipher.reject_peer_key(&id)?;
+                        return Err(CliError::Aborted(format!(
+                            "Kept the pinned key for {}",
+                            peer
+                        )));
+                    }
+                    cipher.accept_peer_key(&id)?;
+                }
+                Err(e) => return Err(e.into()),
+            }
+
+            if alias.is_some() {
+                cipher.set_peer_alias(&id, alias.as_deref())?;
+            }
+            if let Some(announcement) = announcement {
+                let announcement = IdentityAnnouncement::decode(&announcement)?;
+                cipher.set_peer_signing_key(&id, &announcement)?;
+            }
+
+            let info = cipher.peer_info(&id)?;
+            println!(
+                "{} {}",
+                id.bright_yellow(),
+                info.fingerprint.unwrap_or_default()
+            );
+        }
+        PeerCommand::List => {
+            for info in cipher.list_peers()? {
+                let verification = match info.verification {
+                    Verification::Unverified => "unverified".normal(),
+                    Verification::Verified => "verified".green(),
+                    Verification::KeyChanged => "key changed".red().bold(),
+                };
+
+                println!(
+                    "{:<20} {:<40} {}{}",
+                    match &info.alias {
+                        Some(alias) => format!("{} ({})", info.id, alias),
+                        None => info.id.clone(),
+                    },
+                    info.fingerprint.as_deref().unwrap_or("-"),
+                    verification,
+                    if info.pending_fingerprint.is_some() {
+                        "  [key change pending]".red().to_string()
+                    } else {
+                        String::new()
+                    }
+                );
+            }
+        }
+        PeerCommand::Remove { id } => {
+            if !cipher.has_peer(&id)? {
+                return Err(CipherError::PeerNotFound(id).into());
+            }
+            cipher.remove_peer(&id)?;
+            println!("Removed {}.", id);
+        }
+        PeerCommand::Verify { id, code } => {
+            let number = cipher.safety_number(&id)?;
+            console::safety::print_safety_number(&id, &number);
+
+            let matches = match code {
+                Some(code) => number.matches(&code),
+                None => Confirm::with_theme(&ColorfulTheme::default())
+                    .with_prompt("Do the safety numbers match?")
+                    .default(false)
+                    .interact()?,
+            };
+
+            if !matches {
+                cipher.mark_unverified(&id)?;
+                return Err(CliError::Aborted(format!(
+                    "Safety numbers do not match; {} is not verified",
+                    id
+                )));
+            }
+
+            cipher.mark_verified(&id)?;
+            println!("{} is now {}.", id.bright_yellow(), "verified".green());
+        }
+    }
+
+    Ok(())
+}
+
+fn open_input(path: &Option<PathBuf>) -> Result<Box<dyn Read>, CliError> {
+    Ok(match path {
+        Some(path) => Box::new(File::open(path)?),
+        None => Box::new(io::stdin().lock()),
+    })
+}
+
+fn open_output(path: &Option<PathBuf>) -> Result<Box<dyn Write>, CliError> {
+    Ok(match path {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout().lock()),
+    })
+}
+
+fn encrypt(profile: &Profile, args: CryptArgs) -> Result<(), CliError> {
+    let mut cipher = open_cipher(profile)?;
+
+    if let Some(message) = &args.message {
+        println!("{}", cipher.encrypt_text(message, &args.peer)?);
+        return Ok(());
+    }
+
+    let mut input = open_input(&args.input)?;
+    let mut encryptor = cipher.encrypt_stream(open_output(&args.output)?, &args.peer)?;
+    io::copy(&mut input, &mut encryptor)?;
+    encryptor.finish()?.flush()?;
+
+    Ok(())
+}
+
+fn decrypt(profile: &Profile, args: CryptArgs) -> Result<(), CliError> {
+    let mut cipher = open_cipher(profile)?;
+
+    if let Some(message) = &args.message {
+        println!("{}", cipher.decrypt_text(message.trim(), &args.peer)?);
+        return Ok(());
+    }
+
+    let mut decryptor = cipher.decrypt_stream(open_input(&args.input)?, &args.peer)?;
+    let mut output = open_output(&args.output)?;
+    // Decryption errors come back wrapped in `io::Error`.
+    io::copy(&mut decryptor, &mut output).map_err(CipherError::from)?;
+    output.flush()?;
+
+    Ok(())
+}
+
+/// Opens `text` in `$EDITOR`; `None` if it was closed without saving.
+fn edit_text(text: &str) -> Result<Option<String>, CliError> {
+    Ok(Editor::new().extension(".md").edit(text)?)
+}
+
+fn print_note_summary(entry: &NoteSummary) {
+    let tags: Vec<String> = entry.tags.iter().map(|tag| format!("#{}", tag)).collect();
+    println!(
+        "{}  {} {}{}",
+        entry.id.bright_yellow(),
+        entry.title,
+        tags.join(" ").cyan(),
+        if entry.conflicted {
+            "  [conflict]".red().to_string()
+        } else {
+            String::new()
+        }
+    );
+}
+
+fn note(profile: &Profile, command: NoteCommand) -> Result<(), CliError> {
+    let mut cipher = open_cipher(profile)?;
+    let mut vault = NotesVault::open(profile.notes_store()?, &cipher)?;
+
+    match command {
+        NoteCommand::New { title, tags, body } => {
+            let body = match body {
+                Some(body) => body,
+                None => edit_text("")?
+                    .ok_or_else(|| CliError::Aborted("Note not saved".into()))?,
+            };
+
+            let note = vault.create(&title, &body, &tags)?;
+            println!("Created note {}.", note.id.bright_yellow());
+        }
+        NoteCommand::Edit {
+            id,
+            title,
+            tags,
+            body,
+        } => {
+            let mut note = vault.get(&id)?;
+            let edit_body = title.is_none() && tags.is_empty() && body.is_none();
+
+            if let Some(title) = title {
+                note.title = title;
+            }
+            if !tags.is_empty() {
+                note.tags = tags;
+            }
+            if let Some(body) = body {
+                note.body = body;
+            }
+            if edit_body {
+                match edit_text(&note.body)? {
+                    Some(body) if body != note.body => note.body = body,
+                    _ => {
+                        println!("No changes.");
+                        return Ok(());
+                    }
+                }
+            }
+
+            vault.update(note)?;
+            println!("Saved note {}.", id.bright_yellow());
+        }
+        NoteCommand::Show { id } => {
+            let note = vault.get(&id)?;
+            if note.conflicted {
+                println!("{}", "This note has unresolved merge conflicts.".red());
+            }
+            println!("# {}", note.title.bold());
+            if !note.tags.is_empty() {
+                let tags: Vec<String> = note.tags.iter().map(|tag| format!("#{}", tag)).collect();
+                println!("{}", tags.join(" ").cyan());
+            }
+            println!(
+                "{}",
+                format!("updated {}", note.updated_at.format("%Y-%m-%d %H:%M")).dimmed()
+            );
+            if !note.shared_with.is_empty() {
+                println!("{}", format!("shared with {}", note.shared_with.join(", ")).dimmed());
+            }
+            println!();
+            println!("{}", note.body);
+        }
+        NoteCommand::List { tag } => {
+            let notes = match tag {
+                Some(tag) => vault.list_tagged(&tag)?,
+                None => vault.list()?,
+            };
+            for entry in notes {
+                print_note_summary(&entry);
+            }
+        }
+        NoteCommand::Search { query } => {
+            for entry in vault.search(&query)? {
+                print_note_summary(&entry);
+            }
+        }
+        NoteCommand::Delete { id } => {
+            vault.delete(&id)?;
+            println!("Deleted note {}.", id);
+        }
+        NoteCommand::Share { id, peer, output } => {
+            let shared = vault.share(&id, &peer, &mut cipher)?;
+            match output {
+                Some(path) => std::fs::write(path, shared)?,
+                None => println!("{}", URL_SAFE.encode(shared)),
+            }
+        }
+        NoteCommand::Import { peer, data, input } => {
+            let data = match data {
+                Some(data) => URL_SAFE
+                    .decode(data.trim())
+                    .map_err(|e| CipherError::DecryptionFailed(e.to_string()))?,
+                None => {
+                    let mut data = Vec::new();
+                    open_input(&input)?.read_to_end(&mut data)?;
+                    data
+                }
+            };
+
+            let report = vault.import_shared(&data, &peer, &mut cipher)?;
+            console::notes::print_sync_report(&peer, &report);
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads chat lines on their own thread, as the `dialoguer` prompt blocks,
+/// until `/exit` or a prompt error.
+fn spawn_prompt(user: String) -> mpsc::UnboundedReceiver<Result<String, dialoguer::Error>> {
+    let (lines, line_rx) = mpsc::unbounded_channel();
+
+    std::thread::spawn(move || loop {
+        let line = console::chat::chat_prompt(&user);
+        let done = match &line {
+            Ok(line) => line.trim().eq_ignore_ascii_case("/exit"),
+            Err(_) => true,
+        };
+        if lines.send(line).is_err() || done {
+            break;
+        }
+    });
+
+    line_rx
+}
+
+async fn chat(profile: &Profile, peer_id: &str) -> Result<(), CliError> {
+    let mut cipher = open_cipher(profile)?;
+    let peer = cipher.peer_info(peer_id)?;
+    let name = peer.alias.clone().unwrap_or_else(|| peer.id.clone());
+    let fingerprint = peer
+        .fingerprint
+        .clone()
+        .ok_or_else(|| CliError::Network(format!("No identity key recorded for {}", peer_id)))?;
+    let config = profile.config()?;
+    let user = config
+        .display_name
+        .unwrap_or_else(|| profile.name().to_string());
+
+    ctrlc::set_handler(move || {
+        println!("Bye...!");
+        std::process::exit(0);
+    })
+    .map_err(|e| CliError::Prompt(e.to_string()))?;
+
+    let mut node = start_node(&cipher, &config.listen_address).await?.spawn();
+
+    console::chat::print_ascii_banner();
+    if peer.verification == Verification::KeyChanged {
+        console::safety::print_key_changed_warning(&name);
+    }
+    println!("Looking for {} on the local network; messages wait until they show up.", name);
+    println!("/note <id> shares a note, /read <ciphertext> decrypts a pasted message,");
+    println!("/verify shows the safety number, /exit quits.");
+
+    let mut lines = spawn_prompt(user.clone());
+
+    loop {
+        tokio::select! {
+            line = lines.recv() => {
+                let msg = match line {
+                    Some(line) => line?,
+                    None => break,
+                };
+                let msg = msg.trim();
+
+                if msg.eq_ignore_ascii_case("/exit") {
+                    break;
+                }
+                if msg.is_empty() {
+                    continue;
+                }
+                if msg.eq_ignore_ascii_case("/verify") {
+                    let number = cipher.safety_number(peer_id)?;
+                    console::safety::print_safety_number(&name, &number);
+                    continue;
+                }
+                if let Some(ghost) = msg.strip_prefix("/read ") {
+                    match cipher.decrypt_text(ghost.trim(), peer_id) {
+                        Ok(text) => console::chat::print_message(&name, &text),
+                        Err(e) => eprintln!("{}", format!("Could not decrypt: {}", e).red()),
+                    }
+                    continue;
+                }
+
+                let (kind, payload) = match msg.strip_prefix("/note ") {
+                    Some(id) => {
+                        let mut vault = NotesVault::open(profile.notes_store()?, &cipher)?;
+                        match vault.share(id.trim(), peer_id, &mut cipher) {
+                            Ok(shared) => (EnvelopeKind::Note, URL_SAFE.encode(shared)),
+                            Err(e) => {
+                                eprintln!("{}", format!("Could not share note: {}", e).red());
+                                continue;
+                            }
+                        }
+                    }
+                    None => (EnvelopeKind::Text, seal_text(&mut cipher, msg, peer_id)?),
+                };
+
+                if !node.send(&fingerprint, kind, payload) {
+                    return Err(CliError::Network("The network node stopped".into()));
+                }
+            }
+            event = node.next_event() => {
+                let Some(event) = event else {
+                    return Err(CliError::Network("The network node stopped".into()));
+                };
+
+                match event {
+                    NodeEvent::Identified { fingerprint: seen, .. } if seen == fingerprint => {
+                        console::chat::clear_prompt_line();
+                        println!("{}", format!("{} is online.", name).green());
+                    }
+                    NodeEvent::Message { fingerprint, envelope, .. } => {
+                        console::chat::clear_prompt_line();
+                        let received =
+                            receive_envelope(profile, &mut cipher, &fingerprint, &envelope);
+                        if let Err(e) = received {
+                            eprintln!("{}", format!("Could not read message {}: {}", envelope.id, e).red());
+                        }
+                    }
+                    NodeEvent::Failed { error, .. } => {
+                        console::chat::clear_prompt_line();
+                        eprintln!("{}", format!("Message not delivered: {}", error).red());
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_address(address: &str) -> Result<Multiaddr, CliError> {
+    address
+        .parse()
+        .map_err(|e| CliError::Config(format!("Invalid listen address {}: {}", address, e)))
+}
+
+async fn start_node(cipher: &Cipher<ProfileStore>, address: &str) -> Result<P2PNode, CliError> {
+    let address = parse_address(address)?;
+    let mut node = P2PNode::new(cipher)
+        .await
+        .map_err(|e| CliError::Network(e.to_string()))?;
+    node.listen_on(address)
+        .map_err(|e| CliError::Network(e.to_string()))?;
+
+    Ok(node)
+}
+
+/// Registered peer with the identity `fingerprint`.
+fn peer_by_fingerprint(
+    cipher: &Cipher<ProfileStore>,
+    fingerprint: &str,
+) -> Result<Option<PeerInfo>, CliError> {
+    Ok(cipher
+        .list_peers()?
+        .into_iter()
+        .find(|peer| peer.fingerprint.as_deref() == Some(fingerprint)))
+}
+
+/// Encrypts a chat message for `peer_id` in our Double Ratchet session with
+/// it, starting one from both identity keys if there is none yet.
+fn seal_text(
+    cipher: &mut Cipher<ProfileStore>,
+    text: &str,
+    peer_id: &str,
+) -> Result<String, CliError> {
+    if !cipher.has_session(peer_id)? {
+        cipher.start_session(peer_id)?;
+    }
+
+    Ok(URL_SAFE.encode(cipher.session_encrypt(text.as_bytes(), peer_id, None)?))
+}
+
+/// Counterpart of `seal_text`; the first message of a session sets up ours.
+fn open_text(
+    cipher: &mut Cipher<ProfileStore>,
+    payload: &str,
+    peer_id: &str,
+) -> Result<String, CliError> {
+    let sealed = URL_SAFE
+        .decode(payload)
+        .map_err(|e| CipherError::DecryptionFailed(e.to_string()))?;
+    let plain = cipher.session_decrypt(&sealed, peer_id, None)?;
+
+    String::from_utf8(plain).map_err(|e| CipherError::DecryptionFailed(e.to_string()).into())
+}
+
+/// Decrypts and prints an envelope received from the network from the
+/// verified identity `fingerprint`. Notes are synced into the vault.
+fn receive_envelope(
+    profile: &Profile,
+    cipher: &mut Cipher<ProfileStore>,
+    fingerprint: &str,
+    envelope: &Envelope,
+) -> Result<(), CliError> {
+    let Some(peer) = peer_by_fingerprint(cipher, fingerprint)? else {
+        eprintln!(
+            "{}",
+            format!("Dropped a message from unknown identity {}", fingerprint).yellow()
+        );
+        return Ok(());
+    };
+    let name = peer.alias.clone().unwrap_or_else(|| peer.id.clone());
+
+    match envelope.kind {
+        EnvelopeKind::Text => {
+            let text = open_text(cipher, &envelope.payload, &peer.id)?;
+            console::chat::print_message(&name, &text);
+        }
+        EnvelopeKind::Note => {
+            let data = URL_SAFE
+                .decode(&envelope.payload)
+                .map_err(|e| CipherError::DecryptionFailed(e.to_string()))?;
+            let mut vault = NotesVault::open(profile.notes_store()?, cipher)?;
+            let report = vault.import_shared(&data, &peer.id, cipher)?;
+            console::notes::print_sync_report(&name, &report);
+        }
+        EnvelopeKind::Hello => {}
+    }
+
+    Ok(())
+}
+
+async fn listen(profile: &Profile, address: &str) -> Result<(), CliError> {
+    let mut cipher = open_cipher(profile)?;
+    let mut node = start_node(&cipher, address).await?;
+
+    loop {
+        match node.next_event().await {
+            NodeEvent::Listening(address) => println!("Listening on {}", address),
+            NodeEvent::Identified { fingerprint, .. } => {
+                if let Some(peer) = peer_by_fingerprint(&cipher, &fingerprint)? {
+                    println!("{} is online.", peer.id.bright_yellow());
+                }
+            }
+            NodeEvent::Message { fingerprint, envelope, .. } => {
+                if let Err(e) = receive_envelope(profile, &mut cipher, &fingerprint, &envelope) {
+                    eprintln!("{}", format!("Could not read message {}: {}", envelope.id, e).red());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+async fn send(profile: &Profile, args: SendArgs) -> Result<(), CliError> {
+    let mut cipher = open_cipher(profile)?;
+    let fingerprint = cipher
+        .peer_info(&args.peer)?
+        .fingerprint
+        .ok_or_else(|| CliError::Network(format!("No identity key recorded for {}", args.peer)))?;
+
+    let (kind, payload) = match &args.note {
+        Some(id) => {
+            let mut vault = NotesVault::open(profile.notes_store()?, &cipher)?;
+            let shared = vault.share(id, &args.peer, &mut cipher)?;
+            (EnvelopeKind::Note, URL_SAFE.encode(shared))
+        }
+        None => {
+            let message = args.message.as_deref().unwrap_or_default();
+            (EnvelopeKind::Text, seal_text(&mut cipher, message, &args.peer)?)
+        }
+    };
+
+    let mut node = start_node(&cipher, &profile.config()?.listen_address).await?;
+    let deliver = async {
+        let mut payload = Some(payload);
+        let mut sent = None;
+
+        loop {
+            match node.next_event().await {
+                NodeEvent::Identified { peer, fingerprint: seen } if seen == fingerprint => {
+                    if let Some(payload) = payload.take() {
+                        sent = Some(node.send(peer, kind, payload));
+                    }
+                }
+                NodeEvent::Delivered { id, .. } if Some(id) == sent => return Ok(()),
+                NodeEvent::Failed { id, error, .. } if Some(id) == sent => {
+                    return Err(CliError::Network(error));
+                }
+                _ => {}
+            }
+        }
+    };
+
+    tokio::time::timeout(Duration::from_secs(args.timeout), deliver)
+        .await
+        .map_err(|_| {
+            CliError::Network(format!("{} did not answer within {}s", args.peer, args.timeout))
+        })??;
+
+    println!("Delivered to {}.", args.peer.bright_yellow());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Mutex, MutexGuard};
+
+    /// Points the data and config directories at a fresh temporary directory
+    /// and keeps remembered keys out of the OS keyring while it lives. Tests
+    /// that change the environment hold one, so they never overlap.
+    pub(super) struct TestHome {
+        pub path: PathBuf,
+        _guard: MutexGuard<'static, ()>,
+    }
+
+    impl TestHome {
+        pub fn new(name: &str) -> Self {
+            static LOCK: Mutex<()> = Mutex::new(());
+            let guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+            let path = env::temp_dir().join(format!("nyx-cli-{}-{}", std::process::id(), name));
+            let _ = std::fs::remove_dir_all(&path);
+            env::set_var("HOME", &path);
+            env::set_var("XDG_DATA_HOME", path.join("data"));
+            env::set_var("XDG_CONFIG_HOME", path.join("config"));
+            env::set_var(SECRETS_ENV, "file");
+            env::remove_var(PASSWORD_ENV);
+
+            Self {
+                path,
+                _guard: guard,
+            }
+        }
+    }
+
+    impl Drop for TestHome {
+        fn drop(&mut self) {
+            env::remove_var(PASSWORD_ENV);
+            env::remove_var(SECRETS_ENV);
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+
+    #[test]
+    fn exit_codes_tell_errors_apart() {
+        let codes = [
+            CliError::Cipher(CipherError::WrongPassword).exit_code(),
+            CliError::Cipher(CipherError::PeerNotFound("bob".into())).exit_code(),
+            CliError::Cipher(CipherError::PeerKeyUnknown("bob".into())).exit_code(),
+            CliError::Cipher(CipherError::InvalidSignature).exit_code(),
+            CliError::Cipher(CipherError::KeystoreCorrupted("identity".into())).exit_code(),
+            CliError::Aborted("Kept the pinned key for bob".into()).exit_code(),
+        ];
+        assert_eq!(
+            codes,
+            [
+                exit_code::AUTH,
+                exit_code::NOT_FOUND,
+                exit_code::NOT_FOUND,
+                exit_code::UNTRUSTED,
+                exit_code::CORRUPTED,
+                exit_code::ABORTED,
+            ]
+        );
+        assert_eq!(CliError::Config("bad".into()).exit_code(), exit_code::USAGE);
+    }
+
+    fn create(name: &str, store: Option<StoreBackend>) -> Result<(), CliError> {
+        let args = InitArgs {
+            remember: false,
+            store,
+        };
+        let (mut config, path) = (Config::default(), Config::default_path()?);
+        let active = Profile::new(Profile::DEFAULT)?;
+
+        let command = ProfileCommand::Create {
+            name: name.into(),
+            init: args,
+        };
+        profiles(&mut config, &path, &active, command)
+    }
+
+    #[test]
+    fn profiles_are_created_switched_and_deleted() {
+        let _home = TestHome::new("profiles");
+        env::set_var(PASSWORD_ENV, "pw");
+        let config_path = Config::default_path().unwrap();
+        let mut config = Config::load(&config_path).unwrap();
+        let alice = Profile::new("alice").unwrap();
+
+        create("alice", None).unwrap();
+        create("bob", Some(StoreBackend::Sqlite)).unwrap();
+        assert!(matches!(create("alice", None), Err(CliError::AlreadyInitialized(_))));
+        assert_eq!(Profile::list().unwrap(), ["alice", "bob"]);
+        assert!(alice.exists().unwrap());
+        assert_eq!(Profile::new("bob").unwrap().config().unwrap().store, StoreBackend::Sqlite);
+
+        let switch = |name: &str| ProfileCommand::Switch { name: name.into() };
+        profiles(&mut config, &config_path, &alice, switch("bob")).unwrap();
+        assert_eq!(Config::load(&config_path).unwrap().profile.as_deref(), Some("bob"));
+        assert!(matches!(
+            profiles(&mut config, &config_path, &alice, switch("carol")),
+            Err(CliError::NotInitialized(_))
+        ));
+
+        let delete = |name: &str| ProfileCommand::Delete {
+            name: name.into(),
+            yes: true,
+        };
+        assert!(matches!(
+            profiles(&mut config, &config_path, &alice, delete("alice")),
+            Err(CliError::ProfileInUse(_))
+        ));
+        let bob = Profile::new("bob").unwrap();
+        profiles(&mut config, &config_path, &alice, delete("bob")).unwrap();
+        assert!(!bob.exists().unwrap());
+        assert!(!bob.data_dir().unwrap().exists());
+        assert!(!bob.config_path().unwrap().exists());
+        assert_eq!(Profile::list().unwrap(), ["alice"]);
+        assert!(matches!(
+            profiles(&mut config, &config_path, &alice, delete("bob")),
+            Err(CliError::NotInitialized(_))
+        ));
+    }
+
+    #[test]
+    fn exists_does_not_wait_for_an_open_keystore() {
+        let _home = TestHome::new("exists");
+        env::set_var(PASSWORD_ENV, "pw");
+        let profile = Profile::new(Profile::DEFAULT).unwrap();
+        assert!(!profile.exists().unwrap());
+
+        create(Profile::DEFAULT, None).unwrap();
+        let open = open_cipher(&profile).unwrap();
+        assert!(profile.exists().unwrap());
+        drop(open);
+    }
+
+    #[test]
+    fn unlock_of_a_missing_profile_writes_no_config() {
+        let _home = TestHome::new("unlock-missing");
+        let profile = Profile::new("ghost").unwrap();
+
+        assert!(matches!(unlock(&profile), Err(CliError::NotInitialized(_))));
+        assert!(matches!(lock(&profile), Err(CliError::NotInitialized(_))));
+        assert!(!profile.config_path().unwrap().exists());
+    }
+
+    #[test]
+    fn stale_remembered_key_falls_back_to_the_prompt() {
+        let _home = TestHome::new("stale-key");
+        env::set_var(PASSWORD_ENV, "pw");
+        let profile = Profile::new(Profile::DEFAULT).unwrap();
+        let args = InitArgs {
+            remember: true,
+            store: None,
+        };
+        init(&profile, &args).unwrap();
+        env::remove_var(PASSWORD_ENV);
+
+        // The remembered key opens the keystore without asking.
+        let never = |_: &str| -> Result<Zeroizing<String>, CliError> { panic!("prompted") };
+        drop(open_cipher_with(&profile, never).unwrap());
+
+        // Replaced, as if the password had been changed on another machine.
+        let secrets = profile.secret_manager().unwrap();
+        secrets.set_secret(&URL_SAFE.encode([9u8; 32])).unwrap();
+
+        let mut prompted = false;
+        let cipher = open_cipher_with(&profile, |_| {
+            prompted = true;
+            Ok(Zeroizing::new("pw".to_string()))
+        })
+        .unwrap();
+        assert!(prompted);
+        assert!(cipher.is_password_protected());
+        drop(cipher);
+
+        assert!(!profile.config().unwrap().remember_key);
+        assert!(matches!(secrets.get_secret(), Err(KeyringError::NotFound)));
+    }
+}
@@ -0,0 +1,281 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::config::{ProfileConfig, StoreBackend};
+use super::{CliError, SECRETS_ENV, SECRET_VAR_PREFIX};
+use crate::crypto::cipher::{keys, CipherError, Store, StoreOp};
+use crate::crypto::file_store::FileStore;
+use crate::crypto::sqlite_store::SqliteStore;
+use crate::vault::backend::{EncryptedFileBackend, EnvBackend};
+use crate::vault::manager::{KeyringError, SecretManager};
+
+/// A named identity on this machine. Each profile has its own keystore
+/// under `<data-dir>/nyx/profiles/<name>`, its own `ProfileConfig` and its
+/// own OS keyring entry, so nothing is shared between two profiles.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    name: String,
+}
+
+impl Profile {
+    pub const DEFAULT: &'static str = "default";
+    /// Keyring service every profile entry is filed under; the user part is
+    /// `profile:<name>`.
+    pub const KEYRING_SERVICE: &'static str = "nyx";
+
+    pub fn new(name: &str) -> Result<Self, CliError> {
+        // Validates the name.
+        FileStore::profile_dir(name)?;
+
+        Ok(Self { name: name.into() })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn data_dir(&self) -> Result<PathBuf, CliError> {
+        Ok(FileStore::profile_dir(&self.name)?)
+    }
+
+    /// Keystore in the backend the profile config selects.
+    pub fn store(&self) -> Result<ProfileStore, CliError> {
+        Ok(match self.config()?.store {
+            StoreBackend::Files => ProfileStore::Files(FileStore::for_profile(&self.name)?),
+            StoreBackend::Sqlite => {
+                let path = self.data_dir()?.join(SqliteStore::FILE_NAME);
+                ProfileStore::Sqlite(SqliteStore::new(path))
+            }
+        })
+    }
+
+    /// Store of the notes vault, inside the keystore directory.
+    pub fn notes_store(&self) -> Result<FileStore, CliError> {
+        Ok(FileStore::new(self.data_dir()?.join("notes")))
+    }
+
+    pub fn config_path(&self) -> Result<PathBuf, CliError> {
+        ProfileConfig::path(&self.name)
+    }
+
+    pub fn config(&self) -> Result<ProfileConfig, CliError> {
+        ProfileConfig::load(&self.config_path()?)
+    }
+
+    /// Keyring entry that remembers the unlocked keystore key of this
+    /// profile. Without a usable keyring it goes to an `EncryptedFileBackend`
+    /// at `<data-dir>/nyx/profiles/<name>.secrets`, keyed by
+    /// `<config-dir>/nyx/profiles/<name>.key`. `SECRETS_ENV` replaces the
+    /// keyring with that file alone or with the read-only `EnvBackend`.
+    pub fn secret_manager(&self) -> Result<SecretManager, CliError> {
+        let user = format!("profile:{}", self.name);
+        let file =
+            EncryptedFileBackend::with_key_file(self.secrets_path()?, self.secrets_key_path()?);
+
+        let manager = match env::var(SECRETS_ENV).unwrap_or_default().as_str() {
+            "" | "keyring" => SecretManager::new(Self::KEYRING_SERVICE, &user),
+            "env" => SecretManager::with_backend(
+                Self::KEYRING_SERVICE,
+                &user,
+                EnvBackend::new(SECRET_VAR_PREFIX),
+            ),
+            "file" => return Ok(SecretManager::with_backend(Self::KEYRING_SERVICE, &user, file)),
+            other => {
+                return Err(CliError::Config(format!(
+                    "{} must be keyring, file or env, not {:?}",
+                    SECRETS_ENV, other
+                )))
+            }
+        };
+
+        Ok(manager.with_fallback(file))
+    }
+
+    fn secrets_path(&self) -> Result<PathBuf, CliError> {
+        Ok(FileStore::profiles_dir()?.join(format!("{}.secrets", self.name)))
+    }
+
+    fn secrets_key_path(&self) -> Result<PathBuf, CliError> {
+        Ok(self.config_path()?.with_extension("key"))
+    }
+
+    /// Whether the profile has a keystore with an identity in it. Does not
+    /// take the keystore lock, so it also answers while the profile is open
+    /// elsewhere.
+    pub fn exists(&self) -> Result<bool, CliError> {
+        let mut store = self.store()?;
+        if !store.path().exists() {
+            return Ok(false);
+        }
+
+        // Entry files are readable without the lock; SQLite only needs a
+        // connection and does its own locking.
+        if let ProfileStore::Sqlite(store) = &mut store {
+            store.setup()?;
+        }
+        Ok(store.has(keys::IDENTITY)?)
+    }
+
+    /// Names of all profiles with a keystore directory, sorted.
+    pub fn list() -> Result<Vec<String>, CliError> {
+        let dir = FileStore::profiles_dir()?;
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str() {
+                if FileStore::profile_dir(name).is_ok() {
+                    names.push(name.to_string());
+                }
+            }
+        }
+
+        names.sort();
+        Ok(names)
+    }
+
+    /// Deletes the keystore, config and keyring entry of this profile. Fails
+    /// while another process has a file keystore open.
+    pub fn delete(&self) -> Result<(), CliError> {
+        let mut store = self.store()?;
+        if store.path().exists() {
+            // Holding the lock keeps other instances out while deleting.
+            store.setup()?;
+            fs::remove_dir_all(self.data_dir()?)?;
+        }
+
+        match self.secret_manager()?.delete_secret() {
+            Ok(()) | Err(KeyringError::NotFound) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        for path in [self.config_path()?, self.secrets_path()?, self.secrets_key_path()?] {
+            match fs::remove_file(path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Keystore of a profile, in either backend.
+pub enum ProfileStore {
+    Files(FileStore),
+    Sqlite(SqliteStore),
+}
+
+impl ProfileStore {
+    /// Keystore directory or database file.
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Files(store) => store.path(),
+            Self::Sqlite(store) => store.path(),
+        }
+    }
+
+    fn inner(&self) -> &dyn Store {
+        match self {
+            Self::Files(store) => store,
+            Self::Sqlite(store) => store,
+        }
+    }
+
+    fn inner_mut(&mut self) -> &mut dyn Store {
+        match self {
+            Self::Files(store) => store,
+            Self::Sqlite(store) => store,
+        }
+    }
+}
+
+impl Store for ProfileStore {
+    fn setup(&mut self) -> Result<(), CipherError> {
+        self.inner_mut().setup()
+    }
+
+    fn put(&mut self, id: &str, data: Vec<u8>) -> Result<(), CipherError> {
+        self.inner_mut().put(id, data)
+    }
+
+    fn get(&self, id: &str) -> Result<Option<Vec<u8>>, CipherError> {
+        self.inner().get(id)
+    }
+
+    fn delete(&mut self, id: &str) -> Result<(), CipherError> {
+        self.inner_mut().delete(id)
+    }
+
+    fn has(&self, id: &str) -> Result<bool, CipherError> {
+        self.inner().has(id)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>, CipherError> {
+        self.inner().list(prefix)
+    }
+
+    fn apply(&mut self, ops: Vec<StoreOp>) -> Result<(), CipherError> {
+        self.inner_mut().apply(ops)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::tests::TestHome;
+    use crate::crypto::cipher::Cipher;
+
+    #[test]
+    fn sqlite_profile_store_holds_a_keystore() {
+        let dir = std::env::temp_dir().join(format!("nyx-profile-store-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = || ProfileStore::Sqlite(SqliteStore::new(dir.join(SqliteStore::FILE_NAME)));
+
+        let mut cipher = Cipher::new(store());
+        cipher.init(Some("pw")).unwrap();
+        let fingerprint = cipher.export_fingerprint().unwrap();
+        drop(cipher);
+
+        let mut cipher = Cipher::new(store());
+        cipher.init(Some("pw")).unwrap();
+        assert_eq!(cipher.export_fingerprint().unwrap(), fingerprint);
+        assert!(dir.join(SqliteStore::FILE_NAME).exists());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn secrets_env_selects_where_keys_are_remembered() {
+        let _home = TestHome::new("secrets-env");
+        let profile = Profile::new("ci").unwrap();
+
+        profile.secret_manager().unwrap().set_secret("from file").unwrap();
+        assert!(profile.secrets_path().unwrap().exists());
+        assert_eq!(profile.secret_manager().unwrap().get_secret().unwrap(), "from file");
+
+        // The environment wins over the file, and writes go to the file.
+        env::set_var(SECRETS_ENV, "env");
+        env::set_var("NYX_SECRET_NYX_PROFILE_CI", "from env");
+        let manager = profile.secret_manager().unwrap();
+        assert_eq!(manager.get_secret().unwrap(), "from env");
+        env::remove_var("NYX_SECRET_NYX_PROFILE_CI");
+        assert_eq!(manager.get_secret().unwrap(), "from file");
+        manager.delete_secret().unwrap();
+        assert!(!profile.secrets_path().unwrap().exists());
+
+        env::set_var(SECRETS_ENV, "vault");
+        assert!(matches!(profile.secret_manager(), Err(CliError::Config(_))));
+    }
+}
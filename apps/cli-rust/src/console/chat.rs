@@ -1,5 +1,5 @@
 use colored::*;
-use dialoguer::{Input, theme::ColorfulTheme};
+use dialoguer::{console::Term, Input, theme::ColorfulTheme};
 
 pub fn print_ascii_banner() {
     let banner = r#"
@@ -14,11 +14,16 @@ pub fn print_ascii_banner() {
     println!("{}", banner.bright_cyan());
 }
 
-pub fn chat_prompt(user: &str) -> String {
+pub fn chat_prompt(user: &str) -> Result<String, dialoguer::Error> {
     Input::with_theme(&ColorfulTheme::default())
         .with_prompt(format!("{} >", user.bright_green()))
         .interact_text()
-        .unwrap()
+}
+
+/// Clears the line the prompt is on, so a message printed while it waits
+/// for input starts on a clean line.
+pub fn clear_prompt_line() {
+    let _ = Term::stdout().clear_line();
 }
 
 pub fn print_message(sender: &str, msg: &str) {
@@ -0,0 +1,85 @@
+use colored::*;
+
+use crate::vault::md::{Conflict, SyncOutcome, SyncReport};
+use crate::vault::merge::{CONFLICT_END, CONFLICT_SEPARATOR, CONFLICT_START};
+
+/// Prints what syncing a note shared by `peer` did, with every conflict and
+/// the conflicting regions of the body.
+pub fn print_sync_report(peer: &str, report: &SyncReport) {
+    let note = &report.note;
+    let title = note.title.bold();
+
+    let conflicts = match &report.outcome {
+        SyncOutcome::Added => {
+            println!("Added {} ({}) from {}.", title, note.id.bright_yellow(), peer);
+            return;
+        }
+        SyncOutcome::UpToDate => {
+            println!("{} is up to date.", title);
+            return;
+        }
+        SyncOutcome::FastForward => {
+            println!("Updated {} with the changes of {}.", title, peer);
+            return;
+        }
+        SyncOutcome::Merged { conflicts } => conflicts,
+    };
+
+    if conflicts.is_empty() {
+        println!("Merged the changes of {} into {}.", peer, title);
+        return;
+    }
+
+    println!(
+        "{}",
+        format!("Merged the changes of {} into {} with conflicts:", peer, note.title)
+            .bold()
+            .red()
+    );
+    for conflict in conflicts {
+        match conflict {
+            Conflict::Title { kept, dropped } => {
+                println!("  title: kept {:?}, dropped {:?}", kept, dropped);
+            }
+            Conflict::Body { regions } => {
+                println!("  body: {} region(s) changed on both sides", regions);
+            }
+        }
+    }
+
+    if note.conflicted {
+        print_conflict_regions(&note.body);
+        println!(
+            "Resolve them with `nyx note edit {}` and remove the markers.",
+            note.id
+        );
+    }
+}
+
+/// Prints only the conflicting regions of `body`, ours in green and theirs
+/// in red.
+fn print_conflict_regions(body: &str) {
+    let mut side = None;
+
+    for line in body.lines() {
+        match line {
+            CONFLICT_START => {
+                println!("{}", line.bold());
+                side = Some(true);
+            }
+            CONFLICT_SEPARATOR if side.is_some() => {
+                println!("{}", line.bold());
+                side = Some(false);
+            }
+            CONFLICT_END => {
+                println!("{}", line.bold());
+                side = None;
+            }
+            _ => match side {
+                Some(true) => println!("{}", line.green()),
+                Some(false) => println!("{}", line.red()),
+                None => {}
+            },
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use colored::*;
+use dialoguer::{theme::ColorfulTheme, Confirm};
+use qrcode::render::unicode::Dense1x2;
+use qrcode::QrCode;
+
+use crate::crypto::safety::SafetyNumber;
+
+pub fn print_safety_number(peer: &str, number: &SafetyNumber) {
+    println!("Safety number with {}:", peer.bright_yellow());
+
+    for row in number.groups().chunks(4) {
+        println!("    {}", row.join("  ").bright_white());
+    }
+
+    match QrCode::new(number.qr_payload()) {
+        Ok(code) => {
+            let image = code
+                .render::<Dense1x2>()
+                .dark_color(Dense1x2::Light)
+                .light_color(Dense1x2::Dark)
+                .build();
+            println!("{}", image);
+        }
+        Err(e) => println!("{}", format!("Could not render QR code: {}", e).red()),
+    }
+
+    println!(
+        "Compare these digits with {} in person or over a trusted channel.",
+        peer
+    );
+}
+
+pub fn print_key_changed_warning(peer: &str) {
+    println!(
+        "{}",
+        format!(
+            "WARNING: the identity key of {} changed since you verified it. \
+             Compare safety numbers again before trusting this conversation.",
+            peer
+        )
+        .bold()
+        .red()
+    );
+}
+
+/// Shows a changed identity key and asks the user to accept it. Anything but
+/// an explicit yes keeps the pinned key.
+pub fn confirm_key_change(peer: &str, pinned: &str, offered: &str) -> bool {
+    let rule = "=".repeat(64);
+    println!("{}", rule.red());
+    println!(
+        "{}",
+        format!("  IDENTITY KEY OF {} HAS CHANGED", peer.to_uppercase())
+            .bold()
+            .red()
+    );
+    println!("{}", rule.red());
+    println!("  pinned:  {}", pinned.bright_white());
+    println!("  offered: {}", offered.bright_yellow());
+    println!();
+    println!("  This happens when {} reinstalled or moved to a new device,", peer);
+    println!("  but it is also what an attacker in the middle looks like.");
+    println!("  Confirm the new key with {} over a trusted channel first.", peer);
+    println!("{}", rule.red());
+
+    Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt(format!("Trust the new key for {}?", peer))
+        .default(false)
+        .interact()
+        .unwrap_or(false)
+}
@@ -0,0 +1,330 @@
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, KeyInit, Nonce, OsRng, Payload},
+    Aes256Gcm,
+};
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use super::cipher::{CipherError, Verification};
+use super::kdf::PasswordKdf;
+
+/// Portable, passphrase-encrypted copy of an identity and its peers.
+///
+/// ```text
+/// magic "NYXBAK" (6) | format version (1) | KDF length u16 BE (2) | KDF JSON
+///     | salt (32) | nonce (12) | ciphertext | SHA-256 of all previous bytes (32)
+/// ```
+///
+/// The key is derived from the passphrase with the `PasswordKdf` in the
+/// header, and the ciphertext is AES-256-GCM over the JSON `BackupPayload`
+/// with everything before it as associated data. The trailing checksum only
+/// tells a damaged file apart from a wrong passphrase.
+pub const MAGIC: &[u8; 6] = b"NYXBAK";
+pub const FORMAT_VERSION: u8 = 1;
+
+const SALT_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const CHECKSUM_LEN: usize = 32;
+
+#[derive(Zeroize, ZeroizeOnDrop, serde::Serialize, serde::Deserialize)]
+pub(crate) struct BackupIdentity {
+    pub private_key: Vec<u8>,
+    pub public_key: Vec<u8>,
+    #[serde(default)]
+    pub signing_key: Option<Vec<u8>>,
+}
+
+#[derive(Zeroize, ZeroizeOnDrop, serde::Serialize, serde::Deserialize)]
+pub(crate) struct BackupPeer {
+    pub id: String,
+    /// Raw ECDH secret, so peers without a recorded public key survive too.
+    pub secret: Vec<u8>,
+    #[serde(default)]
+    pub public_key: Option<Vec<u8>>,
+    #[zeroize(skip)]
+    #[serde(default)]
+    pub added_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub alias: Option<String>,
+    #[zeroize(skip)]
+    #[serde(default)]
+    pub verification: Verification,
+    #[serde(default)]
+    pub kdf_version: u8,
+    #[serde(default)]
+    pub signing_key: Option<Vec<u8>>,
+}
+
+/// What a backup holds. Ratchet sessions and prekeys are left out: a session
+/// must never run on two machines, and both are re-established on demand.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(crate) struct BackupPayload {
+    pub created_at: DateTime<Utc>,
+    pub identity: BackupIdentity,
+    #[serde(default)]
+    pub peers: Vec<BackupPeer>,
+    #[serde(default)]
+    pub settings: BTreeMap<String, String>,
+}
+
+fn invalid(message: &str) -> CipherError {
+    CipherError::InvalidBackup(message.into())
+}
+
+/// Encrypts `payload` under `passphrase` into the backup format.
+pub(crate) fn seal(payload: &BackupPayload, passphrase: &str) -> Result<Vec<u8>, CipherError> {
+    seal_with(payload, passphrase, &PasswordKdf::CURRENT)
+}
+
+fn seal_with(
+    payload: &BackupPayload,
+    passphrase: &str,
+    password_kdf: &PasswordKdf,
+) -> Result<Vec<u8>, CipherError> {
+    let kdf = serde_json::to_vec(password_kdf)
+        .map_err(|e| CipherError::StorageError(e.to_string()))?;
+
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+
+    let mut out = Vec::from(&MAGIC[..]);
+    out.push(FORMAT_VERSION);
+    out.extend_from_slice(&(kdf.len() as u16).to_be_bytes());
+    out.extend_from_slice(&kdf);
+    out.extend_from_slice(&salt);
+    out.extend_from_slice(&nonce);
+
+    let key = Zeroizing::new(password_kdf.derive(passphrase, &salt)?);
+    let plain = Zeroizing::new(
+        serde_json::to_vec(payload).map_err(|e| CipherError::StorageError(e.to_string()))?,
+    );
+
+    let ciphertext = Aes256Gcm::new_from_slice(key.as_ref())?
+        .encrypt(
+            Nonce::<Aes256Gcm>::from_slice(&nonce),
+            Payload {
+                msg: &plain,
+                aad: &out,
+            },
+        )
+        .map_err(|e| CipherError::EncryptionFailed(e.to_string()))?;
+    out.extend_from_slice(&ciphertext);
+
+    let checksum = Sha256::digest(&out);
+    out.extend_from_slice(&checksum);
+    Ok(out)
+}
+
+/// Checks and decrypts a backup written by any supported format version.
+pub(crate) fn open(data: &[u8], passphrase: &str) -> Result<BackupPayload, CipherError> {
+    if data.len() < MAGIC.len() + 1 || &data[..MAGIC.len()] != MAGIC {
+        return Err(invalid("Not a Nyx backup"));
+    }
+
+    match data[MAGIC.len()] {
+        1 => open_v1(data, passphrase),
+        other => Err(CipherError::UnsupportedVersion(other)),
+    }
+}
+
+fn field<'a>(body: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], CipherError> {
+    let field = body
+        .get(*pos..*pos + len)
+        .ok_or_else(|| invalid("Backup truncated"))?;
+    *pos += len;
+    Ok(field)
+}
+
+fn open_v1(data: &[u8], passphrase: &str) -> Result<BackupPayload, CipherError> {
+    if data.len() < CHECKSUM_LEN {
+        return Err(invalid("Backup truncated"));
+    }
+    let (body, checksum) = data.split_at(data.len() - CHECKSUM_LEN);
+    if Sha256::digest(body).as_slice() != checksum {
+        return Err(invalid("Checksum mismatch"));
+    }
+
+    let mut pos = MAGIC.len() + 1;
+    let kdf_len = field(body, &mut pos, 2)?;
+    let kdf_len = u16::from_be_bytes([kdf_len[0], kdf_len[1]]) as usize;
+    let kdf: PasswordKdf = serde_json::from_slice(field(body, &mut pos, kdf_len)?)
+        .map_err(|_| invalid("Unknown key derivation"))?;
+    // The header is unauthenticated until the key is derived.
+    if !kdf.is_within_limits() {
+        return Err(invalid("Key derivation too expensive"));
+    }
+    let salt = field(body, &mut pos, SALT_LEN)?;
+    let nonce = field(body, &mut pos, NONCE_LEN)?;
+    let (header, ciphertext) = body.split_at(pos);
+
+    let key = Zeroizing::new(kdf.derive(passphrase, salt)?);
+    let plain = Zeroizing::new(
+        Aes256Gcm::new_from_slice(key.as_ref())?
+            .decrypt(
+                Nonce::<Aes256Gcm>::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map_err(|_| CipherError::WrongPassword)?,
+    );
+
+    serde_json::from_slice(&plain).map_err(|_| invalid("Unreadable backup contents"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheap enough for tests.
+    const TEST_KDF: PasswordKdf = PasswordKdf::Argon2id {
+        memory_kib: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    fn payload() -> BackupPayload {
+        BackupPayload {
+            created_at: Utc::now(),
+            identity: BackupIdentity {
+                private_key: vec![1; 32],
+                public_key: vec![2; 65],
+                signing_key: Some(vec![3; 32]),
+            },
+            peers: vec![BackupPeer {
+                id: "bob".into(),
+                secret: vec![4; 32],
+                public_key: Some(vec![5; 65]),
+                added_at: Some(Utc::now()),
+                alias: Some("Bob".into()),
+                verification: Verification::Verified,
+                kdf_version: 1,
+                signing_key: None,
+            }],
+            settings: BTreeMap::from([("theme".to_string(), "dark".to_string())]),
+        }
+    }
+
+    /// Flips a ciphertext byte and fixes the checksum, as an attacker would.
+    fn tamper(backup: &[u8], index: usize) -> Vec<u8> {
+        let mut body = backup[..backup.len() - CHECKSUM_LEN].to_vec();
+        body[index] ^= 1;
+        let checksum = Sha256::digest(&body);
+        body.extend_from_slice(&checksum);
+        body
+    }
+
+    #[test]
+    fn round_trips() {
+        let original = payload();
+        let backup = seal_with(&original, "correct horse", &TEST_KDF).unwrap();
+        assert_eq!(&backup[..MAGIC.len()], MAGIC);
+
+        let opened = open(&backup, "correct horse").unwrap();
+        assert_eq!(opened.created_at, original.created_at);
+        assert_eq!(opened.identity.private_key, original.identity.private_key);
+        assert_eq!(opened.identity.signing_key, original.identity.signing_key);
+        assert_eq!(opened.peers.len(), 1);
+        assert_eq!(opened.peers[0].id, "bob");
+        assert_eq!(opened.peers[0].alias.as_deref(), Some("Bob"));
+        assert_eq!(opened.peers[0].verification, Verification::Verified);
+        assert_eq!(opened.settings, original.settings);
+    }
+
+    #[test]
+    fn wrong_passphrase_and_tampering_do_not_open() {
+        let backup = seal_with(&payload(), "correct horse", &TEST_KDF).unwrap();
+
+        assert!(matches!(
+            open(&backup, "battery staple"),
+            Err(CipherError::WrongPassword)
+        ));
+        assert!(matches!(
+            open(&tamper(&backup, backup.len() - CHECKSUM_LEN - 1), "correct horse"),
+            Err(CipherError::WrongPassword)
+        ));
+
+        // The header is bound as associated data: a different salt or nonce
+        // does not open either.
+        let salt = MAGIC.len() + 3 + serde_json::to_vec(&TEST_KDF).unwrap().len();
+        for index in [salt, salt + SALT_LEN] {
+            assert!(matches!(
+                open(&tamper(&backup, index), "correct horse"),
+                Err(CipherError::WrongPassword)
+            ));
+        }
+    }
+
+    #[test]
+    fn damaged_files_are_told_apart() {
+        let backup = seal_with(&payload(), "correct horse", &TEST_KDF).unwrap();
+
+        let mut damaged = backup.clone();
+        damaged[20] ^= 1;
+        assert!(matches!(
+            open(&damaged, "correct horse"),
+            Err(CipherError::InvalidBackup(_))
+        ));
+        assert!(matches!(
+            open(&backup[..backup.len() - 1], "correct horse"),
+            Err(CipherError::InvalidBackup(_))
+        ));
+        assert!(matches!(
+            open(b"NYXKEY\x01", "correct horse"),
+            Err(CipherError::InvalidBackup(_))
+        ));
+
+        let mut future = backup.clone();
+        future[MAGIC.len()] = 2;
+        assert!(matches!(
+            open(&future, "correct horse"),
+            Err(CipherError::UnsupportedVersion(2))
+        ));
+    }
+
+    #[test]
+    fn rejects_expensive_key_derivation() {
+        let expensive = [
+            PasswordKdf::Argon2id {
+                memory_kib: PasswordKdf::MAX_MEMORY_KIB + 1,
+                iterations: 1,
+                parallelism: 1,
+            },
+            PasswordKdf::Argon2id {
+                memory_kib: 64,
+                iterations: u32::MAX,
+                parallelism: 1,
+            },
+            PasswordKdf::Pbkdf2Sha256 {
+                iterations: u32::MAX,
+            },
+        ];
+
+        let backup = seal_with(&payload(), "correct horse", &TEST_KDF).unwrap();
+        let kdf_len = serde_json::to_vec(&TEST_KDF).unwrap().len();
+        let start = MAGIC.len() + 3;
+
+        for kdf in expensive {
+            // Same layout as a genuine backup, with a checksum that matches.
+            let mut crafted = backup[..MAGIC.len() + 1].to_vec();
+            let json = serde_json::to_vec(&kdf).unwrap();
+            crafted.extend_from_slice(&(json.len() as u16).to_be_bytes());
+            crafted.extend_from_slice(&json);
+            crafted.extend_from_slice(&backup[start + kdf_len..backup.len() - CHECKSUM_LEN]);
+            let checksum = Sha256::digest(&crafted);
+            crafted.extend_from_slice(&checksum);
+
+            match open(&crafted, "correct horse") {
+                Err(CipherError::InvalidBackup(message)) => {
+                    assert_eq!(message, "Key derivation too expensive")
+                }
+                _ => panic!("accepted {:?}", kdf),
+            }
+        }
+    }
+}
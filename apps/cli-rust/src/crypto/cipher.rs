@@ -1,15 +1,37 @@
 use aes_gcm::{
-    aead::{rand_core::RngCore, Aead, KeyInit, Nonce, OsRng},
+    aead::{rand_core::RngCore, Aead, KeyInit, Nonce, OsRng, Payload},
     Aes256Gcm,
 };
 use base64::{engine::general_purpose::URL_SAFE, Engine as _};
-use p256::{elliptic_curve::ecdh::diffie_hellman, PublicKey, SecretKey};
-use pbkdf2::pbkdf2_hmac;
+use chrono::{DateTime, Utc};
+use p256::{
+    ecdsa::{signature::Signer, signature::Verifier, Signature, SigningKey, VerifyingKey},
+    elliptic_curve::ecdh::diffie_hellman,
+    PublicKey, SecretKey,
+};
+use hmac::{Hmac, Mac};
 use sha2::Digest;
 use sha2::Sha256;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
 use thiserror::Error;
-use zeroize::{Zeroize, ZeroizeOnDrop};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use super::envelope::{
+    Envelope, EnvelopeHeader, MessageContext, Suite, FLAG_CONTEXT, FLAG_PREKEY,
+    FLAG_SESSION_INIT, FLAG_SIGNED, SIGNATURE_LEN,
+};
+use super::backup::{self, BackupIdentity, BackupPayload, BackupPeer};
+use super::envelope;
+use super::kdf::{self, PasswordKdf};
+use super::stream::{
+    self, AsyncStreamDecryptor, AsyncStreamEncryptor, StreamDecryptor, StreamEncryptor,
+};
+use super::ratchet::{RatchetMessage, RatchetState, SessionSecrets, ENCRYPTED_HEADER_LEN};
+use super::safety::SafetyNumber;
+use super::x3dh::{self, OneTimePrekey, PrekeyBundle, PrekeyHeader, PREKEY_HEADER_LEN};
+use crate::vault::manager::{KeyringError, SecretManager};
 
 #[derive(Error, Debug)]
 pub enum CipherError {
@@ -25,6 +47,9 @@ pub enum CipherError {
     #[error("Peer not found: {0}")]
     PeerNotFound(String),
 
+    #[error("No public key recorded for peer: {0}")]
+    PeerKeyUnknown(String),
+
     #[error("Invalid key format")]
     InvalidKeyFormat,
 
@@ -33,6 +58,47 @@ pub enum CipherError {
 
     #[error("Wrong password")]
     WrongPassword,
+
+    /// The key is right but stored data does not authenticate.
+    #[error("Keystore corrupted or tampered: {0}")]
+    KeystoreCorrupted(String),
+
+    #[error("Unsupported envelope version: {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Unsupported cipher suite: {0}")]
+    UnsupportedSuite(u8),
+
+    #[error("No session with peer: {0}")]
+    SessionNotFound(String),
+
+    #[error("Ratchet error: {0}")]
+    RatchetError(String),
+
+    #[error("Invalid signature")]
+    InvalidSignature,
+
+    #[error("No signing key recorded for peer: {0}")]
+    SigningKeyNotFound(String),
+
+    #[error("Unsupported operation: {0}")]
+    Unsupported(String),
+
+    #[error("Invalid backup: {0}")]
+    InvalidBackup(String),
+
+    /// A peer offered a key other than the pinned one. It is held until
+    /// `accept_peer_key` or `reject_peer_key`.
+    #[error("Identity key of {peer} changed (pinned {pinned}, offered {offered})")]
+    IdentityKeyChanged {
+        peer: String,
+        pinned: String,
+        offered: String,
+    },
+}
+
+fn to_json<T: serde::Serialize>(value: &T) -> Result<Vec<u8>, CipherError> {
+    serde_json::to_vec(value).map_err(|e| CipherError::StorageError(e.to_string()))
 }
 
 impl From<sha2::digest::InvalidLength> for CipherError {
@@ -41,12 +107,100 @@ impl From<sha2::digest::InvalidLength> for CipherError {
     }
 }
 
+impl From<std::io::Error> for CipherError {
+    fn from(err: std::io::Error) -> Self {
+        // Stream adapters report decryption failures as I/O errors.
+        let message = err.to_string();
+        match err.into_inner().map(|inner| inner.downcast::<CipherError>()) {
+            Some(Ok(inner)) => *inner,
+            _ => CipherError::StorageError(message),
+        }
+    }
+}
+
+/// Store ids used by `Cipher`. Everything except the identity lives under a
+/// namespace so a peer id can never collide with a reserved entry.
+pub mod keys {
+    pub const IDENTITY: &str = "identity";
+    pub const WRAPPING_KEY: &str = "meta:wrapping-key";
+    pub const SALT: &str = "meta:salt";
+    pub const KDF: &str = "meta:kdf";
+    pub const VERIFIER: &str = "meta:verifier";
+    pub const QUARANTINE_PREFIX: &str = "quarantine:";
+    pub const SETTING_PREFIX: &str = "setting:";
+    pub const LAYOUT: &str = "meta:layout";
+    pub const NEXT_PREKEY_ID: &str = "meta:next-prekey-id";
+    pub const PEER_PREFIX: &str = "peer:";
+    pub const SESSION_PREFIX: &str = "session:";
+    pub const SIGNED_PREKEY_PREFIX: &str = "prekey:signed:";
+    pub const ONE_TIME_PREKEY_PREFIX: &str = "prekey:one-time:";
+
+    /// Current key layout, bumped whenever ids are renamed.
+    pub const LAYOUT_VERSION: u8 = 1;
+
+    /// Un-namespaced ids written before `LAYOUT_VERSION` 1.
+    pub const LEGACY_WRAPPING_KEY: &str = "wrapping-key";
+    pub const LEGACY_SALT: &str = "salt";
+
+    pub fn peer(peer_id: &str) -> String {
+        format!("{}{}", PEER_PREFIX, peer_id)
+    }
+
+    /// Prefixes of entries whose whole value is a sealed secret.
+    pub const SEALED_PREFIXES: &[&str] = &[
+        SESSION_PREFIX,
+        SIGNED_PREKEY_PREFIX,
+        ONE_TIME_PREKEY_PREFIX,
+    ];
+
+    pub fn setting(name: &str) -> String {
+        format!("{}{}", SETTING_PREFIX, name)
+    }
+
+    pub fn quarantine(id: &str) -> String {
+        format!("{}{}", QUARANTINE_PREFIX, id)
+    }
+
+    pub fn session(peer_id: &str) -> String {
+        format!("{}{}", SESSION_PREFIX, peer_id)
+    }
+
+    /// Prekey ids are zero-padded so `Store::list` returns them in order.
+    pub fn signed_prekey(id: u32) -> String {
+        format!("{}{:010}", SIGNED_PREKEY_PREFIX, id)
+    }
+
+    pub fn one_time_prekey(id: u32) -> String {
+        format!("{}{:010}", ONE_TIME_PREKEY_PREFIX, id)
+    }
+}
+
+pub enum StoreOp {
+    Put(String, Vec<u8>),
+    Delete(String),
+}
+
 pub trait Store: Send + Sync {
     fn setup(&mut self) -> Result<(), CipherError>;
     fn put(&mut self, id: &str, data: Vec<u8>) -> Result<(), CipherError>;
     fn get(&self, id: &str) -> Result<Option<Vec<u8>>, CipherError>;
     fn delete(&mut self, id: &str) -> Result<(), CipherError>;
     fn has(&self, id: &str) -> Result<bool, CipherError>;
+
+    /// Ids starting with `prefix`, in ascending order.
+    fn list(&self, prefix: &str) -> Result<Vec<String>, CipherError>;
+
+    /// Applies every operation or none of them.
+    fn apply(&mut self, ops: Vec<StoreOp>) -> Result<(), CipherError>;
+
+    fn put_many(&mut self, entries: Vec<(String, Vec<u8>)>) -> Result<(), CipherError> {
+        self.apply(
+            entries
+                .into_iter()
+                .map(|(id, data)| StoreOp::Put(id, data))
+                .collect(),
+        )
+    }
 }
 
 pub struct MemoryStore {
@@ -61,6 +215,12 @@ impl MemoryStore {
     }
 }
 
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Store for MemoryStore {
     fn setup(&mut self) -> Result<(), CipherError> {
         Ok(())
@@ -83,25 +243,283 @@ impl Store for MemoryStore {
     fn has(&self, id: &str) -> Result<bool, CipherError> {
         Ok(self.data.contains_key(id))
     }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>, CipherError> {
+        let mut ids: Vec<String> = self
+            .data
+            .keys()
+            .filter(|id| id.starts_with(prefix))
+            .cloned()
+            .collect();
+        ids.sort();
+        Ok(ids)
+    }
+
+    fn apply(&mut self, ops: Vec<StoreOp>) -> Result<(), CipherError> {
+        for op in ops {
+            match op {
+                StoreOp::Put(id, data) => {
+                    self.data.insert(id, data);
+                }
+                StoreOp::Delete(id) => {
+                    self.data.remove(&id);
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Zeroize, ZeroizeOnDrop)]
 struct WrappingKey([u8; 32]);
 
+impl WrappingKey {
+    /// Constant-time comparison.
+    fn matches(&self, other: &WrappingKey) -> bool {
+        self.0
+            .iter()
+            .zip(other.0.iter())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+    }
+}
+
 #[derive(Zeroize, ZeroizeOnDrop)]
 struct SharedSecret([u8; 32]);
 
+/// Message keys for one peer, derived from its `SharedSecret`.
+#[derive(Zeroize, ZeroizeOnDrop)]
+struct PeerKeys {
+    send: [u8; 32],
+    recv: [u8; 32],
+}
+
 #[derive(serde::Serialize, serde::Deserialize)]
 struct StoredIdentity {
     public_key: Vec<u8>,
     encrypted_private_key: Vec<u8>,
     nonce: [u8; 12],
+    // ECDSA P-256 signing key; created on first load for older identities.
+    #[serde(default)]
+    signing_public_key: Option<Vec<u8>>,
+    #[serde(default)]
+    signing_key: Option<Sealed>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
-struct StoredSharedKey {
+struct StoredPeer {
     encrypted_key: Vec<u8>,
     nonce: [u8; 12],
+    // Contact details; absent on peers registered by older versions.
+    #[serde(default)]
+    public_key: Option<Vec<u8>>,
+    #[serde(default)]
+    added_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    alias: Option<String>,
+    #[serde(default)]
+    verification: Verification,
+    /// One of the `kdf::PEER_KDF_*` versions; missing means raw ECDH output.
+    #[serde(default)]
+    kdf_version: u8,
+    /// SEC1 ECDSA key the peer signs messages with, once known.
+    #[serde(default)]
+    signing_key: Option<Vec<u8>>,
+    /// SEC1 key offered in place of `public_key`, awaiting the user's approval.
+    #[serde(default)]
+    pending_key: Option<Vec<u8>>,
+}
+
+/// Stored under `keys::VERIFIER`. `checksum` is SHA-256 over the keystore
+/// header (label, salt and KDF entry as stored) and catches a damaged header;
+/// `mac` is HMAC-SHA256 over the same bytes with the wrapping key and tells a
+/// wrong password apart from corrupted entries.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct KeystoreVerifier {
+    checksum: Vec<u8>,
+    mac: Vec<u8>,
+}
+
+impl KeystoreVerifier {
+    const LABEL: &'static [u8] = b"nyx/keystore-verifier/v1";
+
+    fn header(salt: &[u8], kdf: &[u8]) -> Vec<u8> {
+        let mut header = Vec::from(Self::LABEL);
+        for field in [salt, kdf] {
+            header.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            header.extend_from_slice(field);
+        }
+        header
+    }
+
+    fn mac(key: &WrappingKey, header: &[u8]) -> Result<Hmac<Sha256>, CipherError> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&key.0)?;
+        mac.update(header);
+        Ok(mac)
+    }
+
+    fn new(key: &WrappingKey, salt: &[u8], kdf: &[u8]) -> Result<Self, CipherError> {
+        let header = Self::header(salt, kdf);
+
+        Ok(Self {
+            checksum: Sha256::digest(&header).to_vec(),
+            mac: Self::mac(key, &header)?.finalize().into_bytes().to_vec(),
+        })
+    }
+}
+
+/// Any other secret at rest, encrypted as a whole with the wrapping key.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Sealed {
+    ciphertext: Vec<u8>,
+    nonce: [u8; 12],
+}
+
+impl Sealed {
+    fn seal(key: &WrappingKey, plaintext: &[u8]) -> Result<Self, CipherError> {
+        let mut nonce_bytes = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce_bytes);
+        let nonce = Nonce::<Aes256Gcm>::from_slice(&nonce_bytes);
+
+        let cipher = Aes256Gcm::new_from_slice(&key.0)?;
+        let ciphertext = cipher
+            .encrypt(nonce, plaintext)
+            .map_err(|e| CipherError::EncryptionFailed(e.to_string()))?;
+
+        Ok(Sealed {
+            ciphertext,
+            nonce: nonce_bytes,
+        })
+    }
+
+    fn open(&self, key: &WrappingKey) -> Result<Vec<u8>, CipherError> {
+        let cipher = Aes256Gcm::new_from_slice(&key.0)?;
+        let nonce = Nonce::<Aes256Gcm>::from_slice(&self.nonce);
+
+        cipher
+            .decrypt(nonce, self.ciphertext.as_ref())
+            .map_err(|e| CipherError::DecryptionFailed(e.to_string()))
+    }
+
+    /// Re-encrypts the secret under `to`.
+    fn rewrap(&self, from: &WrappingKey, to: &WrappingKey) -> Result<Self, CipherError> {
+        let mut plain = self.open(from)?;
+        let sealed = Sealed::seal(to, &plain);
+        plain.zeroize();
+        sealed
+    }
+}
+
+/// A ratchet session at rest. Sessions started from a prekey bundle keep the
+/// `x3dh::PrekeyHeader` to send until the peer replies.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StoredSession {
+    #[serde(flatten)]
+    state: RatchetState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    prekey_header: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Verification {
+    #[default]
+    Unverified,
+    /// The user compared safety numbers for the current key.
+    Verified,
+    /// The peer was verified, then its key changed; warn until re-verified.
+    KeyChanged,
+}
+
+/// Contact record for a registered peer, as returned by `Cipher::peer_info`.
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub id: String,
+    /// Base64url SEC1 public key; `None` for peers registered before keys were recorded.
+    pub public_key: Option<String>,
+    pub fingerprint: Option<String>,
+    pub added_at: Option<DateTime<Utc>>,
+    pub alias: Option<String>,
+    pub verification: Verification,
+    /// Base64url SEC1 signing key, once known.
+    pub signing_key: Option<String>,
+    /// Fingerprint of a changed key waiting for `Cipher::accept_peer_key`.
+    pub pending_fingerprint: Option<String>,
+}
+
+/// Public keys of an identity, signed with both of them so the signing key
+/// can only be tied to the identity key by whoever holds the latter.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct IdentityAnnouncement {
+    /// Base64url SEC1 ECDH key, as `export_public_key`.
+    pub public_key: String,
+    /// Base64url SEC1 ECDSA key, as `export_signing_key`.
+    pub signing_key: String,
+    /// Base64url signature over `"nyx/identity/v1" || public_key || signing_key`
+    /// (the decoded SEC1 bytes), by the signing key.
+    pub signature: String,
+    /// Base64url signature over the same data by the identity key itself.
+    pub identity_signature: String,
+}
+
+impl IdentityAnnouncement {
+    const LABEL: &'static [u8] = b"nyx/identity/v1";
+
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = Vec::from(Self::LABEL);
+        for key in [&self.public_key, &self.signing_key] {
+            data.extend_from_slice(&URL_SAFE.decode(key).unwrap_or_default());
+        }
+        data
+    }
+
+    pub fn verify(&self) -> Result<(), CipherError> {
+        let public_key = URL_SAFE
+            .decode(&self.public_key)
+            .map_err(|_| CipherError::InvalidKeyFormat)?;
+        PublicKey::from_sec1_bytes(&public_key).map_err(|_| CipherError::InvalidKeyFormat)?;
+
+        let signature = URL_SAFE
+            .decode(&self.signature)
+            .map_err(|_| CipherError::InvalidSignature)?;
+
+        verify_signature(&self.signed_data(), &signature, &self.signing_key)?;
+
+        let identity_signature = URL_SAFE
+            .decode(&self.identity_signature)
+            .map_err(|_| CipherError::InvalidSignature)?;
+        verify_signature(&self.signed_data(), &identity_signature, &self.public_key)
+    }
+
+    /// Checks a signature over `data` by the announced signing key; only
+    /// meaningful once `verify` has passed.
+    pub fn verify_data(&self, data: &[u8], signature: &[u8]) -> Result<(), CipherError> {
+        verify_signature(data, signature, &self.signing_key)
+    }
+
+    /// Compact form for the command line: base64url JSON.
+    pub fn encode(&self) -> Result<String, CipherError> {
+        Ok(URL_SAFE.encode(to_json(self)?))
+    }
+
+    pub fn decode(encoded: &str) -> Result<Self, CipherError> {
+        let json = URL_SAFE
+            .decode(encoded.trim())
+            .map_err(|_| CipherError::InvalidKeyFormat)?;
+        serde_json::from_slice(&json).map_err(|_| CipherError::InvalidKeyFormat)
+    }
+}
+
+fn verify_signature(data: &[u8], signature: &[u8], signing_key_b64: &str) -> Result<(), CipherError> {
+    let key_bytes = URL_SAFE
+        .decode(signing_key_b64)
+        .map_err(|_| CipherError::InvalidKeyFormat)?;
+    let key =
+        VerifyingKey::from_sec1_bytes(&key_bytes).map_err(|_| CipherError::InvalidKeyFormat)?;
+    let signature = Signature::from_slice(signature).map_err(|_| CipherError::InvalidSignature)?;
+
+    key.verify(data, &signature)
+        .map_err(|_| CipherError::InvalidSignature)
 }
 
 pub struct Cipher<S: Store> {
@@ -109,88 +527,316 @@ pub struct Cipher<S: Store> {
     wrapping_key: Option<WrappingKey>,
     identity_private: Option<SecretKey>,
     identity_public: Option<PublicKey>,
-    peer_keys: HashMap<String, SharedSecret>,
+    signing_key: Option<SigningKey>,
+    peer_keys: HashMap<String, PeerKeys>,
+    password_protected: bool,
+    /// Where the password-derived key is remembered between runs, if anywhere.
+    key_cache: Option<SecretManager>,
     initialized: bool,
 }
 
 impl<S: Store> Cipher<S> {
-    const PBKDF2_ITERATIONS: u32 = 600_000;
-
     pub fn new(store: S) -> Self {
         Self {
             store,
             wrapping_key: None,
             identity_private: None,
             identity_public: None,
+            signing_key: None,
             peer_keys: HashMap::new(),
+            password_protected: false,
+            key_cache: None,
             initialized: false,
         }
     }
 
+    /// Remembers the key of a password-protected keystore in `secrets` once
+    /// it is unlocked, so later `init(None)` calls need no password.
+    pub fn with_key_cache(mut self, secrets: SecretManager) -> Self {
+        self.key_cache = Some(secrets);
+        self
+    }
+
     pub fn init(&mut self, password: Option<&str>) -> Result<(), CipherError> {
         if self.initialized {
             return Ok(());
         }
 
         self.store.setup()?;
+        self.migrate_layout()?;
 
-        if let Some(pwd) = password {
-            self.init_with_password(pwd)?;
-        } else {
+        let cached = match password {
+            Some(pwd) => {
+                self.init_with_password(pwd)?;
+                false
+            }
+            None => self.init_from_key_cache()?,
+        };
+        if password.is_none() && !cached {
             self.init_basic()?;
         }
 
-        self.load_or_create_identity()?;
+        let unlocked = self.check_verifier().and_then(|verified| {
+            match self.load_or_create_identity() {
+                Err(CipherError::WrongPassword) if verified => {
+                    Err(CipherError::KeystoreCorrupted("identity".into()))
+                }
+                result => result.map(|()| verified),
+            }
+        });
+        let verified = match unlocked {
+            Err(CipherError::WrongPassword) if cached => {
+                // Stale after a password change elsewhere.
+                self.wrapping_key = None;
+                self.password_protected = false;
+                self.forget_cached_key()?;
+                return Err(CipherError::WrongPassword);
+            }
+            result => result?,
+        };
+
+        if !verified {
+            // Keystores from before the verifier: the identity just
+            // decrypted, so the key is right.
+            let key = self
+                .wrapping_key
+                .as_ref()
+                .ok_or(CipherError::NotInitialized)?;
+            let op = self.verifier_op(key)?;
+            self.store.apply(vec![op])?;
+        }
+
+        if let Some(pwd) = password {
+            self.upgrade_password_kdf(pwd)?;
+        }
         self.initialized = true;
+        self.remember_key();
 
         Ok(())
     }
 
+    /// Takes the wrapping key from the key cache, if one is set, the keystore
+    /// has a password and a key was remembered.
+    fn init_from_key_cache(&mut self) -> Result<bool, CipherError> {
+        let Some(cache) = &self.key_cache else {
+            return Ok(false);
+        };
+        if !self.store.has(keys::SALT)? {
+            return Ok(false);
+        }
+        let Ok(encoded) = cache.get_secret().map(Zeroizing::new) else {
+            return Ok(false);
+        };
+
+        let decoded = Zeroizing::new(URL_SAFE.decode(encoded.trim()).unwrap_or_default());
+        if decoded.len() != 32 {
+            return Ok(false);
+        }
+
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&decoded);
+        self.wrapping_key = Some(WrappingKey(key));
+        self.password_protected = true;
+        Ok(true)
+    }
+
+    /// Saves the current password-derived key in the key cache. Failing to do
+    /// so only means the password is asked for again next time.
+    fn remember_key(&self) {
+        let (Some(cache), Some(key)) = (&self.key_cache, &self.wrapping_key) else {
+            return;
+        };
+        if !self.password_protected {
+            return;
+        }
+
+        let encoded = Zeroizing::new(URL_SAFE.encode(key.0));
+        let _ = cache.set_secret(&encoded);
+    }
+
+    /// Drops the key remembered in the key cache; the next `init` needs the
+    /// password again.
+    pub fn forget_cached_key(&self) -> Result<(), CipherError> {
+        let Some(cache) = &self.key_cache else {
+            return Ok(());
+        };
+
+        match cache.delete_secret() {
+            Ok(()) | Err(KeyringError::NotFound) => Ok(()),
+            Err(e) => Err(CipherError::StorageError(e.to_string())),
+        }
+    }
+
+    /// Moves entries written before key namespacing to their `keys` ids.
+    fn migrate_layout(&mut self) -> Result<(), CipherError> {
+        if self.store.has(keys::LAYOUT)? {
+            return Ok(());
+        }
+
+        let mut ops = Vec::new();
+        for id in self.store.list("")? {
+            let target = match id.as_str() {
+                keys::IDENTITY => continue,
+                keys::LEGACY_WRAPPING_KEY => keys::WRAPPING_KEY.to_string(),
+                keys::LEGACY_SALT => keys::SALT.to_string(),
+                peer_id => keys::peer(peer_id),
+            };
+
+            if let Some(data) = self.store.get(&id)? {
+                ops.push(StoreOp::Put(target, data));
+                ops.push(StoreOp::Delete(id));
+            }
+        }
+
+        ops.push(StoreOp::Put(
+            keys::LAYOUT.to_string(),
+            vec![keys::LAYOUT_VERSION],
+        ));
+        self.store.apply(ops)
+    }
+
     fn init_basic(&mut self) -> Result<(), CipherError> {
+        if self.store.has(keys::SALT)? {
+            return Err(CipherError::WrongPassword);
+        }
+
         // Generate or load wrapping key
-        if let Some(data) = self.store.get("wrapping-key")? {
+        if let Some(data) = self.store.get(keys::WRAPPING_KEY)? {
             if data.len() == 32 {
                 let mut key = [0u8; 32];
                 key.copy_from_slice(&data);
                 self.wrapping_key = Some(WrappingKey(key));
             } else {
-                return Err(CipherError::InvalidKeyFormat);
+                return Err(CipherError::KeystoreCorrupted("wrapping key".into()));
             }
         } else {
             let mut key = [0u8; 32];
             OsRng.fill_bytes(&mut key);
             self.wrapping_key = Some(WrappingKey(key));
-            self.store.put("wrapping-key", key.to_vec())?;
+            self.store.put(keys::WRAPPING_KEY, key.to_vec())?;
         }
 
         Ok(())
     }
 
     fn init_with_password(&mut self, password: &str) -> Result<(), CipherError> {
-        let salt = if let Some(data) = self.store.get("salt")? {
-            data
-        } else {
-            let mut salt = vec![0u8; 32];
-            OsRng.fill_bytes(&mut salt);
-            self.store.put("salt", salt.clone())?;
-            salt
+        if self.store.has(keys::WRAPPING_KEY)? {
+            return Err(CipherError::Unsupported(
+                "Keystore is not password protected".into(),
+            ));
+        }
+
+        let (salt, params) = match self.store.get(keys::SALT)? {
+            Some(salt) => (salt, self.password_kdf()?),
+            None => {
+                let mut salt = vec![0u8; 32];
+                OsRng.fill_bytes(&mut salt);
+                self.store.apply(vec![
+                    StoreOp::Put(keys::SALT.to_string(), salt.clone()),
+                    StoreOp::Put(keys::KDF.to_string(), to_json(&PasswordKdf::CURRENT)?),
+                ])?;
+                (salt, PasswordKdf::CURRENT)
+            }
         };
 
-        let mut key = [0u8; 32];
-        pbkdf2_hmac::<Sha256>(
-            password.as_bytes(),
-            &salt,
-            Self::PBKDF2_ITERATIONS,
-            &mut key,
+        self.wrapping_key = Some(WrappingKey(params.derive(password, &salt)?));
+        self.password_protected = true;
+        Ok(())
+    }
+
+    /// Checks the wrapping key against `keys::VERIFIER`. Returns `false` when
+    /// the keystore has no verifier yet.
+    fn check_verifier(&self) -> Result<bool, CipherError> {
+        let Some(data) = self.store.get(keys::VERIFIER)? else {
+            return Ok(false);
+        };
+        let verifier: KeystoreVerifier = serde_json::from_slice(&data)
+            .map_err(|_| CipherError::KeystoreCorrupted("verifier".into()))?;
+
+        let header = KeystoreVerifier::header(
+            &self.store.get(keys::SALT)?.unwrap_or_default(),
+            &self.store.get(keys::KDF)?.unwrap_or_default(),
         );
+        if Sha256::digest(&header).as_slice() != verifier.checksum.as_slice() {
+            return Err(CipherError::KeystoreCorrupted("header".into()));
+        }
 
-        self.wrapping_key = Some(WrappingKey(key));
+        let key = self
+            .wrapping_key
+            .as_ref()
+            .ok_or(CipherError::NotInitialized)?;
+        match KeystoreVerifier::mac(key, &header)?.verify_slice(&verifier.mac) {
+            Ok(()) => Ok(true),
+            Err(_) if self.password_protected => match self.store.get(keys::IDENTITY)? {
+                // The identity still opens, so the password is right.
+                Some(data) if self.entry_is_intact(keys::IDENTITY, &data) => {
+                    Err(CipherError::KeystoreCorrupted("verifier".into()))
+                }
+                _ => Err(CipherError::WrongPassword),
+            },
+            Err(_) => Err(CipherError::KeystoreCorrupted("wrapping key".into())),
+        }
+    }
+
+    /// Store operation writing the verifier for `key` and the current header.
+    fn verifier_op(&self, key: &WrappingKey) -> Result<StoreOp, CipherError> {
+        let verifier = KeystoreVerifier::new(
+            key,
+            &self.store.get(keys::SALT)?.unwrap_or_default(),
+            &self.store.get(keys::KDF)?.unwrap_or_default(),
+        )?;
+
+        Ok(StoreOp::Put(keys::VERIFIER.to_string(), to_json(&verifier)?))
+    }
+
+    /// KDF recorded in the keystore header.
+    fn password_kdf(&self) -> Result<PasswordKdf, CipherError> {
+        let kdf = match self.store.get(keys::KDF)? {
+            Some(data) => serde_json::from_slice(&data)
+                .map_err(|e| CipherError::StorageError(e.to_string()))?,
+            None => PasswordKdf::LEGACY,
+        };
+        // The header is unauthenticated until the key is derived.
+        if !kdf.is_within_limits() {
+            return Err(CipherError::KeystoreCorrupted("kdf".into()));
+        }
+
+        Ok(kdf)
+    }
+
+    /// Re-keys the keystore with `PasswordKdf::CURRENT` once `password` is
+    /// known to be right, if it still uses older parameters.
+    fn upgrade_password_kdf(&mut self, password: &str) -> Result<(), CipherError> {
+        if self.password_kdf()? == PasswordKdf::CURRENT {
+            return Ok(());
+        }
+
+        self.rekey_with_password(password)
+    }
+
+    /// Re-encrypts every secret under a key derived from `password` with a
+    /// fresh salt and `PasswordKdf::CURRENT`, in one `Store::apply`.
+    fn rekey_with_password(&mut self, password: &str) -> Result<(), CipherError> {
+        let mut salt = vec![0u8; 32];
+        OsRng.fill_bytes(&mut salt);
+        let new_key = WrappingKey(PasswordKdf::CURRENT.derive(password, &salt)?);
+
+        let kdf = to_json(&PasswordKdf::CURRENT)?;
+        let verifier = KeystoreVerifier::new(&new_key, &salt, &kdf)?;
+
+        let mut ops = self.rewrap_ops(&new_key)?;
+        ops.push(StoreOp::Put(keys::SALT.to_string(), salt));
+        ops.push(StoreOp::Put(keys::KDF.to_string(), kdf));
+        ops.push(StoreOp::Put(keys::VERIFIER.to_string(), to_json(&verifier)?));
+        self.store.apply(ops)?;
+
+        self.wrapping_key = Some(new_key);
         Ok(())
     }
 
     fn load_or_create_identity(&mut self) -> Result<(), CipherError> {
-        if let Some(data) = self.store.get("identity")? {
-            let stored: StoredIdentity = serde_json::from_slice(&data)
+        if let Some(data) = self.store.get(keys::IDENTITY)? {
+            let mut stored: StoredIdentity = serde_json::from_slice(&data)
                 .map_err(|e| CipherError::StorageError(e.to_string()))?;
 
             // Decrypt private key
@@ -216,6 +862,12 @@ impl<S: Store> Cipher<S> {
                 PublicKey::from_sec1_bytes(&stored.public_key)
                     .map_err(|_| CipherError::InvalidKeyFormat)?,
             );
+
+            if self.load_or_create_signing_key(&mut stored)? {
+                let serialized = serde_json::to_vec(&stored)
+                    .map_err(|e| CipherError::StorageError(e.to_string()))?;
+                self.store.put(keys::IDENTITY, serialized)?;
+            }
         } else {
             // Generate new identity
             let private = SecretKey::random(&mut OsRng);
@@ -237,22 +889,51 @@ impl<S: Store> Cipher<S> {
                 .encrypt(nonce, private_bytes.as_ref())
                 .map_err(|e| CipherError::EncryptionFailed(e.to_string()))?;
 
-            let stored = StoredIdentity {
+            let mut stored = StoredIdentity {
                 public_key: public.to_sec1_bytes().to_vec(),
                 encrypted_private_key: encrypted,
                 nonce: nonce_bytes,
+                signing_public_key: None,
+                signing_key: None,
             };
+            self.load_or_create_signing_key(&mut stored)?;
 
             let serialized = serde_json::to_vec(&stored)
                 .map_err(|e| CipherError::StorageError(e.to_string()))?;
 
-            self.store.put("identity", serialized)?;
+            self.store.put(keys::IDENTITY, serialized)?;
             self.identity_private = Some(private);
             self.identity_public = Some(public);
         }
 
         Ok(())
     }
+    /// Loads the signing key from `stored`, creating one if the identity
+    /// predates signing keys. Returns whether `stored` changed.
+    fn load_or_create_signing_key(
+        &mut self,
+        stored: &mut StoredIdentity,
+    ) -> Result<bool, CipherError> {
+        if let Some(sealed) = &stored.signing_key {
+            let mut scalar = self.open_local(sealed)?;
+            let key = SigningKey::from_slice(&scalar).map_err(|_| CipherError::InvalidKeyFormat);
+            scalar.zeroize();
+
+            self.signing_key = Some(key?);
+            return Ok(false);
+        }
+
+        let key = SigningKey::random(&mut OsRng);
+        let mut scalar: [u8; 32] = key.to_bytes().into();
+        let sealed = self.seal_local(&scalar);
+        scalar.zeroize();
+
+        stored.signing_public_key = Some(key.verifying_key().to_sec1_bytes().to_vec());
+        stored.signing_key = Some(sealed?);
+        self.signing_key = Some(key);
+        Ok(true)
+    }
+
     pub fn export_public_key(&self) -> Result<String, CipherError> {
         let public = self
             .identity_public
@@ -269,38 +950,106 @@ impl<S: Store> Cipher<S> {
             .as_ref()
             .ok_or(CipherError::NotInitialized)?;
 
+        Ok(Self::fingerprint_of(public))
+    }
+
+    /// Stable key for local data such as the notes vault, one per `purpose`.
+    pub fn derive_local_key(&self, purpose: &str) -> Result<Zeroizing<[u8; 32]>, CipherError> {
+        let private = self
+            .identity_private
+            .as_ref()
+            .ok_or(CipherError::NotInitialized)?;
+
+        Ok(Zeroizing::new(kdf::derive_local_key(
+            &private.to_bytes(),
+            purpose.as_bytes(),
+        )?))
+    }
+
+    /// Base64url SEC1 public key that verifies our signatures.
+    pub fn export_signing_key(&self) -> Result<String, CipherError> {
+        let key = self.signing_key.as_ref().ok_or(CipherError::NotInitialized)?;
+        Ok(URL_SAFE.encode(key.verifying_key().to_sec1_bytes()))
+    }
+
+    /// Signs `data` with our signing key. The signature is 64 bytes (r || s).
+    pub fn sign(&self, data: &[u8]) -> Result<Vec<u8>, CipherError> {
+        let key = self.signing_key.as_ref().ok_or(CipherError::NotInitialized)?;
+        let signature: Signature = key.sign(data);
+        Ok(signature.to_bytes().to_vec())
+    }
+
+    /// Checks a signature made by `peer_id` with the signing key on record.
+    pub fn verify(&self, data: &[u8], signature: &[u8], peer_id: &str) -> Result<(), CipherError> {
+        let key = self
+            .load_peer(peer_id)?
+            .signing_key
+            .ok_or_else(|| CipherError::SigningKeyNotFound(peer_id.to_string()))?;
+
+        Self::verify_with_key(data, signature, &URL_SAFE.encode(key))
+    }
+
+    /// Checks a signature against a base64url SEC1 signing key.
+    pub fn verify_with_key(
+        data: &[u8],
+        signature: &[u8],
+        signing_key_b64: &str,
+    ) -> Result<(), CipherError> {
+        verify_signature(data, signature, signing_key_b64)
+    }
+
+    /// Our public keys, signed so a recipient can check that whoever
+    /// announces the identity key also holds the signing key.
+    pub fn identity_announcement(&self) -> Result<IdentityAnnouncement, CipherError> {
+        let identity = self
+            .identity_private
+            .as_ref()
+            .ok_or(CipherError::NotInitialized)?;
+
+        let mut announcement = IdentityAnnouncement {
+            public_key: self.export_public_key()?,
+            signing_key: self.export_signing_key()?,
+            signature: String::new(),
+            identity_signature: String::new(),
+        };
+        let data = announcement.signed_data();
+        announcement.signature = URL_SAFE.encode(self.sign(&data)?);
+
+        let identity_signature: Signature = SigningKey::from(identity).sign(&data);
+        announcement.identity_signature = URL_SAFE.encode(identity_signature.to_bytes());
+
+        Ok(announcement)
+    }
+
+    fn fingerprint_bytes(public: &PublicKey) -> [u8; 16] {
         let bytes = public.to_sec1_bytes();
         let hash = sha2::Sha256::digest(&bytes);
 
-        let fingerprint: String = hash[..16]
+        let mut fingerprint = [0u8; 16];
+        fingerprint.copy_from_slice(&hash[..16]);
+        fingerprint
+    }
+
+    fn fingerprint_of(public: &PublicKey) -> String {
+        Self::fingerprint_bytes(public)
             .iter()
             .map(|b| format!("{:02X}", b))
             .collect::<Vec<_>>()
             .chunks(4)
             .map(|chunk| chunk.iter().cloned().collect::<String>())
             .collect::<Vec<_>>()
-            .join(" ");
-
-        Ok(fingerprint)
+            .join(" ")
     }
 
-    pub fn register_peer(
-        &mut self,
-        peer_id: &str,
-        public_key_b64: &str,
-    ) -> Result<(), CipherError> {
-        if self.store.has(peer_id)? {
-            return Ok(()); // Already registered
-        }
-
+    fn decode_public_key(public_key_b64: &str) -> Result<PublicKey, CipherError> {
         let public_key_bytes = URL_SAFE
             .decode(public_key_b64)
             .map_err(|_| CipherError::InvalidKeyFormat)?;
 
-        let peer_public = PublicKey::from_sec1_bytes(&public_key_bytes)
-            .map_err(|_| CipherError::InvalidKeyFormat)?;
+        PublicKey::from_sec1_bytes(&public_key_bytes).map_err(|_| CipherError::InvalidKeyFormat)
+    }
 
-        // Derive shared secret using ECDH
+    fn derive_shared_secret(&self, peer_public: &PublicKey) -> Result<SharedSecret, CipherError> {
         let private = self
             .identity_private
             .as_ref()
@@ -312,11 +1061,81 @@ impl<S: Store> Cipher<S> {
         let mut shared_key = [0u8; 32];
         shared_key.copy_from_slice(shared_bytes.as_slice());
 
-        // Cache the shared key
-        self.peer_keys
-            .insert(peer_id.to_string(), SharedSecret(shared_key));
+        Ok(SharedSecret(shared_key))
+    }
+
+    fn load_peer(&self, peer_id: &str) -> Result<StoredPeer, CipherError> {
+        let data = self
+            .store
+            .get(&keys::peer(peer_id))?
+            .ok_or_else(|| CipherError::PeerNotFound(peer_id.to_string()))?;
+
+        serde_json::from_slice(&data).map_err(|e| CipherError::StorageError(e.to_string()))
+    }
+
+    fn save_peer(&mut self, peer_id: &str, stored: &StoredPeer) -> Result<(), CipherError> {
+        let serialized =
+            serde_json::to_vec(stored).map_err(|e| CipherError::StorageError(e.to_string()))?;
+
+        self.store.put(&keys::peer(peer_id), serialized)
+    }
+
+    /// Saves `stored` and drops the session with `peer_id` in one batch, so a
+    /// session never outlives the key it was started with.
+    fn save_peer_ending_session(
+        &mut self,
+        peer_id: &str,
+        stored: &StoredPeer,
+    ) -> Result<(), CipherError> {
+        let serialized =
+            serde_json::to_vec(stored).map_err(|e| CipherError::StorageError(e.to_string()))?;
+
+        self.store.apply(vec![
+            StoreOp::Put(keys::peer(peer_id), serialized),
+            StoreOp::Delete(keys::session(peer_id)),
+        ])
+    }
+
+    fn derive_peer_keys(
+        &self,
+        secret: &SharedSecret,
+        kdf_version: u8,
+        peer_public: Option<&PublicKey>,
+    ) -> Result<PeerKeys, CipherError> {
+        match kdf_version {
+            kdf::PEER_KDF_RAW => Ok(PeerKeys {
+                send: secret.0,
+                recv: secret.0,
+            }),
+            kdf::PEER_KDF_HKDF_SHA256 => {
+                let own = self
+                    .identity_public
+                    .as_ref()
+                    .ok_or(CipherError::NotInitialized)?
+                    .to_sec1_bytes();
+                let peer = peer_public.ok_or(CipherError::InvalidKeyFormat)?.to_sec1_bytes();
+
+                Ok(PeerKeys {
+                    send: kdf::derive_directional_key(&secret.0, &own, &peer)?,
+                    recv: kdf::derive_directional_key(&secret.0, &peer, &own)?,
+                })
+            }
+            version => Err(CipherError::StorageError(format!(
+                "Unknown peer key derivation v{}",
+                version
+            ))),
+        }
+    }
+
+    /// Wraps `secret` for storage and caches its message keys for `peer_id`.
+    fn seal_peer_key(
+        &mut self,
+        peer_id: &str,
+        secret: SharedSecret,
+        peer_public: &PublicKey,
+    ) -> Result<(Vec<u8>, [u8; 12]), CipherError> {
+        let keys = self.derive_peer_keys(&secret, kdf::PEER_KDF_CURRENT, Some(peer_public))?;
 
-        // Encrypt and store
         let wrapping_key = self
             .wrapping_key
             .as_ref()
@@ -328,42 +1147,307 @@ impl<S: Store> Cipher<S> {
 
         let cipher = Aes256Gcm::new_from_slice(&wrapping_key.0)?;
         let encrypted = cipher
-            .encrypt(nonce, shared_key.as_ref())
+            .encrypt(nonce, secret.0.as_ref())
             .map_err(|e| CipherError::EncryptionFailed(e.to_string()))?;
 
-        let stored = StoredSharedKey {
-            encrypted_key: encrypted,
-            nonce: nonce_bytes,
+        // Cache the message keys
+        self.peer_keys.insert(peer_id.to_string(), keys);
+
+        Ok((encrypted, nonce_bytes))
+    }
+
+    /// Registers `peer_id` and pins its key on first use. Registering again
+    /// with the same key is a no-op; a different key is held as pending and
+    /// `CipherError::IdentityKeyChanged` is returned.
+    pub fn register_peer(
+        &mut self,
+        peer_id: &str,
+        public_key_b64: &str,
+    ) -> Result<(), CipherError> {
+        let peer_public = Self::decode_public_key(public_key_b64)?;
+
+        if self.has_peer(peer_id)? {
+            return self.check_pinned_key(peer_id, &peer_public);
+        }
+
+        // Derive shared secret using ECDH
+        let secret = self.derive_shared_secret(&peer_public)?;
+        let (encrypted_key, nonce) = self.seal_peer_key(peer_id, secret, &peer_public)?;
+
+        let stored = StoredPeer {
+            encrypted_key,
+            nonce,
+            public_key: Some(peer_public.to_sec1_bytes().to_vec()),
+            added_at: Some(Utc::now()),
+            alias: None,
+            verification: Verification::Unverified,
+            kdf_version: kdf::PEER_KDF_CURRENT,
+            signing_key: None,
+            pending_key: None,
         };
 
-        let serialized =
-            serde_json::to_vec(&stored).map_err(|e| CipherError::StorageError(e.to_string()))?;
+        self.save_peer(peer_id, &stored)
+    }
+
+    fn check_pinned_key(&mut self, peer_id: &str, offered: &PublicKey) -> Result<(), CipherError> {
+        let mut stored = self.load_peer(peer_id)?;
+
+        let pinned = match stored.public_key.as_deref() {
+            Some(bytes) => {
+                Some(PublicKey::from_sec1_bytes(bytes).map_err(|_| CipherError::InvalidKeyFormat)?)
+            }
+            None => None,
+        };
+
+        match &pinned {
+            Some(pinned) if pinned == offered => return Ok(()),
+            Some(_) => {}
+            // Peers from older versions only kept the ECDH secret; pin the
+            // key now if it is the one that secret was derived from.
+            None => {
+                let wrapping_key = self
+                    .wrapping_key
+                    .as_ref()
+                    .ok_or(CipherError::NotInitialized)?;
+                let secret = Zeroizing::new(
+                    Sealed {
+                        ciphertext: stored.encrypted_key.clone(),
+                        nonce: stored.nonce,
+                    }
+                    .open(wrapping_key)
+                    .map_err(|_| CipherError::KeystoreCorrupted(format!("peer {}", peer_id)))?,
+                );
+
+                let derived = self.derive_shared_secret(offered)?;
+                let same = derived
+                    .0
+                    .iter()
+                    .zip(secret.iter())
+                    .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+                    == 0;
+                if secret.len() == derived.0.len() && same {
+                    stored.public_key = Some(offered.to_sec1_bytes().to_vec());
+                    return self.save_peer(peer_id, &stored);
+                }
+            }
+        }
+
+        stored.pending_key = Some(offered.to_sec1_bytes().to_vec());
+        self.save_peer(peer_id, &stored)?;
+
+        Err(CipherError::IdentityKeyChanged {
+            peer: peer_id.to_string(),
+            pinned: pinned
+                .as_ref()
+                .map(Self::fingerprint_of)
+                .unwrap_or_else(|| "unrecorded".to_string()),
+            offered: Self::fingerprint_of(offered),
+        })
+    }
 
-        self.store.put(peer_id, serialized)?;
+    /// Replaces the pinned key of `peer_id` with the pending one, after the
+    /// user has confirmed the change.
+    pub fn accept_peer_key(&mut self, peer_id: &str) -> Result<(), CipherError> {
+        let pending = self.load_peer(peer_id)?.pending_key.ok_or_else(|| {
+            CipherError::Unsupported(format!("No key change pending for peer {}", peer_id))
+        })?;
+        let pending = PublicKey::from_sec1_bytes(&pending).map_err(|_| CipherError::InvalidKeyFormat)?;
+
+        self.update_peer_key(peer_id, &URL_SAFE.encode(pending.to_sec1_bytes()))
+    }
 
+    /// Drops the pending key of `peer_id` and keeps the pinned one.
+    pub fn reject_peer_key(&mut self, peer_id: &str) -> Result<(), CipherError> {
+        let mut stored = self.load_peer(peer_id)?;
+        if stored.pending_key.take().is_some() {
+            self.save_peer(peer_id, &stored)?;
+        }
         Ok(())
     }
 
+    /// Replaces the public key of an existing peer and re-derives the shared
+    /// secret. The alias is kept; the signing key and any session are dropped
+    /// since they belonged to the old key, and a verified peer becomes
+    /// `Verification::KeyChanged`.
+    pub fn update_peer_key(
+        &mut self,
+        peer_id: &str,
+        public_key_b64: &str,
+    ) -> Result<(), CipherError> {
+        let mut stored = self.load_peer(peer_id)?;
+        let peer_public = Self::decode_public_key(public_key_b64)?;
+
+        let secret = self.derive_shared_secret(&peer_public)?;
+        let (encrypted_key, nonce) = self.seal_peer_key(peer_id, secret, &peer_public)?;
+
+        stored.encrypted_key = encrypted_key;
+        stored.nonce = nonce;
+        stored.public_key = Some(peer_public.to_sec1_bytes().to_vec());
+        stored.verification = match stored.verification {
+            Verification::Unverified => Verification::Unverified,
+            Verification::Verified | Verification::KeyChanged => Verification::KeyChanged,
+        };
+        stored.kdf_version = kdf::PEER_KDF_CURRENT;
+        stored.signing_key = None;
+        stored.pending_key = None;
+
+        self.save_peer_ending_session(peer_id, &stored)
+    }
+
+    pub fn set_peer_alias(&mut self, peer_id: &str, alias: Option<&str>) -> Result<(), CipherError> {
+        let mut stored = self.load_peer(peer_id)?;
+        stored.alias = alias.map(str::to_string);
+        self.save_peer(peer_id, &stored)
+    }
+
+    /// Records the key `peer_id` signs with, from its announcement. The
+    /// announcement must be signed by the identity key pinned for `peer_id`;
+    /// otherwise `CipherError::InvalidSignature` is returned.
+    pub fn set_peer_signing_key(
+        &mut self,
+        peer_id: &str,
+        announcement: &IdentityAnnouncement,
+    ) -> Result<(), CipherError> {
+        let pinned = self.peer_public_key(peer_id)?;
+        if Self::decode_public_key(&announcement.public_key)? != pinned {
+            return Err(CipherError::InvalidSignature);
+        }
+        announcement.verify()?;
+
+        let key_bytes = URL_SAFE
+            .decode(&announcement.signing_key)
+            .map_err(|_| CipherError::InvalidKeyFormat)?;
+        let key =
+            VerifyingKey::from_sec1_bytes(&key_bytes).map_err(|_| CipherError::InvalidKeyFormat)?;
+
+        let mut stored = self.load_peer(peer_id)?;
+        stored.signing_key = Some(key.to_sec1_bytes().to_vec());
+        self.save_peer(peer_id, &stored)
+    }
+
+    /// Safety number for the pair of us and `peer_id`, to compare out of band
+    /// before `mark_verified`.
+    pub fn safety_number(&self, peer_id: &str) -> Result<SafetyNumber, CipherError> {
+        let own = self
+            .identity_public
+            .as_ref()
+            .ok_or(CipherError::NotInitialized)?;
+        let peer = self.peer_public_key(peer_id)?;
+
+        Ok(SafetyNumber::new(own, &peer))
+    }
+
+    /// Records that the user compared safety numbers with `peer_id`.
+    pub fn mark_verified(&mut self, peer_id: &str) -> Result<(), CipherError> {
+        self.set_verification(peer_id, Verification::Verified)
+    }
+
+    pub fn mark_unverified(&mut self, peer_id: &str) -> Result<(), CipherError> {
+        self.set_verification(peer_id, Verification::Unverified)
+    }
+
+    fn set_verification(
+        &mut self,
+        peer_id: &str,
+        verification: Verification,
+    ) -> Result<(), CipherError> {
+        // Only a recorded key can have been compared.
+        if verification == Verification::Verified {
+            self.peer_public_key(peer_id)?;
+        }
+
+        let mut stored = self.load_peer(peer_id)?;
+
+        stored.verification = verification;
+        self.save_peer(peer_id, &stored)
+    }
+
+    /// Forgets `peer_id` together with any session with it.
     pub fn remove_peer(&mut self, peer_id: &str) -> Result<(), CipherError> {
         self.peer_keys.remove(peer_id);
-        self.store.delete(peer_id)?;
-        Ok(())
+        self.store.apply(vec![
+            StoreOp::Delete(keys::peer(peer_id)),
+            StoreOp::Delete(keys::session(peer_id)),
+        ])
     }
 
     pub fn has_peer(&self, peer_id: &str) -> Result<bool, CipherError> {
-        self.store.has(peer_id)
+        self.store.has(&keys::peer(peer_id))
+    }
+
+    pub fn peer_ids(&self) -> Result<Vec<String>, CipherError> {
+        Ok(self
+            .store
+            .list(keys::PEER_PREFIX)?
+            .into_iter()
+            .map(|id| id[keys::PEER_PREFIX.len()..].to_string())
+            .collect())
+    }
+
+    pub fn peer_info(&self, peer_id: &str) -> Result<PeerInfo, CipherError> {
+        let stored = self.load_peer(peer_id)?;
+
+        let public = stored
+            .public_key
+            .as_deref()
+            .map(PublicKey::from_sec1_bytes)
+            .transpose()
+            .map_err(|_| CipherError::InvalidKeyFormat)?;
+
+        Ok(PeerInfo {
+            id: peer_id.to_string(),
+            public_key: public.as_ref().map(|key| URL_SAFE.encode(key.to_sec1_bytes())),
+            fingerprint: public.as_ref().map(Self::fingerprint_of),
+            added_at: stored.added_at,
+            alias: stored.alias,
+            verification: stored.verification,
+            signing_key: stored.signing_key.as_ref().map(|key| URL_SAFE.encode(key)),
+            pending_fingerprint: stored
+                .pending_key
+                .as_deref()
+                .and_then(|key| PublicKey::from_sec1_bytes(key).ok())
+                .as_ref()
+                .map(Self::fingerprint_of),
+        })
+    }
+
+    pub fn list_peers(&self) -> Result<Vec<PeerInfo>, CipherError> {
+        self.peer_ids()?
+            .iter()
+            .map(|peer_id| self.peer_info(peer_id))
+            .collect()
+    }
+
+    /// Moves every peer still using an older key derivation to
+    /// `kdf::PEER_KDF_CURRENT`. Both sides must migrate before talking again.
+    /// Returns the ids that could not be migrated because no public key was
+    /// recorded for them; re-add those with `update_peer_key`.
+    pub fn migrate_peer_keys(&mut self) -> Result<Vec<String>, CipherError> {
+        let mut skipped = Vec::new();
+
+        for peer_id in self.peer_ids()? {
+            let mut stored = self.load_peer(&peer_id)?;
+            if stored.kdf_version == kdf::PEER_KDF_CURRENT {
+                continue;
+            }
+
+            if stored.public_key.is_none() {
+                skipped.push(peer_id);
+                continue;
+            }
+
+            stored.kdf_version = kdf::PEER_KDF_CURRENT;
+            self.save_peer(&peer_id, &stored)?;
+            self.peer_keys.remove(&peer_id);
+        }
+
+        Ok(skipped)
     }
 
-    fn get_peer_key(&mut self, peer_id: &str) -> Result<&SharedSecret, CipherError> {
+    fn get_peer_key(&mut self, peer_id: &str) -> Result<&PeerKeys, CipherError> {
         if !self.peer_keys.contains_key(peer_id) {
             // Load from storage
-            let data = self
-                .store
-                .get(peer_id)?
-                .ok_or_else(|| CipherError::PeerNotFound(peer_id.to_string()))?;
-
-            let stored: StoredSharedKey = serde_json::from_slice(&data)
-                .map_err(|e| CipherError::StorageError(e.to_string()))?;
+            let stored = self.load_peer(peer_id)?;
 
             let wrapping_key = self
                 .wrapping_key
@@ -376,35 +1460,182 @@ impl<S: Store> Cipher<S> {
 
             let decrypted = cipher
                 .decrypt(nonce, stored.encrypted_key.as_ref())
-                .map_err(|e| CipherError::DecryptionFailed(e.to_string()))?;
+                .map_err(|_| CipherError::KeystoreCorrupted(format!("peer {}", peer_id)))?;
+
+            if decrypted.len() != 32 {
+                return Err(CipherError::InvalidKeyFormat);
+            }
 
             let mut key = [0u8; 32];
             key.copy_from_slice(&decrypted);
 
-            self.peer_keys
-                .insert(peer_id.to_string(), SharedSecret(key));
+            let peer_public = stored
+                .public_key
+                .as_deref()
+                .map(PublicKey::from_sec1_bytes)
+                .transpose()
+                .map_err(|_| CipherError::InvalidKeyFormat)?;
+
+            let keys =
+                self.derive_peer_keys(&SharedSecret(key), stored.kdf_version, peer_public.as_ref())?;
+
+            self.peer_keys.insert(peer_id.to_string(), keys);
         }
 
         Ok(self.peer_keys.get(peer_id).unwrap())
     }
 
-    pub fn encrypt_text(&mut self, plaintext: &str, peer_id: &str) -> Result<String, CipherError> {
+    fn sender_fingerprint(&self) -> Result<[u8; 16], CipherError> {
+        let public = self
+            .identity_public
+            .as_ref()
+            .ok_or(CipherError::NotInitialized)?;
+
+        Ok(Self::fingerprint_bytes(public))
+    }
+
+    /// Encrypts `data` for `peer_id` into a versioned `Envelope`, optionally
+    /// bound to `context` and signed with our signing key.
+    fn seal(
+        &mut self,
+        data: &[u8],
+        peer_id: &str,
+        context: Option<&MessageContext>,
+        signed: bool,
+    ) -> Result<Vec<u8>, CipherError> {
+        let mut header = EnvelopeHeader::new(Suite::P256HkdfAes256Gcm, self.sender_fingerprint()?);
+        if context.is_some() {
+            header.flags |= FLAG_CONTEXT;
+        }
+        if signed {
+            header.flags |= FLAG_SIGNED;
+        }
+
         let peer_key = self.get_peer_key(peer_id)?;
 
         let mut nonce_bytes = [0u8; 12];
         OsRng.fill_bytes(&mut nonce_bytes);
         let nonce = Nonce::<Aes256Gcm>::from_slice(&nonce_bytes);
 
-        let cipher = Aes256Gcm::new_from_slice(&peer_key.0)?;
+        let cipher = Aes256Gcm::new_from_slice(&peer_key.send)?;
         let ciphertext = cipher
-            .encrypt(nonce, plaintext.as_bytes())
+            .encrypt(
+                nonce,
+                Payload {
+                    msg: data,
+                    aad: &header.associated_data(context),
+                },
+            )
             .map_err(|e| CipherError::EncryptionFailed(e.to_string()))?;
 
-        // Pack: [nonce (12 bytes) || ciphertext]
-        let mut packed = Vec::with_capacity(12 + ciphertext.len());
-        packed.extend_from_slice(&nonce_bytes);
-        packed.extend_from_slice(&ciphertext);
+        let envelope = Envelope {
+            header,
+            nonce: nonce_bytes,
+            ciphertext,
+        };
+
+        let mut packed = envelope.to_bytes();
+        if signed {
+            let signature = self.sign(&envelope::signed_data(&packed, context))?;
+            packed.extend_from_slice(&signature);
+        }
+
+        Ok(packed)
+    }
+
+    /// Decrypts an `Envelope` from `peer_id`, or a bare `[nonce || ciphertext]`
+    /// payload written before envelopes existed. `context` must be given
+    /// exactly when the sender bound one. A signed envelope is always
+    /// verified; `require_signature` rejects unsigned ones.
+    fn open(
+        &mut self,
+        packed: &[u8],
+        peer_id: &str,
+        context: Option<&MessageContext>,
+        require_signature: bool,
+    ) -> Result<Vec<u8>, CipherError> {
+        let mut packed = packed;
+        let signed = Envelope::is_envelope(packed)
+            && EnvelopeHeader::parse(packed)?.has_flag(FLAG_SIGNED);
+
+        if signed {
+            if packed.len() < SIGNATURE_LEN {
+                return Err(CipherError::DecryptionFailed("Message too short".into()));
+            }
+            let (unsigned, signature) = packed.split_at(packed.len() - SIGNATURE_LEN);
+            self.verify(&envelope::signed_data(unsigned, context), signature, peer_id)?;
+            packed = unsigned;
+        } else if require_signature {
+            return Err(CipherError::DecryptionFailed("Message is not signed".into()));
+        }
+
+        let peer_key = self.get_peer_key(peer_id)?;
+        let cipher = Aes256Gcm::new_from_slice(&peer_key.recv)?;
+
+        if !Envelope::is_envelope(packed) {
+            if context.is_some() {
+                return Err(CipherError::DecryptionFailed(
+                    "Message is not bound to a context".into(),
+                ));
+            }
+
+            if packed.len() < 12 {
+                return Err(CipherError::DecryptionFailed("Message too short".into()));
+            }
+
+            let nonce = Nonce::<Aes256Gcm>::from_slice(&packed[..12]);
+            return cipher
+                .decrypt(nonce, &packed[12..])
+                .map_err(|e| CipherError::DecryptionFailed(e.to_string()));
+        }
+
+        let envelope = Envelope::parse(packed)?;
+        match envelope.header.suite {
+            Suite::P256HkdfAes256Gcm => {}
+            Suite::P256DoubleRatchet => {
+                return Err(CipherError::DecryptionFailed(
+                    "Session message, use session_decrypt".into(),
+                ))
+            }
+            Suite::P256HkdfAes256GcmStream => {
+                return Err(CipherError::DecryptionFailed(
+                    "Stream, use decrypt_stream".into(),
+                ))
+            }
+        }
+
+        Self::check_context(&envelope.header, context)?;
+
+        let nonce = Nonce::<Aes256Gcm>::from_slice(&envelope.nonce);
+
+        cipher
+            .decrypt(
+                nonce,
+                Payload {
+                    msg: &envelope.ciphertext,
+                    aad: &envelope.header.associated_data(context),
+                },
+            )
+            .map_err(|e| CipherError::DecryptionFailed(e.to_string()))
+    }
+
+    fn check_context(
+        header: &EnvelopeHeader,
+        context: Option<&MessageContext>,
+    ) -> Result<(), CipherError> {
+        match (header.has_flag(FLAG_CONTEXT), context.is_some()) {
+            (true, false) => Err(CipherError::DecryptionFailed(
+                "Message is bound to a context".into(),
+            )),
+            (false, true) => Err(CipherError::DecryptionFailed(
+                "Message is not bound to a context".into(),
+            )),
+            _ => Ok(()),
+        }
+    }
 
+    pub fn encrypt_text(&mut self, plaintext: &str, peer_id: &str) -> Result<String, CipherError> {
+        let packed = self.seal(plaintext.as_bytes(), peer_id, None, false)?;
         Ok(URL_SAFE.encode(&packed))
     }
 
@@ -413,76 +1644,1974 @@ impl<S: Store> Cipher<S> {
         encrypted_b64: &str,
         peer_id: &str,
     ) -> Result<String, CipherError> {
-        let peer_key = self.get_peer_key(peer_id)?;
+        self.decrypt_text_inner(encrypted_b64, peer_id, None, false)
+    }
+
+    pub fn encrypt_text_with_context(
+        &mut self,
+        plaintext: &str,
+        peer_id: &str,
+        context: &MessageContext,
+    ) -> Result<String, CipherError> {
+        let packed = self.seal(plaintext.as_bytes(), peer_id, Some(context), false)?;
+        Ok(URL_SAFE.encode(&packed))
+    }
+
+    pub fn decrypt_text_with_context(
+        &mut self,
+        encrypted_b64: &str,
+        peer_id: &str,
+        context: &MessageContext,
+    ) -> Result<String, CipherError> {
+        self.decrypt_text_inner(encrypted_b64, peer_id, Some(context), false)
+    }
+
+    /// Encrypts and signs `plaintext`, so `peer_id` can check it came from us
+    /// whatever the transport.
+    pub fn encrypt_text_signed(
+        &mut self,
+        plaintext: &str,
+        peer_id: &str,
+        context: Option<&MessageContext>,
+    ) -> Result<String, CipherError> {
+        let packed = self.seal(plaintext.as_bytes(), peer_id, context, true)?;
+        Ok(URL_SAFE.encode(&packed))
+    }
+
+    /// Like `decrypt_text_with_context`, but fails unless the message carries
+    /// a valid signature from `peer_id`.
+    pub fn decrypt_text_signed(
+        &mut self,
+        encrypted_b64: &str,
+        peer_id: &str,
+        context: Option<&MessageContext>,
+    ) -> Result<String, CipherError> {
+        self.decrypt_text_inner(encrypted_b64, peer_id, context, true)
+    }
 
+    fn decrypt_text_inner(
+        &mut self,
+        encrypted_b64: &str,
+        peer_id: &str,
+        context: Option<&MessageContext>,
+        require_signature: bool,
+    ) -> Result<String, CipherError> {
         let packed = URL_SAFE
             .decode(encrypted_b64)
             .map_err(|_| CipherError::DecryptionFailed("Invalid base64".into()))?;
 
-        if packed.len() < 12 {
-            return Err(CipherError::DecryptionFailed("Message too short".into()));
-        }
-
-        let nonce = Nonce::<Aes256Gcm>::from_slice(&packed[..12]);
-        let ciphertext = &packed[12..];
-
-        let cipher = Aes256Gcm::new_from_slice(&peer_key.0)?;
-        let plaintext = cipher
-            .decrypt(nonce, ciphertext)
-            .map_err(|e| CipherError::DecryptionFailed(e.to_string()))?;
+        let plaintext = self.open(&packed, peer_id, context, require_signature)?;
 
         String::from_utf8(plaintext)
             .map_err(|_| CipherError::DecryptionFailed("Invalid UTF-8".into()))
     }
 
     pub fn encrypt_bytes(&mut self, data: &[u8], peer_id: &str) -> Result<Vec<u8>, CipherError> {
-        let peer_key = self.get_peer_key(peer_id)?;
+        self.seal(data, peer_id, None, false)
+    }
 
-        let mut nonce_bytes = [0u8; 12];
-        OsRng.fill_bytes(&mut nonce_bytes);
-        let nonce = Nonce::<Aes256Gcm>::from_slice(&nonce_bytes);
-        let peer_bytes = &peer_key.0;
+    pub fn decrypt_bytes(
+        &mut self,
+        encrypted: &[u8],
+        peer_id: &str,
+    ) -> Result<Vec<u8>, CipherError> {
+        self.open(encrypted, peer_id, None, false)
+    }
 
-        let cipher = Aes256Gcm::new_from_slice(peer_bytes)?;
-        let ciphertext = cipher
-            .encrypt(nonce, data)
-            .map_err(|e| CipherError::EncryptionFailed(e.to_string()))?;
+    pub fn encrypt_bytes_with_context(
+        &mut self,
+        data: &[u8],
+        peer_id: &str,
+        context: &MessageContext,
+    ) -> Result<Vec<u8>, CipherError> {
+        self.seal(data, peer_id, Some(context), false)
+    }
 
-        let mut packed = Vec::with_capacity(12 + ciphertext.len());
-        packed.extend_from_slice(&nonce_bytes);
-        packed.extend_from_slice(&ciphertext);
+    pub fn decrypt_bytes_with_context(
+        &mut self,
+        encrypted: &[u8],
+        peer_id: &str,
+        context: &MessageContext,
+    ) -> Result<Vec<u8>, CipherError> {
+        self.open(encrypted, peer_id, Some(context), false)
+    }
 
-        Ok(packed)
+    pub fn encrypt_bytes_signed(
+        &mut self,
+        data: &[u8],
+        peer_id: &str,
+        context: Option<&MessageContext>,
+    ) -> Result<Vec<u8>, CipherError> {
+        self.seal(data, peer_id, context, true)
     }
 
-    pub fn decrypt_bytes(
+    pub fn decrypt_bytes_signed(
         &mut self,
         encrypted: &[u8],
         peer_id: &str,
+        context: Option<&MessageContext>,
     ) -> Result<Vec<u8>, CipherError> {
-        let peer_key = self.get_peer_key(peer_id)?;
+        self.open(encrypted, peer_id, context, true)
+    }
 
-        let mut nonce_bytes: [u8; 12] = [0u8; 12];
-        if encrypted.len() < 12 {
-            return Err(CipherError::DecryptionFailed("Message too short".into()));
-        }
+    /// Returns a writer that encrypts everything written to it for `peer_id`
+    /// into `writer`, in chunks of `stream::DEFAULT_CHUNK_SIZE`. Call
+    /// `StreamEncryptor::finish` when done.
+    pub fn encrypt_stream<W: Write>(
+        &mut self,
+        writer: W,
+        peer_id: &str,
+    ) -> Result<StreamEncryptor<W>, CipherError> {
+        let header = stream::stream_header(self.sender_fingerprint()?, stream::DEFAULT_CHUNK_SIZE)?;
+        let mut key = self.get_peer_key(peer_id)?.send;
+        let encryptor = StreamEncryptor::new(writer, &key, header);
+        key.zeroize();
 
-        nonce_bytes.copy_from_slice(&encrypted[..12]);
+        encryptor
+    }
 
-        let nonce = Nonce::<Aes256Gcm>::from_slice(&nonce_bytes);
-        let ciphertext = &encrypted[12..];
+    /// Returns a reader over the plaintext of a stream from `peer_id`.
+    pub fn decrypt_stream<R: Read>(
+        &mut self,
+        reader: R,
+        peer_id: &str,
+    ) -> Result<StreamDecryptor<R>, CipherError> {
+        let mut key = self.get_peer_key(peer_id)?.recv;
+        let decryptor = StreamDecryptor::new(reader, &key);
+        key.zeroize();
 
-        let cipher = Aes256Gcm::new_from_slice(&peer_key.0)?;
-        cipher
-            .decrypt(nonce, ciphertext)
-            .map_err(|e| CipherError::DecryptionFailed(e.to_string()))
+        decryptor
     }
 
-    pub fn is_ready(&self) -> bool {
-        self.initialized
+    /// Async counterpart of `encrypt_stream`. Shut the writer down when
+    /// done, e.g. with `AsyncWriteExt::shutdown`.
+    pub fn encrypt_stream_async<W: AsyncWrite + Unpin>(
+        &mut self,
+        writer: W,
+        peer_id: &str,
+    ) -> Result<AsyncStreamEncryptor<W>, CipherError> {
+        let header = stream::stream_header(self.sender_fingerprint()?, stream::DEFAULT_CHUNK_SIZE)?;
+        let key = Zeroizing::new(self.get_peer_key(peer_id)?.send);
+
+        AsyncStreamEncryptor::new(writer, &key, header)
     }
 
-    pub fn clear_cache(&mut self) {
-        self.peer_keys.clear();
+    /// Async counterpart of `decrypt_stream`.
+    pub async fn decrypt_stream_async<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: R,
+        peer_id: &str,
+    ) -> Result<AsyncStreamDecryptor<R>, CipherError> {
+        let key = Zeroizing::new(self.get_peer_key(peer_id)?.recv);
+
+        AsyncStreamDecryptor::new(reader, &key).await
+    }
+
+    /// Encrypts all of `reader` into `writer` and returns the number of
+    /// plaintext bytes.
+    pub async fn encrypt_all_async<R, W>(
+        &mut self,
+        mut reader: R,
+        writer: W,
+        peer_id: &str,
+    ) -> Result<u64, CipherError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut encryptor = self.encrypt_stream_async(writer, peer_id)?;
+        let total = tokio::io::copy(&mut reader, &mut encryptor).await?;
+        encryptor.shutdown().await?;
+        Ok(total)
+    }
+
+    /// Decrypts a whole stream from `reader` into `writer` and returns the
+    /// number of plaintext bytes. On error, discard whatever was written.
+    pub async fn decrypt_all_async<R, W>(
+        &mut self,
+        reader: R,
+        mut writer: W,
+        peer_id: &str,
+    ) -> Result<u64, CipherError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut decryptor = self.decrypt_stream_async(reader, peer_id).await?;
+        let total = tokio::io::copy(&mut decryptor, &mut writer).await?;
+        writer.flush().await?;
+        Ok(total)
+    }
+
+    fn seal_local(&self, plaintext: &[u8]) -> Result<Sealed, CipherError> {
+        let wrapping_key = self
+            .wrapping_key
+            .as_ref()
+            .ok_or(CipherError::NotInitialized)?;
+
+        Sealed::seal(wrapping_key, plaintext)
+    }
+
+    fn open_local(&self, sealed: &Sealed) -> Result<Vec<u8>, CipherError> {
+        let wrapping_key = self
+            .wrapping_key
+            .as_ref()
+            .ok_or(CipherError::NotInitialized)?;
+
+        sealed
+            .open(wrapping_key)
+            .map_err(|_| CipherError::KeystoreCorrupted("sealed entry".into()))
+    }
+
+    /// Store operations that re-encrypt every wrapped secret under `new_key`.
+    /// Nothing is written; any entry that fails to decrypt aborts the lot.
+    fn rewrap_ops(&self, new_key: &WrappingKey) -> Result<Vec<StoreOp>, CipherError> {
+        let old_key = self
+            .wrapping_key
+            .as_ref()
+            .ok_or(CipherError::NotInitialized)?;
+        let mut ops = Vec::new();
+
+        if let Some(data) = self.store.get(keys::IDENTITY)? {
+            let mut stored: StoredIdentity = serde_json::from_slice(&data)
+                .map_err(|e| CipherError::StorageError(e.to_string()))?;
+
+            let private = Sealed {
+                ciphertext: stored.encrypted_private_key,
+                nonce: stored.nonce,
+            }
+            .rewrap(old_key, new_key)?;
+            stored.encrypted_private_key = private.ciphertext;
+            stored.nonce = private.nonce;
+
+            if let Some(signing_key) = &stored.signing_key {
+                stored.signing_key = Some(signing_key.rewrap(old_key, new_key)?);
+            }
+
+            ops.push(StoreOp::Put(keys::IDENTITY.to_string(), to_json(&stored)?));
+        }
+
+        for peer_id in self.peer_ids()? {
+            let mut stored = self.load_peer(&peer_id)?;
+
+            let secret = Sealed {
+                ciphertext: stored.encrypted_key,
+                nonce: stored.nonce,
+            }
+            .rewrap(old_key, new_key)?;
+            stored.encrypted_key = secret.ciphertext;
+            stored.nonce = secret.nonce;
+
+            ops.push(StoreOp::Put(keys::peer(&peer_id), to_json(&stored)?));
+        }
+
+        for prefix in keys::SEALED_PREFIXES {
+            for id in self.store.list(prefix)? {
+                let Some(data) = self.store.get(&id)? else {
+                    continue;
+                };
+                let sealed: Sealed = serde_json::from_slice(&data)
+                    .map_err(|e| CipherError::StorageError(e.to_string()))?;
+
+                ops.push(StoreOp::Put(id, to_json(&sealed.rewrap(old_key, new_key)?)?));
+            }
+        }
+
+        Ok(ops)
+    }
+
+    /// Whether the secrets in entry `id` decrypt with the wrapping key.
+    fn entry_is_intact(&self, id: &str, data: &[u8]) -> bool {
+        let Some(key) = self.wrapping_key.as_ref() else {
+            return false;
+        };
+
+        let sealed: Vec<Sealed> = if id == keys::IDENTITY {
+            match serde_json::from_slice::<StoredIdentity>(data) {
+                Ok(stored) => {
+                    let mut sealed = vec![Sealed {
+                        ciphertext: stored.encrypted_private_key,
+                        nonce: stored.nonce,
+                    }];
+                    sealed.extend(stored.signing_key);
+                    sealed
+                }
+                Err(_) => return false,
+            }
+        } else if id.starts_with(keys::PEER_PREFIX) {
+            match serde_json::from_slice::<StoredPeer>(data) {
+                Ok(stored) => vec![Sealed {
+                    ciphertext: stored.encrypted_key,
+                    nonce: stored.nonce,
+                }],
+                Err(_) => return false,
+            }
+        } else {
+            match serde_json::from_slice::<Sealed>(data) {
+                Ok(sealed) => vec![sealed],
+                Err(_) => return false,
+            }
+        };
+
+        sealed.iter().all(|sealed| match sealed.open(key) {
+            Ok(mut plain) => {
+                plain.zeroize();
+                true
+            }
+            Err(_) => false,
+        })
+    }
+
+    /// Ids of entries holding secrets that no longer decrypt. Also usable
+    /// after `init` failed with `KeystoreCorrupted`.
+    pub fn check_keystore(&self) -> Result<Vec<String>, CipherError> {
+        if self.wrapping_key.is_none() {
+            return Err(CipherError::NotInitialized);
+        }
+
+        let mut ids = Vec::new();
+        if self.store.has(keys::IDENTITY)? {
+            ids.push(keys::IDENTITY.to_string());
+        }
+        ids.extend(self.store.list(keys::PEER_PREFIX)?);
+        for prefix in keys::SEALED_PREFIXES {
+            ids.extend(self.store.list(prefix)?);
+        }
+
+        let mut corrupted = Vec::new();
+        for id in ids {
+            if let Some(data) = self.store.get(&id)? {
+                if !self.entry_is_intact(&id, &data) {
+                    corrupted.push(id);
+                }
+            }
+        }
+
+        Ok(corrupted)
+    }
+
+    /// Moves every entry found by `check_keystore` under `keys::QUARANTINE_PREFIX`
+    /// so the rest of the keystore can be used again, and returns their ids.
+    /// Nothing is deleted: `quarantined_entries` exports them for inspection.
+    /// A quarantined identity is replaced by a new one on the next `init`, so
+    /// every peer has to register the new key.
+    pub fn quarantine_corrupted(&mut self) -> Result<Vec<String>, CipherError> {
+        // Only with a key the verifier vouches for, or everything would look
+        // corrupted.
+        if !self.check_verifier()? {
+            return Err(CipherError::Unsupported(
+                "Keystore has no verifier yet, unlock it once first".into(),
+            ));
+        }
+
+        let corrupted = self.check_keystore()?;
+        let mut ops = Vec::new();
+        for id in &corrupted {
+            if let Some(data) = self.store.get(id)? {
+                ops.push(StoreOp::Put(keys::quarantine(id), data));
+                ops.push(StoreOp::Delete(id.clone()));
+            }
+        }
+        self.store.apply(ops)?;
+
+        for id in &corrupted {
+            if let Some(peer_id) = id.strip_prefix(keys::PEER_PREFIX) {
+                self.peer_keys.remove(peer_id);
+            }
+        }
+
+        Ok(corrupted)
+    }
+
+    /// Raw content of quarantined entries, by their original id.
+    pub fn quarantined_entries(&self) -> Result<Vec<(String, Vec<u8>)>, CipherError> {
+        let mut entries = Vec::new();
+        for id in self.store.list(keys::QUARANTINE_PREFIX)? {
+            if let Some(data) = self.store.get(&id)? {
+                entries.push((id[keys::QUARANTINE_PREFIX.len()..].to_string(), data));
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Re-encrypts every secret in the keystore under a key derived from
+    /// `new_password` and a fresh salt, with the current KDF parameters.
+    /// Either everything is re-encrypted or the keystore is left untouched.
+    pub fn change_password(
+        &mut self,
+        old_password: &str,
+        new_password: &str,
+    ) -> Result<(), CipherError> {
+        if !self.initialized {
+            return Err(CipherError::NotInitialized);
+        }
+        if !self.password_protected {
+            return Err(CipherError::Unsupported(
+                "Keystore is not password protected".into(),
+            ));
+        }
+
+        let salt = self
+            .store
+            .get(keys::SALT)?
+            .ok_or(CipherError::NotInitialized)?;
+        let old_key = WrappingKey(self.password_kdf()?.derive(old_password, &salt)?);
+        let current = self
+            .wrapping_key
+            .as_ref()
+            .ok_or(CipherError::NotInitialized)?;
+        if !current.matches(&old_key) {
+            return Err(CipherError::WrongPassword);
+        }
+
+        self.rekey_with_password(new_password)?;
+        self.remember_key();
+        Ok(())
+    }
+
+    /// Replaces the stored wrapping key of a keystore without a password and
+    /// re-encrypts every secret under it, atomically. Password-protected
+    /// keystores rotate their key through `change_password`.
+    pub fn rotate_wrapping_key(&mut self) -> Result<(), CipherError> {
+        if !self.initialized {
+            return Err(CipherError::NotInitialized);
+        }
+        if self.password_protected {
+            return Err(CipherError::Unsupported(
+                "Wrapping key is derived from the password, use change_password".into(),
+            ));
+        }
+
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        let new_key = WrappingKey(key);
+        key.zeroize();
+
+        let mut ops = self.rewrap_ops(&new_key)?;
+        ops.push(StoreOp::Put(keys::WRAPPING_KEY.to_string(), new_key.0.to_vec()));
+        ops.push(self.verifier_op(&new_key)?);
+        self.store.apply(ops)?;
+
+        self.wrapping_key = Some(new_key);
+        Ok(())
+    }
+
+    fn peer_public_key(&self, peer_id: &str) -> Result<PublicKey, CipherError> {
+        let stored = self.load_peer(peer_id)?;
+        let bytes = stored
+            .public_key
+            .ok_or_else(|| CipherError::PeerKeyUnknown(peer_id.to_string()))?;
+
+        PublicKey::from_sec1_bytes(&bytes).map_err(|_| CipherError::InvalidKeyFormat)
+    }
+
+    /// Session secrets and associated data for a ratchet bootstrapped from
+    /// the static identity keys of both parties.
+    fn bootstrap_session(
+        &self,
+        peer_public: &PublicKey,
+        we_initiate: bool,
+    ) -> Result<(SessionSecrets, Vec<u8>), CipherError> {
+        let own = self
+            .identity_public
+            .as_ref()
+            .ok_or(CipherError::NotInitialized)?
+            .to_sec1_bytes();
+        let peer = peer_public.to_sec1_bytes();
+
+        let mut parties = Vec::with_capacity(own.len() + peer.len());
+        if we_initiate {
+            parties.extend_from_slice(&own);
+            parties.extend_from_slice(&peer);
+        } else {
+            parties.extend_from_slice(&peer);
+            parties.extend_from_slice(&own);
+        }
+
+        let secret = self.derive_shared_secret(peer_public)?;
+        let mut info = Vec::from(&b"nyx/ratchet/bootstrap"[..]);
+        info.extend_from_slice(&parties);
+
+        Ok((SessionSecrets::derive(&secret.0, &info)?, parties))
+    }
+
+    fn load_session(&self, peer_id: &str) -> Result<Option<StoredSession>, CipherError> {
+        let Some(data) = self.store.get(&keys::session(peer_id))? else {
+            return Ok(None);
+        };
+
+        let sealed: Sealed =
+            serde_json::from_slice(&data).map_err(|e| CipherError::StorageError(e.to_string()))?;
+        let mut plain = self.open_local(&sealed)?;
+        let session = serde_json::from_slice(&plain)
+            .map_err(|e| CipherError::StorageError(e.to_string()));
+        plain.zeroize();
+
+        session.map(Some)
+    }
+
+    fn save_session(&mut self, peer_id: &str, session: &StoredSession) -> Result<(), CipherError> {
+        let mut plain =
+            serde_json::to_vec(session).map_err(|e| CipherError::StorageError(e.to_string()))?;
+        let sealed = self.seal_local(&plain);
+        plain.zeroize();
+
+        let serialized = serde_json::to_vec(&sealed?)
+            .map_err(|e| CipherError::StorageError(e.to_string()))?;
+        self.store.put(&keys::session(peer_id), serialized)
+    }
+
+    /// Starts a Double Ratchet session with a registered peer, replacing any
+    /// existing one. The peer sets up its side when the first message arrives.
+    pub fn start_session(&mut self, peer_id: &str) -> Result<(), CipherError> {
+        let peer_public = self.peer_public_key(peer_id)?;
+        let (secrets, associated_data) = self.bootstrap_session(&peer_public, true)?;
+
+        let state = RatchetState::initiator(&secrets, &peer_public, associated_data)?;
+        self.save_session(
+            peer_id,
+            &StoredSession {
+                state,
+                prekey_header: None,
+            },
+        )
+    }
+
+    pub fn has_session(&self, peer_id: &str) -> Result<bool, CipherError> {
+        self.store.has(&keys::session(peer_id))
+    }
+
+    pub fn end_session(&mut self, peer_id: &str) -> Result<(), CipherError> {
+        self.store.delete(&keys::session(peer_id))
+    }
+
+    fn seal_prekey(&self, key: &SecretKey) -> Result<Vec<u8>, CipherError> {
+        let mut scalar: [u8; 32] = key.to_bytes().into();
+        let sealed = self.seal_local(&scalar);
+        scalar.zeroize();
+
+        serde_json::to_vec(&sealed?).map_err(|e| CipherError::StorageError(e.to_string()))
+    }
+
+    fn load_prekey(&self, id: &str) -> Result<Option<SecretKey>, CipherError> {
+        let Some(data) = self.store.get(id)? else {
+            return Ok(None);
+        };
+
+        let sealed: Sealed =
+            serde_json::from_slice(&data).map_err(|e| CipherError::StorageError(e.to_string()))?;
+        let mut scalar = self.open_local(&sealed)?;
+        let key = SecretKey::from_slice(&scalar).map_err(|_| CipherError::InvalidKeyFormat);
+        scalar.zeroize();
+
+        key.map(Some)
+    }
+
+    /// Ids of the stored prekeys under `prefix`, in ascending order.
+    fn prekey_ids(&self, prefix: &str) -> Result<Vec<u32>, CipherError> {
+        Ok(self
+            .store
+            .list(prefix)?
+            .iter()
+            .filter_map(|id| id[prefix.len()..].parse().ok())
+            .collect())
+    }
+
+    /// Prekey ids are never reused, so a bundle fetched long ago cannot
+    /// name a different key than the one it was built from.
+    fn next_prekey_id(&self) -> Result<u32, CipherError> {
+        let Some(data) = self.store.get(keys::NEXT_PREKEY_ID)? else {
+            return Ok(1);
+        };
+
+        let bytes: [u8; 4] = data
+            .as_slice()
+            .try_into()
+            .map_err(|_| CipherError::StorageError("Invalid prekey counter".into()))?;
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    /// Makes sure a signed prekey exists and adds `count` one-time prekeys.
+    pub fn generate_prekeys(&mut self, count: u32) -> Result<(), CipherError> {
+        if !self.initialized {
+            return Err(CipherError::NotInitialized);
+        }
+
+        let mut next_id = self.next_prekey_id()?;
+        let mut ops = Vec::new();
+
+        if self.prekey_ids(keys::SIGNED_PREKEY_PREFIX)?.is_empty() {
+            let key = SecretKey::random(&mut OsRng);
+            ops.push(StoreOp::Put(keys::signed_prekey(next_id), self.seal_prekey(&key)?));
+            next_id += 1;
+        }
+
+        for _ in 0..count {
+            let key = SecretKey::random(&mut OsRng);
+            ops.push(StoreOp::Put(keys::one_time_prekey(next_id), self.seal_prekey(&key)?));
+            next_id += 1;
+        }
+
+        ops.push(StoreOp::Put(
+            keys::NEXT_PREKEY_ID.to_string(),
+            next_id.to_be_bytes().to_vec(),
+        ));
+        self.store.apply(ops)
+    }
+
+    /// Replaces the signed prekey and returns the new id. The previous one is
+    /// kept so sessions started from a bundle fetched before the rotation
+    /// still work; older ones are deleted.
+    pub fn rotate_signed_prekey(&mut self) -> Result<u32, CipherError> {
+        if !self.initialized {
+            return Err(CipherError::NotInitialized);
+        }
+
+        let id = self.next_prekey_id()?;
+        let key = SecretKey::random(&mut OsRng);
+
+        let mut ops = vec![
+            StoreOp::Put(keys::signed_prekey(id), self.seal_prekey(&key)?),
+            StoreOp::Put(
+                keys::NEXT_PREKEY_ID.to_string(),
+                (id + 1).to_be_bytes().to_vec(),
+            ),
+        ];
+
+        let existing = self.prekey_ids(keys::SIGNED_PREKEY_PREFIX)?;
+        for old in existing.iter().rev().skip(1) {
+            ops.push(StoreOp::Delete(keys::signed_prekey(*old)));
+        }
+
+        self.store.apply(ops)?;
+        Ok(id)
+    }
+
+    /// Bundle to publish: the current signed prekey and every unused
+    /// one-time prekey. Call `generate_prekeys` first.
+    pub fn prekey_bundle(&self) -> Result<PrekeyBundle, CipherError> {
+        let identity = self
+            .identity_private
+            .as_ref()
+            .ok_or(CipherError::NotInitialized)?;
+        let signing_key = self.signing_key.as_ref().ok_or(CipherError::NotInitialized)?;
+
+        let signed_prekey_id = *self
+            .prekey_ids(keys::SIGNED_PREKEY_PREFIX)?
+            .last()
+            .ok_or_else(|| CipherError::RatchetError("No signed prekey generated".into()))?;
+        let signed_prekey = self
+            .load_prekey(&keys::signed_prekey(signed_prekey_id))?
+            .ok_or_else(|| CipherError::RatchetError("No signed prekey generated".into()))?
+            .public_key();
+
+        let mut one_time_prekeys = Vec::new();
+        for id in self.prekey_ids(keys::ONE_TIME_PREKEY_PREFIX)? {
+            if let Some(key) = self.load_prekey(&keys::one_time_prekey(id))? {
+                one_time_prekeys.push(OneTimePrekey {
+                    id,
+                    key: x3dh::encode_key(&key.public_key()),
+                });
+            }
+        }
+
+        Ok(PrekeyBundle {
+            version: x3dh::BUNDLE_VERSION,
+            identity_key: x3dh::encode_key(&identity.public_key()),
+            signed_prekey_id,
+            signing_key: Some(self.export_signing_key()?),
+            signed_prekey: x3dh::encode_key(&signed_prekey),
+            signed_prekey_signature: x3dh::sign_prekey(signing_key, &signed_prekey),
+            one_time_prekeys,
+        })
+    }
+
+    /// Registers `peer_id` with `identity_key`, or checks it against the
+    /// pinned key.
+    fn register_or_check_peer(
+        &mut self,
+        peer_id: &str,
+        identity_key: &PublicKey,
+    ) -> Result<(), CipherError> {
+        self.register_peer(peer_id, &x3dh::encode_key(identity_key))
+    }
+
+    /// Starts a session with the owner of `bundle`, who may be offline,
+    /// replacing any existing one. The peer is registered if it is new. A
+    /// version 2 bundle must be signed by the signing key recorded for the
+    /// peer with `set_peer_signing_key`.
+    pub fn start_session_with_bundle(
+        &mut self,
+        peer_id: &str,
+        bundle: &PrekeyBundle,
+    ) -> Result<(), CipherError> {
+        let identity = self
+            .identity_private
+            .as_ref()
+            .ok_or(CipherError::NotInitialized)?;
+        let initiation = x3dh::initiate(identity, bundle)?;
+
+        // The bundle's signature only counts if its signing key is the one
+        // the pinned identity key vouched for; version 1 bundles are signed
+        // by the identity key itself.
+        if let Some(signing_key) = &bundle.signing_key {
+            let recorded = match self.has_peer(peer_id)? {
+                true => self.load_peer(peer_id)?.signing_key,
+                false => None,
+            }
+            .ok_or_else(|| CipherError::SigningKeyNotFound(peer_id.to_string()))?;
+            let recorded = VerifyingKey::from_sec1_bytes(&recorded)
+                .map_err(|_| CipherError::InvalidKeyFormat)?;
+            if VerifyingKey::from(&x3dh::decode_key(signing_key)?) != recorded {
+                return Err(CipherError::InvalidSignature);
+            }
+        }
+
+        // Checked against the verified bundle, before anything is stored.
+        self.register_or_check_peer(peer_id, &x3dh::decode_key(&bundle.identity_key)?)?;
+
+        let state = RatchetState::initiator(
+            &initiation.secrets,
+            &initiation.responder_ratchet_key,
+            initiation.associated_data,
+        )?;
+        self.save_session(
+            peer_id,
+            &StoredSession {
+                state,
+                prekey_header: Some(initiation.header.to_bytes()),
+            },
+        )
+    }
+
+    /// Encrypts `data` with a fresh message key from the session with `peer_id`.
+    pub fn session_encrypt(
+        &mut self,
+        data: &[u8],
+        peer_id: &str,
+        context: Option<&MessageContext>,
+    ) -> Result<Vec<u8>, CipherError> {
+        let mut session = self
+            .load_session(peer_id)?
+            .ok_or_else(|| CipherError::SessionNotFound(peer_id.to_string()))?;
+
+        let mut header = EnvelopeHeader::new(Suite::P256DoubleRatchet, self.sender_fingerprint()?);
+        if context.is_some() {
+            header.flags |= FLAG_CONTEXT;
+        }
+
+        let mut prefix = Vec::new();
+        if session.state.is_awaiting_reply() {
+            header.flags |= FLAG_SESSION_INIT;
+            if let Some(prekey_header) = &session.prekey_header {
+                header.flags |= FLAG_PREKEY;
+                prefix.extend_from_slice(prekey_header);
+            }
+        } else {
+            session.prekey_header = None;
+        }
+
+        let mut ad = header.associated_data(context);
+        ad.extend_from_slice(&prefix);
+
+        let message = session.state.encrypt(data, &ad)?;
+        self.save_session(peer_id, &session)?;
+
+        let mut ciphertext = prefix;
+        ciphertext.extend_from_slice(&message.encrypted_header);
+        ciphertext.extend_from_slice(&message.ciphertext);
+
+        Ok(Envelope {
+            header,
+            nonce: message.header_nonce,
+            ciphertext,
+        }
+        .to_bytes())
+    }
+
+    /// Our side of a session started by a message flagged `FLAG_PREKEY`.
+    fn prekey_responder(
+        &self,
+        header: &PrekeyHeader,
+    ) -> Result<RatchetState, CipherError> {
+        let identity = self
+            .identity_private
+            .as_ref()
+            .ok_or(CipherError::NotInitialized)?;
+
+        let unknown_prekey = || CipherError::RatchetError("Unknown or used prekey".into());
+        let signed_prekey = self
+            .load_prekey(&keys::signed_prekey(header.signed_prekey_id))?
+            .ok_or_else(unknown_prekey)?;
+        let one_time_prekey = match header.one_time_prekey_id {
+            Some(id) => Some(
+                self.load_prekey(&keys::one_time_prekey(id))?
+                    .ok_or_else(unknown_prekey)?,
+            ),
+            None => None,
+        };
+
+        let (secrets, associated_data) =
+            x3dh::respond(identity, &signed_prekey, one_time_prekey.as_ref(), header)?;
+
+        Ok(RatchetState::responder(&secrets, &signed_prekey, associated_data))
+    }
+
+    /// Decrypts a session message from `peer_id`. A message flagged as a
+    /// session start sets up (or restarts) our side of the session; when both
+    /// sides started one at the same time, the peer with the lower public key
+    /// keeps the initiator role. A session started from our prekey bundle
+    /// registers `peer_id` if it is new and consumes the one-time prekey.
+    pub fn session_decrypt(
+        &mut self,
+        packed: &[u8],
+        peer_id: &str,
+        context: Option<&MessageContext>,
+    ) -> Result<Vec<u8>, CipherError> {
+        let envelope = Envelope::parse(packed)?;
+        if envelope.header.suite != Suite::P256DoubleRatchet {
+            return Err(CipherError::DecryptionFailed("Not a session message".into()));
+        }
+
+        Self::check_context(&envelope.header, context)?;
+
+        let mut ad = envelope.header.associated_data(context);
+        let mut body = envelope.ciphertext.as_slice();
+        let mut prekey_header = None;
+
+        if envelope.header.has_flag(FLAG_PREKEY) {
+            if body.len() < PREKEY_HEADER_LEN {
+                return Err(CipherError::DecryptionFailed("Message too short".into()));
+            }
+            let (prefix, rest) = body.split_at(PREKEY_HEADER_LEN);
+            prekey_header = Some(PrekeyHeader::parse(prefix)?);
+            ad.extend_from_slice(prefix);
+            body = rest;
+        }
+
+        if body.len() < ENCRYPTED_HEADER_LEN {
+            return Err(CipherError::DecryptionFailed("Message too short".into()));
+        }
+
+        let (encrypted_header, ciphertext) = body.split_at(ENCRYPTED_HEADER_LEN);
+        let message = RatchetMessage {
+            header_nonce: envelope.nonce,
+            encrypted_header: encrypted_header.to_vec(),
+            ciphertext: ciphertext.to_vec(),
+        };
+
+        let mut existing = self.load_session(peer_id)?;
+        let is_init = envelope.header.has_flag(FLAG_SESSION_INIT);
+
+        let error = match existing.as_mut() {
+            Some(session) => match session.state.decrypt(&message, &ad) {
+                Ok(plaintext) => {
+                    self.save_session(peer_id, session)?;
+                    return Ok(plaintext);
+                }
+                Err(e) if !is_init => return Err(e),
+                Err(e) => {
+                    if session.state.is_awaiting_reply()
+                        && !self.yields_initiator_role(peer_id)?
+                    {
+                        return Err(e);
+                    }
+                    e
+                }
+            },
+            None if is_init => CipherError::SessionNotFound(peer_id.to_string()),
+            None => return Err(CipherError::SessionNotFound(peer_id.to_string())),
+        };
+
+        let mut state = match &prekey_header {
+            Some(header) => {
+                // The peer may be new; a known peer must use the key on record.
+                if self.has_peer(peer_id)?
+                    && self.peer_public_key(peer_id)? != header.identity_key
+                {
+                    return Err(error);
+                }
+                self.prekey_responder(header)?
+            }
+            None => {
+                let peer_public = self.peer_public_key(peer_id)?;
+                let (secrets, associated_data) = self.bootstrap_session(&peer_public, false)?;
+                let identity = self
+                    .identity_private
+                    .as_ref()
+                    .ok_or(CipherError::NotInitialized)?;
+                RatchetState::responder(&secrets, identity, associated_data)
+            }
+        };
+        let plaintext = state.decrypt(&message, &ad).map_err(|_| error)?;
+
+        // A replayed first message of the current session must not reset it.
+        if existing.is_some_and(|current| current.state.same_origin(&state)) {
+            return Err(CipherError::DecryptionFailed("Replayed session message".into()));
+        }
+
+        if let Some(header) = &prekey_header {
+            self.register_or_check_peer(peer_id, &header.identity_key)?;
+            if let Some(id) = header.one_time_prekey_id {
+                self.store.delete(&keys::one_time_prekey(id))?;
+            }
+        }
+
+        self.save_session(
+            peer_id,
+            &StoredSession {
+                state,
+                prekey_header: None,
+            },
+        )?;
+
+        Ok(plaintext)
+    }
+
+    /// Whether our pending session with `peer_id` gives way to theirs when
+    /// both sides started one simultaneously.
+    fn yields_initiator_role(&self, peer_id: &str) -> Result<bool, CipherError> {
+        let own = self
+            .identity_public
+            .as_ref()
+            .ok_or(CipherError::NotInitialized)?
+            .to_sec1_bytes();
+        let peer = self.peer_public_key(peer_id)?.to_sec1_bytes();
+
+        Ok(peer < own)
+    }
+
+    /// Non-secret preference stored with the keystore and carried in backups.
+    pub fn setting(&self, name: &str) -> Result<Option<String>, CipherError> {
+        self.store
+            .get(&keys::setting(name))?
+            .map(|data| {
+                String::from_utf8(data).map_err(|e| CipherError::StorageError(e.to_string()))
+            })
+            .transpose()
+    }
+
+    pub fn set_setting(&mut self, name: &str, value: Option<&str>) -> Result<(), CipherError> {
+        match value {
+            Some(value) => self.store.put(&keys::setting(name), value.as_bytes().to_vec()),
+            None => self.store.delete(&keys::setting(name)),
+        }
+    }
+
+    /// Exports the identity, peer records and settings as a backup encrypted
+    /// with `passphrase`, in the `backup` format.
+    pub fn export_backup(&mut self, passphrase: &str) -> Result<Vec<u8>, CipherError> {
+        if !self.initialized {
+            return Err(CipherError::NotInitialized);
+        }
+
+        let private = self
+            .identity_private
+            .as_ref()
+            .ok_or(CipherError::NotInitialized)?;
+        let signing_key = self.signing_key.as_ref().ok_or(CipherError::NotInitialized)?;
+        let wrapping_key = self
+            .wrapping_key
+            .as_ref()
+            .ok_or(CipherError::NotInitialized)?;
+
+        let identity = BackupIdentity {
+            private_key: private.to_bytes().to_vec(),
+            public_key: private.public_key().to_sec1_bytes().to_vec(),
+            signing_key: Some(signing_key.to_bytes().to_vec()),
+        };
+
+        let mut peers = Vec::new();
+        for peer_id in self.peer_ids()? {
+            let stored = self.load_peer(&peer_id)?;
+            let secret = Sealed {
+                ciphertext: stored.encrypted_key,
+                nonce: stored.nonce,
+            }
+            .open(wrapping_key)
+            .map_err(|_| CipherError::KeystoreCorrupted(format!("peer {}", peer_id)))?;
+
+            peers.push(BackupPeer {
+                id: peer_id,
+                secret,
+                public_key: stored.public_key,
+                added_at: stored.added_at,
+                alias: stored.alias,
+                verification: stored.verification,
+                kdf_version: stored.kdf_version,
+                signing_key: stored.signing_key,
+            });
+        }
+
+        let mut settings = BTreeMap::new();
+        for id in self.store.list(keys::SETTING_PREFIX)? {
+            if let Some(value) = self.setting(&id[keys::SETTING_PREFIX.len()..])? {
+                settings.insert(id[keys::SETTING_PREFIX.len()..].to_string(), value);
+            }
+        }
+
+        backup::seal(
+            &BackupPayload {
+                created_at: Utc::now(),
+                identity,
+                peers,
+                settings,
+            },
+            passphrase,
+        )
+    }
+
+    /// Restores a backup made by `export_backup` into this keystore.
+    ///
+    /// When the backup holds a different identity, it replaces everything
+    /// tied to the current one: peers, settings, sessions and prekeys.
+    /// Otherwise its peers and settings are merged in, overwriting entries
+    /// with the same id.
+    pub fn import_backup(&mut self, data: &[u8], passphrase: &str) -> Result<(), CipherError> {
+        if !self.initialized {
+            return Err(CipherError::NotInitialized);
+        }
+
+        let payload = backup::open(data, passphrase)?;
+
+        let private = SecretKey::from_slice(&payload.identity.private_key)
+            .map_err(|_| CipherError::InvalidBackup("Invalid identity key".into()))?;
+        let public = private.public_key();
+        if public.to_sec1_bytes().as_ref() != payload.identity.public_key.as_slice() {
+            return Err(CipherError::InvalidBackup("Identity keys do not match".into()));
+        }
+
+        let wrapping_key = self
+            .wrapping_key
+            .as_ref()
+            .ok_or(CipherError::NotInitialized)?;
+        let mut ops = Vec::new();
+
+        let replacing = self.identity_public.as_ref() != Some(&public);
+        if replacing {
+            for prefix in [keys::PEER_PREFIX, keys::SETTING_PREFIX]
+                .iter()
+                .chain(keys::SEALED_PREFIXES)
+            {
+                for id in self.store.list(prefix)? {
+                    ops.push(StoreOp::Delete(id));
+                }
+            }
+        }
+
+        let sealed_private = Sealed::seal(wrapping_key, &payload.identity.private_key)?;
+        let mut stored = StoredIdentity {
+            public_key: payload.identity.public_key.clone(),
+            encrypted_private_key: sealed_private.ciphertext,
+            nonce: sealed_private.nonce,
+            signing_public_key: None,
+            signing_key: None,
+        };
+        if let Some(raw) = &payload.identity.signing_key {
+            let signing_key = SigningKey::from_slice(raw)
+                .map_err(|_| CipherError::InvalidBackup("Invalid signing key".into()))?;
+            stored.signing_public_key = Some(signing_key.verifying_key().to_sec1_bytes().to_vec());
+            stored.signing_key = Some(Sealed::seal(wrapping_key, raw)?);
+        }
+
+        for peer in &payload.peers {
+            if peer.secret.len() != 32 {
+                return Err(CipherError::InvalidBackup(format!(
+                    "Invalid key for peer {}",
+                    peer.id
+                )));
+            }
+            let sealed = Sealed::seal(wrapping_key, &peer.secret)?;
+
+            let record = StoredPeer {
+                encrypted_key: sealed.ciphertext,
+                nonce: sealed.nonce,
+                public_key: peer.public_key.clone(),
+                added_at: peer.added_at,
+                alias: peer.alias.clone(),
+                verification: peer.verification,
+                kdf_version: peer.kdf_version,
+                signing_key: peer.signing_key.clone(),
+                pending_key: None,
+            };
+            ops.push(StoreOp::Put(keys::peer(&peer.id), to_json(&record)?));
+        }
+
+        for (name, value) in &payload.settings {
+            ops.push(StoreOp::Put(keys::setting(name), value.as_bytes().to_vec()));
+        }
+
+        // Creates a signing key if the backup predates them, and loads it.
+        let previous_signing_key = self.signing_key.take();
+        let result = self.load_or_create_signing_key(&mut stored).and_then(|_| {
+            ops.push(StoreOp::Put(keys::IDENTITY.to_string(), to_json(&stored)?));
+            self.store.apply(ops)
+        });
+        if let Err(e) = result {
+            self.signing_key = previous_signing_key;
+            return Err(e);
+        }
+
+        self.identity_private = Some(private);
+        self.identity_public = Some(public);
+        self.peer_keys.clear();
+        Ok(())
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.initialized
+    }
+
+    pub fn is_password_protected(&self) -> bool {
+        self.password_protected
+    }
+
+    pub fn clear_cache(&mut self) {
+        self.peer_keys.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cipher() -> Cipher<MemoryStore> {
+        let mut cipher = Cipher::new(MemoryStore::new());
+        cipher.init(None).unwrap();
+        cipher
+    }
+
+    /// Alice and Bob, each registered as the other's peer.
+    fn pair() -> (Cipher<MemoryStore>, Cipher<MemoryStore>) {
+        let (mut alice, mut bob) = (cipher(), cipher());
+        alice.register_peer("bob", &bob.export_public_key().unwrap()).unwrap();
+        bob.register_peer("alice", &alice.export_public_key().unwrap()).unwrap();
+        (alice, bob)
+    }
+
+    #[test]
+    fn session_round_trips_out_of_order() {
+        let (mut alice, mut bob) = pair();
+        alice.start_session("bob").unwrap();
+
+        let first = alice.session_encrypt(b"first", "bob", None).unwrap();
+        let second = alice.session_encrypt(b"second", "bob", None).unwrap();
+        assert_eq!(bob.session_decrypt(&second, "alice", None).unwrap(), b"second");
+        assert_eq!(bob.session_decrypt(&first, "alice", None).unwrap(), b"first");
+
+        let reply = bob.session_encrypt(b"reply", "alice", None).unwrap();
+        assert_eq!(alice.session_decrypt(&reply, "bob", None).unwrap(), b"reply");
+        assert!(bob.session_decrypt(&second, "alice", None).is_err());
+    }
+
+    #[test]
+    fn session_rejects_tampering() {
+        let (mut alice, mut bob) = pair();
+        alice.start_session("bob").unwrap();
+
+        let mut message = alice.session_encrypt(b"hello", "bob", None).unwrap();
+        let last = message.len() - 1;
+        message[last] ^= 1;
+        assert!(bob.session_decrypt(&message, "alice", None).is_err());
+        assert!(!bob.has_session("alice").unwrap());
+    }
+
+    #[test]
+    fn session_from_prekey_bundle() {
+        let mut alice = cipher();
+        let mut bob = cipher();
+        bob.generate_prekeys(2).unwrap();
+        let bundle = bob.prekey_bundle().unwrap();
+        assert_eq!(bundle.one_time_prekeys.len(), 2);
+
+        alice.register_peer("bob", &bob.export_public_key().unwrap()).unwrap();
+        alice
+            .set_peer_signing_key("bob", &bob.identity_announcement().unwrap())
+            .unwrap();
+        alice.start_session_with_bundle("bob", &bundle).unwrap();
+
+        // Bob learns about Alice from the first message.
+        let first = alice.session_encrypt(b"first", "bob", None).unwrap();
+        let second = alice.session_encrypt(b"second", "bob", None).unwrap();
+        assert_eq!(bob.session_decrypt(&second, "alice", None).unwrap(), b"second");
+        assert_eq!(
+            bob.peer_info("alice").unwrap().public_key,
+            Some(alice.export_public_key().unwrap())
+        );
+        assert_eq!(bob.prekey_bundle().unwrap().one_time_prekeys.len(), 1);
+        assert_eq!(bob.session_decrypt(&first, "alice", None).unwrap(), b"first");
+
+        let reply = bob.session_encrypt(b"reply", "alice", None).unwrap();
+        assert_eq!(alice.session_decrypt(&reply, "bob", None).unwrap(), b"reply");
+
+        // The first message cannot restart the session once the prekey is gone.
+        assert!(bob.session_decrypt(&first, "alice", None).is_err());
+    }
+
+    #[test]
+    fn prekey_bundle_must_match_the_pinned_key() {
+        let (mut alice, bob) = pair();
+        alice
+            .set_peer_signing_key("bob", &bob.identity_announcement().unwrap())
+            .unwrap();
+        let mut mallory = cipher();
+        mallory.generate_prekeys(1).unwrap();
+
+        assert!(alice
+            .start_session_with_bundle("bob", &mallory.prekey_bundle().unwrap())
+            .is_err());
+        assert!(!alice.has_session("bob").unwrap());
+        assert_eq!(alice.peer_info("bob").unwrap().pending_fingerprint, None);
+    }
+
+    #[test]
+    fn signing_key_needs_an_announcement_by_the_pinned_key() {
+        let (mut alice, bob) = pair();
+        let mallory = cipher();
+
+        // Mallory's own announcement is for another identity key.
+        let theirs = mallory.identity_announcement().unwrap();
+        assert!(alice.set_peer_signing_key("bob", &theirs).is_err());
+
+        // Claiming Bob's identity key, Mallory cannot produce its signature.
+        let mut forged = theirs.clone();
+        forged.public_key = bob.export_public_key().unwrap();
+        forged.signature = URL_SAFE.encode(mallory.sign(&forged.signed_data()).unwrap());
+        assert!(matches!(
+            alice.set_peer_signing_key("bob", &forged),
+            Err(CipherError::InvalidSignature)
+        ));
+        assert_eq!(alice.peer_info("bob").unwrap().signing_key, None);
+
+        let announcement = IdentityAnnouncement::decode(
+            &bob.identity_announcement().unwrap().encode().unwrap(),
+        )
+        .unwrap();
+        alice.set_peer_signing_key("bob", &announcement).unwrap();
+        assert_eq!(
+            alice.peer_info("bob").unwrap().signing_key,
+            Some(bob.export_signing_key().unwrap())
+        );
+    }
+
+    #[test]
+    fn prekey_bundle_needs_the_recorded_signing_key() {
+        let (mut alice, mut bob) = pair();
+        bob.generate_prekeys(1).unwrap();
+        let bundle = bob.prekey_bundle().unwrap();
+
+        assert!(matches!(
+            alice.start_session_with_bundle("bob", &bundle),
+            Err(CipherError::SigningKeyNotFound(_))
+        ));
+        assert!(matches!(
+            cipher().start_session_with_bundle("bob", &bundle),
+            Err(CipherError::SigningKeyNotFound(_))
+        ));
+
+        // A bundle re-signed by another key does not match the recorded one.
+        alice
+            .set_peer_signing_key("bob", &bob.identity_announcement().unwrap())
+            .unwrap();
+        let mallory = cipher();
+        let mut forged = bundle.clone();
+        forged.signing_key = Some(mallory.export_signing_key().unwrap());
+        forged.signed_prekey_signature = x3dh::sign_prekey(
+            mallory.signing_key.as_ref().unwrap(),
+            &x3dh::decode_key(&bundle.signed_prekey).unwrap(),
+        );
+        assert!(matches!(
+            alice.start_session_with_bundle("bob", &forged),
+            Err(CipherError::InvalidSignature)
+        ));
+        assert!(!alice.has_session("bob").unwrap());
+
+        alice.start_session_with_bundle("bob", &bundle).unwrap();
+    }
+
+    #[test]
+    fn removing_a_peer_ends_its_session() {
+        let (mut alice, _bob) = pair();
+        alice.start_session("bob").unwrap();
+
+        alice.remove_peer("bob").unwrap();
+        assert!(!alice.has_peer("bob").unwrap());
+        assert!(!alice.has_session("bob").unwrap());
+    }
+
+    #[test]
+    fn key_change_ends_session_and_keeps_added_at() {
+        let (mut alice, bob) = pair();
+        alice.start_session("bob").unwrap();
+        let added_at = alice.peer_info("bob").unwrap().added_at;
+
+        let new_bob = cipher();
+        let new_key = new_bob.export_public_key().unwrap();
+        assert!(matches!(
+            alice.register_peer("bob", &new_key),
+            Err(CipherError::IdentityKeyChanged { .. })
+        ));
+        assert!(alice.has_session("bob").unwrap());
+
+        alice.accept_peer_key("bob").unwrap();
+        let info = alice.peer_info("bob").unwrap();
+        assert_eq!(info.public_key, Some(new_key));
+        assert_eq!(info.added_at, added_at);
+        assert!(!alice.has_session("bob").unwrap());
+
+        alice.start_session("bob").unwrap();
+        alice.update_peer_key("bob", &bob.export_public_key().unwrap()).unwrap();
+        assert!(!alice.has_session("bob").unwrap());
+    }
+
+    #[test]
+    fn memory_store_lists_by_prefix_and_applies_batches() {
+        let mut store = MemoryStore::new();
+        store.put("peer:carol", b"c".to_vec()).unwrap();
+        store
+            .put_many(vec![("peer:bob".into(), b"b".to_vec()), ("peerless".into(), Vec::new())])
+            .unwrap();
+        store
+            .apply(vec![
+                StoreOp::Put("session:bob".into(), b"s".to_vec()),
+                StoreOp::Delete("peerless".into()),
+            ])
+            .unwrap();
+
+        assert_eq!(store.list("peer:").unwrap(), vec!["peer:bob", "peer:carol"]);
+        assert_eq!(store.list("").unwrap(), vec!["peer:bob", "peer:carol", "session:bob"]);
+        assert_eq!(store.get("peer:bob").unwrap(), Some(b"b".to_vec()));
+        assert!(!store.has("peerless").unwrap());
+    }
+
+    #[test]
+    fn a_peer_named_like_a_key_does_not_clobber_it() {
+        let (mut alice, bob) = pair();
+        let fingerprint = alice.export_fingerprint().unwrap();
+
+        for name in [keys::IDENTITY, keys::LEGACY_WRAPPING_KEY, keys::WRAPPING_KEY] {
+            alice.register_peer(name, &bob.export_public_key().unwrap()).unwrap();
+        }
+
+        let mut reopened = Cipher::new(std::mem::take(&mut alice.store));
+        reopened.init(None).unwrap();
+        assert_eq!(reopened.export_fingerprint().unwrap(), fingerprint);
+        assert!(reopened.store.has(&keys::peer(keys::IDENTITY)).unwrap());
+        assert_eq!(reopened.list_peers().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn legacy_layout_is_migrated() {
+        let (alice, mut bob) = pair();
+        let sealed = bob.encrypt_text("before", "alice").unwrap();
+
+        // What a keystore looked like before namespacing: the identity, the
+        // wrapping key and peers under their bare ids, nothing else.
+        let mut legacy = MemoryStore::new();
+        for (id, target) in [
+            (keys::IDENTITY.to_string(), keys::IDENTITY),
+            (keys::WRAPPING_KEY.to_string(), keys::LEGACY_WRAPPING_KEY),
+            (keys::peer("bob"), "bob"),
+        ] {
+            legacy.put(target, alice.store.get(&id).unwrap().unwrap()).unwrap();
+        }
+
+        let mut migrated = Cipher::new(legacy);
+        migrated.init(None).unwrap();
+        assert_eq!(
+            migrated.export_fingerprint().unwrap(),
+            alice.export_fingerprint().unwrap()
+        );
+        assert_eq!(migrated.decrypt_text(&sealed, "bob").unwrap(), "before");
+        assert!(migrated.store.has(keys::WRAPPING_KEY).unwrap());
+        assert!(!migrated.store.has(keys::LEGACY_WRAPPING_KEY).unwrap());
+        assert!(!migrated.store.has("bob").unwrap());
+        assert_eq!(migrated.store.get(keys::LAYOUT).unwrap(), Some(vec![keys::LAYOUT_VERSION]));
+    }
+
+    #[test]
+    fn lists_peers_sorted_by_id() {
+        let mut alice = cipher();
+        let (bob, carol) = (cipher(), cipher());
+        alice.register_peer("carol", &carol.export_public_key().unwrap()).unwrap();
+        alice.register_peer("bob", &bob.export_public_key().unwrap()).unwrap();
+        alice.set_peer_alias("bob", Some("Bob")).unwrap();
+
+        let peers = alice.list_peers().unwrap();
+        let ids: Vec<&str> = peers.iter().map(|peer| peer.id.as_str()).collect();
+        assert_eq!(ids, ["bob", "carol"]);
+
+        let bob_info = &peers[0];
+        assert_eq!(bob_info.alias.as_deref(), Some("Bob"));
+        assert_eq!(bob_info.public_key, Some(bob.export_public_key().unwrap()));
+        assert_eq!(bob_info.fingerprint, Some(bob.export_fingerprint().unwrap()));
+        assert!(bob_info.added_at.is_some());
+        assert!(peers[1].alias.is_none());
+    }
+
+    #[test]
+    fn unknown_peer_is_not_found() {
+        let alice = cipher();
+
+        assert!(matches!(alice.peer_info("nobody"), Err(CipherError::PeerNotFound(_))));
+        assert!(alice.list_peers().unwrap().is_empty());
+    }
+
+    #[test]
+    fn updated_peer_key_replaces_the_derived_secret() {
+        let (mut alice, mut bob) = pair();
+        let old = bob.encrypt_text("old key", "alice").unwrap();
+
+        let mut new_bob = cipher();
+        new_bob.register_peer("alice", &alice.export_public_key().unwrap()).unwrap();
+        alice.update_peer_key("bob", &new_bob.export_public_key().unwrap()).unwrap();
+
+        assert!(alice.decrypt_text(&old, "bob").is_err());
+        let new = new_bob.encrypt_text("new key", "alice").unwrap();
+        assert_eq!(alice.decrypt_text(&new, "bob").unwrap(), "new key");
+        assert_eq!(
+            alice.peer_info("bob").unwrap().fingerprint,
+            Some(new_bob.export_fingerprint().unwrap())
+        );
+    }
+
+    /// Rewrites `peer_id` as registered before HKDF: raw ECDH output as the
+    /// key in both directions.
+    fn downgrade_to_raw_kdf(cipher: &mut Cipher<MemoryStore>, peer_id: &str) {
+        let mut stored = cipher.load_peer(peer_id).unwrap();
+        stored.kdf_version = kdf::PEER_KDF_RAW;
+        cipher.save_peer(peer_id, &stored).unwrap();
+        cipher.peer_keys.remove(peer_id);
+    }
+
+    #[test]
+    fn raw_ecdh_peers_are_migrated() {
+        let (mut alice, mut bob) = pair();
+        downgrade_to_raw_kdf(&mut alice, "bob");
+        downgrade_to_raw_kdf(&mut bob, "alice");
+        let raw = alice.encrypt_text("raw", "bob").unwrap();
+        assert_eq!(bob.decrypt_text(&raw, "alice").unwrap(), "raw");
+
+        assert!(alice.migrate_peer_keys().unwrap().is_empty());
+        // Only one side has migrated: the keys no longer agree.
+        let hkdf = alice.encrypt_text("hkdf", "bob").unwrap();
+        assert!(bob.decrypt_text(&hkdf, "alice").is_err());
+
+        assert!(bob.migrate_peer_keys().unwrap().is_empty());
+        assert_eq!(bob.decrypt_text(&hkdf, "alice").unwrap(), "hkdf");
+        assert_eq!(alice.load_peer("bob").unwrap().kdf_version, kdf::PEER_KDF_CURRENT);
+    }
+
+    #[test]
+    fn raw_peers_without_a_public_key_are_skipped() {
+        let (mut alice, _bob) = pair();
+        downgrade_to_raw_kdf(&mut alice, "bob");
+        let mut stored = alice.load_peer("bob").unwrap();
+        stored.public_key = None;
+        alice.save_peer("bob", &stored).unwrap();
+
+        assert_eq!(alice.migrate_peer_keys().unwrap(), ["bob"]);
+        assert_eq!(alice.load_peer("bob").unwrap().kdf_version, kdf::PEER_KDF_RAW);
+    }
+
+    #[test]
+    fn tampered_envelope_header_fails() {
+        let (mut alice, mut bob) = pair();
+        let sealed = alice.encrypt_bytes(b"secret", "bob").unwrap();
+        assert_eq!(bob.decrypt_bytes(&sealed, "alice").unwrap(), b"secret");
+
+        // Flags, sender and timestamp are only protected as associated data.
+        for index in [5, 6, 21, 29] {
+            let mut tampered = sealed.clone();
+            tampered[index] ^= 0x01;
+            assert!(bob.decrypt_bytes(&tampered, "alice").is_err(), "byte {}", index);
+        }
+
+        let mut newer = sealed.clone();
+        newer[3] = envelope::VERSION + 1;
+        assert!(matches!(
+            bob.decrypt_bytes(&newer, "alice"),
+            Err(CipherError::UnsupportedVersion(_))
+        ));
+        assert!(bob.decrypt_bytes(&sealed[..envelope::HEADER_LEN], "alice").is_err());
+    }
+
+    fn context() -> MessageContext {
+        MessageContext {
+            conversation_id: "alice-bob".into(),
+            message_id: "m-1".into(),
+            sender: "alice".into(),
+            recipient: "bob".into(),
+            sequence: 1,
+        }
+    }
+
+    #[test]
+    fn context_bound_messages_only_open_in_their_context() {
+        let (mut alice, mut bob) = pair();
+        let sealed = alice.encrypt_bytes_with_context(b"hi", "bob", &context()).unwrap();
+        assert_eq!(bob.decrypt_bytes_with_context(&sealed, "alice", &context()).unwrap(), b"hi");
+
+        let moved: [fn(&mut MessageContext); 3] = [
+            |c| c.conversation_id = "alice-carol".into(),
+            |c| c.recipient = "carol".into(),
+            |c| c.sequence = 2,
+        ];
+        for change in moved {
+            let mut other = context();
+            change(&mut other);
+            assert!(bob.decrypt_bytes_with_context(&sealed, "alice", &other).is_err());
+        }
+
+        // Nor without any context at all.
+        assert!(bob.decrypt_bytes(&sealed, "alice").is_err());
+    }
+
+    #[test]
+    fn signed_context_messages_only_open_in_their_context() {
+        let (mut alice, mut bob) = pair();
+        bob.set_peer_signing_key("alice", &alice.identity_announcement().unwrap()).unwrap();
+        let sealed = alice.encrypt_text_signed("hi", "bob", Some(&context())).unwrap();
+        assert_eq!(bob.decrypt_text_signed(&sealed, "alice", Some(&context())).unwrap(), "hi");
+
+        let mut other = context();
+        other.sequence = 2;
+        assert!(bob.decrypt_text_signed(&sealed, "alice", Some(&other)).is_err());
+    }
+
+    #[tokio::test]
+    async fn async_streams_round_trip() {
+        let (mut alice, mut bob) = pair();
+        let plain: Vec<u8> = (0..=255u8).cycle().take(3 * stream::DEFAULT_CHUNK_SIZE).collect();
+
+        let mut sealed = Vec::new();
+        let written = alice.encrypt_all_async(plain.as_slice(), &mut sealed, "bob").await.unwrap();
+        assert_eq!(written, plain.len() as u64);
+
+        let mut opened = Vec::new();
+        bob.decrypt_all_async(sealed.as_slice(), &mut opened, "alice").await.unwrap();
+        assert_eq!(opened, plain);
+
+        // Alice's sending key is not her receiving key.
+        assert!(alice.decrypt_all_async(sealed.as_slice(), &mut Vec::new(), "bob").await.is_err());
+    }
+
+    /// `MemoryStore` whose `apply` fails while `fail_apply` is set.
+    #[derive(Default)]
+    struct FailingStore {
+        inner: MemoryStore,
+        fail_apply: bool,
+    }
+
+    impl Store for FailingStore {
+        fn setup(&mut self) -> Result<(), CipherError> {
+            self.inner.setup()
+        }
+
+        fn put(&mut self, id: &str, data: Vec<u8>) -> Result<(), CipherError> {
+            self.inner.put(id, data)
+        }
+
+        fn get(&self, id: &str) -> Result<Option<Vec<u8>>, CipherError> {
+            self.inner.get(id)
+        }
+
+        fn delete(&mut self, id: &str) -> Result<(), CipherError> {
+            self.inner.delete(id)
+        }
+
+        fn has(&self, id: &str) -> Result<bool, CipherError> {
+            self.inner.has(id)
+        }
+
+        fn list(&self, prefix: &str) -> Result<Vec<String>, CipherError> {
+            self.inner.list(prefix)
+        }
+
+        fn apply(&mut self, ops: Vec<StoreOp>) -> Result<(), CipherError> {
+            if self.fail_apply {
+                return Err(CipherError::StorageError("injected failure".into()));
+            }
+            self.inner.apply(ops)
+        }
+    }
+
+    /// Unlocks `store` again from scratch, as a new process would.
+    fn reopen<S: Store>(store: S, password: Option<&str>) -> Result<Cipher<S>, CipherError> {
+        let mut cipher = Cipher::new(store);
+        cipher.init(password)?;
+        Ok(cipher)
+    }
+
+    /// Alice with a password-protected keystore on `store`, and Bob, each
+    /// registered as the other's peer.
+    fn protected_pair<S: Store>(store: S, password: &str) -> (Cipher<S>, Cipher<MemoryStore>) {
+        let mut alice = reopen(store, Some(password)).unwrap();
+        let mut bob = cipher();
+        alice.register_peer("bob", &bob.export_public_key().unwrap()).unwrap();
+        bob.register_peer("alice", &alice.export_public_key().unwrap()).unwrap();
+        (alice, bob)
+    }
+
+    #[test]
+    fn changed_password_keeps_secrets_readable() {
+        let (mut alice, mut bob) = protected_pair(MemoryStore::new(), "old");
+        let public = alice.export_public_key().unwrap();
+        let sealed = bob.encrypt_bytes(b"hello", "alice").unwrap();
+
+        alice.change_password("old", "new").unwrap();
+        assert_eq!(alice.decrypt_bytes(&sealed, "bob").unwrap(), b"hello");
+
+        let mut alice = reopen(std::mem::take(&mut alice.store), Some("new")).unwrap();
+        assert_eq!(alice.export_public_key().unwrap(), public);
+        assert_eq!(alice.decrypt_bytes(&sealed, "bob").unwrap(), b"hello");
+
+        assert!(matches!(
+            reopen(std::mem::take(&mut alice.store), Some("old")),
+            Err(CipherError::WrongPassword)
+        ));
+    }
+
+    #[test]
+    fn wrong_old_password_leaves_the_keystore_untouched() {
+        let (mut alice, mut bob) = protected_pair(MemoryStore::new(), "old");
+        let sealed = bob.encrypt_bytes(b"hello", "alice").unwrap();
+        let before = alice.store.data.clone();
+
+        assert!(matches!(
+            alice.change_password("wrong", "new"),
+            Err(CipherError::WrongPassword)
+        ));
+        assert_eq!(alice.store.data, before);
+
+        let mut alice = reopen(std::mem::take(&mut alice.store), Some("old")).unwrap();
+        assert_eq!(alice.decrypt_bytes(&sealed, "bob").unwrap(), b"hello");
+    }
+
+    #[test]
+    fn failed_password_change_leaves_the_previous_keystore_readable() {
+        let (mut alice, mut bob) = protected_pair(FailingStore::default(), "old");
+        let sealed = bob.encrypt_bytes(b"hello", "alice").unwrap();
+        let before = alice.store.inner.data.clone();
+
+        alice.store.fail_apply = true;
+        assert!(alice.change_password("old", "new").is_err());
+        alice.store.fail_apply = false;
+        assert_eq!(alice.store.inner.data, before);
+        assert_eq!(alice.decrypt_bytes(&sealed, "bob").unwrap(), b"hello");
+
+        let mut alice = reopen(std::mem::take(&mut alice.store), Some("old")).unwrap();
+        assert_eq!(alice.decrypt_bytes(&sealed, "bob").unwrap(), b"hello");
+        assert!(matches!(
+            reopen(std::mem::take(&mut alice.store), Some("new")),
+            Err(CipherError::WrongPassword)
+        ));
+    }
+
+    #[test]
+    fn rotated_wrapping_key_keeps_secrets_readable() {
+        let (mut alice, mut bob) = (reopen(FailingStore::default(), None).unwrap(), cipher());
+        alice.register_peer("bob", &bob.export_public_key().unwrap()).unwrap();
+        bob.register_peer("alice", &alice.export_public_key().unwrap()).unwrap();
+        let sealed = bob.encrypt_bytes(b"hello", "alice").unwrap();
+        let old_key = alice.store.get(keys::WRAPPING_KEY).unwrap();
+
+        alice.rotate_wrapping_key().unwrap();
+        let rotated = alice.store.get(keys::WRAPPING_KEY).unwrap();
+        assert_ne!(rotated, old_key);
+
+        let mut alice = reopen(std::mem::take(&mut alice.store), None).unwrap();
+        assert_eq!(alice.decrypt_bytes(&sealed, "bob").unwrap(), b"hello");
+
+        // A rotation that fails to commit changes nothing.
+        let before = alice.store.inner.data.clone();
+        alice.store.fail_apply = true;
+        assert!(alice.rotate_wrapping_key().is_err());
+        alice.store.fail_apply = false;
+        assert_eq!(alice.store.inner.data, before);
+
+        let mut alice = reopen(std::mem::take(&mut alice.store), None).unwrap();
+        assert_eq!(alice.decrypt_bytes(&sealed, "bob").unwrap(), b"hello");
+    }
+
+    /// Rewrites the JSON entry `id` of `store` through `edit`.
+    fn tamper<T, S>(store: &mut S, id: &str, edit: impl FnOnce(&mut T))
+    where
+        T: serde::Serialize + serde::de::DeserializeOwned,
+        S: Store,
+    {
+        let mut value: T = serde_json::from_slice(&store.get(id).unwrap().unwrap()).unwrap();
+        edit(&mut value);
+        store.put(id, serde_json::to_vec(&value).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn wrong_password_is_not_corruption() {
+        let mut alice = reopen(MemoryStore::new(), Some("secret")).unwrap();
+        assert!(matches!(
+            reopen(std::mem::take(&mut alice.store), Some("wrong")),
+            Err(CipherError::WrongPassword)
+        ));
+    }
+
+    #[test]
+    fn tampered_identity_is_corruption() {
+        let mut alice = reopen(MemoryStore::new(), Some("secret")).unwrap();
+        tamper(&mut alice.store, keys::IDENTITY, |stored: &mut StoredIdentity| {
+            stored.encrypted_private_key[0] ^= 1;
+        });
+
+        assert!(matches!(
+            reopen(std::mem::take(&mut alice.store), Some("secret")),
+            Err(CipherError::KeystoreCorrupted(entry)) if entry == "identity"
+        ));
+    }
+
+    #[test]
+    fn tampered_verifier_is_corruption() {
+        let mut alice = reopen(MemoryStore::new(), Some("secret")).unwrap();
+        tamper(&mut alice.store, keys::VERIFIER, |verifier: &mut KeystoreVerifier| {
+            verifier.mac[0] ^= 1;
+        });
+
+        let mut alice = Cipher::new(std::mem::take(&mut alice.store));
+        assert!(matches!(
+            alice.init(Some("secret")),
+            Err(CipherError::KeystoreCorrupted(entry)) if entry == "verifier"
+        ));
+        assert!(matches!(alice.init(Some("wrong")), Err(CipherError::WrongPassword)));
+    }
+
+    #[test]
+    fn quarantined_identity_is_replaced() {
+        let mut alice = reopen(MemoryStore::new(), Some("secret")).unwrap();
+        let public = alice.export_public_key().unwrap();
+        tamper(&mut alice.store, keys::IDENTITY, |stored: &mut StoredIdentity| {
+            stored.encrypted_private_key[0] ^= 1;
+        });
+        let damaged = alice.store.get(keys::IDENTITY).unwrap().unwrap();
+
+        let mut alice = Cipher::new(std::mem::take(&mut alice.store));
+        assert!(alice.init(Some("secret")).is_err());
+        assert_eq!(alice.check_keystore().unwrap(), vec![keys::IDENTITY]);
+        assert_eq!(alice.quarantine_corrupted().unwrap(), vec![keys::IDENTITY]);
+
+        alice.init(Some("secret")).unwrap();
+        assert_ne!(alice.export_public_key().unwrap(), public);
+        assert_eq!(
+            alice.quarantined_entries().unwrap(),
+            vec![(keys::IDENTITY.to_string(), damaged)]
+        );
+    }
+
+    #[test]
+    fn quarantined_peer_leaves_the_rest_usable() {
+        let (mut alice, mut bob) = protected_pair(MemoryStore::new(), "secret");
+        let carol = cipher();
+        alice.register_peer("carol", &carol.export_public_key().unwrap()).unwrap();
+        tamper(&mut alice.store, &keys::peer("carol"), |stored: &mut StoredPeer| {
+            stored.encrypted_key[0] ^= 1;
+        });
+
+        let mut alice = reopen(std::mem::take(&mut alice.store), Some("secret")).unwrap();
+        assert!(alice.export_backup("backup").is_err());
+        assert_eq!(alice.check_keystore().unwrap(), vec![keys::peer("carol")]);
+        assert_eq!(alice.quarantine_corrupted().unwrap(), vec![keys::peer("carol")]);
+
+        assert!(alice.check_keystore().unwrap().is_empty());
+        assert_eq!(alice.list_peers().unwrap().len(), 1);
+        let sealed = bob.encrypt_bytes(b"hello", "alice").unwrap();
+        assert_eq!(alice.decrypt_bytes(&sealed, "bob").unwrap(), b"hello");
+        alice.export_backup("backup").unwrap();
+        assert_eq!(alice.quarantined_entries().unwrap()[0].0, keys::peer("carol"));
+    }
+
+    /// Re-encrypts `cipher` under `password` with `kdf`, as keystores created
+    /// before Argon2id were.
+    fn downgrade_password_kdf(cipher: &mut Cipher<MemoryStore>, password: &str, kdf: PasswordKdf) {
+        let salt = cipher.store.get(keys::SALT).unwrap().unwrap();
+        let key = WrappingKey(kdf.derive(password, &salt).unwrap());
+        let header = to_json(&kdf).unwrap();
+        let verifier = KeystoreVerifier::new(&key, &salt, &header).unwrap();
+
+        let mut ops = cipher.rewrap_ops(&key).unwrap();
+        ops.push(StoreOp::Put(keys::KDF.to_string(), header));
+        ops.push(StoreOp::Put(keys::VERIFIER.to_string(), to_json(&verifier).unwrap()));
+        cipher.store.apply(ops).unwrap();
+        cipher.wrapping_key = Some(key);
+    }
+
+    #[test]
+    fn new_keystores_use_the_current_password_kdf() {
+        let alice = reopen(MemoryStore::new(), Some("secret")).unwrap();
+        assert_eq!(alice.password_kdf().unwrap(), PasswordKdf::CURRENT);
+        assert!(matches!(alice.password_kdf().unwrap(), PasswordKdf::Argon2id { .. }));
+    }
+
+    #[test]
+    fn legacy_password_kdf_is_upgraded_on_unlock() {
+        let (mut alice, mut bob) = protected_pair(MemoryStore::new(), "secret");
+        let sealed = bob.encrypt_bytes(b"hello", "alice").unwrap();
+        // `PasswordKdf::LEGACY` at a cost that keeps the test fast.
+        let legacy = PasswordKdf::Pbkdf2Sha256 { iterations: 1000 };
+        downgrade_password_kdf(&mut alice, "secret", legacy);
+        let salt = alice.store.get(keys::SALT).unwrap();
+
+        let mut alice = reopen(std::mem::take(&mut alice.store), Some("secret")).unwrap();
+        assert_eq!(alice.password_kdf().unwrap(), PasswordKdf::CURRENT);
+        assert_ne!(alice.store.get(keys::SALT).unwrap(), salt);
+        assert_eq!(alice.decrypt_bytes(&sealed, "bob").unwrap(), b"hello");
+
+        let mut alice = reopen(std::mem::take(&mut alice.store), Some("secret")).unwrap();
+        assert_eq!(alice.decrypt_bytes(&sealed, "bob").unwrap(), b"hello");
+        assert!(matches!(
+            reopen(std::mem::take(&mut alice.store), Some("wrong")),
+            Err(CipherError::WrongPassword)
+        ));
+    }
+
+    #[test]
+    fn keystores_without_a_kdf_header_use_the_legacy_kdf() {
+        let mut alice = reopen(MemoryStore::new(), Some("secret")).unwrap();
+        alice.store.delete(keys::KDF).unwrap();
+        assert_eq!(alice.password_kdf().unwrap(), PasswordKdf::LEGACY);
+    }
+
+    #[test]
+    fn expensive_password_kdf_is_rejected() {
+        let mut alice = reopen(MemoryStore::new(), Some("secret")).unwrap();
+        let expensive = PasswordKdf::Argon2id {
+            memory_kib: PasswordKdf::MAX_MEMORY_KIB + 1,
+            iterations: 1,
+            parallelism: 1,
+        };
+        alice.store.put(keys::KDF, to_json(&expensive).unwrap()).unwrap();
+
+        assert!(matches!(
+            reopen(std::mem::take(&mut alice.store), Some("secret")),
+            Err(CipherError::KeystoreCorrupted(entry)) if entry == "kdf"
+        ));
+    }
+
+    #[test]
+    fn both_sides_see_the_same_safety_number() {
+        let (alice, bob) = pair();
+        let number = alice.safety_number("bob").unwrap();
+        assert_eq!(bob.safety_number("alice").unwrap(), number);
+        assert!(bob.safety_number("alice").unwrap().matches(&number.qr_payload()));
+    }
+
+    #[test]
+    fn new_peer_key_clears_verification() {
+        let (mut alice, _bob) = pair();
+        alice.mark_verified("bob").unwrap();
+        let before = alice.safety_number("bob").unwrap();
+
+        let reinstalled = cipher();
+        alice.update_peer_key("bob", &reinstalled.export_public_key().unwrap()).unwrap();
+
+        assert_eq!(alice.peer_info("bob").unwrap().verification, Verification::KeyChanged);
+        assert_ne!(alice.safety_number("bob").unwrap(), before);
+    }
+
+    #[test]
+    fn peer_without_a_public_key_cannot_be_verified() {
+        let (mut alice, _bob) = pair();
+        let mut stored = alice.load_peer("bob").unwrap();
+        stored.public_key = None;
+        alice.save_peer("bob", &stored).unwrap();
+
+        assert!(matches!(alice.safety_number("bob"), Err(CipherError::PeerKeyUnknown(_))));
+        assert!(matches!(alice.mark_verified("bob"), Err(CipherError::PeerKeyUnknown(_))));
+        assert_eq!(alice.peer_info("bob").unwrap().verification, Verification::Unverified);
+    }
+
+    /// Decoded SEC1 bytes of an exported public key.
+    fn sec1(cipher: &Cipher<MemoryStore>) -> Vec<u8> {
+        URL_SAFE.decode(cipher.export_public_key().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn first_key_is_pinned() {
+        let (mut alice, bob) = pair();
+        assert_eq!(alice.load_peer("bob").unwrap().public_key, Some(sec1(&bob)));
+
+        alice.register_peer("bob", &bob.export_public_key().unwrap()).unwrap();
+        assert_eq!(alice.load_peer("bob").unwrap().pending_key, None);
+    }
+
+    #[test]
+    fn changed_key_is_held_until_rejected() {
+        let (mut alice, mut bob) = pair();
+        let mallory = cipher();
+        let pinned = alice.load_peer("bob").unwrap();
+
+        match alice.register_peer("bob", &mallory.export_public_key().unwrap()) {
+            Err(CipherError::IdentityKeyChanged { peer, pinned, offered }) => {
+                assert_eq!(peer, "bob");
+                assert_eq!(pinned, bob.export_fingerprint().unwrap());
+                assert_eq!(offered, mallory.export_fingerprint().unwrap());
+            }
+            other => panic!("expected IdentityKeyChanged, got {:?}", other),
+        }
+
+        let held = alice.load_peer("bob").unwrap();
+        assert_eq!(held.pending_key, Some(sec1(&mallory)));
+        assert_eq!(held.public_key, pinned.public_key);
+        assert_eq!(held.encrypted_key, pinned.encrypted_key);
+        let sealed = bob.encrypt_bytes(b"still bob", "alice").unwrap();
+        assert_eq!(alice.decrypt_bytes(&sealed, "bob").unwrap(), b"still bob");
+
+        alice.reject_peer_key("bob").unwrap();
+        let kept = alice.load_peer("bob").unwrap();
+        assert_eq!(kept.pending_key, None);
+        assert_eq!(kept.public_key, pinned.public_key);
+        assert!(alice.accept_peer_key("bob").is_err());
+    }
+
+    #[test]
+    fn accepted_key_replaces_the_pinned_one() {
+        let (mut alice, _bob) = pair();
+        let mut reinstalled = cipher();
+        reinstalled.register_peer("alice", &alice.export_public_key().unwrap()).unwrap();
+        alice.start_session("bob").unwrap();
+
+        let offered = reinstalled.export_public_key().unwrap();
+        assert!(matches!(
+            alice.register_peer("bob", &offered),
+            Err(CipherError::IdentityKeyChanged { .. })
+        ));
+        alice.accept_peer_key("bob").unwrap();
+
+        let stored = alice.load_peer("bob").unwrap();
+        assert_eq!(stored.public_key, Some(sec1(&reinstalled)));
+        assert_eq!(stored.pending_key, None);
+        assert!(!alice.has_session("bob").unwrap());
+        let sealed = reinstalled.encrypt_bytes(b"new key", "alice").unwrap();
+        assert_eq!(alice.decrypt_bytes(&sealed, "bob").unwrap(), b"new key");
+    }
+
+    #[test]
+    fn legacy_peer_pins_the_key_its_secret_came_from() {
+        let (mut alice, bob) = pair();
+        downgrade_to_raw_kdf(&mut alice, "bob");
+        let mut stored = alice.load_peer("bob").unwrap();
+        stored.public_key = None;
+        alice.save_peer("bob", &stored).unwrap();
+
+        // Any other key cannot be told apart from a change.
+        let mallory = cipher();
+        match alice.register_peer("bob", &mallory.export_public_key().unwrap()) {
+            Err(CipherError::IdentityKeyChanged { pinned, .. }) => assert_eq!(pinned, "unrecorded"),
+            other => panic!("expected IdentityKeyChanged, got {:?}", other),
+        }
+        assert_eq!(alice.load_peer("bob").unwrap().public_key, None);
+        alice.reject_peer_key("bob").unwrap();
+
+        alice.register_peer("bob", &bob.export_public_key().unwrap()).unwrap();
+        let pinned = alice.load_peer("bob").unwrap();
+        assert_eq!(pinned.public_key, Some(sec1(&bob)));
+        assert_eq!(pinned.pending_key, None);
     }
 }
@@ -0,0 +1,293 @@
+use super::cipher::CipherError;
+
+/// Self-describing container for everything `Cipher` encrypts for a peer.
+///
+/// ```text
+/// magic "NYX" (3) | version (1) | suite (1) | flags (1) | sender fingerprint (16)
+///     | timestamp, unix seconds BE (8) | nonce (12) | ciphertext
+/// ```
+///
+/// Everything before the nonce is the header and is bound to the ciphertext
+/// as AES-GCM associated data, so none of it can be altered in transit. When
+/// `FLAG_CONTEXT` is set, the encoded `MessageContext` follows the header in
+/// the associated data.
+///
+/// When `FLAG_SIGNED` is set, the envelope ends with a `SIGNATURE_LEN`-byte
+/// ECDSA P-256 signature (r || s) by the sender's signing key over every byte
+/// before it, followed by the encoded `MessageContext` if one is bound. The
+/// signature is not part of the AEAD ciphertext.
+///
+/// Payloads without the magic are the pre-envelope `[nonce || ciphertext]`
+/// format and are still accepted on decryption.
+pub const MAGIC: &[u8; 3] = b"NYX";
+pub const VERSION: u8 = 1;
+
+/// The ciphertext is bound to a `MessageContext`.
+pub const FLAG_CONTEXT: u8 = 0b0000_0001;
+
+/// Sent by the initiator of a ratchet session until the peer replies, so the
+/// peer knows to set up its side of the session.
+pub const FLAG_SESSION_INIT: u8 = 0b0000_0010;
+
+/// The ciphertext starts with an `x3dh::PrekeyHeader`.
+pub const FLAG_PREKEY: u8 = 0b0000_0100;
+
+/// The envelope ends with the sender's signature.
+pub const FLAG_SIGNED: u8 = 0b0000_1000;
+
+pub const SIGNATURE_LEN: usize = 64;
+
+pub const HEADER_LEN: usize = 30;
+pub const NONCE_LEN: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Suite {
+    /// P-256 ECDH, HKDF-SHA256 peer keys, AES-256-GCM.
+    P256HkdfAes256Gcm = 1,
+    /// `ratchet::RatchetState` session; the envelope nonce is the header
+    /// nonce and the ciphertext starts with the encrypted ratchet header.
+    P256DoubleRatchet = 2,
+    /// `stream` chunked encryption with a key derived from the peer key.
+    P256HkdfAes256GcmStream = 3,
+}
+
+impl TryFrom<u8> for Suite {
+    type Error = CipherError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Suite::P256HkdfAes256Gcm),
+            2 => Ok(Suite::P256DoubleRatchet),
+            3 => Ok(Suite::P256HkdfAes256GcmStream),
+            other => Err(CipherError::UnsupportedSuite(other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvelopeHeader {
+    pub version: u8,
+    pub suite: Suite,
+    /// Bit set of `FLAG_*` values.
+    pub flags: u8,
+    /// First 16 bytes of SHA-256 over the sender's SEC1 public key.
+    pub sender: [u8; 16],
+    pub timestamp: i64,
+}
+
+impl EnvelopeHeader {
+    pub fn new(suite: Suite, sender: [u8; 16]) -> Self {
+        Self {
+            version: VERSION,
+            suite,
+            flags: 0,
+            sender,
+            timestamp: chrono::Utc::now().timestamp(),
+        }
+    }
+
+    pub fn has_flag(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// Associated data for the AEAD: the header, then `context` if bound.
+    pub fn associated_data(&self, context: Option<&MessageContext>) -> Vec<u8> {
+        let mut aad = self.to_bytes().to_vec();
+        if let Some(context) = context {
+            aad.extend_from_slice(&context.to_bytes());
+        }
+        aad
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut out = [0u8; HEADER_LEN];
+        out[..3].copy_from_slice(MAGIC);
+        out[3] = self.version;
+        out[4] = self.suite as u8;
+        out[5] = self.flags;
+        out[6..22].copy_from_slice(&self.sender);
+        out[22..30].copy_from_slice(&self.timestamp.to_be_bytes());
+        out
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, CipherError> {
+        if bytes.len() < HEADER_LEN || &bytes[..3] != MAGIC {
+            return Err(CipherError::DecryptionFailed("Invalid envelope header".into()));
+        }
+
+        let version = bytes[3];
+        if version != VERSION {
+            return Err(CipherError::UnsupportedVersion(version));
+        }
+
+        let suite = Suite::try_from(bytes[4])?;
+        let flags = bytes[5];
+
+        let mut sender = [0u8; 16];
+        sender.copy_from_slice(&bytes[6..22]);
+
+        let mut timestamp = [0u8; 8];
+        timestamp.copy_from_slice(&bytes[22..30]);
+
+        Ok(Self {
+            version,
+            suite,
+            flags,
+            sender,
+            timestamp: i64::from_be_bytes(timestamp),
+        })
+    }
+}
+
+pub struct Envelope {
+    pub header: EnvelopeHeader,
+    pub nonce: [u8; NONCE_LEN],
+    pub ciphertext: Vec<u8>,
+}
+
+impl Envelope {
+    pub fn is_envelope(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + NONCE_LEN + self.ciphertext.len());
+        out.extend_from_slice(&self.header.to_bytes());
+        out.extend_from_slice(&self.nonce);
+        out.extend_from_slice(&self.ciphertext);
+        out
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, CipherError> {
+        let header = EnvelopeHeader::parse(bytes)?;
+
+        if bytes.len() < HEADER_LEN + NONCE_LEN {
+            return Err(CipherError::DecryptionFailed("Message too short".into()));
+        }
+
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&bytes[HEADER_LEN..HEADER_LEN + NONCE_LEN]);
+
+        Ok(Self {
+            header,
+            nonce,
+            ciphertext: bytes[HEADER_LEN + NONCE_LEN..].to_vec(),
+        })
+    }
+}
+
+/// Bytes covered by the signature of a `FLAG_SIGNED` envelope, given the
+/// envelope without its trailing signature.
+pub fn signed_data(unsigned: &[u8], context: Option<&MessageContext>) -> Vec<u8> {
+    let mut data = unsigned.to_vec();
+    if let Some(context) = context {
+        data.extend_from_slice(&context.to_bytes());
+    }
+    data
+}
+
+/// Message metadata bound to a ciphertext as associated data.
+///
+/// The context is not transmitted: the recipient rebuilds it from what it
+/// expects (the conversation it is reading, the recipient it is, ...) and
+/// decryption fails if anything differs, so a relay cannot move a ciphertext
+/// to another conversation or replay it to another recipient.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessageContext {
+    pub conversation_id: String,
+    pub message_id: String,
+    pub sender: String,
+    pub recipient: String,
+    pub sequence: u64,
+}
+
+impl MessageContext {
+    const LABEL: &'static [u8] = b"nyx/context/v1";
+
+    /// `"nyx/context/v1"`, then each string as u32 BE length + UTF-8 bytes in
+    /// field order, then the sequence number as u64 BE.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::from(Self::LABEL);
+
+        for field in [
+            &self.conversation_id,
+            &self.message_id,
+            &self.sender,
+            &self.recipient,
+        ] {
+            out.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            out.extend_from_slice(field.as_bytes());
+        }
+
+        out.extend_from_slice(&self.sequence.to_be_bytes());
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packed() -> Vec<u8> {
+        Envelope {
+            header: EnvelopeHeader::new(Suite::P256HkdfAes256Gcm, [7; 16]),
+            nonce: [9; NONCE_LEN],
+            ciphertext: b"ciphertext".to_vec(),
+        }
+        .to_bytes()
+    }
+
+    #[test]
+    fn round_trips() {
+        let bytes = packed();
+        let envelope = Envelope::parse(&bytes).unwrap();
+
+        assert!(Envelope::is_envelope(&bytes));
+        assert_eq!(envelope.header.suite, Suite::P256HkdfAes256Gcm);
+        assert_eq!(envelope.header.sender, [7; 16]);
+        assert_eq!(envelope.nonce, [9; NONCE_LEN]);
+        assert_eq!(envelope.ciphertext, b"ciphertext");
+        assert_eq!(envelope.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn rejects_unknown_version_and_suite() {
+        let mut bytes = packed();
+        bytes[3] = VERSION + 1;
+        assert!(matches!(
+            Envelope::parse(&bytes),
+            Err(CipherError::UnsupportedVersion(v)) if v == VERSION + 1
+        ));
+
+        let mut bytes = packed();
+        bytes[4] = 0xee;
+        assert!(matches!(Envelope::parse(&bytes), Err(CipherError::UnsupportedSuite(0xee))));
+    }
+
+    #[test]
+    fn rejects_bad_magic_and_truncation_without_panicking() {
+        let mut bytes = packed();
+        bytes[0] = b'X';
+        assert!(matches!(Envelope::parse(&bytes), Err(CipherError::DecryptionFailed(_))));
+
+        let bytes = packed();
+        for len in 0..HEADER_LEN + NONCE_LEN {
+            assert!(Envelope::parse(&bytes[..len]).is_err(), "{} bytes", len);
+        }
+        assert!(EnvelopeHeader::parse(&bytes[..HEADER_LEN - 1]).is_err());
+        assert!(Envelope::parse(&bytes[..HEADER_LEN + NONCE_LEN]).is_ok());
+    }
+    #[test]
+    fn context_encoding_is_length_prefixed() {
+        let split = |a: &str, b: &str| MessageContext {
+            conversation_id: a.into(),
+            message_id: b.into(),
+            ..Default::default()
+        };
+
+        // Moving bytes from one field to the next changes the encoding.
+        assert_ne!(split("ab", "c").to_bytes(), split("a", "bc").to_bytes());
+        assert!(split("ab", "c").to_bytes().starts_with(b"nyx/context/v1"));
+    }
+}
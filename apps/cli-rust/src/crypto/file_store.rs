@@ -0,0 +1,440 @@
+use base64::{
+    engine::general_purpose::{URL_SAFE, URL_SAFE_NO_PAD},
+    Engine as _,
+};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use super::cipher::{CipherError, Store, StoreOp};
+
+/// One operation of a batch recorded in the journal before it is applied.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct JournalEntry {
+    id: String,
+    /// Base64url value to write, `None` to delete.
+    data: Option<String>,
+}
+
+/// Directory-backed `Store`.
+///
+/// Every entry lives in its own file under `<root>/entries`, named after the
+/// base64url encoding of its id. Values are written to a temporary file and
+/// renamed into place so a crash never leaves a half-written key behind. The
+/// whole directory is guarded by an exclusive lock on `<root>/.lock`, held
+/// from `setup` until the store is dropped, so two CLI instances can never
+/// write to the same keystore at once.
+///
+/// Batches from `apply` are first written to `<root>/journal`; if the process
+/// dies halfway through, the next `setup` replays the journal so the batch is
+/// either fully applied or, if the journal itself never hit the disk, not at all.
+pub struct FileStore {
+    root: PathBuf,
+    lock: Option<File>,
+}
+
+impl FileStore {
+    const ENTRIES_DIR: &'static str = "entries";
+    const LOCK_FILE: &'static str = ".lock";
+    const JOURNAL_FILE: &'static str = "journal";
+    const APP_DIR: &'static str = "nyx";
+    const PROFILES_DIR: &'static str = "profiles";
+
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            lock: None,
+        }
+    }
+
+    /// Store rooted at `<data-dir>/nyx/profiles/<profile>`.
+    pub fn for_profile(profile: &str) -> Result<Self, CipherError> {
+        Ok(Self::new(Self::profile_dir(profile)?))
+    }
+
+    pub fn profile_dir(profile: &str) -> Result<PathBuf, CipherError> {
+        let valid = !profile.is_empty()
+            && profile
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+        if !valid {
+            return Err(CipherError::StorageError(format!(
+                "Invalid profile name: {:?}",
+                profile
+            )));
+        }
+
+        Ok(Self::profiles_dir()?.join(profile))
+    }
+
+    /// `<data-dir>/nyx/profiles`, which holds one directory per profile.
+    pub fn profiles_dir() -> Result<PathBuf, CipherError> {
+        let base = dirs::data_local_dir()
+            .ok_or_else(|| CipherError::StorageError("No data directory available".into()))?;
+
+        Ok(base.join(Self::APP_DIR).join(Self::PROFILES_DIR))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    fn entries_dir(&self) -> PathBuf {
+        self.root.join(Self::ENTRIES_DIR)
+    }
+
+    fn entry_path(&self, id: &str) -> PathBuf {
+        self.entries_dir().join(URL_SAFE_NO_PAD.encode(id))
+    }
+
+    fn journal_path(&self) -> PathBuf {
+        self.root.join(Self::JOURNAL_FILE)
+    }
+
+    fn entry_id(file_name: &str) -> Option<String> {
+        if file_name.starts_with('.') {
+            return None;
+        }
+
+        let bytes = URL_SAFE_NO_PAD.decode(file_name).ok()?;
+        String::from_utf8(bytes).ok()
+    }
+
+    fn replay_journal(&self) -> Result<(), CipherError> {
+        let data = match fs::read(self.journal_path()) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let entries: Vec<JournalEntry> = serde_json::from_slice(&data)
+            .map_err(|e| CipherError::StorageError(format!("Corrupted journal: {}", e)))?;
+
+        for entry in entries {
+            let path = self.entry_path(&entry.id);
+            match entry.data {
+                Some(encoded) => {
+                    let data = URL_SAFE
+                        .decode(encoded)
+                        .map_err(|e| CipherError::StorageError(format!("Corrupted journal: {}", e)))?;
+                    Self::write_atomic(&path, &data)?;
+                }
+                None => match fs::remove_file(&path) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                },
+            }
+        }
+
+        Self::sync_dir(&self.entries_dir())?;
+        fs::remove_file(self.journal_path())?;
+        Self::sync_dir(&self.root)?;
+        Ok(())
+    }
+
+    fn create_private_dir(path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, fs::Permissions::from_mode(0o700))?;
+        }
+
+        Ok(())
+    }
+
+    fn open_private_file(path: &Path) -> io::Result<File> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        options.open(path)
+    }
+
+    fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+        let file_name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+        let tmp = path.with_file_name(format!(".{}.tmp", file_name));
+
+        let result = (|| {
+            let mut file = Self::open_private_file(&tmp)?;
+            file.write_all(data)?;
+            file.sync_all()?;
+            fs::rename(&tmp, path)?;
+            Self::sync_dir(path.parent().unwrap_or(Path::new(".")))
+        })();
+
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+
+        result
+    }
+
+    fn sync_dir(_path: &Path) -> io::Result<()> {
+        // Persist the rename itself; directories cannot be opened this way on Windows.
+        #[cfg(unix)]
+        File::open(_path)?.sync_all()?;
+
+        Ok(())
+    }
+}
+
+impl Store for FileStore {
+    fn setup(&mut self) -> Result<(), CipherError> {
+        if self.lock.is_some() {
+            return Ok(());
+        }
+
+        Self::create_private_dir(&self.root)?;
+        Self::create_private_dir(&self.entries_dir())?;
+
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true).truncate(false);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let lock = options.open(self.root.join(Self::LOCK_FILE))?;
+
+        match lock.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(CipherError::StorageError(format!(
+                    "Keystore {} is in use by another process",
+                    self.root.display()
+                )))
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+
+        self.lock = Some(lock);
+        self.replay_journal()
+    }
+
+    fn put(&mut self, id: &str, data: Vec<u8>) -> Result<(), CipherError> {
+        if self.lock.is_none() {
+            return Err(CipherError::NotInitialized);
+        }
+
+        Self::write_atomic(&self.entry_path(id), &data)?;
+        Ok(())
+    }
+
+    fn get(&self, id: &str) -> Result<Option<Vec<u8>>, CipherError> {
+        match fs::read(self.entry_path(id)) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn delete(&mut self, id: &str) -> Result<(), CipherError> {
+        if self.lock.is_none() {
+            return Err(CipherError::NotInitialized);
+        }
+
+        match fs::remove_file(self.entry_path(id)) {
+            Ok(()) => Self::sync_dir(&self.entries_dir())?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(())
+    }
+
+    fn has(&self, id: &str) -> Result<bool, CipherError> {
+        Ok(self.entry_path(id).is_file())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>, CipherError> {
+        let dir = match fs::read_dir(self.entries_dir()) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut ids = Vec::new();
+        for entry in dir {
+            let entry = entry?;
+            if let Some(id) = entry.file_name().to_str().and_then(Self::entry_id) {
+                if id.starts_with(prefix) {
+                    ids.push(id);
+                }
+            }
+        }
+
+        ids.sort();
+        Ok(ids)
+    }
+
+    fn apply(&mut self, ops: Vec<StoreOp>) -> Result<(), CipherError> {
+        if self.lock.is_none() {
+            return Err(CipherError::NotInitialized);
+        }
+
+        let entries: Vec<JournalEntry> = ops
+            .into_iter()
+            .map(|op| match op {
+                StoreOp::Put(id, data) => JournalEntry {
+                    id,
+                    data: Some(URL_SAFE.encode(data)),
+                },
+                StoreOp::Delete(id) => JournalEntry { id, data: None },
+            })
+            .collect();
+
+        let journal =
+            serde_json::to_vec(&entries).map_err(|e| CipherError::StorageError(e.to_string()))?;
+
+        // The journal write is the commit point; everything after it is redo.
+        Self::write_atomic(&self.journal_path(), &journal)?;
+        self.replay_journal()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fresh directory under the system temp dir, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("nyx-file-store-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn journal(ops: &[(&str, Option<&[u8]>)]) -> Vec<u8> {
+        let entries: Vec<JournalEntry> = ops
+            .iter()
+            .map(|(id, data)| JournalEntry {
+                id: id.to_string(),
+                data: data.map(|data| URL_SAFE.encode(data)),
+            })
+            .collect();
+        serde_json::to_vec(&entries).unwrap()
+    }
+
+    #[test]
+    fn persists_entries_across_instances() {
+        let dir = TempDir::new("persist");
+
+        let mut store = FileStore::new(&dir.0);
+        store.setup().unwrap();
+        store.put("identity", b"secret".to_vec()).unwrap();
+        store
+            .apply(vec![
+                StoreOp::Put("peer:bob".into(), b"b".to_vec()),
+                StoreOp::Put("peer:carol".into(), b"c".to_vec()),
+                StoreOp::Delete("identity".into()),
+            ])
+            .unwrap();
+        drop(store);
+
+        let mut store = FileStore::new(&dir.0);
+        store.setup().unwrap();
+        assert_eq!(store.get("identity").unwrap(), None);
+        assert_eq!(store.get("peer:bob").unwrap(), Some(b"b".to_vec()));
+        assert_eq!(store.list("peer:").unwrap(), vec!["peer:bob", "peer:carol"]);
+        assert!(!store.journal_path().exists());
+    }
+
+    #[test]
+    fn second_instance_is_locked_out() {
+        let dir = TempDir::new("lock");
+
+        let mut first = FileStore::new(&dir.0);
+        first.setup().unwrap();
+        assert!(FileStore::new(&dir.0).setup().is_err());
+
+        drop(first);
+        FileStore::new(&dir.0).setup().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn files_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new("private");
+
+        let mut store = FileStore::new(&dir.0);
+        store.setup().unwrap();
+        store.put("identity", b"secret".to_vec()).unwrap();
+
+        for path in [dir.0.join(FileStore::LOCK_FILE), store.entry_path("identity")] {
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600, "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn replays_journal_committed_before_crash() {
+        let dir = TempDir::new("replay");
+
+        let mut store = FileStore::new(&dir.0);
+        store.setup().unwrap();
+        store.put("old", b"old".to_vec()).unwrap();
+        let journal_path = store.journal_path();
+        drop(store);
+
+        // Crash after the journal was committed, before any entry was written.
+        fs::write(&journal_path, journal(&[("new", Some(b"new")), ("old", None)])).unwrap();
+
+        let mut store = FileStore::new(&dir.0);
+        store.setup().unwrap();
+        assert_eq!(store.get("new").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(store.get("old").unwrap(), None);
+        assert!(!journal_path.exists());
+    }
+
+    #[test]
+    fn ignores_journal_not_committed_before_crash() {
+        let dir = TempDir::new("uncommitted");
+
+        let mut store = FileStore::new(&dir.0);
+        store.setup().unwrap();
+        store.put("old", b"old".to_vec()).unwrap();
+        let journal_path = store.journal_path();
+        drop(store);
+
+        // Crash while the journal was still being written: only the temporary
+        // file exists, so the batch never happened.
+        let tmp = journal_path.with_file_name(format!(".{}.tmp", FileStore::JOURNAL_FILE));
+        fs::write(&tmp, &journal(&[("new", Some(b"new")), ("old", None)])[..10]).unwrap();
+
+        let mut store = FileStore::new(&dir.0);
+        store.setup().unwrap();
+        assert_eq!(store.get("new").unwrap(), None);
+        assert_eq!(store.get("old").unwrap(), Some(b"old".to_vec()));
+    }
+
+    #[test]
+    fn rejects_writes_before_setup() {
+        let dir = TempDir::new("setup");
+        let mut store = FileStore::new(&dir.0);
+
+        assert!(matches!(store.put("id", Vec::new()), Err(CipherError::NotInitialized)));
+    }
+}
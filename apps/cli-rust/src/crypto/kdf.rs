@@ -0,0 +1,242 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use hkdf::Hkdf;
+use pbkdf2::pbkdf2_hmac;
+use sha2::Sha256;
+
+use super::cipher::CipherError;
+
+// How the raw ECDH output of a peer is turned into message keys. Stored next
+// to every peer so entries created before HKDF keep working until migrated.
+pub const PEER_KDF_RAW: u8 = 0;
+pub const PEER_KDF_HKDF_SHA256: u8 = 1;
+
+/// Derivation used for newly registered peers.
+pub const PEER_KDF_CURRENT: u8 = PEER_KDF_HKDF_SHA256;
+
+const PEER_KEY_LABEL: &[u8] = b"nyx/peer-key/v1";
+const PEER_KEY_CONTEXT: &[u8] = b"aes-256-gcm";
+
+/// Derives the key used for messages going from `sender` to `recipient`.
+///
+/// HKDF-SHA256 with an empty salt over the ECDH x-coordinate, and as info:
+///
+/// ```text
+/// "nyx/peer-key/v1" || sender SEC1 (65 bytes) || recipient SEC1 (65 bytes) || "aes-256-gcm"
+/// ```
+///
+/// Public keys are the uncompressed SEC1 encoding, the same bytes returned by
+/// `export_public_key`. Swapping the two keys yields the opposite direction, so
+/// each side's sending key is the other side's receiving key.
+pub fn derive_directional_key(
+    shared_secret: &[u8],
+    sender_public: &[u8],
+    recipient_public: &[u8],
+) -> Result<[u8; 32], CipherError> {
+    let mut info = Vec::with_capacity(
+        PEER_KEY_LABEL.len() + sender_public.len() + recipient_public.len() + PEER_KEY_CONTEXT.len(),
+    );
+    info.extend_from_slice(PEER_KEY_LABEL);
+    info.extend_from_slice(sender_public);
+    info.extend_from_slice(recipient_public);
+    info.extend_from_slice(PEER_KEY_CONTEXT);
+
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, shared_secret)
+        .expand(&info, &mut key)
+        .map_err(|e| CipherError::EncryptionFailed(format!("HKDF error: {}", e)))?;
+
+    Ok(key)
+}
+
+const LOCAL_KEY_LABEL: &[u8] = b"nyx/local-key/v1";
+
+/// Derives a key for data we keep for ourselves, such as notes, from the
+/// identity private key: HKDF-SHA256 with an empty salt and as info
+/// `"nyx/local-key/v1" || purpose`. Each purpose gets an independent key.
+pub fn derive_local_key(identity_secret: &[u8], purpose: &[u8]) -> Result<[u8; 32], CipherError> {
+    let mut info = Vec::with_capacity(LOCAL_KEY_LABEL.len() + purpose.len());
+    info.extend_from_slice(LOCAL_KEY_LABEL);
+    info.extend_from_slice(purpose);
+
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, identity_secret)
+        .expand(&info, &mut key)
+        .map_err(|e| CipherError::EncryptionFailed(format!("HKDF error: {}", e)))?;
+
+    Ok(key)
+}
+
+/// Password KDF of a keystore, stored as JSON under `keys::KDF` so the cost can
+/// be raised later. Keystores without it predate the header and use
+/// `PasswordKdf::LEGACY`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "algorithm", rename_all = "kebab-case")]
+pub enum PasswordKdf {
+    Pbkdf2Sha256 {
+        iterations: u32,
+    },
+    Argon2id {
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+}
+
+impl PasswordKdf {
+    pub const LEGACY: PasswordKdf = PasswordKdf::Pbkdf2Sha256 {
+        iterations: 600_000,
+    };
+
+    /// Used for new keystores, and for existing ones on their next unlock.
+    #[cfg(not(test))]
+    pub const CURRENT: PasswordKdf = PasswordKdf::Argon2id {
+        memory_kib: 64 * 1024,
+        iterations: 3,
+        parallelism: 1,
+    };
+
+    /// Argon2id at its lowest cost, so unit tests unlocking keystores stay fast.
+    #[cfg(test)]
+    pub const CURRENT: PasswordKdf = PasswordKdf::Argon2id {
+        memory_kib: 8,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    /// Highest costs accepted from data we did not write ourselves, such as
+    /// backups: enough headroom to raise `CURRENT`, without letting a crafted
+    /// file claim gigabytes of memory or hours of work.
+    pub const MAX_MEMORY_KIB: u32 = 1024 * 1024;
+    pub const MAX_ITERATIONS: u32 = 16;
+    pub const MAX_PARALLELISM: u32 = 8;
+    pub const MAX_PBKDF2_ITERATIONS: u32 = 10_000_000;
+
+    /// Whether the costs are within the `MAX_*` limits.
+    pub fn is_within_limits(&self) -> bool {
+        match *self {
+            PasswordKdf::Pbkdf2Sha256 { iterations } => iterations <= Self::MAX_PBKDF2_ITERATIONS,
+            PasswordKdf::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } => {
+                memory_kib <= Self::MAX_MEMORY_KIB
+                    && iterations <= Self::MAX_ITERATIONS
+                    && parallelism <= Self::MAX_PARALLELISM
+            }
+        }
+    }
+
+    pub fn derive(&self, password: &str, salt: &[u8]) -> Result<[u8; 32], CipherError> {
+        let mut key = [0u8; 32];
+
+        match *self {
+            PasswordKdf::Pbkdf2Sha256 { iterations } => {
+                pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut key);
+            }
+            PasswordKdf::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } => {
+                let params = Params::new(memory_kib, iterations, parallelism, Some(key.len()))
+                    .map_err(|e| CipherError::EncryptionFailed(format!("Argon2 error: {}", e)))?;
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(password.as_bytes(), salt, &mut key)
+                    .map_err(|e| CipherError::EncryptionFailed(format!("Argon2 error: {}", e)))?;
+            }
+        }
+
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes_gcm::aead::OsRng;
+    use p256::{ecdh::diffie_hellman, SecretKey};
+
+    fn key(byte: u8) -> Vec<u8> {
+        let mut key = vec![byte; 65];
+        key[0] = 0x04;
+        key
+    }
+
+    #[test]
+    fn directional_key_matches_the_interop_vector() {
+        // HKDF-SHA256(salt = empty, ikm = 00..1f,
+        //             info = "nyx/peer-key/v1" || 04 11.. || 04 22.. || "aes-256-gcm")
+        let secret: Vec<u8> = (0..32).collect();
+        let expected = [
+            0x7b, 0xeb, 0x44, 0x1d, 0x5c, 0xbf, 0xab, 0xe2, 0x64, 0xff, 0x7b, 0x5c, 0x7e, 0x4f,
+            0x5c, 0x68, 0xde, 0xeb, 0xc3, 0xce, 0x30, 0x10, 0x03, 0xf0, 0x4e, 0x2a, 0xe7, 0x92,
+            0xf3, 0x9a, 0x96, 0xac,
+        ];
+
+        assert_eq!(derive_directional_key(&secret, &key(0x11), &key(0x22)).unwrap(), expected);
+    }
+
+    #[test]
+    fn each_direction_has_its_own_key() {
+        let alice = SecretKey::random(&mut OsRng);
+        let bob = SecretKey::random(&mut OsRng);
+        let (alice_public, bob_public) = (alice.public_key(), bob.public_key());
+        let (alice_sec1, bob_sec1) = (alice_public.to_sec1_bytes(), bob_public.to_sec1_bytes());
+
+        // Each side computes the secret from its own private key.
+        let at_alice = diffie_hellman(alice.to_nonzero_scalar(), bob_public.as_affine());
+        let at_bob = diffie_hellman(bob.to_nonzero_scalar(), alice_public.as_affine());
+        let at_alice = at_alice.raw_secret_bytes();
+        let at_bob = at_bob.raw_secret_bytes();
+
+        let alice_sends = derive_directional_key(at_alice, &alice_sec1, &bob_sec1).unwrap();
+        let bob_receives = derive_directional_key(at_bob, &alice_sec1, &bob_sec1).unwrap();
+        let bob_sends = derive_directional_key(at_bob, &bob_sec1, &alice_sec1).unwrap();
+
+        assert_eq!(alice_sends, bob_receives);
+        assert_ne!(alice_sends, bob_sends);
+        assert_ne!(alice_sends[..], at_alice[..]);
+    }
+
+    #[test]
+    fn limits_allow_raising_the_current_costs() {
+        assert!(PasswordKdf::CURRENT.is_within_limits());
+        assert!(PasswordKdf::LEGACY.is_within_limits());
+        assert!(PasswordKdf::Argon2id {
+            memory_kib: PasswordKdf::MAX_MEMORY_KIB,
+            iterations: PasswordKdf::MAX_ITERATIONS,
+            parallelism: PasswordKdf::MAX_PARALLELISM,
+        }
+        .is_within_limits());
+    }
+
+    #[test]
+    fn limits_reject_any_cost_above_the_maximum() {
+        let argon2 = |memory_kib, iterations, parallelism| PasswordKdf::Argon2id {
+            memory_kib,
+            iterations,
+            parallelism,
+        };
+        let (memory, iterations, parallelism) = (
+            PasswordKdf::MAX_MEMORY_KIB,
+            PasswordKdf::MAX_ITERATIONS,
+            PasswordKdf::MAX_PARALLELISM,
+        );
+
+        assert!(!argon2(memory + 1, iterations, parallelism).is_within_limits());
+        assert!(!argon2(memory, iterations + 1, parallelism).is_within_limits());
+        assert!(!argon2(memory, iterations, parallelism + 1).is_within_limits());
+        assert!(!PasswordKdf::Pbkdf2Sha256 {
+            iterations: PasswordKdf::MAX_PBKDF2_ITERATIONS + 1,
+        }
+        .is_within_limits());
+    }
+
+    #[test]
+    fn password_kdf_header_round_trips() {
+        let json = serde_json::to_string(&PasswordKdf::CURRENT).unwrap();
+        assert!(json.contains("\"algorithm\":\"argon2id\""));
+        assert_eq!(serde_json::from_str::<PasswordKdf>(&json).unwrap(), PasswordKdf::CURRENT);
+    }
+}
@@ -1 +1,10 @@
-pub mod cipher;
\ No newline at end of file
+pub mod backup;
+pub mod cipher;
+pub mod envelope;
+pub mod file_store;
+pub mod kdf;
+pub mod ratchet;
+pub mod safety;
+pub mod sqlite_store;
+pub mod stream;
+pub mod x3dh;
@@ -0,0 +1,621 @@
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, KeyInit, Nonce, OsRng, Payload},
+    Aes256Gcm,
+};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use p256::{elliptic_curve::ecdh::diffie_hellman, PublicKey, SecretKey};
+use sha2::Sha256;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use super::cipher::CipherError;
+
+/// Maximum number of message keys skipped within a single receiving chain.
+pub const MAX_SKIP: u32 = 1000;
+
+/// Maximum number of skipped message keys kept across all chains; the oldest
+/// are dropped first.
+pub const MAX_SKIPPED_KEYS: usize = 2000;
+
+/// Plain header: sender ratchet key (SEC1 uncompressed), previous chain
+/// length and message number, both u32 BE.
+const HEADER_LEN: usize = 65 + 4 + 4;
+
+/// Encrypted header: plain header plus the AES-GCM tag.
+pub const ENCRYPTED_HEADER_LEN: usize = HEADER_LEN + 16;
+
+type Key = [u8; 32];
+
+const ROOT_INFO: &[u8] = b"nyx/ratchet/root";
+const MESSAGE_INFO: &[u8] = b"nyx/ratchet/message";
+
+/// Secrets both parties agree on before the first ratchet step.
+#[derive(Zeroize, ZeroizeOnDrop)]
+pub struct SessionSecrets {
+    pub root_key: [u8; 32],
+    /// Initial header key of the initiator's sending chain.
+    pub header_key_initiator: [u8; 32],
+    /// Initial next-header key of the responder's sending chain.
+    pub header_key_responder: [u8; 32],
+}
+
+impl SessionSecrets {
+    /// Expands `secret` (e.g. an ECDH or X3DH output) into the root and
+    /// header keys, with `info` naming the handshake that produced it.
+    pub fn derive(secret: &[u8], info: &[u8]) -> Result<Self, CipherError> {
+        let mut okm = [0u8; 96];
+        Hkdf::<Sha256>::new(None, secret)
+            .expand(info, &mut okm)
+            .map_err(|e| CipherError::RatchetError(format!("HKDF error: {}", e)))?;
+
+        let mut secrets = Self {
+            root_key: [0u8; 32],
+            header_key_initiator: [0u8; 32],
+            header_key_responder: [0u8; 32],
+        };
+        secrets.root_key.copy_from_slice(&okm[..32]);
+        secrets.header_key_initiator.copy_from_slice(&okm[32..64]);
+        secrets.header_key_responder.copy_from_slice(&okm[64..]);
+        okm.zeroize();
+
+        Ok(secrets)
+    }
+}
+
+pub struct RatchetMessage {
+    pub header_nonce: [u8; 12],
+    pub encrypted_header: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+struct Header {
+    ratchet_key: PublicKey,
+    previous_chain_length: u32,
+    message_number: u32,
+}
+
+impl Header {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN);
+        out.extend_from_slice(&self.ratchet_key.to_sec1_bytes());
+        out.extend_from_slice(&self.previous_chain_length.to_be_bytes());
+        out.extend_from_slice(&self.message_number.to_be_bytes());
+        out
+    }
+
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != HEADER_LEN {
+            return None;
+        }
+
+        Some(Self {
+            ratchet_key: PublicKey::from_sec1_bytes(&bytes[..65]).ok()?,
+            previous_chain_length: u32::from_be_bytes(bytes[65..69].try_into().ok()?),
+            message_number: u32::from_be_bytes(bytes[69..73].try_into().ok()?),
+        })
+    }
+}
+
+#[derive(Clone, Zeroize, ZeroizeOnDrop, serde::Serialize, serde::Deserialize)]
+struct SkippedKey {
+    header_key: [u8; 32],
+    message_number: u32,
+    message_key: [u8; 32],
+}
+
+/// Double Ratchet with header encryption (Signal spec, section 4), over P-256
+/// ECDH, HKDF-SHA256 / HMAC-SHA256 chains and AES-256-GCM.
+///
+/// The state is plain data so `Cipher` can persist it through its `Store`
+/// after every message; nothing here touches storage.
+#[derive(Clone, Zeroize, ZeroizeOnDrop, serde::Serialize, serde::Deserialize)]
+pub struct RatchetState {
+    dh_self: [u8; 32],
+    root_key: [u8; 32],
+    chain_send: Option<[u8; 32]>,
+    chain_recv: Option<[u8; 32]>,
+    header_send: Option<[u8; 32]>,
+    header_recv: Option<[u8; 32]>,
+    next_header_send: [u8; 32],
+    next_header_recv: [u8; 32],
+    sent: u32,
+    received: u32,
+    previous_sent: u32,
+    /// Oldest first.
+    skipped: Vec<SkippedKey>,
+    /// Bound into every message; identifies both parties of the session.
+    associated_data: Vec<u8>,
+    /// True until the initiator hears back from the responder.
+    awaiting_reply: bool,
+    /// The initiator's first ratchet public key; identifies the session so
+    /// a replayed first message is not mistaken for a new one.
+    origin: Option<Vec<u8>>,
+}
+
+impl RatchetState {
+    /// Session for the party that sends first, towards the responder's
+    /// current ratchet public key.
+    pub fn initiator(
+        secrets: &SessionSecrets,
+        remote_ratchet_key: &PublicKey,
+        associated_data: Vec<u8>,
+    ) -> Result<Self, CipherError> {
+        let dh_self = SecretKey::random(&mut OsRng);
+        let dh_out = dh(&dh_self, remote_ratchet_key);
+        let (root_key, chain_send, next_header_send) = kdf_root(&secrets.root_key, &dh_out)?;
+        let origin = dh_self.public_key().to_sec1_bytes().to_vec();
+
+        Ok(Self {
+            dh_self: dh_self.to_bytes().into(),
+            root_key,
+            chain_send: Some(chain_send),
+            chain_recv: None,
+            header_send: Some(secrets.header_key_initiator),
+            header_recv: None,
+            next_header_send,
+            next_header_recv: secrets.header_key_responder,
+            sent: 0,
+            received: 0,
+            previous_sent: 0,
+            skipped: Vec::new(),
+            associated_data,
+            awaiting_reply: true,
+            origin: Some(origin),
+        })
+    }
+
+    /// Session for the party that waits for the first message, using the
+    /// ratchet key pair the initiator was told about.
+    pub fn responder(
+        secrets: &SessionSecrets,
+        own_ratchet_key: &SecretKey,
+        associated_data: Vec<u8>,
+    ) -> Self {
+        Self {
+            dh_self: own_ratchet_key.to_bytes().into(),
+            root_key: secrets.root_key,
+            chain_send: None,
+            chain_recv: None,
+            header_send: None,
+            header_recv: None,
+            next_header_send: secrets.header_key_responder,
+            next_header_recv: secrets.header_key_initiator,
+            sent: 0,
+            received: 0,
+            previous_sent: 0,
+            skipped: Vec::new(),
+            associated_data,
+            awaiting_reply: false,
+            origin: None,
+        }
+    }
+
+    pub fn is_awaiting_reply(&self) -> bool {
+        self.awaiting_reply
+    }
+
+    /// Whether both states were set up from the same initiator message.
+    pub fn same_origin(&self, other: &RatchetState) -> bool {
+        self.origin.is_some() && self.origin == other.origin
+    }
+
+    pub fn encrypt(&mut self, plaintext: &[u8], ad: &[u8]) -> Result<RatchetMessage, CipherError> {
+        let (chain_send, header_send) = match (self.chain_send, self.header_send) {
+            (Some(chain), Some(header)) => (chain, header),
+            _ => {
+                return Err(CipherError::RatchetError(
+                    "Session is waiting for the peer's first message".into(),
+                ))
+            }
+        };
+
+        let (next_chain, message_key) = kdf_chain(&chain_send)?;
+        let header = Header {
+            ratchet_key: secret_key(&self.dh_self)?.public_key(),
+            previous_chain_length: self.previous_sent,
+            message_number: self.sent,
+        };
+
+        let mut header_nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut header_nonce);
+        let encrypted_header = Aes256Gcm::new_from_slice(&header_send)?
+            .encrypt(
+                Nonce::<Aes256Gcm>::from_slice(&header_nonce),
+                header.to_bytes().as_ref(),
+            )
+            .map_err(|e| CipherError::EncryptionFailed(e.to_string()))?;
+
+        let ciphertext = seal_message(
+            &message_key,
+            plaintext,
+            &self.message_ad(ad, &header_nonce, &encrypted_header),
+        )?;
+
+        self.chain_send = Some(next_chain);
+        self.sent += 1;
+
+        Ok(RatchetMessage {
+            header_nonce,
+            encrypted_header,
+            ciphertext,
+        })
+    }
+
+    /// Decrypts `message`. The state is only updated when decryption
+    /// succeeds, so a forged message cannot desynchronise the session.
+    pub fn decrypt(&mut self, message: &RatchetMessage, ad: &[u8]) -> Result<Vec<u8>, CipherError> {
+        let mut next = self.clone();
+        let plaintext = next.decrypt_inner(message, ad)?;
+        *self = next;
+        Ok(plaintext)
+    }
+
+    fn decrypt_inner(&mut self, message: &RatchetMessage, ad: &[u8]) -> Result<Vec<u8>, CipherError> {
+        let message_ad = self.message_ad(ad, &message.header_nonce, &message.encrypted_header);
+
+        if let Some(plaintext) = self.try_skipped_keys(message, &message_ad)? {
+            return Ok(plaintext);
+        }
+
+        let (header, needs_ratchet) = self.decrypt_header(message)?;
+
+        if needs_ratchet {
+            self.skip_message_keys(header.previous_chain_length)?;
+            self.dh_ratchet(&header.ratchet_key)?;
+        }
+
+        self.skip_message_keys(header.message_number)?;
+
+        let chain_recv = self
+            .chain_recv
+            .ok_or_else(|| CipherError::RatchetError("No receiving chain".into()))?;
+        let (next_chain, message_key) = kdf_chain(&chain_recv)?;
+        self.chain_recv = Some(next_chain);
+        self.received += 1;
+
+        let plaintext = open_message(&message_key, &message.ciphertext, &message_ad)?;
+        self.awaiting_reply = false;
+        Ok(plaintext)
+    }
+
+    fn message_ad(&self, ad: &[u8], header_nonce: &[u8], encrypted_header: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(
+            self.associated_data.len() + ad.len() + header_nonce.len() + encrypted_header.len(),
+        );
+        out.extend_from_slice(&self.associated_data);
+        out.extend_from_slice(ad);
+        out.extend_from_slice(header_nonce);
+        out.extend_from_slice(encrypted_header);
+        out
+    }
+
+    fn try_skipped_keys(
+        &mut self,
+        message: &RatchetMessage,
+        message_ad: &[u8],
+    ) -> Result<Option<Vec<u8>>, CipherError> {
+        let position = self.skipped.iter().position(|skipped| {
+            open_header(&skipped.header_key, message)
+                .is_some_and(|header| header.message_number == skipped.message_number)
+        });
+
+        match position {
+            Some(index) => {
+                let skipped = self.skipped.remove(index);
+                open_message(&skipped.message_key, &message.ciphertext, message_ad).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn decrypt_header(&self, message: &RatchetMessage) -> Result<(Header, bool), CipherError> {
+        if let Some(header) = self
+            .header_recv
+            .and_then(|key| open_header(&key, message))
+        {
+            return Ok((header, false));
+        }
+
+        if let Some(header) = open_header(&self.next_header_recv, message) {
+            return Ok((header, true));
+        }
+
+        Err(CipherError::DecryptionFailed("Unknown message header".into()))
+    }
+
+    fn skip_message_keys(&mut self, until: u32) -> Result<(), CipherError> {
+        if until > self.received.saturating_add(MAX_SKIP) {
+            return Err(CipherError::RatchetError("Too many skipped messages".into()));
+        }
+
+        if let (Some(mut chain), Some(header_key)) = (self.chain_recv, self.header_recv) {
+            while self.received < until {
+                let (next_chain, message_key) = kdf_chain(&chain)?;
+                self.skipped.push(SkippedKey {
+                    header_key,
+                    message_number: self.received,
+                    message_key,
+                });
+                chain = next_chain;
+                self.received += 1;
+            }
+            self.chain_recv = Some(chain);
+
+            if self.skipped.len() > MAX_SKIPPED_KEYS {
+                let excess = self.skipped.len() - MAX_SKIPPED_KEYS;
+                self.skipped.drain(..excess);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn dh_ratchet(&mut self, remote: &PublicKey) -> Result<(), CipherError> {
+        if self.origin.is_none() {
+            self.origin = Some(remote.to_sec1_bytes().to_vec());
+        }
+
+        self.previous_sent = self.sent;
+        self.sent = 0;
+        self.received = 0;
+        self.header_send = Some(self.next_header_send);
+        self.header_recv = Some(self.next_header_recv);
+
+        let dh_out = dh(&secret_key(&self.dh_self)?, remote);
+        let (root_key, chain_recv, next_header_recv) = kdf_root(&self.root_key, &dh_out)?;
+        self.root_key = root_key;
+        self.chain_recv = Some(chain_recv);
+        self.next_header_recv = next_header_recv;
+
+        let dh_self = SecretKey::random(&mut OsRng);
+        let dh_out = dh(&dh_self, remote);
+        let (root_key, chain_send, next_header_send) = kdf_root(&self.root_key, &dh_out)?;
+        self.dh_self = dh_self.to_bytes().into();
+        self.root_key = root_key;
+        self.chain_send = Some(chain_send);
+        self.next_header_send = next_header_send;
+
+        Ok(())
+    }
+}
+
+fn secret_key(bytes: &[u8; 32]) -> Result<SecretKey, CipherError> {
+    SecretKey::from_bytes(bytes.into()).map_err(|_| CipherError::InvalidKeyFormat)
+}
+
+fn dh(private: &SecretKey, public: &PublicKey) -> [u8; 32] {
+    let shared = diffie_hellman(private.to_nonzero_scalar(), public.as_affine());
+    let mut out = [0u8; 32];
+    out.copy_from_slice(shared.raw_secret_bytes().as_slice());
+    out
+}
+
+/// KDF_RK_HE: new root key, chain key and next header key.
+fn kdf_root(
+    root_key: &[u8; 32],
+    dh_out: &[u8; 32],
+) -> Result<(Key, Key, Key), CipherError> {
+    let mut okm = [0u8; 96];
+    Hkdf::<Sha256>::new(Some(root_key), dh_out)
+        .expand(ROOT_INFO, &mut okm)
+        .map_err(|e| CipherError::RatchetError(format!("HKDF error: {}", e)))?;
+
+    let mut root = [0u8; 32];
+    let mut chain = [0u8; 32];
+    let mut header = [0u8; 32];
+    root.copy_from_slice(&okm[..32]);
+    chain.copy_from_slice(&okm[32..64]);
+    header.copy_from_slice(&okm[64..]);
+    okm.zeroize();
+
+    Ok((root, chain, header))
+}
+
+/// KDF_CK: next chain key and message key.
+fn kdf_chain(chain_key: &Key) -> Result<(Key, Key), CipherError> {
+    let step = |constant: u8| -> Result<[u8; 32], CipherError> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(chain_key)?;
+        mac.update(&[constant]);
+        Ok(mac.finalize().into_bytes().into())
+    };
+
+    Ok((step(0x02)?, step(0x01)?))
+}
+
+fn message_cipher(message_key: &[u8; 32]) -> Result<(Aes256Gcm, [u8; 12]), CipherError> {
+    let mut okm = [0u8; 44];
+    Hkdf::<Sha256>::new(None, message_key)
+        .expand(MESSAGE_INFO, &mut okm)
+        .map_err(|e| CipherError::RatchetError(format!("HKDF error: {}", e)))?;
+
+    let cipher = Aes256Gcm::new_from_slice(&okm[..32])?;
+    let mut nonce = [0u8; 12];
+    nonce.copy_from_slice(&okm[32..]);
+    okm.zeroize();
+
+    Ok((cipher, nonce))
+}
+
+fn seal_message(message_key: &[u8; 32], plaintext: &[u8], ad: &[u8]) -> Result<Vec<u8>, CipherError> {
+    let (cipher, nonce) = message_cipher(message_key)?;
+    cipher
+        .encrypt(
+            Nonce::<Aes256Gcm>::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: ad,
+            },
+        )
+        .map_err(|e| CipherError::EncryptionFailed(e.to_string()))
+}
+
+fn open_message(message_key: &[u8; 32], ciphertext: &[u8], ad: &[u8]) -> Result<Vec<u8>, CipherError> {
+    let (cipher, nonce) = message_cipher(message_key)?;
+    cipher
+        .decrypt(
+            Nonce::<Aes256Gcm>::from_slice(&nonce),
+            Payload {
+                msg: ciphertext,
+                aad: ad,
+            },
+        )
+        .map_err(|e| CipherError::DecryptionFailed(e.to_string()))
+}
+
+fn open_header(header_key: &[u8; 32], message: &RatchetMessage) -> Option<Header> {
+    let plain = Aes256Gcm::new_from_slice(header_key)
+        .ok()?
+        .decrypt(
+            Nonce::<Aes256Gcm>::from_slice(&message.header_nonce),
+            message.encrypted_header.as_ref(),
+        )
+        .ok()?;
+
+    Header::parse(&plain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AD: &[u8] = b"alice||bob";
+
+    /// Alice initiates towards Bob's ratchet key; both share the secrets.
+    fn pair() -> (RatchetState, RatchetState) {
+        let secrets = SessionSecrets::derive(b"shared secret", b"nyx/ratchet/test").unwrap();
+        let bob_key = SecretKey::random(&mut OsRng);
+
+        let alice = RatchetState::initiator(&secrets, &bob_key.public_key(), AD.to_vec()).unwrap();
+        let bob = RatchetState::responder(&secrets, &bob_key, AD.to_vec());
+        (alice, bob)
+    }
+
+    fn send(state: &mut RatchetState, text: &str) -> RatchetMessage {
+        state.encrypt(text.as_bytes(), b"").unwrap()
+    }
+
+    fn receive(state: &mut RatchetState, message: &RatchetMessage) -> String {
+        String::from_utf8(state.decrypt(message, b"").unwrap()).unwrap()
+    }
+
+    #[test]
+    fn round_trips_across_ratchet_steps() {
+        let (mut alice, mut bob) = pair();
+        assert!(bob.encrypt(b"too early", b"").is_err());
+
+        for round in 0..3 {
+            let hello = send(&mut alice, &format!("hello {}", round));
+            assert_eq!(receive(&mut bob, &hello), format!("hello {}", round));
+
+            let reply = send(&mut bob, &format!("reply {}", round));
+            assert_eq!(receive(&mut alice, &reply), format!("reply {}", round));
+        }
+        assert!(!alice.is_awaiting_reply());
+        assert!(alice.same_origin(&bob));
+    }
+
+    #[test]
+    fn decrypts_out_of_order_within_a_chain() {
+        let (mut alice, mut bob) = pair();
+        let messages: Vec<_> = (0..4).map(|i| send(&mut alice, &i.to_string())).collect();
+
+        assert_eq!(receive(&mut bob, &messages[2]), "2");
+        assert_eq!(bob.skipped.len(), 2);
+        assert_eq!(receive(&mut bob, &messages[0]), "0");
+        assert_eq!(receive(&mut bob, &messages[3]), "3");
+        assert_eq!(receive(&mut bob, &messages[1]), "1");
+        assert!(bob.skipped.is_empty());
+    }
+
+    #[test]
+    fn decrypts_messages_from_a_previous_chain() {
+        let (mut alice, mut bob) = pair();
+        let early = send(&mut alice, "early");
+        let first = send(&mut alice, "first");
+        assert_eq!(receive(&mut bob, &first), "first");
+
+        let reply = send(&mut bob, "reply");
+        assert_eq!(receive(&mut alice, &reply), "reply");
+        let after = send(&mut alice, "after");
+        assert_eq!(receive(&mut bob, &after), "after");
+
+        // Kept under the header key of the chain it was sent on.
+        assert_eq!(receive(&mut bob, &early), "early");
+    }
+
+    #[test]
+    fn skipped_keys_are_single_use() {
+        let (mut alice, mut bob) = pair();
+        let first = send(&mut alice, "first");
+        let second = send(&mut alice, "second");
+
+        assert_eq!(receive(&mut bob, &second), "second");
+        assert_eq!(receive(&mut bob, &first), "first");
+        assert!(bob.decrypt(&first, b"").is_err());
+        assert!(bob.decrypt(&second, b"").is_err());
+    }
+
+    #[test]
+    fn refuses_to_skip_more_than_max_skip() {
+        let (mut alice, mut bob) = pair();
+        alice.sent = MAX_SKIP + 1;
+
+        let message = send(&mut alice, "far ahead");
+        assert!(matches!(
+            bob.decrypt(&message, b""),
+            Err(CipherError::RatchetError(_))
+        ));
+        assert!(bob.skipped.is_empty());
+    }
+
+    #[test]
+    fn header_is_encrypted() {
+        let (mut alice, mut bob) = pair();
+        let ratchet_key = secret_key(&alice.dh_self).unwrap().public_key().to_sec1_bytes();
+
+        let message = send(&mut alice, "hello");
+        assert_eq!(message.encrypted_header.len(), ENCRYPTED_HEADER_LEN);
+        assert!(!message
+            .encrypted_header
+            .windows(ratchet_key.len())
+            .any(|window| window == ratchet_key.as_ref()));
+
+        let header_key = alice.header_send.unwrap();
+        let header = open_header(&header_key, &message).unwrap();
+        assert_eq!(header.ratchet_key.to_sec1_bytes(), ratchet_key);
+        assert_eq!(header.message_number, 0);
+
+        assert!(open_header(&[0u8; 32], &message).is_none());
+        assert_eq!(receive(&mut bob, &message), "hello");
+    }
+
+    #[test]
+    fn rejects_tampering_without_changing_state() {
+        let (mut alice, mut bob) = pair();
+        let message = send(&mut alice, "hello");
+
+        let mut forged = RatchetMessage {
+            header_nonce: message.header_nonce,
+            encrypted_header: message.encrypted_header.clone(),
+            ciphertext: message.ciphertext.clone(),
+        };
+        forged.ciphertext[0] ^= 1;
+        assert!(bob.decrypt(&forged, b"").is_err());
+
+        forged.ciphertext[0] ^= 1;
+        forged.encrypted_header[0] ^= 1;
+        assert!(bob.decrypt(&forged, b"").is_err());
+
+        assert!(bob.decrypt(&message, b"other context").is_err());
+        assert_eq!(receive(&mut bob, &message), "hello");
+    }
+
+    #[test]
+    fn rejects_replays() {
+        let (mut alice, mut bob) = pair();
+        let message = send(&mut alice, "hello");
+
+        assert_eq!(receive(&mut bob, &message), "hello");
+        assert!(bob.decrypt(&message, b"").is_err());
+    }
+}
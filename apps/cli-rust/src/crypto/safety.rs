@@ -0,0 +1,132 @@
+use p256::PublicKey;
+use sha2::{Digest, Sha512};
+use std::fmt;
+
+/// Pairwise safety number two users compare out of band, Signal style.
+///
+/// Each identity key is hashed on its own as
+///
+/// ```text
+/// h_0 = SHA-512(version u16 BE || SEC1 key), h_i = SHA-512(h_(i-1) || SEC1 key)
+/// ```
+///
+/// for `ITERATIONS` rounds, and the first 30 bytes of the result become 30
+/// digits: six 5-byte big-endian chunks, each mod 100000. The two halves are
+/// sorted and concatenated, so both sides see the same 60 digits.
+pub const VERSION: u16 = 1;
+pub const ITERATIONS: usize = 5200;
+
+/// Prefix of the QR code payload, followed by the 60 digits.
+pub const QR_PREFIX: &str = "nyx-safety:1:";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SafetyNumber {
+    digits: String,
+}
+
+fn key_digits(key: &PublicKey) -> String {
+    let key = key.to_sec1_bytes();
+
+    let mut hash = Sha512::new()
+        .chain_update(VERSION.to_be_bytes())
+        .chain_update(&key)
+        .finalize();
+    for _ in 1..ITERATIONS {
+        hash = Sha512::new().chain_update(hash).chain_update(&key).finalize();
+    }
+
+    hash[..30]
+        .chunks(5)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+            format!("{:05}", value % 100_000)
+        })
+        .collect()
+}
+
+impl SafetyNumber {
+    pub fn new(a: &PublicKey, b: &PublicKey) -> Self {
+        let mut halves = [key_digits(a), key_digits(b)];
+        halves.sort();
+
+        Self {
+            digits: halves.concat(),
+        }
+    }
+
+    pub fn digits(&self) -> &str {
+        &self.digits
+    }
+
+    /// Twelve groups of five digits.
+    pub fn groups(&self) -> Vec<&str> {
+        (0..self.digits.len())
+            .step_by(5)
+            .map(|start| &self.digits[start..start + 5])
+            .collect()
+    }
+
+    /// Text to encode in a QR code for the other side to scan.
+    pub fn qr_payload(&self) -> String {
+        format!("{}{}", QR_PREFIX, self.digits)
+    }
+
+    /// Whether `input` (typed digits, with any spacing, or a scanned QR
+    /// payload) is this safety number.
+    pub fn matches(&self, input: &str) -> bool {
+        let input = input.trim();
+        let input = input.strip_prefix(QR_PREFIX).unwrap_or(input);
+        let digits: String = input.chars().filter(|c| !c.is_whitespace()).collect();
+
+        digits == self.digits
+    }
+}
+
+impl fmt::Display for SafetyNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.groups().join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes_gcm::aead::OsRng;
+    use p256::SecretKey;
+
+    fn key() -> PublicKey {
+        SecretKey::random(&mut OsRng).public_key()
+    }
+
+    #[test]
+    fn both_sides_compute_the_same_number() {
+        let (alice, bob) = (key(), key());
+        let number = SafetyNumber::new(&alice, &bob);
+
+        assert_eq!(SafetyNumber::new(&bob, &alice), number);
+        assert_eq!(number.digits().len(), 60);
+        assert!(number.digits().chars().all(|c| c.is_ascii_digit()));
+        assert_eq!(number.groups().len(), 12);
+    }
+
+    #[test]
+    fn changes_with_either_key() {
+        let (alice, bob) = (key(), key());
+        let number = SafetyNumber::new(&alice, &bob);
+
+        assert_ne!(SafetyNumber::new(&key(), &bob), number);
+        assert_ne!(SafetyNumber::new(&alice, &key()), number);
+    }
+
+    #[test]
+    fn matches_typed_digits_and_scanned_payloads() {
+        let number = SafetyNumber::new(&key(), &key());
+
+        assert!(number.matches(number.digits()));
+        assert!(number.matches(&number.to_string()));
+        assert!(number.matches(&format!("  {}\n", number.qr_payload())));
+        assert!(!number.matches(&number.digits()[1..]));
+        assert!(!number.matches(&SafetyNumber::new(&key(), &key()).qr_payload()));
+        assert!(!number.matches(&format!("nyx-safety:2:{}", number.digits())));
+    }
+}
@@ -0,0 +1,328 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use super::cipher::{CipherError, Store, StoreOp};
+
+/// Schema migrations, applied in order. The position in this list (1-based)
+/// is the schema version recorded in `PRAGMA user_version`; never edit an
+/// entry once released, append a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // v1: key/value entries used by `Cipher`.
+    "CREATE TABLE entries (
+        id TEXT PRIMARY KEY NOT NULL,
+        data BLOB NOT NULL,
+        updated_at INTEGER NOT NULL
+    );",
+];
+
+/// SQLite-backed `Store`.
+///
+/// Keeps every entry in a single database file so identity, peer keys and
+/// later message history can be backed up and inspected as one unit. Writes
+/// run inside transactions and the schema is versioned through `MIGRATIONS`.
+pub struct SqliteStore {
+    path: PathBuf,
+    conn: Option<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    pub const FILE_NAME: &'static str = "nyx.db";
+
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            conn: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Latest schema version this build knows how to migrate to.
+    pub fn latest_version() -> u32 {
+        MIGRATIONS.len() as u32
+    }
+
+    pub fn schema_version(&self) -> Result<u32, CipherError> {
+        let conn = self.conn()?;
+        Self::user_version(&conn)
+    }
+
+    /// Writes a consistent snapshot of the database to `dest`.
+    pub fn backup(&self, dest: &Path) -> Result<(), CipherError> {
+        let conn = self.conn()?;
+        Self::create_private_file(dest)?;
+        conn.execute("VACUUM INTO ?1", params![dest.to_string_lossy()])
+            .map_err(Self::map_err)?;
+        Ok(())
+    }
+
+    fn conn(&self) -> Result<MutexGuard<'_, Connection>, CipherError> {
+        self.conn
+            .as_ref()
+            .ok_or(CipherError::NotInitialized)?
+            .lock()
+            .map_err(|_| CipherError::StorageError("SQLite connection poisoned".into()))
+    }
+
+    fn map_err(err: rusqlite::Error) -> CipherError {
+        CipherError::StorageError(err.to_string())
+    }
+
+    fn user_version(conn: &Connection) -> Result<u32, CipherError> {
+        conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+            .map_err(Self::map_err)
+    }
+
+    fn migrate(conn: &mut Connection) -> Result<(), CipherError> {
+        let current = Self::user_version(conn)?;
+        let latest = Self::latest_version();
+
+        if current > latest {
+            return Err(CipherError::StorageError(format!(
+                "Database schema v{} is newer than supported v{}",
+                current, latest
+            )));
+        }
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(current as usize) {
+            let version = index as u32 + 1;
+            let tx = conn.transaction().map_err(Self::map_err)?;
+            tx.execute_batch(migration).map_err(Self::map_err)?;
+            tx.pragma_update(None, "user_version", version)
+                .map_err(Self::map_err)?;
+            tx.commit().map_err(Self::map_err)?;
+        }
+
+        Ok(())
+    }
+
+    fn create_private_file(path: &Path) -> Result<(), CipherError> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .mode(0o600)
+                .open(path)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Store for SqliteStore {
+    fn setup(&mut self) -> Result<(), CipherError> {
+        if self.conn.is_some() {
+            return Ok(());
+        }
+
+        Self::create_private_file(&self.path)?;
+
+        let mut conn = Connection::open(&self.path).map_err(Self::map_err)?;
+        conn.busy_timeout(std::time::Duration::from_secs(5))
+            .map_err(Self::map_err)?;
+        conn.pragma_update(None, "journal_mode", "WAL")
+            .map_err(Self::map_err)?;
+
+        Self::migrate(&mut conn)?;
+
+        self.conn = Some(Mutex::new(conn));
+        Ok(())
+    }
+
+    fn put(&mut self, id: &str, data: Vec<u8>) -> Result<(), CipherError> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction().map_err(Self::map_err)?;
+        tx.execute(
+            "INSERT INTO entries (id, data, updated_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT(id) DO UPDATE SET data = excluded.data, updated_at = excluded.updated_at",
+            params![id, data, chrono::Utc::now().timestamp()],
+        )
+        .map_err(Self::map_err)?;
+        tx.commit().map_err(Self::map_err)
+    }
+
+    fn get(&self, id: &str) -> Result<Option<Vec<u8>>, CipherError> {
+        let conn = self.conn()?;
+        conn.query_row("SELECT data FROM entries WHERE id = ?1", params![id], |row| {
+            row.get(0)
+        })
+        .optional()
+        .map_err(Self::map_err)
+    }
+
+    fn delete(&mut self, id: &str) -> Result<(), CipherError> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction().map_err(Self::map_err)?;
+        tx.execute("DELETE FROM entries WHERE id = ?1", params![id])
+            .map_err(Self::map_err)?;
+        tx.commit().map_err(Self::map_err)
+    }
+
+    fn has(&self, id: &str) -> Result<bool, CipherError> {
+        let conn = self.conn()?;
+        conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM entries WHERE id = ?1)",
+            params![id],
+            |row| row.get(0),
+        )
+        .map_err(Self::map_err)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>, CipherError> {
+        let conn = self.conn()?;
+        let mut stmt = conn
+            .prepare("SELECT id FROM entries WHERE substr(id, 1, length(?1)) = ?1 ORDER BY id")
+            .map_err(Self::map_err)?;
+        let ids = stmt
+            .query_map(params![prefix], |row| row.get(0))
+            .map_err(Self::map_err)?
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(Self::map_err)?;
+        Ok(ids)
+    }
+
+    fn apply(&mut self, ops: Vec<StoreOp>) -> Result<(), CipherError> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction().map_err(Self::map_err)?;
+        let now = chrono::Utc::now().timestamp();
+
+        for op in ops {
+            match op {
+                StoreOp::Put(id, data) => tx.execute(
+                    "INSERT INTO entries (id, data, updated_at) VALUES (?1, ?2, ?3)
+                     ON CONFLICT(id) DO UPDATE SET data = excluded.data, updated_at = excluded.updated_at",
+                    params![id, data, now],
+                ),
+                StoreOp::Delete(id) => {
+                    tx.execute("DELETE FROM entries WHERE id = ?1", params![id])
+                }
+            }
+            .map_err(Self::map_err)?;
+        }
+
+        tx.commit().map_err(Self::map_err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Database path under a fresh temp directory, removed on drop.
+    struct TempDb(PathBuf);
+
+    impl TempDb {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir()
+                .join(format!("nyx-sqlite-store-{}-{}", std::process::id(), name));
+            let _ = std::fs::remove_dir_all(&dir);
+            Self(dir.join(SqliteStore::FILE_NAME))
+        }
+
+        fn open(&self) -> SqliteStore {
+            let mut store = SqliteStore::new(&self.0);
+            store.setup().unwrap();
+            store
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            if let Some(dir) = self.0.parent() {
+                let _ = std::fs::remove_dir_all(dir);
+            }
+        }
+    }
+
+    #[test]
+    fn fresh_database_is_at_the_latest_version() {
+        let db = TempDb::new("version");
+        let store = db.open();
+
+        assert_eq!(store.schema_version().unwrap(), SqliteStore::latest_version());
+        drop(store);
+        // Reopening runs no migration twice.
+        assert_eq!(db.open().schema_version().unwrap(), SqliteStore::latest_version());
+    }
+
+    #[test]
+    fn refuses_a_newer_schema() {
+        let db = TempDb::new("newer");
+        drop(db.open());
+
+        let conn = Connection::open(&db.0).unwrap();
+        conn.pragma_update(None, "user_version", SqliteStore::latest_version() + 1)
+            .unwrap();
+        drop(conn);
+
+        let mut store = SqliteStore::new(&db.0);
+        assert!(matches!(store.setup(), Err(CipherError::StorageError(_))));
+    }
+
+    #[test]
+    fn round_trips_entries_across_instances() {
+        let db = TempDb::new("round-trip");
+
+        let mut store = db.open();
+        store.put("keys/identity", b"secret".to_vec()).unwrap();
+        store.put("keys/identity", b"replaced".to_vec()).unwrap();
+        store
+            .apply(vec![
+                StoreOp::Put("peers/bob".into(), b"b".to_vec()),
+                StoreOp::Put("peers/carol".into(), b"c".to_vec()),
+                StoreOp::Put("peersx".into(), b"x".to_vec()),
+                StoreOp::Delete("peers/carol".into()),
+            ])
+            .unwrap();
+        drop(store);
+
+        let mut store = db.open();
+        assert_eq!(store.get("keys/identity").unwrap(), Some(b"replaced".to_vec()));
+        assert!(store.has("peers/bob").unwrap());
+        assert!(!store.has("peers/carol").unwrap());
+        assert_eq!(store.list("peers/").unwrap(), vec!["peers/bob"]);
+        assert_eq!(store.list("").unwrap(), vec!["keys/identity", "peers/bob", "peersx"]);
+
+        store.delete("peers/bob").unwrap();
+        assert_eq!(store.get("peers/bob").unwrap(), None);
+    }
+
+    #[test]
+    fn failing_batch_is_rolled_back() {
+        let db = TempDb::new("rollback");
+        let mut store = db.open();
+        store.put("old", b"old".to_vec()).unwrap();
+
+        // Make the last write of the batch fail after the others went through.
+        let trigger = "CREATE TRIGGER fail BEFORE INSERT ON entries WHEN NEW.id = 'bad'
+                       BEGIN SELECT RAISE(ABORT, 'injected'); END;";
+        store.conn().unwrap().execute_batch(trigger).unwrap();
+
+        let result = store.apply(vec![
+            StoreOp::Delete("old".into()),
+            StoreOp::Put("new".into(), b"new".to_vec()),
+            StoreOp::Put("bad".into(), b"bad".to_vec()),
+        ]);
+        assert!(matches!(result, Err(CipherError::StorageError(_))));
+        assert_eq!(store.get("old").unwrap(), Some(b"old".to_vec()));
+        assert!(!store.has("new").unwrap());
+    }
+
+    #[test]
+    fn rejects_use_before_setup() {
+        let db = TempDb::new("setup");
+        let mut store = SqliteStore::new(&db.0);
+
+        assert!(matches!(store.put("id", Vec::new()), Err(CipherError::NotInitialized)));
+    }
+}
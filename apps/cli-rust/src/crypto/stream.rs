@@ -0,0 +1,681 @@
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, KeyInit, Nonce, OsRng, Payload},
+    Aes256Gcm,
+};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::io::{self, Read, Write};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use super::cipher::CipherError;
+use super::envelope::{EnvelopeHeader, Suite, HEADER_LEN};
+
+/// Chunked AEAD for payloads too large to hold in memory (STREAM
+/// construction, Hoang–Reyhanitabar–Rogaway–Vizár).
+///
+/// ```text
+/// envelope header (30, suite 3) | salt (32) | chunk size u32 BE (4)
+///     | chunk 0 | chunk 1 | ... | final chunk
+/// ```
+///
+/// Each stream gets its own key, HKDF-SHA256 over the peer key with the salt
+/// and `"nyx/stream/v1"` as info. Chunk `i` is AES-256-GCM over at most
+/// `chunk size` plaintext bytes with nonce `[0; 7] || i u32 BE || last flag`
+/// and the whole stream header as associated data. Every chunk but the last
+/// holds exactly `chunk size` bytes, so the final one is always shorter (and
+/// empty when the plaintext is a multiple of the chunk size): reordered,
+/// dropped or truncated chunks fail to decrypt.
+pub const STREAM_HEADER_LEN: usize = HEADER_LEN + SALT_LEN + 4;
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Largest chunk size accepted from a stream header.
+pub const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+const SALT_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+const STREAM_KEY_INFO: &[u8] = b"nyx/stream/v1";
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        CipherError::DecryptionFailed(message.into()),
+    )
+}
+
+/// Per-stream key and chunk counter shared by both directions.
+#[derive(Zeroize, ZeroizeOnDrop)]
+struct StreamState {
+    key: [u8; 32],
+    #[zeroize(skip)]
+    header: Vec<u8>,
+    counter: u32,
+    finished: bool,
+}
+
+impl StreamState {
+    fn new(peer_key: &[u8; 32], header: Vec<u8>) -> Result<Self, CipherError> {
+        let salt = &header[HEADER_LEN..HEADER_LEN + SALT_LEN];
+
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(salt), peer_key)
+            .expand(STREAM_KEY_INFO, &mut key)
+            .map_err(|e| CipherError::EncryptionFailed(format!("HKDF error: {}", e)))?;
+
+        Ok(Self {
+            key,
+            header,
+            counter: 0,
+            finished: false,
+        })
+    }
+
+    fn nonce(&self, last: bool) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[7..11].copy_from_slice(&self.counter.to_be_bytes());
+        nonce[11] = last as u8;
+        nonce
+    }
+
+    fn advance(&mut self, last: bool) -> Result<(), CipherError> {
+        if last {
+            self.finished = true;
+            return Ok(());
+        }
+
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| CipherError::EncryptionFailed("Stream too long".into()))?;
+        Ok(())
+    }
+
+    fn seal_chunk(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>, CipherError> {
+        let cipher = Aes256Gcm::new_from_slice(&self.key)?;
+        let nonce = self.nonce(last);
+
+        let sealed = cipher
+            .encrypt(
+                Nonce::<Aes256Gcm>::from_slice(&nonce),
+                Payload {
+                    msg: chunk,
+                    aad: &self.header,
+                },
+            )
+            .map_err(|e| CipherError::EncryptionFailed(e.to_string()))?;
+
+        self.advance(last)?;
+        Ok(sealed)
+    }
+
+    fn open_chunk(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>, CipherError> {
+        let cipher = Aes256Gcm::new_from_slice(&self.key)?;
+        let nonce = self.nonce(last);
+
+        let plain = cipher
+            .decrypt(
+                Nonce::<Aes256Gcm>::from_slice(&nonce),
+                Payload {
+                    msg: chunk,
+                    aad: &self.header,
+                },
+            )
+            .map_err(|_| CipherError::DecryptionFailed("Stream chunk rejected".into()))?;
+
+        self.advance(last)?;
+        Ok(plain)
+    }
+}
+
+/// Builds the header of a new stream from `sender` with a fresh salt.
+pub fn stream_header(sender: [u8; 16], chunk_size: usize) -> Result<Vec<u8>, CipherError> {
+    if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+        return Err(CipherError::EncryptionFailed("Invalid chunk size".into()));
+    }
+
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+
+    let mut header = EnvelopeHeader::new(Suite::P256HkdfAes256GcmStream, sender)
+        .to_bytes()
+        .to_vec();
+    header.extend_from_slice(&salt);
+    header.extend_from_slice(&(chunk_size as u32).to_be_bytes());
+    Ok(header)
+}
+
+/// Checks a stream header read from the wire and returns its chunk size.
+pub fn parse_stream_header(header: &[u8]) -> Result<usize, CipherError> {
+    if header.len() != STREAM_HEADER_LEN {
+        return Err(CipherError::DecryptionFailed("Invalid stream header".into()));
+    }
+
+    if EnvelopeHeader::parse(header)?.suite != Suite::P256HkdfAes256GcmStream {
+        return Err(CipherError::DecryptionFailed("Not a stream".into()));
+    }
+
+    let mut size = [0u8; 4];
+    size.copy_from_slice(&header[STREAM_HEADER_LEN - 4..]);
+    let chunk_size = u32::from_be_bytes(size) as usize;
+
+    if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+        return Err(CipherError::DecryptionFailed("Invalid chunk size".into()));
+    }
+
+    Ok(chunk_size)
+}
+
+/// Fills `buf` from `reader` until it is full or the input ends, returning
+/// the number of bytes read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// `Write` adapter that encrypts everything written to it. Call `finish`
+/// once done: a stream dropped without it is truncated and will not decrypt.
+pub struct StreamEncryptor<W: Write> {
+    inner: W,
+    state: StreamState,
+    chunk_size: usize,
+    buffer: Zeroizing<Vec<u8>>,
+}
+
+impl<W: Write> StreamEncryptor<W> {
+    /// Writes `header` (from `stream_header`) to `inner`.
+    pub(crate) fn new(mut inner: W, peer_key: &[u8; 32], header: Vec<u8>) -> Result<Self, CipherError> {
+        let chunk_size = parse_stream_header(&header)?;
+        inner.write_all(&header)?;
+
+        Ok(Self {
+            inner,
+            state: StreamState::new(peer_key, header)?,
+            chunk_size,
+            buffer: Zeroizing::new(Vec::with_capacity(chunk_size)),
+        })
+    }
+
+    /// Writes the final chunk and returns the inner writer.
+    pub fn finish(mut self) -> Result<W, CipherError> {
+        if self.buffer.len() == self.chunk_size {
+            let sealed = self.state.seal_chunk(&self.buffer, false)?;
+            self.buffer.zeroize();
+            self.inner.write_all(&sealed)?;
+        }
+
+        let sealed = self.state.seal_chunk(&self.buffer, true)?;
+        self.inner.write_all(&sealed)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for StreamEncryptor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A full buffer is only sealed once more data arrives, since the
+        // final chunk must be shorter than `chunk_size`.
+        if self.buffer.len() == self.chunk_size && !buf.is_empty() {
+            let sealed = self
+                .state
+                .seal_chunk(&self.buffer, false)
+                .map_err(io::Error::other)?;
+            self.buffer.zeroize();
+            self.inner.write_all(&sealed)?;
+        }
+
+        let take = buf.len().min(self.chunk_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..take]);
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// `Read` adapter that yields the plaintext of a stream. Fails with
+/// `InvalidData` on a tampered, reordered or truncated stream; data already
+/// returned before the failure must be discarded by the caller.
+pub struct StreamDecryptor<R: Read> {
+    inner: R,
+    state: StreamState,
+    chunk_size: usize,
+    plain: Zeroizing<Vec<u8>>,
+    position: usize,
+}
+
+impl<R: Read> StreamDecryptor<R> {
+    /// Reads and checks the stream header from `inner`.
+    pub(crate) fn new(mut inner: R, peer_key: &[u8; 32]) -> Result<Self, CipherError> {
+        let mut header = vec![0u8; STREAM_HEADER_LEN];
+        if read_full(&mut inner, &mut header)? != STREAM_HEADER_LEN {
+            return Err(CipherError::DecryptionFailed("Invalid stream header".into()));
+        }
+        let chunk_size = parse_stream_header(&header)?;
+
+        Ok(Self {
+            inner,
+            state: StreamState::new(peer_key, header)?,
+            chunk_size,
+            plain: Zeroizing::new(Vec::new()),
+            position: 0,
+        })
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let mut chunk = vec![0u8; self.chunk_size + TAG_LEN];
+        let read = read_full(&mut self.inner, &mut chunk)?;
+
+        if read < TAG_LEN {
+            return Err(invalid("Stream truncated"));
+        }
+
+        let last = read < chunk.len();
+        self.plain = Zeroizing::new(
+            self.state
+                .open_chunk(&chunk[..read], last)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        );
+        self.position = 0;
+
+        if last && self.inner.read(&mut [0u8; 1])? != 0 {
+            return Err(invalid("Data after final chunk"));
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for StreamDecryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plain.len() {
+            if self.state.finished {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+
+        let n = buf.len().min(self.plain.len() - self.position);
+        buf[..n].copy_from_slice(&self.plain[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/// `AsyncWrite` counterpart of `StreamEncryptor`. `shutdown` writes the
+/// final chunk and shuts `inner` down; a stream dropped without it is
+/// truncated and will not decrypt. At most one sealed chunk is buffered.
+pub struct AsyncStreamEncryptor<W: AsyncWrite + Unpin> {
+    inner: W,
+    state: StreamState,
+    chunk_size: usize,
+    buffer: Zeroizing<Vec<u8>>,
+    /// Sealed bytes not yet taken by `inner`, starting with the header.
+    pending: Vec<u8>,
+    written: usize,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncStreamEncryptor<W> {
+    /// The header (from `stream_header`) goes out with the first write.
+    pub(crate) fn new(inner: W, peer_key: &[u8; 32], header: Vec<u8>) -> Result<Self, CipherError> {
+        let chunk_size = parse_stream_header(&header)?;
+
+        Ok(Self {
+            inner,
+            state: StreamState::new(peer_key, header.clone())?,
+            chunk_size,
+            buffer: Zeroizing::new(Vec::with_capacity(chunk_size)),
+            pending: header,
+            written: 0,
+        })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.pending.len() {
+            let pending = &self.pending[self.written..];
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, pending))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+
+        self.pending.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    fn seal_buffer(&mut self, last: bool) -> io::Result<()> {
+        self.pending = self
+            .state
+            .seal_chunk(&self.buffer, last)
+            .map_err(io::Error::other)?;
+        self.buffer.zeroize();
+        Ok(())
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for AsyncStreamEncryptor<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+
+        // As in `StreamEncryptor`, a full buffer is only sealed once more
+        // data arrives.
+        if this.buffer.len() == this.chunk_size && !buf.is_empty() {
+            this.seal_buffer(false)?;
+        }
+
+        let take = buf.len().min(this.chunk_size - this.buffer.len());
+        this.buffer.extend_from_slice(&buf[..take]);
+        Poll::Ready(Ok(take))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            ready!(this.poll_pending(cx))?;
+            if this.state.finished {
+                break;
+            }
+            // A full buffer goes out as a regular chunk, then an empty final one.
+            let last = this.buffer.len() < this.chunk_size;
+            this.seal_buffer(last)?;
+        }
+
+        ready!(Pin::new(&mut this.inner).poll_flush(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// `AsyncRead` counterpart of `StreamDecryptor`, with the same guarantees.
+pub struct AsyncStreamDecryptor<R: AsyncRead + Unpin> {
+    inner: R,
+    state: StreamState,
+    chunk: Vec<u8>,
+    filled: usize,
+    plain: Zeroizing<Vec<u8>>,
+    position: usize,
+    /// Whether the input was seen to end right after the final chunk.
+    ended: bool,
+}
+
+impl<R: AsyncRead + Unpin> AsyncStreamDecryptor<R> {
+    /// Reads and checks the stream header from `inner`.
+    pub(crate) async fn new(mut inner: R, peer_key: &[u8; 32]) -> Result<Self, CipherError> {
+        let mut header = vec![0u8; STREAM_HEADER_LEN];
+        if inner.read_exact(&mut header).await.is_err() {
+            return Err(CipherError::DecryptionFailed("Invalid stream header".into()));
+        }
+        let chunk_size = parse_stream_header(&header)?;
+
+        Ok(Self {
+            inner,
+            state: StreamState::new(peer_key, header)?,
+            chunk: vec![0u8; chunk_size + TAG_LEN],
+            filled: 0,
+            plain: Zeroizing::new(Vec::new()),
+            position: 0,
+            ended: false,
+        })
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Reads the next sealed chunk, or what is left of the input.
+    fn poll_chunk(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.filled < self.chunk.len() {
+            let mut buf = ReadBuf::new(&mut self.chunk[self.filled..]);
+            ready!(Pin::new(&mut self.inner).poll_read(cx, &mut buf))?;
+            match buf.filled().len() {
+                0 => break,
+                n => self.filled += n,
+            }
+        }
+
+        if self.filled < TAG_LEN {
+            return Poll::Ready(Err(invalid("Stream truncated")));
+        }
+
+        let last = self.filled < self.chunk.len();
+        let plain = self
+            .state
+            .open_chunk(&self.chunk[..self.filled], last)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.plain = Zeroizing::new(plain);
+        self.position = 0;
+        self.filled = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_end(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut byte = [0u8; 1];
+        let mut buf = ReadBuf::new(&mut byte);
+        ready!(Pin::new(&mut self.inner).poll_read(cx, &mut buf))?;
+        if !buf.filled().is_empty() {
+            return Poll::Ready(Err(invalid("Data after final chunk")));
+        }
+
+        self.ended = true;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for AsyncStreamDecryptor<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            // Like `StreamDecryptor`, nothing of the final chunk is returned
+            // before the input is known to end there.
+            if this.state.finished && !this.ended {
+                ready!(this.poll_end(cx))?;
+            }
+
+            if this.position < this.plain.len() {
+                let n = buf.remaining().min(this.plain.len() - this.position);
+                buf.put_slice(&this.plain[this.position..this.position + n]);
+                this.position += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.state.finished {
+                return Poll::Ready(Ok(()));
+            }
+
+            ready!(this.poll_chunk(cx))?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    const KEY: [u8; 32] = [7u8; 32];
+    const CHUNK: usize = 16;
+    const SEALED: usize = CHUNK + TAG_LEN;
+
+    fn encrypt(plain: &[u8]) -> Vec<u8> {
+        let header = stream_header([1u8; 16], CHUNK).unwrap();
+        let mut encryptor = StreamEncryptor::new(Vec::new(), &KEY, header).unwrap();
+        encryptor.write_all(plain).unwrap();
+        encryptor.finish().unwrap()
+    }
+
+    fn decrypt(sealed: &[u8], key: &[u8; 32]) -> io::Result<Vec<u8>> {
+        let mut decryptor = StreamDecryptor::new(sealed, key).map_err(io::Error::other)?;
+        let mut plain = Vec::new();
+        decryptor.read_to_end(&mut plain)?;
+        Ok(plain)
+    }
+
+    /// Range of chunk `i` in a sealed stream.
+    fn chunk(i: usize) -> std::ops::Range<usize> {
+        let start = STREAM_HEADER_LEN + i * SEALED;
+        start..start + SEALED
+    }
+
+    #[test]
+    fn round_trips_around_chunk_boundaries() {
+        for len in [0, 1, CHUNK - 1, CHUNK, CHUNK + 1, 3 * CHUNK, 3 * CHUNK + 5] {
+            let plain: Vec<u8> = (0..len as u8).collect();
+            let sealed = encrypt(&plain);
+
+            // The final chunk is always shorter than a full one.
+            let chunks = len / CHUNK + 1;
+            assert_eq!(sealed.len(), STREAM_HEADER_LEN + len + chunks * TAG_LEN);
+            assert_eq!(decrypt(&sealed, &KEY).unwrap(), plain, "length {}", len);
+        }
+    }
+
+    #[test]
+    fn rejects_truncation() {
+        let sealed = encrypt(&[42u8; 3 * CHUNK + 5]);
+
+        // Cut at a chunk boundary, the last full chunk is taken as final.
+        assert!(decrypt(&sealed[..chunk(2).end], &KEY).is_err());
+        assert!(decrypt(&sealed[..chunk(1).start + 3], &KEY).is_err());
+        assert!(decrypt(&sealed[..sealed.len() - 1], &KEY).is_err());
+        assert!(decrypt(&sealed[..STREAM_HEADER_LEN], &KEY).is_err());
+        assert!(decrypt(&sealed[..STREAM_HEADER_LEN - 1], &KEY).is_err());
+
+        // Same for a multiple of the chunk size, whose final chunk is empty.
+        let sealed = encrypt(&[42u8; 2 * CHUNK]);
+        assert!(decrypt(&sealed[..chunk(1).end], &KEY).is_err());
+    }
+
+    #[test]
+    fn rejects_reordered_and_dropped_chunks() {
+        let plain: Vec<u8> = (0..3 * CHUNK as u8 + 5).collect();
+        let sealed = encrypt(&plain);
+
+        let mut swapped = sealed[..STREAM_HEADER_LEN].to_vec();
+        swapped.extend_from_slice(&sealed[chunk(1)]);
+        swapped.extend_from_slice(&sealed[chunk(0)]);
+        swapped.extend_from_slice(&sealed[chunk(2).start..]);
+        assert_eq!(swapped.len(), sealed.len());
+        assert!(decrypt(&swapped, &KEY).is_err());
+
+        let mut dropped = sealed[..chunk(1).start].to_vec();
+        dropped.extend_from_slice(&sealed[chunk(2).start..]);
+        assert!(decrypt(&dropped, &KEY).is_err());
+    }
+
+    #[test]
+    fn rejects_tampering_and_trailing_data() {
+        let sealed = encrypt(b"attack at dawn, bring snacks");
+
+        for index in [HEADER_LEN + 1, STREAM_HEADER_LEN + 2, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[index] ^= 1;
+            assert!(decrypt(&tampered, &KEY).is_err(), "byte {}", index);
+        }
+
+        let mut trailing = sealed.clone();
+        trailing.push(0);
+        assert!(decrypt(&trailing, &KEY).is_err());
+
+        assert!(decrypt(&sealed, &[8u8; 32]).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_chunk_sizes() {
+        assert!(stream_header([0u8; 16], 0).is_err());
+        assert!(stream_header([0u8; 16], MAX_CHUNK_SIZE + 1).is_err());
+
+        let mut header = stream_header([0u8; 16], CHUNK).unwrap();
+        header[STREAM_HEADER_LEN - 4..].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(parse_stream_header(&header).is_err());
+    }
+
+    async fn encrypt_async<W: AsyncWrite + Unpin>(plain: &[u8], writer: W) -> W {
+        let header = stream_header([1u8; 16], CHUNK).unwrap();
+        let mut encryptor = AsyncStreamEncryptor::new(writer, &KEY, header).unwrap();
+        // Odd-sized writes straddle chunk boundaries.
+        for part in plain.chunks(7) {
+            encryptor.write_all(part).await.unwrap();
+        }
+        encryptor.shutdown().await.unwrap();
+        encryptor.into_inner()
+    }
+
+    async fn decrypt_async<R: AsyncRead + Unpin>(sealed: R) -> io::Result<Vec<u8>> {
+        let mut decryptor = AsyncStreamDecryptor::new(sealed, &KEY)
+            .await
+            .map_err(io::Error::other)?;
+        let mut plain = Vec::new();
+        decryptor.read_to_end(&mut plain).await?;
+        Ok(plain)
+    }
+
+    #[tokio::test]
+    async fn async_adapters_match_the_sync_format() {
+        for len in [0, CHUNK, 200] {
+            let plain: Vec<u8> = (0..len as u8).collect();
+
+            let sealed = encrypt_async(&plain, Vec::new()).await;
+            assert_eq!(decrypt(&sealed, &KEY).unwrap(), plain, "length {}", len);
+
+            let sealed = encrypt(&plain);
+            assert_eq!(decrypt_async(sealed.as_slice()).await.unwrap(), plain);
+        }
+    }
+
+    #[tokio::test]
+    async fn async_adapters_compose_over_a_narrow_pipe() {
+        let plain: Vec<u8> = (0..=255u8).cycle().take(10 * CHUNK + 3).collect();
+        // A 5-byte pipe makes every write and read partial.
+        let (writer, reader) = tokio::io::duplex(5);
+
+        let sent = plain.clone();
+        let writing = tokio::spawn(async move { encrypt_async(&sent, writer).await });
+        assert_eq!(decrypt_async(reader).await.unwrap(), plain);
+        writing.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn async_decryptor_rejects_truncation_and_trailing_data() {
+        let sealed = encrypt(&[42u8; 3 * CHUNK + 5]);
+
+        assert!(decrypt_async(&sealed[..chunk(2).end]).await.is_err());
+        assert!(decrypt_async(&sealed[..sealed.len() - 1]).await.is_err());
+        assert!(decrypt_async(&sealed[..STREAM_HEADER_LEN - 1]).await.is_err());
+
+        let mut trailing = sealed.clone();
+        trailing.push(0);
+        assert!(decrypt_async(trailing.as_slice()).await.is_err());
+    }
+}
@@ -0,0 +1,418 @@
+use aes_gcm::aead::OsRng;
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use p256::{
+    ecdsa::{signature::Signer, signature::Verifier, Signature, SigningKey, VerifyingKey},
+    elliptic_curve::ecdh::diffie_hellman,
+    PublicKey, SecretKey,
+};
+use zeroize::Zeroize;
+
+use super::cipher::CipherError;
+use super::ratchet::SessionSecrets;
+
+/// Version 1 bundles are signed with the ECDH identity key itself, version 2
+/// with the separate identity signing key.
+pub const BUNDLE_VERSION: u8 = 2;
+
+/// Encoded `PrekeyHeader`: identity key, ephemeral key (SEC1 uncompressed),
+/// signed prekey id (u32 BE), one-time prekey flag and id (u32 BE).
+pub const PREKEY_HEADER_LEN: usize = 65 + 65 + 4 + 1 + 4;
+
+const SIGNATURE_LABEL: &[u8] = b"nyx/signed-prekey/v1";
+const X3DH_INFO: &[u8] = b"nyx/x3dh/v1";
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct OneTimePrekey {
+    pub id: u32,
+    /// Base64url SEC1 public key.
+    pub key: String,
+}
+
+/// Public prekeys a user publishes so others can start a session with them
+/// while they are offline. All keys are base64url SEC1 public keys, the same
+/// encoding as `export_public_key`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct PrekeyBundle {
+    pub version: u8,
+    pub identity_key: String,
+    /// The identity signing key, as `Cipher::export_signing_key`; since
+    /// version 2.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing_key: Option<String>,
+    pub signed_prekey_id: u32,
+    pub signed_prekey: String,
+    /// Base64url ECDSA P-256 signature over the signed prekey, by the signing
+    /// key (the identity key in version 1).
+    pub signed_prekey_signature: String,
+    /// Published in bulk; a server hands out at most one per fetched bundle.
+    #[serde(default)]
+    pub one_time_prekeys: Vec<OneTimePrekey>,
+}
+
+/// Sent in front of the initiator's messages until the responder replies, so
+/// the responder can repeat the key agreement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrekeyHeader {
+    pub identity_key: PublicKey,
+    pub ephemeral_key: PublicKey,
+    pub signed_prekey_id: u32,
+    pub one_time_prekey_id: Option<u32>,
+}
+
+impl PrekeyHeader {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(PREKEY_HEADER_LEN);
+        out.extend_from_slice(&self.identity_key.to_sec1_bytes());
+        out.extend_from_slice(&self.ephemeral_key.to_sec1_bytes());
+        out.extend_from_slice(&self.signed_prekey_id.to_be_bytes());
+        match self.one_time_prekey_id {
+            Some(id) => {
+                out.push(1);
+                out.extend_from_slice(&id.to_be_bytes());
+            }
+            None => {
+                out.push(0);
+                out.extend_from_slice(&[0u8; 4]);
+            }
+        }
+        out
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, CipherError> {
+        if bytes.len() != PREKEY_HEADER_LEN {
+            return Err(CipherError::DecryptionFailed("Invalid prekey header".into()));
+        }
+
+        let key = |range: std::ops::Range<usize>| {
+            PublicKey::from_sec1_bytes(&bytes[range]).map_err(|_| CipherError::InvalidKeyFormat)
+        };
+        let id = |start: usize| {
+            let mut raw = [0u8; 4];
+            raw.copy_from_slice(&bytes[start..start + 4]);
+            u32::from_be_bytes(raw)
+        };
+
+        Ok(Self {
+            identity_key: key(0..65)?,
+            ephemeral_key: key(65..130)?,
+            signed_prekey_id: id(130),
+            one_time_prekey_id: match bytes[134] {
+                0 => None,
+                _ => Some(id(135)),
+            },
+        })
+    }
+}
+
+pub fn encode_key(key: &PublicKey) -> String {
+    URL_SAFE.encode(key.to_sec1_bytes())
+}
+
+pub fn decode_key(encoded: &str) -> Result<PublicKey, CipherError> {
+    let bytes = URL_SAFE
+        .decode(encoded)
+        .map_err(|_| CipherError::InvalidKeyFormat)?;
+    PublicKey::from_sec1_bytes(&bytes).map_err(|_| CipherError::InvalidKeyFormat)
+}
+
+fn signed_message(prekey: &PublicKey) -> Vec<u8> {
+    let mut message = Vec::from(SIGNATURE_LABEL);
+    message.extend_from_slice(&prekey.to_sec1_bytes());
+    message
+}
+
+/// Signs a signed prekey with the identity signing key.
+pub fn sign_prekey(signing_key: &SigningKey, prekey: &PublicKey) -> String {
+    let signature: Signature = signing_key.sign(&signed_message(prekey));
+    URL_SAFE.encode(signature.to_bytes())
+}
+
+/// Keys of a `PrekeyBundle` whose signature checked out.
+pub struct VerifiedBundle {
+    pub identity_key: PublicKey,
+    pub signed_prekey: PublicKey,
+    /// The one-time prekey to use, if the bundle had any.
+    pub one_time_prekey: Option<(u32, PublicKey)>,
+}
+
+/// Result of `initiate`.
+pub struct Initiation {
+    pub secrets: SessionSecrets,
+    pub associated_data: Vec<u8>,
+    pub header: PrekeyHeader,
+    /// The signed prekey, which is the responder's first ratchet key.
+    pub responder_ratchet_key: PublicKey,
+}
+
+pub fn verify_bundle(bundle: &PrekeyBundle) -> Result<VerifiedBundle, CipherError> {
+    let identity = decode_key(&bundle.identity_key)?;
+    let signed_prekey = decode_key(&bundle.signed_prekey)?;
+
+    let verifying_key = match (bundle.version, &bundle.signing_key) {
+        (1, _) => VerifyingKey::from(&identity),
+        (2, Some(signing_key)) => VerifyingKey::from(&decode_key(signing_key)?),
+        (2, None) => return Err(CipherError::InvalidSignature),
+        (version, _) => return Err(CipherError::UnsupportedVersion(version)),
+    };
+
+    let signature_bytes = URL_SAFE
+        .decode(&bundle.signed_prekey_signature)
+        .map_err(|_| CipherError::InvalidSignature)?;
+    let signature =
+        Signature::from_slice(&signature_bytes).map_err(|_| CipherError::InvalidSignature)?;
+
+    verifying_key
+        .verify(&signed_message(&signed_prekey), &signature)
+        .map_err(|_| CipherError::InvalidSignature)?;
+
+    let one_time = bundle
+        .one_time_prekeys
+        .first()
+        .map(|prekey| Ok::<_, CipherError>((prekey.id, decode_key(&prekey.key)?)))
+        .transpose()?;
+
+    Ok(VerifiedBundle {
+        identity_key: identity,
+        signed_prekey,
+        one_time_prekey: one_time,
+    })
+}
+
+fn dh(private: &SecretKey, public: &PublicKey) -> [u8; 32] {
+    let shared = diffie_hellman(private.to_nonzero_scalar(), public.as_affine());
+    let mut out = [0u8; 32];
+    out.copy_from_slice(shared.raw_secret_bytes().as_slice());
+    out
+}
+
+/// KDF(F || DH1 || DH2 || DH3 [|| DH4]) with F = 32 0xFF bytes, and the
+/// associated data `initiator identity || responder identity`.
+fn derive(
+    dh_outputs: &[[u8; 32]],
+    initiator: &PublicKey,
+    responder: &PublicKey,
+) -> Result<(SessionSecrets, Vec<u8>), CipherError> {
+    let mut ikm = vec![0xFFu8; 32];
+    for output in dh_outputs {
+        ikm.extend_from_slice(output);
+    }
+
+    let mut associated_data = initiator.to_sec1_bytes().to_vec();
+    associated_data.extend_from_slice(&responder.to_sec1_bytes());
+
+    let mut info = Vec::from(X3DH_INFO);
+    info.extend_from_slice(&associated_data);
+
+    let secrets = SessionSecrets::derive(&ikm, &info);
+    ikm.zeroize();
+
+    Ok((secrets?, associated_data))
+}
+
+/// Initiator side: agrees on the session secrets with the owner of `bundle`.
+pub fn initiate(identity: &SecretKey, bundle: &PrekeyBundle) -> Result<Initiation, CipherError> {
+    let VerifiedBundle {
+        identity_key: responder_identity,
+        signed_prekey,
+        one_time_prekey: one_time,
+    } = verify_bundle(bundle)?;
+    let ephemeral = SecretKey::random(&mut OsRng);
+
+    let mut outputs = vec![
+        dh(identity, &signed_prekey),
+        dh(&ephemeral, &responder_identity),
+        dh(&ephemeral, &signed_prekey),
+    ];
+    if let Some((_, one_time_key)) = &one_time {
+        outputs.push(dh(&ephemeral, one_time_key));
+    }
+
+    let initiator_identity = identity.public_key();
+    let derived = derive(&outputs, &initiator_identity, &responder_identity);
+    outputs.zeroize();
+    let (secrets, associated_data) = derived?;
+
+    let header = PrekeyHeader {
+        identity_key: initiator_identity,
+        ephemeral_key: ephemeral.public_key(),
+        signed_prekey_id: bundle.signed_prekey_id,
+        one_time_prekey_id: one_time.map(|(id, _)| id),
+    };
+
+    Ok(Initiation {
+        secrets,
+        associated_data,
+        header,
+        responder_ratchet_key: signed_prekey,
+    })
+}
+
+/// Responder side: repeats the key agreement from `header` with the private
+/// prekeys it names.
+pub fn respond(
+    identity: &SecretKey,
+    signed_prekey: &SecretKey,
+    one_time_prekey: Option<&SecretKey>,
+    header: &PrekeyHeader,
+) -> Result<(SessionSecrets, Vec<u8>), CipherError> {
+    let mut outputs = vec![
+        dh(signed_prekey, &header.identity_key),
+        dh(identity, &header.ephemeral_key),
+        dh(signed_prekey, &header.ephemeral_key),
+    ];
+    if let Some(one_time_prekey) = one_time_prekey {
+        outputs.push(dh(one_time_prekey, &header.ephemeral_key));
+    }
+
+    let derived = derive(&outputs, &header.identity_key, &identity.public_key());
+    outputs.zeroize();
+    derived
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Responder {
+        identity: SecretKey,
+        signing_key: SigningKey,
+        signed_prekey: SecretKey,
+        one_time_prekey: SecretKey,
+    }
+
+    impl Responder {
+        fn new() -> Self {
+            Self {
+                identity: SecretKey::random(&mut OsRng),
+                signing_key: SigningKey::random(&mut OsRng),
+                signed_prekey: SecretKey::random(&mut OsRng),
+                one_time_prekey: SecretKey::random(&mut OsRng),
+            }
+        }
+
+        fn bundle(&self) -> PrekeyBundle {
+            let signed_prekey = self.signed_prekey.public_key();
+            PrekeyBundle {
+                version: BUNDLE_VERSION,
+                identity_key: encode_key(&self.identity.public_key()),
+                signing_key: Some(encode_key(&PublicKey::from(
+                    self.signing_key.verifying_key(),
+                ))),
+                signed_prekey_id: 7,
+                signed_prekey: encode_key(&signed_prekey),
+                signed_prekey_signature: sign_prekey(&self.signing_key, &signed_prekey),
+                one_time_prekeys: vec![OneTimePrekey {
+                    id: 9,
+                    key: encode_key(&self.one_time_prekey.public_key()),
+                }],
+            }
+        }
+    }
+
+    #[test]
+    fn both_sides_agree() {
+        let responder = Responder::new();
+        let initiator = SecretKey::random(&mut OsRng);
+
+        let initiation = initiate(&initiator, &responder.bundle()).unwrap();
+        assert_eq!(initiation.header.signed_prekey_id, 7);
+        assert_eq!(initiation.header.one_time_prekey_id, Some(9));
+        assert_eq!(initiation.responder_ratchet_key, responder.signed_prekey.public_key());
+
+        let (secrets, associated_data) = respond(
+            &responder.identity,
+            &responder.signed_prekey,
+            Some(&responder.one_time_prekey),
+            &initiation.header,
+        )
+        .unwrap();
+        assert_eq!(secrets.root_key, initiation.secrets.root_key);
+        assert_eq!(secrets.header_key_initiator, initiation.secrets.header_key_initiator);
+        assert_eq!(associated_data, initiation.associated_data);
+
+        // Without the one-time prekey the agreement differs.
+        let (secrets, _) =
+            respond(&responder.identity, &responder.signed_prekey, None, &initiation.header)
+                .unwrap();
+        assert_ne!(secrets.root_key, initiation.secrets.root_key);
+    }
+
+    #[test]
+    fn agrees_without_one_time_prekeys() {
+        let responder = Responder::new();
+        let initiator = SecretKey::random(&mut OsRng);
+        let mut bundle = responder.bundle();
+        bundle.one_time_prekeys.clear();
+
+        let initiation = initiate(&initiator, &bundle).unwrap();
+        assert_eq!(initiation.header.one_time_prekey_id, None);
+
+        let (secrets, _) =
+            respond(&responder.identity, &responder.signed_prekey, None, &initiation.header)
+                .unwrap();
+        assert_eq!(secrets.root_key, initiation.secrets.root_key);
+    }
+
+    #[test]
+    fn rejects_bad_signatures() {
+        let responder = Responder::new();
+        let initiator = SecretKey::random(&mut OsRng);
+
+        let mut swapped = responder.bundle();
+        swapped.signed_prekey = encode_key(&SecretKey::random(&mut OsRng).public_key());
+        assert!(matches!(
+            initiate(&initiator, &swapped),
+            Err(CipherError::InvalidSignature)
+        ));
+
+        let mut other_signer = responder.bundle();
+        other_signer.signing_key = Some(encode_key(&PublicKey::from(
+            SigningKey::random(&mut OsRng).verifying_key(),
+        )));
+        assert!(verify_bundle(&other_signer).is_err());
+
+        let mut unsigned = responder.bundle();
+        unsigned.signing_key = None;
+        assert!(verify_bundle(&unsigned).is_err());
+
+        let mut future = responder.bundle();
+        future.version = 3;
+        assert!(matches!(
+            verify_bundle(&future),
+            Err(CipherError::UnsupportedVersion(3))
+        ));
+    }
+
+    #[test]
+    fn accepts_version_1_bundles_signed_by_the_identity_key() {
+        let responder = Responder::new();
+        let signed_prekey = responder.signed_prekey.public_key();
+
+        let mut bundle = responder.bundle();
+        bundle.version = 1;
+        bundle.signing_key = None;
+        bundle.signed_prekey_signature =
+            sign_prekey(&SigningKey::from(&responder.identity), &signed_prekey);
+        verify_bundle(&bundle).unwrap();
+    }
+
+    #[test]
+    fn prekey_header_round_trips() {
+        let header = PrekeyHeader {
+            identity_key: SecretKey::random(&mut OsRng).public_key(),
+            ephemeral_key: SecretKey::random(&mut OsRng).public_key(),
+            signed_prekey_id: 3,
+            one_time_prekey_id: Some(4),
+        };
+        let bytes = header.to_bytes();
+        assert_eq!(bytes.len(), PREKEY_HEADER_LEN);
+        assert_eq!(PrekeyHeader::parse(&bytes).unwrap(), header);
+
+        let without = PrekeyHeader {
+            one_time_prekey_id: None,
+            ..header
+        };
+        assert_eq!(PrekeyHeader::parse(&without.to_bytes()).unwrap(), without);
+        assert!(PrekeyHeader::parse(&bytes[1..]).is_err());
+    }
+}
@@ -0,0 +1,5 @@
+pub mod cli;
+pub mod console;
+pub mod crypto;
+pub mod peer;
+pub mod vault;
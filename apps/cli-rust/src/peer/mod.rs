@@ -1 +1,2 @@
-pub mod p2p;
\ No newline at end of file
+pub mod msg;
+pub mod p2p;
@@ -0,0 +1,631 @@
+use libp2p::{
+    core::{
+        upgrade::{InboundUpgrade, OutboundUpgrade, UpgradeInfo},
+        Endpoint,
+    },
+    futures::{future::BoxFuture, AsyncReadExt, AsyncWriteExt, FutureExt},
+    swarm::{
+        dial_opts::DialOpts,
+        handler::{
+            ConnectionEvent, DialUpgradeError, FullyNegotiatedInbound, FullyNegotiatedOutbound,
+            ListenUpgradeError,
+        },
+        ConnectionDenied, ConnectionHandler, ConnectionHandlerEvent, ConnectionId, FromSwarm,
+        NetworkBehaviour, NotifyHandler, Stream, SubstreamProtocol, THandler, THandlerInEvent,
+        THandlerOutEvent, ToSwarm,
+    },
+    Multiaddr, PeerId, StreamProtocol,
+};
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::crypto::cipher::{Cipher, CipherError, IdentityAnnouncement, Store};
+
+/// Direct messages between two nodes: one request per substream, answered
+/// with an `Ack` once the receiver has read it.
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/nyx/msg/1.0.0");
+
+/// Largest frame read from a substream; a shared note is the biggest thing
+/// we send.
+const MAX_FRAME: usize = 4 * 1024 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type MessageId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EnvelopeKind {
+    /// Sent on every new connection so the remote learns which identity is
+    /// behind our `PeerId`; the payload is an encoded `Hello`.
+    Hello,
+    /// `Cipher::session_encrypt` output, base64url.
+    Text,
+    /// `NotesVault::share` output, base64url.
+    Note,
+}
+
+/// What travels over `PROTOCOL`. Nothing in it is trusted: `sender` is only
+/// believed once the node behind the connection has sent a `Hello` for it
+/// that verifies.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Envelope {
+    pub id: MessageId,
+    /// Identity fingerprint of the sender, as from `Cipher::export_fingerprint`.
+    pub sender: String,
+    pub kind: EnvelopeKind,
+    pub payload: String,
+}
+
+/// Proof that a node speaks for an identity: the identity's announcement
+/// and a signature by its signing key over `"nyx/hello/v1" || PeerId`.
+/// Noise already proves the `PeerId`, so a hello seen on one connection is
+/// worthless to any other node.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Hello {
+    pub announcement: IdentityAnnouncement,
+    /// Base64url signature by `announcement.signing_key`.
+    pub signature: String,
+}
+
+impl Hello {
+    const LABEL: &'static [u8] = b"nyx/hello/v1";
+
+    fn signed_data(peer: &PeerId) -> Vec<u8> {
+        let mut data = Vec::from(Self::LABEL);
+        data.extend_from_slice(&peer.to_bytes());
+        data
+    }
+
+    /// Hello of our identity for the node `peer`, which must be our own.
+    pub fn new<S: Store>(cipher: &Cipher<S>, peer: &PeerId) -> Result<Self, CipherError> {
+        Ok(Self {
+            announcement: cipher.identity_announcement()?,
+            signature: URL_SAFE.encode(cipher.sign(&Self::signed_data(peer))?),
+        })
+    }
+
+    /// Checks that `peer` speaks for the identity whose pinned key is
+    /// `public_key` (base64url SEC1, as `PeerInfo::public_key`).
+    pub fn verify(&self, peer: &PeerId, public_key: &str) -> Result<(), CipherError> {
+        if self.announcement.public_key != public_key {
+            return Err(CipherError::InvalidSignature);
+        }
+        self.announcement.verify()?;
+
+        let signature = URL_SAFE
+            .decode(&self.signature)
+            .map_err(|_| CipherError::InvalidSignature)?;
+        self.announcement
+            .verify_data(&Self::signed_data(peer), &signature)
+    }
+
+    /// Envelope payload: base64url JSON.
+    pub fn encode(&self) -> Result<String, CipherError> {
+        let json =
+            serde_json::to_vec(self).map_err(|e| CipherError::EncryptionFailed(e.to_string()))?;
+        Ok(URL_SAFE.encode(json))
+    }
+
+    pub fn decode(payload: &str) -> Result<Self, CipherError> {
+        let json = URL_SAFE
+            .decode(payload)
+            .map_err(|_| CipherError::InvalidKeyFormat)?;
+        serde_json::from_slice(&json).map_err(|_| CipherError::InvalidKeyFormat)
+    }
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+struct Ack {
+    id: MessageId,
+}
+
+async fn write_frame<T: serde::Serialize>(stream: &mut Stream, value: &T) -> io::Result<()> {
+    let data = serde_json::to_vec(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if data.len() > MAX_FRAME {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Message too large"));
+    }
+
+    stream.write_all(&(data.len() as u32).to_be_bytes()).await?;
+    stream.write_all(&data).await?;
+    stream.flush().await
+}
+
+async fn read_frame<T: serde::de::DeserializeOwned>(stream: &mut Stream) -> io::Result<T> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).await?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Message too large"));
+    }
+
+    let mut data = vec![0u8; len];
+    stream.read_exact(&mut data).await?;
+    serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Inbound substream: reads an `Envelope` and acknowledges it.
+#[derive(Debug, Clone, Default)]
+pub struct InboundMessage;
+
+/// Outbound substream: sends an `Envelope` and waits for its `Ack`.
+#[derive(Debug)]
+pub struct OutboundMessage(Envelope);
+
+impl UpgradeInfo for InboundMessage {
+    type Info = StreamProtocol;
+    type InfoIter = std::iter::Once<StreamProtocol>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        std::iter::once(PROTOCOL)
+    }
+}
+
+impl UpgradeInfo for OutboundMessage {
+    type Info = StreamProtocol;
+    type InfoIter = std::iter::Once<StreamProtocol>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        std::iter::once(PROTOCOL)
+    }
+}
+
+impl InboundUpgrade<Stream> for InboundMessage {
+    type Output = Envelope;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, io::Result<Envelope>>;
+
+    fn upgrade_inbound(self, mut stream: Stream, _: StreamProtocol) -> Self::Future {
+        async move {
+            let envelope: Envelope = read_frame(&mut stream).await?;
+            write_frame(&mut stream, &Ack { id: envelope.id }).await?;
+            stream.close().await?;
+            Ok(envelope)
+        }
+        .boxed()
+    }
+}
+
+impl OutboundUpgrade<Stream> for OutboundMessage {
+    type Output = MessageId;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, io::Result<MessageId>>;
+
+    fn upgrade_outbound(self, mut stream: Stream, _: StreamProtocol) -> Self::Future {
+        async move {
+            let id = self.0.id;
+            write_frame(&mut stream, &self.0).await?;
+            let ack: Ack = read_frame(&mut stream).await?;
+            stream.close().await?;
+
+            if ack.id != id {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Ack for another message"));
+            }
+            Ok(id)
+        }
+        .boxed()
+    }
+}
+
+#[derive(Debug)]
+pub enum HandlerEvent {
+    Received(Envelope),
+    Acked(MessageId),
+    Failed(MessageId, String),
+}
+
+/// Opens one substream per envelope on a connection.
+#[derive(Default)]
+pub struct Handler {
+    queued: VecDeque<Envelope>,
+    in_flight: usize,
+    events: VecDeque<HandlerEvent>,
+}
+
+impl ConnectionHandler for Handler {
+    type FromBehaviour = Envelope;
+    type ToBehaviour = HandlerEvent;
+    type InboundProtocol = InboundMessage;
+    type OutboundProtocol = OutboundMessage;
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = MessageId;
+
+    fn listen_protocol(&self) -> SubstreamProtocol<InboundMessage, ()> {
+        SubstreamProtocol::new(InboundMessage, ()).with_timeout(REQUEST_TIMEOUT)
+    }
+
+    fn connection_keep_alive(&self) -> bool {
+        !self.queued.is_empty() || self.in_flight > 0
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context<'_>,
+    ) -> Poll<ConnectionHandlerEvent<OutboundMessage, MessageId, HandlerEvent>> {
+        if let Some(event) = self.events.pop_front() {
+            return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(event));
+        }
+
+        if let Some(envelope) = self.queued.pop_front() {
+            self.in_flight += 1;
+            let id = envelope.id;
+            return Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest {
+                protocol: SubstreamProtocol::new(OutboundMessage(envelope), id)
+                    .with_timeout(REQUEST_TIMEOUT),
+            });
+        }
+
+        Poll::Pending
+    }
+
+    fn on_behaviour_event(&mut self, envelope: Envelope) {
+        self.queued.push_back(envelope);
+    }
+
+    fn on_connection_event(
+        &mut self,
+        event: ConnectionEvent<InboundMessage, OutboundMessage, (), MessageId>,
+    ) {
+        match event {
+            ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound {
+                protocol: envelope,
+                ..
+            }) => self.events.push_back(HandlerEvent::Received(envelope)),
+            ConnectionEvent::FullyNegotiatedOutbound(FullyNegotiatedOutbound {
+                protocol: id,
+                ..
+            }) => {
+                self.in_flight -= 1;
+                self.events.push_back(HandlerEvent::Acked(id));
+            }
+            ConnectionEvent::DialUpgradeError(DialUpgradeError { info: id, error }) => {
+                self.in_flight -= 1;
+                self.events.push_back(HandlerEvent::Failed(id, error.to_string()));
+            }
+            // A bad inbound request is simply not acknowledged.
+            ConnectionEvent::ListenUpgradeError(ListenUpgradeError { .. }) => {}
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MessagingEvent {
+    /// An envelope from `peer`, already acknowledged.
+    Received { peer: PeerId, envelope: Envelope },
+    /// `peer` acknowledged message `id`.
+    Delivered { peer: PeerId, id: MessageId },
+    /// Message `id` could not be delivered to `peer`.
+    Failed {
+        peer: PeerId,
+        id: MessageId,
+        error: String,
+    },
+}
+
+/// `NetworkBehaviour` for `PROTOCOL`. `send` dials the peer when there is
+/// no connection yet and the first connection to a peer starts with a
+/// `Hello` for `fingerprint`.
+pub struct Messaging {
+    fingerprint: String,
+    /// Encoded `Hello`, the payload of ours.
+    hello: String,
+    next_id: MessageId,
+    /// Open connections to each peer, oldest first; envelopes go on the
+    /// oldest.
+    connections: HashMap<PeerId, Vec<ConnectionId>>,
+    /// Envelopes handed to each connection and neither acknowledged nor
+    /// failed yet.
+    pending: HashMap<ConnectionId, HashSet<MessageId>>,
+    /// Envelopes waiting for a connection to their peer.
+    waiting: HashMap<PeerId, Vec<Envelope>>,
+    actions: VecDeque<ToSwarm<MessagingEvent, Envelope>>,
+}
+
+impl Messaging {
+    /// `hello` is our encoded `Hello`, sent on the first connection to every
+    /// peer.
+    pub fn new(fingerprint: &str, hello: String) -> Self {
+        Self {
+            fingerprint: fingerprint.to_string(),
+            hello,
+            next_id: 1,
+            connections: HashMap::new(),
+            pending: HashMap::new(),
+            waiting: HashMap::new(),
+            actions: VecDeque::new(),
+        }
+    }
+
+    fn dispatch(&mut self, peer: PeerId, connection: ConnectionId, envelope: Envelope) {
+        self.pending.entry(connection).or_default().insert(envelope.id);
+        self.actions.push_back(ToSwarm::NotifyHandler {
+            peer_id: peer,
+            handler: NotifyHandler::One(connection),
+            event: envelope,
+        });
+    }
+
+    /// Queues `payload` for `peer`; its id comes back in `Delivered` or
+    /// `Failed`.
+    pub fn send(&mut self, peer: PeerId, kind: EnvelopeKind, payload: String) -> MessageId {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let envelope = Envelope {
+            id,
+            sender: self.fingerprint.clone(),
+            kind,
+            payload,
+        };
+
+        let connection = self.connections.get(&peer).and_then(|list| list.first());
+        if let Some(&connection) = connection {
+            self.dispatch(peer, connection, envelope);
+        } else {
+            let waiting = self.waiting.entry(peer).or_default();
+            if waiting.is_empty() {
+                self.actions.push_back(ToSwarm::Dial {
+                    opts: DialOpts::peer_id(peer).build(),
+                });
+            }
+            waiting.push(envelope);
+        }
+
+        id
+    }
+
+    pub fn is_connected(&self, peer: &PeerId) -> bool {
+        self.connections.contains_key(peer)
+    }
+}
+
+impl NetworkBehaviour for Messaging {
+    type ConnectionHandler = Handler;
+    type ToSwarm = MessagingEvent;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        _: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(Handler::default())
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        _: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(Handler::default())
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        match event {
+            FromSwarm::ConnectionEstablished(established) => {
+                let peer = established.peer_id;
+                let connections = self.connections.entry(peer).or_default();
+                connections.push(established.connection_id);
+                if connections.len() > 1 {
+                    return;
+                }
+
+                self.send(peer, EnvelopeKind::Hello, self.hello.clone());
+                for envelope in self.waiting.remove(&peer).unwrap_or_default() {
+                    self.dispatch(peer, established.connection_id, envelope);
+                }
+            }
+            FromSwarm::ConnectionClosed(closed) => {
+                let peer = closed.peer_id;
+                if let Some(connections) = self.connections.get_mut(&peer) {
+                    connections.retain(|connection| *connection != closed.connection_id);
+                    if connections.is_empty() {
+                        self.connections.remove(&peer);
+                    }
+                }
+
+                // The handler went down with whatever it had queued or in flight.
+                let mut lost: Vec<MessageId> = self
+                    .pending
+                    .remove(&closed.connection_id)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .collect();
+                lost.sort_unstable();
+                for id in lost {
+                    self.actions
+                        .push_back(ToSwarm::GenerateEvent(MessagingEvent::Failed {
+                            peer,
+                            id,
+                            error: "Connection closed".to_string(),
+                        }));
+                }
+            }
+            FromSwarm::DialFailure(failure) => {
+                let Some(peer) = failure.peer_id else {
+                    return;
+                };
+                if self.is_connected(&peer) {
+                    return;
+                }
+
+                for envelope in self.waiting.remove(&peer).unwrap_or_default() {
+                    self.actions
+                        .push_back(ToSwarm::GenerateEvent(MessagingEvent::Failed {
+                            peer,
+                            id: envelope.id,
+                            error: failure.error.to_string(),
+                        }));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer: PeerId,
+        connection: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        if let HandlerEvent::Acked(id) | HandlerEvent::Failed(id, _) = &event {
+            if let Some(pending) = self.pending.get_mut(&connection) {
+                pending.remove(id);
+            }
+        }
+
+        let event = match event {
+            HandlerEvent::Received(envelope) => MessagingEvent::Received { peer, envelope },
+            HandlerEvent::Acked(id) => MessagingEvent::Delivered { peer, id },
+            HandlerEvent::Failed(id, error) => MessagingEvent::Failed { peer, id, error },
+        };
+        self.actions.push_back(ToSwarm::GenerateEvent(event));
+    }
+
+    fn poll(&mut self, _: &mut Context<'_>) -> Poll<ToSwarm<MessagingEvent, THandlerInEvent<Self>>> {
+        match self.actions.pop_front() {
+            Some(action) => Poll::Ready(action),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::cipher::MemoryStore;
+    use libp2p::core::ConnectedPoint;
+    use libp2p::swarm::behaviour::{ConnectionClosed, ConnectionEstablished};
+
+    fn cipher() -> Cipher<MemoryStore> {
+        let mut cipher = Cipher::new(MemoryStore::new());
+        cipher.init(None).unwrap();
+        cipher
+    }
+
+    fn connection(n: usize) -> ConnectionId {
+        ConnectionId::new_unchecked(n)
+    }
+
+    fn endpoint() -> ConnectedPoint {
+        ConnectedPoint::Dialer {
+            address: "/ip4/127.0.0.1/tcp/4001".parse().unwrap(),
+            role_override: Endpoint::Dialer,
+        }
+    }
+
+    fn establish(messaging: &mut Messaging, peer: PeerId, n: usize) {
+        let endpoint = endpoint();
+        messaging.on_swarm_event(FromSwarm::ConnectionEstablished(ConnectionEstablished {
+            peer_id: peer,
+            connection_id: connection(n),
+            endpoint: &endpoint,
+            failed_addresses: &[],
+            other_established: 0,
+        }));
+    }
+
+    fn close(messaging: &mut Messaging, peer: PeerId, n: usize, remaining: usize) {
+        let endpoint = endpoint();
+        messaging.on_swarm_event(FromSwarm::ConnectionClosed(ConnectionClosed {
+            peer_id: peer,
+            connection_id: connection(n),
+            endpoint: &endpoint,
+            remaining_established: remaining,
+        }));
+    }
+
+    /// Envelopes handed to handlers, as (connection, kind), and the ids of
+    /// `Failed` events, in order.
+    fn drain(messaging: &mut Messaging) -> (Vec<(ConnectionId, EnvelopeKind)>, Vec<MessageId>) {
+        let (mut sent, mut failed) = (Vec::new(), Vec::new());
+        for action in messaging.actions.drain(..) {
+            match action {
+                ToSwarm::NotifyHandler {
+                    handler: NotifyHandler::One(connection),
+                    event,
+                    ..
+                } => sent.push((connection, event.kind)),
+                ToSwarm::GenerateEvent(MessagingEvent::Failed { id, .. }) => failed.push(id),
+                _ => {}
+            }
+        }
+        (sent, failed)
+    }
+
+    #[test]
+    fn hello_proves_the_identity_for_one_node() {
+        let (alice, mallory) = (cipher(), cipher());
+        let node = PeerId::random();
+        let hello = Hello::decode(&Hello::new(&alice, &node).unwrap().encode().unwrap()).unwrap();
+        let pinned = alice.export_public_key().unwrap();
+
+        hello.verify(&node, &pinned).unwrap();
+        // Replayed by another node.
+        assert!(hello.verify(&PeerId::random(), &pinned).is_err());
+        // For an identity other than the pinned one.
+        assert!(hello.verify(&node, &mallory.export_public_key().unwrap()).is_err());
+
+        // Alice's announcement with Mallory's signature.
+        let mut forged = hello.clone();
+        forged.signature = Hello::new(&mallory, &node).unwrap().signature;
+        assert!(forged.verify(&node, &pinned).is_err());
+    }
+
+    #[test]
+    fn first_connection_says_hello_then_flushes_waiting() {
+        let mut messaging = Messaging::new("alice", "hello".to_string());
+        let peer = PeerId::random();
+
+        messaging.send(peer, EnvelopeKind::Text, "one".to_string());
+        assert!(matches!(messaging.actions.pop_front(), Some(ToSwarm::Dial { .. })));
+
+        establish(&mut messaging, peer, 1);
+        establish(&mut messaging, peer, 2);
+        messaging.send(peer, EnvelopeKind::Text, "two".to_string());
+
+        let (sent, failed) = drain(&mut messaging);
+        assert_eq!(
+            sent,
+            [
+                (connection(1), EnvelopeKind::Hello),
+                (connection(1), EnvelopeKind::Text),
+                (connection(1), EnvelopeKind::Text),
+            ]
+        );
+        assert!(failed.is_empty());
+    }
+
+    #[test]
+    fn closing_a_connection_fails_what_it_still_had() {
+        let mut messaging = Messaging::new("alice", "hello".to_string());
+        let peer = PeerId::random();
+
+        establish(&mut messaging, peer, 1);
+        let text = messaging.send(peer, EnvelopeKind::Text, "text".to_string());
+        let note = messaging.send(peer, EnvelopeKind::Note, "note".to_string());
+        drain(&mut messaging);
+
+        messaging.on_connection_handler_event(peer, connection(1), HandlerEvent::Acked(text));
+        establish(&mut messaging, peer, 2);
+        close(&mut messaging, peer, 1, 1);
+
+        // The hello (id 1) and the unacknowledged note; nothing is left
+        // pending on the surviving connection.
+        let (_, failed) = drain(&mut messaging);
+        assert_eq!(failed, [1, note]);
+        assert!(messaging.is_connected(&peer));
+
+        let later = messaging.send(peer, EnvelopeKind::Text, "later".to_string());
+        assert_eq!(drain(&mut messaging).0, [(connection(2), EnvelopeKind::Text)]);
+        close(&mut messaging, peer, 2, 0);
+        assert_eq!(drain(&mut messaging).1, [later]);
+        assert!(!messaging.is_connected(&peer));
+    }
+}
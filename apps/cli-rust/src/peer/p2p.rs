@@ -4,20 +4,31 @@ use libp2p::{
     mdns::{tokio::Behaviour as Mdns, Config as MdnsConfig, Event as MdnsEvent},
     noise,
     swarm::{NetworkBehaviour, SwarmEvent},
-    tcp, yamux, PeerId, Swarm, Transport, // <--- IMPORTANTE: 'Transport' es necesario para .upgrade()
+    tcp, yamux, Multiaddr, PeerId, Swarm, Transport,
 };
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
 use std::time::Duration;
+use tokio::sync::mpsc;
+
+use super::msg::{Envelope, EnvelopeKind, Hello, MessageId, Messaging, MessagingEvent};
+use crate::crypto::cipher::{Cipher, Store};
+
+/// Envelopes kept per node while its `Hello` has not verified yet; the rest
+/// are dropped.
+const MAX_UNIDENTIFIED: usize = 32;
 
 #[derive(NetworkBehaviour)]
 #[behaviour(to_swarm = "MyBehaviourEvent")]
-struct MyBehaviour {
+pub struct MyBehaviour {
     mdns: Mdns,
+    msg: Messaging,
 }
 
 #[derive(Debug)]
-enum MyBehaviourEvent {
+pub enum MyBehaviourEvent {
     Mdns(MdnsEvent),
+    Msg(MessagingEvent),
 }
 
 impl From<MdnsEvent> for MyBehaviourEvent {
@@ -26,25 +37,106 @@ impl From<MdnsEvent> for MyBehaviourEvent {
     }
 }
 
+impl From<MessagingEvent> for MyBehaviourEvent {
+    fn from(event: MessagingEvent) -> Self {
+        MyBehaviourEvent::Msg(event)
+    }
+}
+
+/// What `P2PNode::next_event` reports.
+#[derive(Debug)]
+pub enum NodeEvent {
+    Listening(Multiaddr),
+    Discovered(PeerId),
+    Expired(PeerId),
+    /// `peer` proved with its `Hello` that it speaks for the registered
+    /// identity with this fingerprint.
+    Identified { peer: PeerId, fingerprint: String },
+    /// A `Text` or `Note` envelope from an identified node; `fingerprint` is
+    /// its verified identity.
+    Message {
+        peer: PeerId,
+        fingerprint: String,
+        envelope: Envelope,
+    },
+    Delivered { peer: PeerId, id: MessageId },
+    Failed {
+        peer: PeerId,
+        id: MessageId,
+        error: String,
+    },
+}
+
+#[derive(Debug)]
+pub enum NodeCommand {
+    /// Send to whichever node proves to be `fingerprint`, now or once it
+    /// does.
+    Send {
+        fingerprint: String,
+        kind: EnvelopeKind,
+        payload: String,
+    },
+}
+
+/// The application side of a node running in the background; see
+/// `P2PNode::spawn`. Dropping it stops the node.
+pub struct NodeHandle {
+    commands: mpsc::UnboundedSender<NodeCommand>,
+    events: mpsc::UnboundedReceiver<NodeEvent>,
+}
+
+impl NodeHandle {
+    /// Queues an envelope for the identity `fingerprint`. `false` if the
+    /// node has stopped.
+    pub fn send(&self, fingerprint: &str, kind: EnvelopeKind, payload: String) -> bool {
+        self.commands
+            .send(NodeCommand::Send {
+                fingerprint: fingerprint.to_string(),
+                kind,
+                payload,
+            })
+            .is_ok()
+    }
+
+    /// Next event of the node; `None` once it has stopped.
+    pub async fn next_event(&mut self) -> Option<NodeEvent> {
+        self.events.recv().await
+    }
+}
+
 pub struct P2PNode {
     pub peer_id: PeerId,
     pub swarm: Swarm<MyBehaviour>,
+    /// Pinned identity key of every registered peer, by fingerprint.
+    trusted: HashMap<String, String>,
+    /// Verified fingerprint of each identified node.
+    identities: HashMap<PeerId, String>,
+    /// Envelopes from nodes whose `Hello` has not arrived yet.
+    unidentified: HashMap<PeerId, Vec<Envelope>>,
+    /// Events ready before the swarm has anything new.
+    events: VecDeque<NodeEvent>,
 }
 
 impl P2PNode {
-    pub async fn new() -> Result<Self, Box<dyn Error>> {
+    /// Node for the identity of `cipher`: it says hello as that identity and
+    /// only believes hellos from the peers registered in it.
+    pub async fn new<S: Store>(cipher: &Cipher<S>) -> Result<Self, Box<dyn Error>> {
         let id_keys = identity::Keypair::generate_ed25519();
         let peer_id = PeerId::from(id_keys.public());
-        println!("Identidad local generada: {}", peer_id);
+        let hello = Hello::new(cipher, &peer_id)?.encode()?;
+        let trusted = cipher
+            .list_peers()?
+            .into_iter()
+            .filter_map(|peer| Some((peer.fingerprint?, peer.public_key?)))
+            .collect();
 
         let transport = tcp::tokio::Transport::new(tcp::Config::default().nodelay(true))
-            .upgrade(libp2p::core::upgrade::Version::V1) // Requiere 'use libp2p::Transport'
-            .authenticate(noise::Config::new(&id_keys).expect("Error config noise"))
+            .upgrade(libp2p::core::upgrade::Version::V1)
+            .authenticate(noise::Config::new(&id_keys)?)
             .multiplex(yamux::Config::default())
             .boxed();
 
-        // 2. Configuración de mDNS (Async)
-        // Nota: Ajustamos el ttl para detectar peers más rápido en pruebas
+        // A short TTL so nodes that go away are noticed quickly.
         let mdns_config = MdnsConfig {
             ttl: Duration::from_secs(20),
             query_interval: Duration::from_secs(5),
@@ -52,43 +144,306 @@ impl P2PNode {
         };
         let mdns = Mdns::new(mdns_config, peer_id)?;
 
-        let behaviour = MyBehaviour { mdns };
+        let behaviour = MyBehaviour {
+            mdns,
+            msg: Messaging::new(&cipher.export_fingerprint()?, hello),
+        };
 
-        // 3. Construcción del Swarm
-        // En v0.54, si construyes el transporte manualmente, usa Swarm::new 
-        // y añade la configuración predeterminada.
         let swarm = Swarm::new(
             transport,
             behaviour,
             peer_id,
-            libp2p::swarm::Config::with_tokio_executor() // <--- Nueva forma de asignar el executor
+            libp2p::swarm::Config::with_tokio_executor()
                 .with_idle_connection_timeout(Duration::from_secs(60)),
         );
 
-        Ok(Self { peer_id, swarm })
+        Ok(Self {
+            peer_id,
+            swarm,
+            trusted,
+            identities: HashMap::new(),
+            unidentified: HashMap::new(),
+            events: VecDeque::new(),
+        })
     }
 
-    pub async fn run(&mut self) -> Result<(), Box<dyn Error>> {
-        self.swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+    pub fn listen_on(&mut self, address: Multiaddr) -> Result<(), Box<dyn Error>> {
+        self.swarm.listen_on(address)?;
+        Ok(())
+    }
 
+    /// Node identified as `fingerprint`, if any is connected.
+    pub fn peer_with_fingerprint(&self, fingerprint: &str) -> Option<PeerId> {
+        self.identities
+            .iter()
+            .find(|(_, known)| known.as_str() == fingerprint)
+            .map(|(peer, _)| *peer)
+    }
+
+    /// Sends an envelope to `peer` over `/nyx/msg/1.0.0`, dialing it first if
+    /// needed. The result comes back as `Delivered` or `Failed` with the
+    /// returned id.
+    pub fn send(&mut self, peer: PeerId, kind: EnvelopeKind, payload: String) -> MessageId {
+        self.swarm.behaviour_mut().msg.send(peer, kind, payload)
+    }
+
+    /// Whether the `Hello` in `envelope` proves that `peer` speaks for a
+    /// registered identity.
+    fn verify_hello(&self, peer: &PeerId, envelope: &Envelope) -> bool {
+        let Some(public_key) = self.trusted.get(&envelope.sender) else {
+            return false;
+        };
+
+        Hello::decode(&envelope.payload)
+            .and_then(|hello| hello.verify(peer, public_key))
+            .is_ok()
+    }
+
+    /// Drives the swarm until something the application cares about happens.
+    /// Discovered nodes are dialed so they say hello.
+    pub async fn next_event(&mut self) -> NodeEvent {
         loop {
-            // select_next_some() requiere 'use futures::StreamExt'
+            if let Some(event) = self.events.pop_front() {
+                return event;
+            }
+
             match self.swarm.select_next_some().await {
-                SwarmEvent::NewListenAddr { address, .. } => {
-                    println!("Escuchando en la dirección: {:?}", address);
-                }
+                SwarmEvent::NewListenAddr { address, .. } => return NodeEvent::Listening(address),
                 SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(MdnsEvent::Discovered(list))) => {
-                    for (peer_id, _multiaddr) in list {
-                        println!("--> mDNS descubrió al peer: {}", peer_id);
+                    // mDNS reports one entry per address.
+                    let mut seen = HashSet::new();
+                    let mut peers: Vec<PeerId> = list.into_iter().map(|(peer, _)| peer).collect();
+                    peers.retain(|peer| seen.insert(*peer));
+                    for peer in &peers {
+                        if !self.swarm.behaviour().msg.is_connected(peer) {
+                            let _ = self.swarm.dial(*peer);
+                        }
+                    }
+                    if let Some(peer) = peers.first() {
+                        return NodeEvent::Discovered(*peer);
                     }
                 }
                 SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(MdnsEvent::Expired(list))) => {
-                    for (peer_id, _multiaddr) in list {
-                        println!("<-- mDNS peer expiró: {}", peer_id);
+                    if let Some((peer, _)) = list.into_iter().next() {
+                        return NodeEvent::Expired(peer);
                     }
                 }
+                SwarmEvent::ConnectionClosed {
+                    peer_id,
+                    num_established: 0,
+                    ..
+                } => {
+                    self.identities.remove(&peer_id);
+                    self.unidentified.remove(&peer_id);
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Msg(event)) => match event {
+                    MessagingEvent::Received { peer, envelope } if envelope.kind == EnvelopeKind::Hello => {
+                        let known = self.identities.contains_key(&peer);
+                        if known || !self.verify_hello(&peer, &envelope) {
+                            continue;
+                        }
+
+                        let fingerprint = envelope.sender;
+                        self.identities.insert(peer, fingerprint.clone());
+                        // Envelopes can overtake the hello on another substream.
+                        for envelope in self.unidentified.remove(&peer).unwrap_or_default() {
+                            if envelope.sender == fingerprint {
+                                self.events.push_back(NodeEvent::Message {
+                                    peer,
+                                    fingerprint: fingerprint.clone(),
+                                    envelope,
+                                });
+                            }
+                        }
+                        return NodeEvent::Identified { peer, fingerprint };
+                    }
+                    MessagingEvent::Received { peer, envelope } => {
+                        match self.identities.get(&peer) {
+                            Some(fingerprint) if *fingerprint == envelope.sender => {
+                                return NodeEvent::Message {
+                                    peer,
+                                    fingerprint: fingerprint.clone(),
+                                    envelope,
+                                };
+                            }
+                            // Claims an identity the node has not proven.
+                            Some(_) => {}
+                            None => {
+                                let early = self.unidentified.entry(peer).or_default();
+                                if early.len() < MAX_UNIDENTIFIED {
+                                    early.push(envelope);
+                                }
+                            }
+                        }
+                    }
+                    MessagingEvent::Delivered { peer, id } => return NodeEvent::Delivered { peer, id },
+                    MessagingEvent::Failed { peer, id, error } => {
+                        return NodeEvent::Failed { peer, id, error };
+                    }
+                },
                 _ => {}
             }
         }
     }
-}
\ No newline at end of file
+
+    /// Runs the node on a tokio task that takes `NodeCommand`s and hands
+    /// back every `NodeEvent` through the returned handle.
+    pub fn spawn(mut self) -> NodeHandle {
+        let (commands, mut command_rx) = mpsc::unbounded_channel();
+        let (event_tx, events) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            // Envelopes for identities that have not said hello yet.
+            let mut waiting: Vec<(String, EnvelopeKind, String)> = Vec::new();
+
+            loop {
+                tokio::select! {
+                    command = command_rx.recv() => match command {
+                        Some(NodeCommand::Send { fingerprint, kind, payload }) => {
+                            match self.peer_with_fingerprint(&fingerprint) {
+                                Some(peer) => {
+                                    self.send(peer, kind, payload);
+                                }
+                                None => waiting.push((fingerprint, kind, payload)),
+                            }
+                        }
+                        None => break,
+                    },
+                    event = self.next_event() => {
+                        if let NodeEvent::Identified { peer, fingerprint } = &event {
+                            let (ready, rest) = waiting
+                                .drain(..)
+                                .partition(|(waiting_for, _, _)| waiting_for == fingerprint);
+                            waiting = rest;
+                            for (_, kind, payload) in ready {
+                                self.send(*peer, kind, payload);
+                            }
+                        }
+
+                        if event_tx.send(event).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+
+        NodeHandle { commands, events }
+    }
+
+    /// Listens on `address` and prints what happens on the network until
+    /// interrupted.
+    pub async fn run(&mut self, address: Multiaddr) -> Result<(), Box<dyn Error>> {
+        self.listen_on(address)?;
+
+        loop {
+            match self.next_event().await {
+                NodeEvent::Listening(address) => println!("Listening on {}", address),
+                NodeEvent::Discovered(peer) => println!("Discovered {}", peer),
+                NodeEvent::Expired(peer) => println!("{} went away", peer),
+                NodeEvent::Identified { peer, fingerprint } => {
+                    println!("{} is {}", peer, fingerprint);
+                }
+                NodeEvent::Message {
+                    peer,
+                    fingerprint,
+                    envelope,
+                } => {
+                    println!(
+                        "{:?} message {} from {} ({})",
+                        envelope.kind, envelope.id, fingerprint, peer
+                    );
+                }
+                NodeEvent::Delivered { .. } => {}
+                NodeEvent::Failed { peer, id, error } => {
+                    println!("Message {} to {} failed: {}", id, peer, error);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::cipher::MemoryStore;
+
+    fn cipher() -> Cipher<MemoryStore> {
+        let mut cipher = Cipher::new(MemoryStore::new());
+        cipher.init(None).unwrap();
+        cipher
+    }
+
+    async fn listening(cipher: &Cipher<MemoryStore>) -> (P2PNode, Multiaddr) {
+        let mut node = P2PNode::new(cipher).await.unwrap();
+        node.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        loop {
+            if let NodeEvent::Listening(address) = node.next_event().await {
+                return (node, address);
+            }
+        }
+    }
+
+    /// Dials `address`, sends `text` to the node there once it is
+    /// identified and waits for the acknowledgement.
+    async fn deliver(cipher: &Cipher<MemoryStore>, address: Multiaddr, text: &str) -> PeerId {
+        let mut node = P2PNode::new(cipher).await.unwrap();
+        node.swarm.dial(address).unwrap();
+        let mut sent = None;
+        loop {
+            match node.next_event().await {
+                NodeEvent::Identified { peer, .. } if sent.is_none() => {
+                    sent = Some(node.send(peer, EnvelopeKind::Text, text.to_string()));
+                }
+                NodeEvent::Delivered { id, .. } if Some(id) == sent => return node.peer_id,
+                NodeEvent::Failed { error, .. } => panic!("{}", error),
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn only_registered_identities_are_believed() {
+        let (mut alice, mut bob, mut carol) = (cipher(), cipher(), cipher());
+        bob.register_peer("alice", &alice.export_public_key().unwrap()).unwrap();
+        alice.register_peer("bob", &bob.export_public_key().unwrap()).unwrap();
+        // Carol knows Bob, but Bob has never heard of her.
+        carol.register_peer("bob", &bob.export_public_key().unwrap()).unwrap();
+
+        let (mut node, address) = listening(&bob).await;
+        let (events, mut received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                if events.send(node.next_event().await).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let run = async {
+            let carol_node = deliver(&carol, address.clone(), "from carol").await;
+            let alice_node = deliver(&alice, address, "from alice").await;
+
+            loop {
+                match received.recv().await.unwrap() {
+                    NodeEvent::Identified { peer, fingerprint } => {
+                        assert_eq!(peer, alice_node);
+                        assert_eq!(fingerprint, alice.export_fingerprint().unwrap());
+                    }
+                    NodeEvent::Message {
+                        peer,
+                        fingerprint,
+                        envelope,
+                    } => {
+                        assert_ne!(peer, carol_node);
+                        assert_eq!(fingerprint, alice.export_fingerprint().unwrap());
+                        assert_eq!(envelope.payload, "from alice");
+                        return;
+                    }
+                    _ => {}
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(20), run).await.unwrap();
+    }
+}
@@ -0,0 +1,460 @@
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, KeyInit, Nonce, OsRng, Payload},
+    Aes256Gcm,
+};
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use zeroize::Zeroizing;
+
+use super::manager::KeyringError;
+
+/// Somewhere secrets can be kept, addressed by `service` and `user` like an
+/// OS keyring entry.
+pub trait SecretBackend: Send + Sync {
+    fn get(&self, service: &str, user: &str) -> Result<String, KeyringError>;
+
+    fn set(&self, service: &str, user: &str, secret: &str) -> Result<(), KeyringError>;
+
+    /// Removes the secret; `KeyringError::NotFound` if there was none.
+    fn delete(&self, service: &str, user: &str) -> Result<(), KeyringError>;
+}
+
+/// The platform keyring: Keychain, Credential Manager or the kernel keyring.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KeyringBackend;
+
+impl KeyringBackend {
+    fn entry(service: &str, user: &str) -> Result<keyring::Entry, KeyringError> {
+        keyring::Entry::new(service, user).map_err(Self::map_error)
+    }
+
+    fn map_error(err: keyring::Error) -> KeyringError {
+        match err {
+            keyring::Error::NoEntry => KeyringError::NotFound,
+            keyring::Error::NoStorageAccess(e) => KeyringError::AccessDenied(e.to_string()),
+            keyring::Error::PlatformFailure(e) => KeyringError::BackendUnavailable(e.to_string()),
+            other => KeyringError::OperationFailed(other.to_string()),
+        }
+    }
+}
+
+impl SecretBackend for KeyringBackend {
+    fn get(&self, service: &str, user: &str) -> Result<String, KeyringError> {
+        Self::entry(service, user)?.get_password().map_err(Self::map_error)
+    }
+
+    fn set(&self, service: &str, user: &str, secret: &str) -> Result<(), KeyringError> {
+        Self::entry(service, user)?
+            .set_password(secret)
+            .map_err(|e| match e {
+                // Some keyrings report a missing session keyring this way.
+                keyring::Error::NoEntry => KeyringError::BackendUnavailable(e.to_string()),
+                e => Self::map_error(e),
+            })
+    }
+
+    fn delete(&self, service: &str, user: &str) -> Result<(), KeyringError> {
+        Self::entry(service, user)?.delete_credential().map_err(Self::map_error)
+    }
+}
+
+/// Secrets in a single file, each sealed with AES-256-GCM under a 32-byte
+/// key kept elsewhere; the service and user are bound in as associated data.
+///
+/// The file is JSON mapping `"<service>/<user>"` to base64url
+/// `nonce || ciphertext`. Writes go through a temporary file and a rename.
+pub struct EncryptedFileBackend {
+    path: PathBuf,
+    key: FileKey,
+}
+
+enum FileKey {
+    Key(Zeroizing<[u8; 32]>),
+    /// Read from this file, created with a random key on the first `set`.
+    File(PathBuf),
+}
+
+impl EncryptedFileBackend {
+    const NONCE_LEN: usize = 12;
+
+    pub fn new(path: impl Into<PathBuf>, key: [u8; 32]) -> Self {
+        Self {
+            path: path.into(),
+            key: FileKey::Key(Zeroizing::new(key)),
+        }
+    }
+
+    /// Backend keyed by the 32 bytes in `key_path`. Keep it apart from `path`
+    /// so one copied file is not enough to read the secrets.
+    pub fn with_key_file(path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            key: FileKey::File(key_path.into()),
+        }
+    }
+
+    fn cipher(&self, create: bool) -> Result<Aes256Gcm, KeyringError> {
+        let key_path = match &self.key {
+            FileKey::Key(key) => return Ok(Aes256Gcm::new(key.as_ref().into())),
+            FileKey::File(key_path) => key_path,
+        };
+
+        let key = match fs::read(key_path) {
+            Ok(data) => Zeroizing::new(data),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && create => {
+                let mut key = Zeroizing::new(vec![0u8; 32]);
+                OsRng.fill_bytes(&mut key);
+                write_private(key_path, &key)?;
+                key
+            }
+            Err(e) => return Err(io_error(e)),
+        };
+
+        Aes256Gcm::new_from_slice(&key)
+            .map_err(|_| KeyringError::OperationFailed(format!("Invalid key file {}", key_path.display())))
+    }
+
+    fn id(service: &str, user: &str) -> String {
+        format!("{}/{}", service, user)
+    }
+
+    fn load(&self) -> Result<BTreeMap<String, String>, KeyringError> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(e) => return Err(io_error(e)),
+        };
+
+        serde_json::from_slice(&data)
+            .map_err(|e| KeyringError::OperationFailed(format!("{}: {}", self.path.display(), e)))
+    }
+
+    fn save(&self, entries: &BTreeMap<String, String>) -> Result<(), KeyringError> {
+        let data = serde_json::to_vec(entries).map_err(|e| KeyringError::OperationFailed(e.to_string()))?;
+        write_private(&self.path, &data)
+    }
+}
+
+impl SecretBackend for EncryptedFileBackend {
+    fn get(&self, service: &str, user: &str) -> Result<String, KeyringError> {
+        let id = Self::id(service, user);
+        let entries = self.load()?;
+        let sealed = URL_SAFE
+            .decode(entries.get(&id).ok_or(KeyringError::NotFound)?)
+            .map_err(|e| KeyringError::OperationFailed(e.to_string()))?;
+        if sealed.len() < Self::NONCE_LEN {
+            return Err(KeyringError::OperationFailed(format!("Damaged entry {}", id)));
+        }
+        let (nonce, ciphertext) = sealed.split_at(Self::NONCE_LEN);
+
+        let plain = Zeroizing::new(
+            self.cipher(false)?
+                .decrypt(
+                    Nonce::<Aes256Gcm>::from_slice(nonce),
+                    Payload {
+                        msg: ciphertext,
+                        aad: id.as_bytes(),
+                    },
+                )
+                // Sealed under another key: not ours to read.
+                .map_err(|_| KeyringError::AccessDenied(format!("Cannot decrypt entry {}", id)))?,
+        );
+
+        String::from_utf8(plain.to_vec()).map_err(|e| KeyringError::OperationFailed(e.to_string()))
+    }
+
+    fn set(&self, service: &str, user: &str, secret: &str) -> Result<(), KeyringError> {
+        let id = Self::id(service, user);
+        let mut nonce = [0u8; Self::NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+
+        let ciphertext = self
+            .cipher(true)?
+            .encrypt(
+                Nonce::<Aes256Gcm>::from_slice(&nonce),
+                Payload {
+                    msg: secret.as_bytes(),
+                    aad: id.as_bytes(),
+                },
+            )
+            .map_err(|e| KeyringError::OperationFailed(e.to_string()))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+
+        let mut entries = self.load()?;
+        entries.insert(id, URL_SAFE.encode(sealed));
+        self.save(&entries)
+    }
+
+    fn delete(&self, service: &str, user: &str) -> Result<(), KeyringError> {
+        let mut entries = self.load()?;
+        entries
+            .remove(&Self::id(service, user))
+            .ok_or(KeyringError::NotFound)?;
+
+        if entries.is_empty() {
+            return fs::remove_file(&self.path).map_err(io_error);
+        }
+        self.save(&entries)
+    }
+}
+
+/// Read-only secrets from environment variables, for CI. The variable for an
+/// entry is `<prefix>_<SERVICE>_<USER>`, upper-cased, with every character
+/// that is not ASCII alphanumeric replaced by `_`.
+#[derive(Debug, Clone)]
+pub struct EnvBackend {
+    prefix: String,
+}
+
+impl EnvBackend {
+    pub fn new(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+        }
+    }
+
+    pub fn variable(&self, service: &str, user: &str) -> String {
+        format!("{}_{}_{}", self.prefix, service, user)
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+            .collect()
+    }
+}
+
+impl SecretBackend for EnvBackend {
+    fn get(&self, service: &str, user: &str) -> Result<String, KeyringError> {
+        match env::var(self.variable(service, user)) {
+            Ok(secret) => Ok(secret),
+            Err(env::VarError::NotPresent) => Err(KeyringError::NotFound),
+            Err(e) => Err(KeyringError::OperationFailed(e.to_string())),
+        }
+    }
+
+    fn set(&self, _service: &str, _user: &str, _secret: &str) -> Result<(), KeyringError> {
+        Err(KeyringError::AccessDenied("The environment is read-only".into()))
+    }
+
+    fn delete(&self, _service: &str, _user: &str) -> Result<(), KeyringError> {
+        Err(KeyringError::AccessDenied("The environment is read-only".into()))
+    }
+}
+
+type MemoryEntries = HashMap<(String, String), Zeroizing<String>>;
+
+/// Secrets held in memory only, for tests.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    entries: Mutex<MemoryEntries>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn entries(&self) -> Result<MutexGuard<'_, MemoryEntries>, KeyringError> {
+        self.entries
+            .lock()
+            .map_err(|_| KeyringError::BackendUnavailable("Memory backend poisoned".into()))
+    }
+}
+
+impl SecretBackend for MemoryBackend {
+    fn get(&self, service: &str, user: &str) -> Result<String, KeyringError> {
+        self.entries()?
+            .get(&(service.to_string(), user.to_string()))
+            .map(|secret| secret.to_string())
+            .ok_or(KeyringError::NotFound)
+    }
+
+    fn set(&self, service: &str, user: &str, secret: &str) -> Result<(), KeyringError> {
+        self.entries()?.insert(
+            (service.to_string(), user.to_string()),
+            Zeroizing::new(secret.to_string()),
+        );
+        Ok(())
+    }
+
+    fn delete(&self, service: &str, user: &str) -> Result<(), KeyringError> {
+        self.entries()?
+            .remove(&(service.to_string(), user.to_string()))
+            .map(|_| ())
+            .ok_or(KeyringError::NotFound)
+    }
+}
+
+fn io_error(err: io::Error) -> KeyringError {
+    match err.kind() {
+        io::ErrorKind::NotFound => KeyringError::NotFound,
+        io::ErrorKind::PermissionDenied => KeyringError::AccessDenied(err.to_string()),
+        _ => KeyringError::OperationFailed(err.to_string()),
+    }
+}
+
+fn write_private(path: &Path, data: &[u8]) -> Result<(), KeyringError> {
+    let write = || -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file_name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+        let tmp = path.with_file_name(format!(".{}.tmp", file_name));
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let mut file = options.open(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    };
+
+    write().map_err(io_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Directory under the system temp dir, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("nyx-backend-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn round_trip(backend: &dyn SecretBackend) {
+        assert!(matches!(backend.get("nyx", "alice"), Err(KeyringError::NotFound)));
+
+        backend.set("nyx", "alice", "first").unwrap();
+        backend.set("nyx", "alice", "second").unwrap();
+        backend.set("nyx", "bob", "other").unwrap();
+        assert_eq!(backend.get("nyx", "alice").unwrap(), "second");
+        assert_eq!(backend.get("nyx", "bob").unwrap(), "other");
+
+        backend.delete("nyx", "alice").unwrap();
+        assert!(matches!(backend.get("nyx", "alice"), Err(KeyringError::NotFound)));
+        assert!(matches!(backend.delete("nyx", "alice"), Err(KeyringError::NotFound)));
+        assert_eq!(backend.get("nyx", "bob").unwrap(), "other");
+    }
+
+    #[test]
+    fn memory_backend_round_trips() {
+        round_trip(&MemoryBackend::new());
+    }
+
+    #[test]
+    fn file_backend_round_trips() {
+        let dir = TempDir::new("round-trip");
+        round_trip(&EncryptedFileBackend::new(dir.0.join("secrets"), [7; 32]));
+
+        let backend = EncryptedFileBackend::with_key_file(dir.0.join("keyed"), dir.0.join("key"));
+        round_trip(&backend);
+        assert_eq!(fs::read(dir.0.join("key")).unwrap().len(), 32);
+        assert!(!fs::read(dir.0.join("keyed")).unwrap().windows(5).any(|w| w == b"other"));
+    }
+
+    #[test]
+    fn file_backend_with_the_wrong_key_is_denied() {
+        let dir = TempDir::new("wrong-key");
+        let path = dir.0.join("secrets");
+        EncryptedFileBackend::new(&path, [1; 32]).set("nyx", "alice", "secret").unwrap();
+
+        let other = EncryptedFileBackend::new(&path, [2; 32]);
+        assert!(matches!(other.get("nyx", "alice"), Err(KeyringError::AccessDenied(_))));
+        assert!(matches!(other.get("nyx", "bob"), Err(KeyringError::NotFound)));
+    }
+
+    #[test]
+    fn file_backend_entries_are_bound_to_their_id() {
+        let dir = TempDir::new("bound");
+        let backend = EncryptedFileBackend::new(dir.0.join("secrets"), [1; 32]);
+        backend.set("nyx", "alice", "secret").unwrap();
+
+        // Alice's sealed value copied over Bob's entry does not open.
+        let mut entries = backend.load().unwrap();
+        let alice = entries["nyx/alice"].clone();
+        entries.insert("nyx/bob".into(), alice);
+        backend.save(&entries).unwrap();
+        assert!(matches!(backend.get("nyx", "bob"), Err(KeyringError::AccessDenied(_))));
+    }
+
+    #[test]
+    fn file_backend_without_its_key_file_is_not_found() {
+        let dir = TempDir::new("no-key");
+        let backend = EncryptedFileBackend::with_key_file(dir.0.join("secrets"), dir.0.join("key"));
+        backend.set("nyx", "alice", "secret").unwrap();
+        fs::remove_file(dir.0.join("key")).unwrap();
+
+        assert!(matches!(backend.get("nyx", "alice"), Err(KeyringError::NotFound)));
+    }
+
+    #[test]
+    fn env_backend_reads_variables_and_is_read_only() {
+        let backend = EnvBackend::new("NYX_TEST_SECRET");
+        let variable = backend.variable("nyx", "profile:ci-1");
+        assert_eq!(variable, "NYX_TEST_SECRET_NYX_PROFILE_CI_1");
+
+        assert!(matches!(backend.get("nyx", "profile:ci-1"), Err(KeyringError::NotFound)));
+        env::set_var(&variable, "secret");
+        assert_eq!(backend.get("nyx", "profile:ci-1").unwrap(), "secret");
+        env::remove_var(&variable);
+
+        assert!(matches!(backend.set("nyx", "a", "b"), Err(KeyringError::AccessDenied(_))));
+        assert!(matches!(backend.delete("nyx", "a"), Err(KeyringError::AccessDenied(_))));
+    }
+
+    #[test]
+    fn errors_map_to_the_keyring_errors() {
+        let platform = || Box::new(io::Error::other("no dbus"));
+        assert!(matches!(
+            KeyringBackend::map_error(keyring::Error::NoEntry),
+            KeyringError::NotFound
+        ));
+        assert!(matches!(
+            KeyringBackend::map_error(keyring::Error::NoStorageAccess(platform())),
+            KeyringError::AccessDenied(_)
+        ));
+        assert!(matches!(
+            KeyringBackend::map_error(keyring::Error::PlatformFailure(platform())),
+            KeyringError::BackendUnavailable(_)
+        ));
+
+        assert!(matches!(
+            io_error(io::ErrorKind::NotFound.into()),
+            KeyringError::NotFound
+        ));
+        assert!(matches!(
+            io_error(io::ErrorKind::PermissionDenied.into()),
+            KeyringError::AccessDenied(_)
+        ));
+        assert!(matches!(
+            io_error(io::ErrorKind::InvalidData.into()),
+            KeyringError::OperationFailed(_)
+        ));
+    }
+}
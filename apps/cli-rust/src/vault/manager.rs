@@ -1,74 +1,180 @@
-use keyring::Entry;
 use thiserror::Error;
 
+use super::backend::{KeyringBackend, SecretBackend};
+
 #[derive(Error, Debug)]
 pub enum KeyringError {
+    #[error("No secret stored for this entry")]
+    NotFound,
+
+    #[error("Access to the secret store denied: {0}")]
+    AccessDenied(String),
+
+    /// No usable store, such as a headless Linux box without a keyring.
+    #[error("Secret store unavailable: {0}")]
+    BackendUnavailable(String),
+
     #[error("Keyring operation failed: {0}")]
     OperationFailed(String),
 }
 
+impl KeyringError {
+    /// Whether the store itself could not be used, as opposed to the entry.
+    pub fn is_unavailable(&self) -> bool {
+        matches!(self, KeyringError::AccessDenied(_) | KeyringError::BackendUnavailable(_))
+    }
+}
+
+/// One secret, `service` and `user`, in a `SecretBackend`; the OS keyring
+/// unless another backend is given.
+///
+/// With `with_fallback`, a primary backend that is unavailable or denies
+/// access is replaced by the fallback, and lookups check both.
 pub struct SecretManager {
     service: String,
     user: String,
+    backend: Box<dyn SecretBackend>,
+    fallback: Option<Box<dyn SecretBackend>>,
 }
 
 impl SecretManager {
     pub fn new(service: &str, user: &str) -> Self {
+        Self::with_backend(service, user, KeyringBackend)
+    }
+
+    pub fn with_backend(service: &str, user: &str, backend: impl SecretBackend + 'static) -> Self {
         Self {
             service: service.to_string(),
             user: user.to_string(),
+            backend: Box::new(backend),
+            fallback: None,
         }
     }
 
+    pub fn with_fallback(mut self, backend: impl SecretBackend + 'static) -> Self {
+        self.fallback = Some(Box::new(backend));
+        self
+    }
+
     pub fn set_secret(&self, secret: &str) -> Result<(), KeyringError> {
-        let entry = Entry::new(&self.service, &self.user).map_err(|e| KeyringError::OperationFailed(e.to_string()))?;
-        entry.set_password(secret).map_err(|e| KeyringError::OperationFailed(e.to_string()))
+        match (self.backend.set(&self.service, &self.user, secret), &self.fallback) {
+            (Ok(()), Some(fallback)) => {
+                // A stale copy from when the primary backend was unavailable.
+                match fallback.delete(&self.service, &self.user) {
+                    Ok(()) | Err(KeyringError::NotFound) => Ok(()),
+                    Err(e) => Err(e),
+                }
+            }
+            (Err(e), Some(fallback)) if e.is_unavailable() => fallback.set(&self.service, &self.user, secret),
+            (result, _) => result,
+        }
     }
 
     pub fn get_secret(&self) -> Result<String, KeyringError> {
-        let entry = Entry::new(&self.service, &self.user).map_err(|e| KeyringError::OperationFailed(e.to_string()))?;
-        entry.get_password().map_err(|e| KeyringError::OperationFailed(e.to_string()))
+        match (self.backend.get(&self.service, &self.user), &self.fallback) {
+            (Err(e), Some(fallback)) => match fallback.get(&self.service, &self.user) {
+                Err(KeyringError::NotFound) => Err(e),
+                result => result,
+            },
+            (result, _) => result,
+        }
     }
 
+    /// Deletes the secret from every backend holding it; `NotFound` if none did.
     pub fn delete_secret(&self) -> Result<(), KeyringError> {
-        let entry = Entry::new(&self.service, &self.user).map_err(|e| KeyringError::OperationFailed(e.to_string()))?;
-        entry.delete_credential().map_err(|e| KeyringError::OperationFailed(e.to_string()))
+        let deleted = self.backend.delete(&self.service, &self.user);
+        let Some(fallback) = &self.fallback else {
+            return deleted;
+        };
+
+        match (deleted, fallback.delete(&self.service, &self.user)) {
+            (Ok(()), _) | (_, Ok(())) => Ok(()),
+            // Nothing could have been stored in a backend that is unavailable.
+            (Err(e), Err(KeyringError::NotFound)) if e.is_unavailable() => Err(KeyringError::NotFound),
+            (Err(KeyringError::NotFound), Err(e)) | (Err(e), Err(_)) => Err(e),
+        }
     }
 }
-use keyring::Entry;
-use thiserror::Error;
 
-#[derive(Error, Debug)]
-pub enum KeyringError {
-    #[error("Keyring operation failed: {0}")]
-    OperationFailed(String),
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vault::backend::MemoryBackend;
+    use std::sync::Arc;
 
-pub struct SecretManager {
-    service: String,
-    user: String,
-}
+    /// A primary backend that cannot be reached, like a keyring without a
+    /// session bus.
+    struct Unavailable;
 
-impl SecretManager {
-    pub fn new(service: &str, user: &str) -> Self {
-        Self {
-            service: service.to_string(),
-            user: user.to_string(),
+    impl SecretBackend for Unavailable {
+        fn get(&self, _service: &str, _user: &str) -> Result<String, KeyringError> {
+            Err(KeyringError::BackendUnavailable("no keyring".into()))
+        }
+
+        fn set(&self, _service: &str, _user: &str, _secret: &str) -> Result<(), KeyringError> {
+            Err(KeyringError::BackendUnavailable("no keyring".into()))
+        }
+
+        fn delete(&self, _service: &str, _user: &str) -> Result<(), KeyringError> {
+            Err(KeyringError::BackendUnavailable("no keyring".into()))
         }
     }
 
-    pub fn set_secret(&self, secret: &str) -> Result<(), KeyringError> {
-        let entry = Entry::new(&self.service, &self.user).map_err(|e| KeyringError::OperationFailed(e.to_string()))?;
-        entry.set_password(secret).map_err(|e| KeyringError::OperationFailed(e.to_string()))
+    /// A `MemoryBackend` the test can still look into.
+    struct Shared(Arc<MemoryBackend>);
+
+    impl SecretBackend for Shared {
+        fn get(&self, service: &str, user: &str) -> Result<String, KeyringError> {
+            self.0.get(service, user)
+        }
+
+        fn set(&self, service: &str, user: &str, secret: &str) -> Result<(), KeyringError> {
+            self.0.set(service, user, secret)
+        }
+
+        fn delete(&self, service: &str, user: &str) -> Result<(), KeyringError> {
+            self.0.delete(service, user)
+        }
     }
 
-    pub fn get_secret(&self) -> Result<String, KeyringError> {
-        let entry = Entry::new(&self.service, &self.user).map_err(|e| KeyringError::OperationFailed(e.to_string()))?;
-        entry.get_password().map_err(|e| KeyringError::OperationFailed(e.to_string()))
+    #[test]
+    fn unavailable_primary_falls_back() {
+        let fallback = Arc::new(MemoryBackend::new());
+        let manager = SecretManager::with_backend("nyx", "alice", Unavailable)
+            .with_fallback(Shared(fallback.clone()));
+
+        assert!(matches!(manager.get_secret(), Err(KeyringError::BackendUnavailable(_))));
+        manager.set_secret("secret").unwrap();
+        assert_eq!(fallback.get("nyx", "alice").unwrap(), "secret");
+        assert_eq!(manager.get_secret().unwrap(), "secret");
+
+        manager.delete_secret().unwrap();
+        assert!(matches!(fallback.get("nyx", "alice"), Err(KeyringError::NotFound)));
+        assert!(matches!(manager.delete_secret(), Err(KeyringError::NotFound)));
     }
 
-    pub fn delete_secret(&self) -> Result<(), KeyringError> {
-        let entry = Entry::new(&self.service, &self.user).map_err(|e| KeyringError::OperationFailed(e.to_string()))?;
-        entry.delete_credential().map_err(|e| KeyringError::OperationFailed(e.to_string()))
+    #[test]
+    fn available_primary_replaces_the_fallback_copy() {
+        let (primary, fallback) = (Arc::new(MemoryBackend::new()), Arc::new(MemoryBackend::new()));
+        fallback.set("nyx", "alice", "stale").unwrap();
+        let manager = SecretManager::with_backend("nyx", "alice", Shared(primary.clone()))
+            .with_fallback(Shared(fallback.clone()));
+
+        assert_eq!(manager.get_secret().unwrap(), "stale");
+        manager.set_secret("fresh").unwrap();
+        assert_eq!(primary.get("nyx", "alice").unwrap(), "fresh");
+        assert!(matches!(fallback.get("nyx", "alice"), Err(KeyringError::NotFound)));
+        assert_eq!(manager.get_secret().unwrap(), "fresh");
+    }
+
+    #[test]
+    fn entry_errors_do_not_fall_back() {
+        let fallback = Arc::new(MemoryBackend::new());
+        let manager = SecretManager::with_backend("nyx", "alice", MemoryBackend::new())
+            .with_fallback(Shared(fallback.clone()));
+
+        assert!(matches!(manager.get_secret(), Err(KeyringError::NotFound)));
+        manager.set_secret("secret").unwrap();
+        assert!(matches!(fallback.get("nyx", "alice"), Err(KeyringError::NotFound)));
     }
 }
@@ -1 +1,918 @@
-pub mod manager;
\ No newline at end of file
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, KeyInit, Nonce, OsRng, Payload},
+    Aes256Gcm,
+};
+use chrono::{DateTime, Utc};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use super::merge::{self, VersionVector};
+use crate::crypto::cipher::{Cipher, CipherError, Store, StoreOp};
+
+/// Purpose passed to `Cipher::derive_local_key` for the vault key.
+const KEY_PURPOSE: &str = "notes";
+/// Snapshots kept per note as bases for three-way merges.
+const HISTORY_LEN: usize = 8;
+
+/// Store ids used by `NotesVault`.
+pub mod keys {
+    /// Sealed list of `NoteSummary`, so listing never opens a note.
+    pub const INDEX: &str = "index";
+    pub const NOTE_PREFIX: &str = "note:";
+    /// Sealed snapshots of a note as last sent to or received from peers.
+    pub const HISTORY_PREFIX: &str = "history:";
+
+    pub fn note(id: &str) -> String {
+        format!("{}{}", NOTE_PREFIX, id)
+    }
+
+    pub fn history(id: &str) -> String {
+        format!("{}{}", HISTORY_PREFIX, id)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum VaultError {
+    #[error(transparent)]
+    Cipher(#[from] CipherError),
+
+    #[error("Note not found: {0}")]
+    NotFound(String),
+
+    #[error("Vault entry corrupted or sealed under another identity: {0}")]
+    Corrupted(String),
+
+    /// A peer sent a note we never shared with it, or claimed edits of ours
+    /// that we do not have.
+    #[error("Rejected note {id} from {peer}: {reason}")]
+    Rejected {
+        id: String,
+        peer: String,
+        reason: String,
+    },
+}
+
+/// A markdown note.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Note {
+    pub id: String,
+    pub title: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub body: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    #[serde(default)]
+    pub version: VersionVector,
+    /// The body still has conflict markers from a merge.
+    #[serde(default)]
+    pub conflicted: bool,
+    /// Peers the note was shared with or received from; only they can sync
+    /// changes into it.
+    #[serde(default)]
+    pub shared_with: Vec<String>,
+}
+
+/// Index entry of a note: everything but the body.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct NoteSummary {
+    pub id: String,
+    pub title: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub updated_at: DateTime<Utc>,
+    #[serde(default)]
+    pub conflicted: bool,
+}
+
+impl From<&Note> for NoteSummary {
+    fn from(note: &Note) -> Self {
+        Self {
+            id: note.id.clone(),
+            title: note.title.clone(),
+            tags: note.tags.clone(),
+            updated_at: note.updated_at,
+            conflicted: note.conflicted,
+        }
+    }
+}
+
+/// A note as sent to a peer by `NotesVault::share`.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SharedNote {
+    id: String,
+    title: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    body: String,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    #[serde(default)]
+    version: VersionVector,
+}
+
+impl From<&Note> for SharedNote {
+    fn from(note: &Note) -> Self {
+        Self {
+            id: note.id.clone(),
+            title: note.title.clone(),
+            tags: note.tags.clone(),
+            body: note.body.clone(),
+            created_at: note.created_at,
+            updated_at: note.updated_at,
+            version: note.version.clone(),
+        }
+    }
+}
+
+/// Content of a note at some version, kept to find the common base of two
+/// concurrent versions.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+struct Snapshot {
+    version: VersionVector,
+    title: String,
+    tags: Vec<String>,
+    body: String,
+}
+
+impl From<&SharedNote> for Snapshot {
+    fn from(note: &SharedNote) -> Self {
+        Self {
+            version: note.version.clone(),
+            title: note.title.clone(),
+            tags: note.tags.clone(),
+            body: note.body.clone(),
+        }
+    }
+}
+
+/// What `NotesVault::import_shared` did with a note.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncOutcome {
+    /// We did not have the note yet.
+    Added,
+    /// We already had every change in it.
+    UpToDate,
+    /// Only the peer had changed the note; their version replaced ours.
+    FastForward,
+    /// Both sides changed the note since they last synced; `conflicts` lists
+    /// what could not be merged cleanly.
+    Merged { conflicts: Vec<Conflict> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Conflict {
+    /// Both sides renamed the note; the more recently updated title won.
+    Title { kept: String, dropped: String },
+    /// Regions of the body both sides changed, left between conflict markers
+    /// for the user to resolve with `NotesVault::update`.
+    Body { regions: usize },
+}
+
+#[derive(Debug, Clone)]
+pub struct SyncReport {
+    pub note: Note,
+    pub outcome: SyncOutcome,
+}
+
+/// Entry as stored: AES-256-GCM with the store id as associated data, so
+/// entries cannot be swapped for one another.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SealedEntry {
+    ciphertext: Vec<u8>,
+    nonce: [u8; 12],
+}
+
+/// Local, encrypted markdown notes.
+///
+/// Notes and their index live in their own `Store`, sealed with a key
+/// derived from the identity of a `Cipher` (see `Cipher::derive_local_key`).
+/// Only that identity can read them; a restored backup of the identity is
+/// enough to open the vault again.
+///
+/// Every note carries a `VersionVector` so copies shared with peers can be
+/// synced: `import_shared` fast-forwards to a newer copy and three-way merges
+/// concurrent edits, marking whatever it cannot merge instead of dropping it.
+pub struct NotesVault<S: Store> {
+    store: S,
+    key: Zeroizing<[u8; 32]>,
+    /// Our entry in version vectors: the identity fingerprint.
+    replica: String,
+}
+
+fn normalize_tags(tags: &[String]) -> Vec<String> {
+    let mut tags: Vec<String> = tags
+        .iter()
+        .map(|tag| tag.trim().trim_start_matches('#').to_lowercase())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+fn new_id() -> String {
+    let mut bytes = [0u8; 8];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn to_json<T: serde::Serialize>(value: &T) -> Result<Vec<u8>, VaultError> {
+    serde_json::to_vec(value).map_err(|e| CipherError::StorageError(e.to_string()).into())
+}
+
+impl<S: Store> NotesVault<S> {
+    /// Opens the vault in `store` with the identity of `cipher`, which must
+    /// be initialized.
+    pub fn open<T: Store>(mut store: S, cipher: &Cipher<T>) -> Result<Self, VaultError> {
+        store.setup()?;
+
+        Ok(Self {
+            store,
+            key: cipher.derive_local_key(KEY_PURPOSE)?,
+            replica: cipher.export_fingerprint()?.replace(' ', ""),
+        })
+    }
+
+    fn seal(&self, id: &str, plaintext: &[u8]) -> Result<Vec<u8>, VaultError> {
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+
+        let ciphertext = Aes256Gcm::new(self.key.as_ref().into())
+            .encrypt(
+                Nonce::<Aes256Gcm>::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: id.as_bytes(),
+                },
+            )
+            .map_err(|e| CipherError::EncryptionFailed(e.to_string()))?;
+
+        to_json(&SealedEntry { ciphertext, nonce })
+    }
+
+    fn open_entry<T: serde::de::DeserializeOwned>(
+        &self,
+        id: &str,
+    ) -> Result<Option<T>, VaultError> {
+        let Some(data) = self.store.get(id)? else {
+            return Ok(None);
+        };
+
+        let sealed: SealedEntry =
+            serde_json::from_slice(&data).map_err(|_| VaultError::Corrupted(id.into()))?;
+        let plain = Zeroizing::new(
+            Aes256Gcm::new(self.key.as_ref().into())
+                .decrypt(
+                    Nonce::<Aes256Gcm>::from_slice(&sealed.nonce),
+                    Payload {
+                        msg: &sealed.ciphertext,
+                        aad: id.as_bytes(),
+                    },
+                )
+                .map_err(|_| VaultError::Corrupted(id.into()))?,
+        );
+
+        serde_json::from_slice(&plain)
+            .map(Some)
+            .map_err(|_| VaultError::Corrupted(id.into()))
+    }
+
+    fn index(&self) -> Result<Vec<NoteSummary>, VaultError> {
+        Ok(self.open_entry(keys::INDEX)?.unwrap_or_default())
+    }
+
+    fn history(&self, id: &str) -> Result<Vec<Snapshot>, VaultError> {
+        Ok(self.open_entry(&keys::history(id))?.unwrap_or_default())
+    }
+
+    /// Op adding `snapshot` to the history of note `id`, unless a snapshot
+    /// of that version is already there.
+    fn remember(&self, id: &str, snapshot: Snapshot) -> Result<Option<StoreOp>, VaultError> {
+        let mut history = self.history(id)?;
+        if history.iter().any(|known| known.version == snapshot.version) {
+            return Ok(None);
+        }
+
+        history.push(snapshot);
+        if history.len() > HISTORY_LEN {
+            history.remove(0);
+        }
+
+        let key = keys::history(id);
+        let data = Zeroizing::new(to_json(&history)?);
+        Ok(Some(StoreOp::Put(key.clone(), self.seal(&key, &data)?)))
+    }
+
+    /// Latest snapshot both `ours` and `theirs` descend from.
+    fn common_base(
+        &self,
+        id: &str,
+        ours: &VersionVector,
+        theirs: &VersionVector,
+    ) -> Result<Snapshot, VaultError> {
+        let mut base = Snapshot::default();
+
+        for snapshot in self.history(id)? {
+            let shared = snapshot.version <= *ours && snapshot.version <= *theirs;
+            if shared && snapshot.version >= base.version {
+                base = snapshot;
+            }
+        }
+
+        Ok(base)
+    }
+
+    /// Writes `note`, the index entry for it and `extra` in one `Store::apply`.
+    fn write(&mut self, note: &Note, extra: Option<StoreOp>) -> Result<(), VaultError> {
+        let mut index = self.index()?;
+        index.retain(|entry| entry.id != note.id);
+        index.push(NoteSummary::from(note));
+
+        let id = keys::note(&note.id);
+        let body = Zeroizing::new(to_json(note)?);
+        let mut ops = vec![
+            StoreOp::Put(id.clone(), self.seal(&id, &body)?),
+            StoreOp::Put(keys::INDEX.to_string(), self.seal(keys::INDEX, &to_json(&index)?)?),
+        ];
+        ops.extend(extra);
+        self.store.apply(ops)?;
+        Ok(())
+    }
+
+    pub fn create(&mut self, title: &str, body: &str, tags: &[String]) -> Result<Note, VaultError> {
+        let now = Utc::now();
+        let mut note = Note {
+            id: new_id(),
+            title: title.trim().to_string(),
+            tags: normalize_tags(tags),
+            body: body.to_string(),
+            created_at: now,
+            updated_at: now,
+            version: VersionVector::default(),
+            conflicted: merge::has_conflicts(body),
+            shared_with: Vec::new(),
+        };
+        note.version.increment(&self.replica);
+
+        self.write(&note, None)?;
+        Ok(note)
+    }
+
+    pub fn get(&self, id: &str) -> Result<Note, VaultError> {
+        self.open_entry(&keys::note(id))?
+            .ok_or_else(|| VaultError::NotFound(id.into()))
+    }
+
+    /// Saves changes to the title, tags or body of an existing note. The
+    /// note stays conflicted until no conflict markers are left in the body.
+    pub fn update(&mut self, mut note: Note) -> Result<Note, VaultError> {
+        let current = self.get(&note.id)?;
+
+        note.title = note.title.trim().to_string();
+        note.tags = normalize_tags(&note.tags);
+        note.created_at = current.created_at;
+        note.shared_with = current.shared_with;
+        note.updated_at = Utc::now();
+        note.version = current.version;
+        note.version.increment(&self.replica);
+        note.conflicted = merge::has_conflicts(&note.body);
+
+        self.write(&note, None)?;
+        Ok(note)
+    }
+
+    pub fn delete(&mut self, id: &str) -> Result<(), VaultError> {
+        let mut index = self.index()?;
+        let before = index.len();
+        index.retain(|entry| entry.id != id);
+        if index.len() == before && !self.store.has(&keys::note(id))? {
+            return Err(VaultError::NotFound(id.into()));
+        }
+
+        self.store.apply(vec![
+            StoreOp::Delete(keys::note(id)),
+            StoreOp::Delete(keys::history(id)),
+            StoreOp::Put(keys::INDEX.to_string(), self.seal(keys::INDEX, &to_json(&index)?)?),
+        ])?;
+        Ok(())
+    }
+
+    /// All notes, most recently updated first.
+    pub fn list(&self) -> Result<Vec<NoteSummary>, VaultError> {
+        let mut index = self.index()?;
+        index.sort_by_key(|entry| std::cmp::Reverse(entry.updated_at));
+        Ok(index)
+    }
+
+    pub fn list_tagged(&self, tag: &str) -> Result<Vec<NoteSummary>, VaultError> {
+        let tag = normalize_tags(&[tag.to_string()]);
+
+        Ok(self
+            .list()?
+            .into_iter()
+            .filter(|entry| tag.iter().all(|tag| entry.tags.contains(tag)))
+            .collect())
+    }
+
+    /// Notes whose title, tags or body contain `query`, ignoring case.
+    pub fn search(&self, query: &str) -> Result<Vec<NoteSummary>, VaultError> {
+        let query = query.trim().to_lowercase();
+        let mut found = Vec::new();
+
+        for entry in self.list()? {
+            let in_index = entry.title.to_lowercase().contains(&query)
+                || entry.tags.iter().any(|tag| tag.contains(&query));
+
+            if in_index || self.get(&entry.id)?.body.to_lowercase().contains(&query) {
+                found.push(entry);
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Encrypts note `id` for `peer_id` with `Cipher::encrypt_bytes`, for
+    /// them to pass to `import_shared`. Sharing again later sends our
+    /// changes since, and lets the peer send theirs.
+    pub fn share<T: Store>(
+        &mut self,
+        id: &str,
+        peer_id: &str,
+        cipher: &mut Cipher<T>,
+    ) -> Result<Vec<u8>, VaultError> {
+        let mut note = self.get(id)?;
+        let shared = SharedNote::from(&note);
+        let plain = Zeroizing::new(to_json(&shared)?);
+        let data = cipher.encrypt_bytes(&plain, peer_id)?;
+
+        // What we sent is a base for merging the peer's later edits.
+        let remember = self.remember(id, Snapshot::from(&shared))?;
+        if !note.shared_with.iter().any(|peer| peer == peer_id) {
+            note.shared_with.push(peer_id.to_string());
+            self.write(&note, remember)?;
+        } else if let Some(op) = remember {
+            self.store.apply(vec![op])?;
+        }
+
+        Ok(data)
+    }
+
+    /// Decrypts a note shared by `peer_id` and syncs it into the vault: a
+    /// note we do not have is added, a newer copy replaces ours and
+    /// concurrent edits are merged (see `SyncOutcome`). A note we have only
+    /// syncs with peers in its `shared_with`, and never with a version that
+    /// claims more of our own edits than we made.
+    pub fn import_shared<T: Store>(
+        &mut self,
+        data: &[u8],
+        peer_id: &str,
+        cipher: &mut Cipher<T>,
+    ) -> Result<SyncReport, VaultError> {
+        let plain = Zeroizing::new(cipher.decrypt_bytes(data, peer_id)?);
+        let mut theirs: SharedNote = serde_json::from_slice(&plain)
+            .map_err(|_| VaultError::Corrupted(format!("note shared by {}", peer_id)))?;
+
+        let valid_id =
+            !theirs.id.is_empty() && theirs.id.chars().all(|c| c.is_ascii_alphanumeric());
+        if !valid_id {
+            return Err(VaultError::Corrupted(format!("note shared by {}", peer_id)));
+        }
+        theirs.tags = normalize_tags(&theirs.tags);
+
+        let existing = self.open_entry::<Note>(&keys::note(&theirs.id))?;
+        let rejected = |reason: &str| VaultError::Rejected {
+            id: theirs.id.clone(),
+            peer: peer_id.to_string(),
+            reason: reason.to_string(),
+        };
+        if let Some(ours) = &existing {
+            if !ours.shared_with.iter().any(|peer| peer == peer_id) {
+                return Err(rejected("the note is not shared with this peer"));
+            }
+        }
+        let own_edits = existing
+            .as_ref()
+            .map_or(0, |ours| ours.version.get(&self.replica));
+        if theirs.version.get(&self.replica) > own_edits {
+            return Err(rejected("it claims edits of ours that we do not have"));
+        }
+
+        let remember = self.remember(&theirs.id, Snapshot::from(&theirs))?;
+
+        let ours = match existing {
+            Some(ours) => ours,
+            None => {
+                let note = Note {
+                    conflicted: merge::has_conflicts(&theirs.body),
+                    id: theirs.id,
+                    title: theirs.title,
+                    tags: theirs.tags,
+                    body: theirs.body,
+                    created_at: theirs.created_at,
+                    updated_at: theirs.updated_at,
+                    version: theirs.version,
+                    shared_with: vec![peer_id.to_string()],
+                };
+                self.write(&note, remember)?;
+                return Ok(SyncReport {
+                    note,
+                    outcome: SyncOutcome::Added,
+                });
+            }
+        };
+
+        let (note, outcome) = match ours.version.partial_cmp(&theirs.version) {
+            Some(std::cmp::Ordering::Greater | std::cmp::Ordering::Equal) => {
+                if let Some(op) = remember {
+                    self.store.apply(vec![op])?;
+                }
+                return Ok(SyncReport {
+                    note: ours,
+                    outcome: SyncOutcome::UpToDate,
+                });
+            }
+            Some(std::cmp::Ordering::Less) => {
+                let note = Note {
+                    conflicted: merge::has_conflicts(&theirs.body),
+                    id: theirs.id,
+                    title: theirs.title,
+                    tags: theirs.tags,
+                    body: theirs.body,
+                    created_at: ours.created_at.min(theirs.created_at),
+                    updated_at: theirs.updated_at,
+                    version: theirs.version,
+                    shared_with: ours.shared_with,
+                };
+                (note, SyncOutcome::FastForward)
+            }
+            None => {
+                let base = self.common_base(&ours.id, &ours.version, &theirs.version)?;
+                self.merge(ours, theirs, &base)
+            }
+        };
+
+        self.write(&note, remember)?;
+        Ok(SyncReport { note, outcome })
+    }
+
+    fn merge(&self, ours: Note, theirs: SharedNote, base: &Snapshot) -> (Note, SyncOutcome) {
+        let mut conflicts = Vec::new();
+
+        let title = if ours.title == base.title || ours.title == theirs.title {
+            theirs.title.clone()
+        } else if theirs.title == base.title {
+            ours.title.clone()
+        } else {
+            // Last writer wins; equal times fall back to the titles so both
+            // sides keep the same one.
+            let ours_later = (ours.updated_at, &ours.title) > (theirs.updated_at, &theirs.title);
+            let (kept, dropped) = if ours_later {
+                (ours.title.clone(), theirs.title.clone())
+            } else {
+                (theirs.title.clone(), ours.title.clone())
+            };
+            conflicts.push(Conflict::Title {
+                kept: kept.clone(),
+                dropped,
+            });
+            kept
+        };
+
+        // A tag added or removed on one side is added or removed.
+        let mut tags: Vec<String> = ours.tags.iter().chain(&theirs.tags).cloned().collect();
+        tags.retain(|tag| {
+            let in_ours = ours.tags.contains(tag);
+            if in_ours != base.tags.contains(tag) {
+                in_ours
+            } else {
+                theirs.tags.contains(tag)
+            }
+        });
+        let tags = normalize_tags(&tags);
+
+        let body = merge::merge_text(&base.body, &ours.body, &theirs.body);
+        if body.conflicts > 0 {
+            conflicts.push(Conflict::Body {
+                regions: body.conflicts,
+            });
+        }
+
+        let mut version = ours.version.clone();
+        version.merge(&theirs.version);
+        let same_as_theirs =
+            title == theirs.title && tags == theirs.tags && body.text == theirs.body;
+        if !same_as_theirs {
+            // A new state neither side had.
+            version.increment(&self.replica);
+        }
+
+        let note = Note {
+            conflicted: merge::has_conflicts(&body.text),
+            id: ours.id,
+            title,
+            tags,
+            body: body.text,
+            created_at: ours.created_at.min(theirs.created_at),
+            updated_at: if same_as_theirs { theirs.updated_at } else { Utc::now() },
+            version,
+            shared_with: ours.shared_with,
+        };
+
+        (note, SyncOutcome::Merged { conflicts })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::cipher::MemoryStore;
+
+    fn cipher() -> Cipher<MemoryStore> {
+        let mut cipher = Cipher::new(MemoryStore::new());
+        cipher.init(None).unwrap();
+        cipher
+    }
+
+    fn vault(cipher: &Cipher<MemoryStore>) -> NotesVault<MemoryStore> {
+        NotesVault::open(MemoryStore::new(), cipher).unwrap()
+    }
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    /// Someone with a vault, registered with the others as their peer.
+    struct Party {
+        name: &'static str,
+        cipher: Cipher<MemoryStore>,
+        vault: NotesVault<MemoryStore>,
+    }
+
+    fn parties<const N: usize>(names: [&'static str; N]) -> [Party; N] {
+        let mut parties = names.map(|name| {
+            let cipher = cipher();
+            let vault = vault(&cipher);
+            Party { name, cipher, vault }
+        });
+
+        let keys: Vec<String> = parties
+            .iter()
+            .map(|party| party.cipher.export_public_key().unwrap())
+            .collect();
+        for party in parties.iter_mut() {
+            for (other, key) in names.iter().zip(&keys) {
+                if *other != party.name {
+                    party.cipher.register_peer(other, key).unwrap();
+                }
+            }
+        }
+        parties
+    }
+
+    /// `from` shares note `id` with `to`, who imports it.
+    fn sync(from: &mut Party, to: &mut Party, id: &str) -> Result<SyncReport, VaultError> {
+        let data = from.vault.share(id, to.name, &mut from.cipher).unwrap();
+        to.vault.import_shared(&data, from.name, &mut to.cipher)
+    }
+
+    /// `from` sends `note` to `to` as is, without going through `share`.
+    fn send_raw(
+        from: &mut Party,
+        to: &mut Party,
+        note: &SharedNote,
+    ) -> Result<SyncReport, VaultError> {
+        let data = from
+            .cipher
+            .encrypt_bytes(&to_json(note).unwrap(), to.name)
+            .unwrap();
+        to.vault.import_shared(&data, from.name, &mut to.cipher)
+    }
+
+    fn edit(party: &mut Party, id: &str, change: impl FnOnce(&mut Note)) -> Note {
+        let mut note = party.vault.get(id).unwrap();
+        change(&mut note);
+        party.vault.update(note).unwrap()
+    }
+
+    #[test]
+    fn creates_updates_and_deletes_notes() {
+        let cipher = cipher();
+        let mut vault = vault(&cipher);
+
+        let note = vault
+            .create(" Groceries ", "- milk\n", &tags(&["#Home", "home", " errands"]))
+            .unwrap();
+        assert_eq!(note.title, "Groceries");
+        assert_eq!(note.tags, tags(&["errands", "home"]));
+        assert_eq!(vault.get(&note.id).unwrap(), note);
+
+        let mut changed = note.clone();
+        changed.body.push_str("- eggs\n");
+        let changed = vault.update(changed).unwrap();
+        assert_eq!(changed.created_at, note.created_at);
+        assert!(changed.version > note.version);
+        assert_eq!(vault.get(&note.id).unwrap().body, "- milk\n- eggs\n");
+
+        vault.delete(&note.id).unwrap();
+        assert!(matches!(vault.get(&note.id), Err(VaultError::NotFound(_))));
+        assert!(matches!(vault.delete(&note.id), Err(VaultError::NotFound(_))));
+        assert!(vault.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn lists_and_searches() {
+        let cipher = cipher();
+        let mut vault = vault(&cipher);
+
+        let first = vault.create("Trip", "Pack the tent", &tags(&["travel"])).unwrap();
+        let second = vault.create("Budget", "Tent: 120", &tags(&["money"])).unwrap();
+        vault.update(first.clone()).unwrap();
+
+        let ids = |found: Vec<NoteSummary>| found.into_iter().map(|e| e.id).collect::<Vec<_>>();
+        assert_eq!(ids(vault.list().unwrap()), [first.id.as_str(), &second.id]);
+        assert_eq!(ids(vault.list_tagged("#Travel").unwrap()), [first.id.as_str()]);
+        assert_eq!(ids(vault.search("TENT").unwrap()), [first.id.as_str(), &second.id]);
+        assert_eq!(ids(vault.search("money").unwrap()), [second.id.as_str()]);
+        assert!(vault.search("boat").unwrap().is_empty());
+    }
+
+    #[test]
+    fn entries_are_sealed() {
+        let cipher = cipher();
+        let mut vault = vault(&cipher);
+        let note = vault.create("Secret plan", "Meet at noon", &[]).unwrap();
+
+        for id in vault.store.list("").unwrap() {
+            let data = String::from_utf8_lossy(&vault.store.get(&id).unwrap().unwrap()).to_string();
+            assert!(!data.contains("Secret") && !data.contains("noon"), "{}", id);
+        }
+
+        // Sealed under the store id: another note's entry does not open.
+        let other = vault.create("Other", "", &[]).unwrap();
+        let swapped = vault.store.get(&keys::note(&other.id)).unwrap().unwrap();
+        vault.store.put(&keys::note(&note.id), swapped).unwrap();
+        assert!(matches!(vault.get(&note.id), Err(VaultError::Corrupted(_))));
+    }
+
+    #[test]
+    fn only_the_same_identity_opens_the_vault() {
+        let cipher = cipher();
+        let mut vault = vault(&cipher);
+        let note = vault.create("Mine", "", &[]).unwrap();
+
+        let stranger = NotesVault {
+            store: std::mem::take(&mut vault.store),
+            key: Zeroizing::new([0u8; 32]),
+            replica: String::new(),
+        };
+        assert!(matches!(stranger.list(), Err(VaultError::Corrupted(_))));
+
+        let reopened = NotesVault {
+            store: stranger.store,
+            key: cipher.derive_local_key(KEY_PURPOSE).unwrap(),
+            replica: String::new(),
+        };
+        assert_eq!(reopened.get(&note.id).unwrap().title, "Mine");
+    }
+
+    #[test]
+    fn syncs_one_sided_changes() {
+        let [mut alice, mut bob] = parties(["alice", "bob"]);
+        let note = alice.vault.create("Plan", "one\ntwo\n", &tags(&["trip"])).unwrap();
+
+        let report = sync(&mut alice, &mut bob, &note.id).unwrap();
+        assert_eq!(report.outcome, SyncOutcome::Added);
+        assert_eq!(report.note.body, "one\ntwo\n");
+        assert_eq!(report.note.shared_with, ["alice"]);
+        assert_eq!(alice.vault.get(&note.id).unwrap().shared_with, ["bob"]);
+
+        edit(&mut alice, &note.id, |note| note.body.push_str("three\n"));
+        let report = sync(&mut alice, &mut bob, &note.id).unwrap();
+        assert_eq!(report.outcome, SyncOutcome::FastForward);
+        assert_eq!(bob.vault.get(&note.id).unwrap().body, "one\ntwo\nthree\n");
+
+        // Bob's copy holds nothing new for Alice.
+        let report = sync(&mut bob, &mut alice, &note.id).unwrap();
+        assert_eq!(report.outcome, SyncOutcome::UpToDate);
+    }
+
+    #[test]
+    fn merges_concurrent_edits() {
+        let [mut alice, mut bob] = parties(["alice", "bob"]);
+        let note = alice
+            .vault
+            .create("Plan", "one\ntwo\nthree\n", &tags(&["trip", "todo"]))
+            .unwrap();
+        sync(&mut alice, &mut bob, &note.id).unwrap();
+
+        edit(&mut alice, &note.id, |note| {
+            note.body = "ONE\ntwo\nthree\n".into();
+            note.tags = tags(&["trip", "todo", "urgent"]);
+        });
+        edit(&mut bob, &note.id, |note| {
+            note.body = "one\ntwo\nTHREE\n".into();
+            note.tags = tags(&["trip"]);
+        });
+
+        let report = sync(&mut bob, &mut alice, &note.id).unwrap();
+        assert_eq!(report.outcome, SyncOutcome::Merged { conflicts: vec![] });
+        assert_eq!(report.note.body, "ONE\ntwo\nTHREE\n");
+        assert_eq!(report.note.tags, tags(&["trip", "urgent"]));
+        assert!(!report.note.conflicted);
+
+        // Sending the merge back fast-forwards Bob to the same state.
+        let report = sync(&mut alice, &mut bob, &note.id).unwrap();
+        assert_eq!(report.outcome, SyncOutcome::FastForward);
+        assert_eq!(report.note.body, "ONE\ntwo\nTHREE\n");
+        assert_eq!(report.note.version, alice.vault.get(&note.id).unwrap().version);
+    }
+
+    #[test]
+    fn marks_conflicting_edits() {
+        let [mut alice, mut bob] = parties(["alice", "bob"]);
+        let note = alice.vault.create("Plan", "one\ntwo\nthree\n", &[]).unwrap();
+        sync(&mut alice, &mut bob, &note.id).unwrap();
+
+        edit(&mut alice, &note.id, |note| {
+            note.title = "Alice's plan".into();
+            note.body = "one\nalice\nthree\n".into();
+        });
+        let bobs = edit(&mut bob, &note.id, |note| {
+            note.title = "Bob's plan".into();
+            note.body = "one\nbob\nthree\n".into();
+        });
+
+        let report = sync(&mut bob, &mut alice, &note.id).unwrap();
+        let SyncOutcome::Merged { conflicts } = &report.outcome else {
+            panic!("not merged: {:?}", report.outcome);
+        };
+        // Bob edited last, so his title wins.
+        assert_eq!(
+            conflicts,
+            &[
+                Conflict::Title {
+                    kept: bobs.title.clone(),
+                    dropped: "Alice's plan".into(),
+                },
+                Conflict::Body { regions: 1 },
+            ]
+        );
+        assert_eq!(
+            report.note.body,
+            "one\n<<<<<<< ours\nalice\n=======\nbob\n>>>>>>> theirs\nthree\n"
+        );
+        assert!(report.note.conflicted);
+        assert!(alice.vault.list().unwrap()[0].conflicted);
+
+        let resolved = edit(&mut alice, &note.id, |note| note.body = "one\nboth\nthree\n".into());
+        assert!(!resolved.conflicted);
+    }
+
+    #[test]
+    fn rejects_notes_not_shared_with_the_sender() {
+        let [mut alice, mut bob, mut carol] = parties(["alice", "bob", "carol"]);
+        let private = alice.vault.create("Diary", "dear diary\n", &[]).unwrap();
+        let shared = alice.vault.create("Plan", "one\n", &[]).unwrap();
+        sync(&mut alice, &mut bob, &shared.id).unwrap();
+
+        // Carol picks the ids of Alice's notes, private or shared with Bob.
+        for id in [&private.id, &shared.id] {
+            let mut forged = SharedNote::from(&alice.vault.get(id).unwrap());
+            forged.body = "overwritten\n".into();
+            forged.version.increment(&carol.vault.replica);
+
+            assert!(matches!(
+                send_raw(&mut carol, &mut alice, &forged),
+                Err(VaultError::Rejected { .. })
+            ));
+            assert_ne!(alice.vault.get(id).unwrap().body, "overwritten\n");
+        }
+    }
+
+    #[test]
+    fn rejects_versions_claiming_our_edits() {
+        let [mut alice, mut bob] = parties(["alice", "bob"]);
+        let note = alice.vault.create("Plan", "one\n", &[]).unwrap();
+        sync(&mut alice, &mut bob, &note.id).unwrap();
+        edit(&mut alice, &note.id, |note| note.body = "alice's edit\n".into());
+
+        // Bob's version pretends to include Alice's latest edits, which would
+        // make it replace them.
+        let mut forged = SharedNote::from(&bob.vault.get(&note.id).unwrap());
+        forged.body = "bob wins\n".into();
+        for _ in 0..5 {
+            forged.version.increment(&alice.vault.replica);
+        }
+
+        assert!(matches!(
+            send_raw(&mut bob, &mut alice, &forged),
+            Err(VaultError::Rejected { .. })
+        ));
+        assert_eq!(alice.vault.get(&note.id).unwrap().body, "alice's edit\n");
+
+        // A new note cannot claim edits of ours either.
+        forged.id = "0123456789abcdef".into();
+        assert!(send_raw(&mut bob, &mut alice, &forged).is_err());
+    }
+}
@@ -0,0 +1,351 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+pub const CONFLICT_START: &str = "<<<<<<< ours";
+pub const CONFLICT_SEPARATOR: &str = "=======";
+pub const CONFLICT_END: &str = ">>>>>>> theirs";
+
+/// Edit counters per replica. Every replica bumps its own counter when it
+/// changes a note, so `a < b` means `b` already includes every change of `a`,
+/// and two vectors that compare as `None` were edited concurrently.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct VersionVector(BTreeMap<String, u64>);
+
+impl VersionVector {
+    pub fn get(&self, replica: &str) -> u64 {
+        self.0.get(replica).copied().unwrap_or(0)
+    }
+
+    pub fn increment(&mut self, replica: &str) {
+        *self.0.entry(replica.to_string()).or_insert(0) += 1;
+    }
+
+    /// Raises every counter to the highest of `self` and `other`.
+    pub fn merge(&mut self, other: &VersionVector) {
+        for (replica, &count) in &other.0 {
+            let entry = self.0.entry(replica.clone()).or_insert(0);
+            *entry = (*entry).max(count);
+        }
+    }
+}
+
+impl PartialOrd for VersionVector {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        let mut less = false;
+        let mut greater = false;
+
+        for replica in self.0.keys().chain(other.0.keys()) {
+            match self.get(replica).cmp(&other.get(replica)) {
+                Ordering::Less => less = true,
+                Ordering::Greater => greater = true,
+                Ordering::Equal => {}
+            }
+        }
+
+        match (less, greater) {
+            (false, false) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Less),
+            (false, true) => Some(Ordering::Greater),
+            (true, true) => None,
+        }
+    }
+}
+
+/// Result of `merge_text`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergedText {
+    pub text: String,
+    /// Number of regions both sides changed differently; each is left in
+    /// `text` between conflict markers.
+    pub conflicts: usize,
+}
+
+/// Line-based three-way merge of `ours` and `theirs`, both edited from
+/// `base`. A region changed on one side only takes that side; a region
+/// changed differently on both sides is kept twice, between
+/// `CONFLICT_START`, `CONFLICT_SEPARATOR` and `CONFLICT_END` lines.
+pub fn merge_text(base: &str, ours: &str, theirs: &str) -> MergedText {
+    let base: Vec<&str> = base.split_inclusive('\n').collect();
+    let ours: Vec<&str> = ours.split_inclusive('\n').collect();
+    let theirs: Vec<&str> = theirs.split_inclusive('\n').collect();
+
+    let in_ours = matching_lines(&base, &ours);
+    let in_theirs = matching_lines(&base, &theirs);
+
+    let mut merged = MergedText {
+        text: String::new(),
+        conflicts: 0,
+    };
+    let (mut b, mut o, mut t) = (0, 0, 0);
+
+    loop {
+        // Next base line both sides kept; everything before it is one chunk.
+        let sync = (b..base.len()).find_map(|k| match (in_ours[k], in_theirs[k]) {
+            (Some(ko), Some(kt)) => Some((k, ko, kt)),
+            _ => None,
+        });
+        let (k, ko, kt) = sync.unwrap_or((base.len(), ours.len(), theirs.len()));
+
+        merge_chunk(&mut merged, &base[b..k], &ours[o..ko], &theirs[t..kt]);
+
+        if sync.is_none() {
+            break;
+        }
+        merged.text.push_str(base[k]);
+        (b, o, t) = (k + 1, ko + 1, kt + 1);
+    }
+
+    merged
+}
+
+fn merge_chunk(merged: &mut MergedText, base: &[&str], ours: &[&str], theirs: &[&str]) {
+    let take = if ours == base || ours == theirs {
+        theirs
+    } else if theirs == base {
+        ours
+    } else {
+        merged.conflicts += 1;
+        push_marker(&mut merged.text, CONFLICT_START);
+        push_lines(&mut merged.text, ours);
+        push_marker(&mut merged.text, CONFLICT_SEPARATOR);
+        push_lines(&mut merged.text, theirs);
+        push_marker(&mut merged.text, CONFLICT_END);
+        return;
+    };
+
+    push_lines(&mut merged.text, take);
+}
+
+fn push_lines(text: &mut String, lines: &[&str]) {
+    for line in lines {
+        text.push_str(line);
+    }
+}
+
+fn push_marker(text: &mut String, marker: &str) {
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    text.push_str(marker);
+    text.push('\n');
+}
+
+/// For each line of `base`, the index of the line of `other` it is matched
+/// with in a longest common subsequence, if any. Linear in memory
+/// (Hirschberg), so large notes cannot exhaust it.
+fn matching_lines(base: &[&str], other: &[&str]) -> Vec<Option<usize>> {
+    let mut matches = vec![None; base.len()];
+
+    // Unchanged head and tail lines are the common case; match them first.
+    let prefix = base
+        .iter()
+        .zip(other)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = base[prefix..]
+        .iter()
+        .rev()
+        .zip(other[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    for (i, matched) in matches.iter_mut().take(prefix).enumerate() {
+        *matched = Some(i);
+    }
+    for k in 1..=suffix {
+        matches[base.len() - k] = Some(other.len() - k);
+    }
+
+    let (n, m) = (base.len() - suffix, other.len() - suffix);
+    hirschberg(&base[prefix..n], &other[prefix..m], prefix, prefix, &mut matches);
+    matches
+}
+
+/// Fills `matches` for `a` against `b`, which start at `a_start` and
+/// `b_start` in the full texts.
+fn hirschberg(
+    a: &[&str],
+    b: &[&str],
+    a_start: usize,
+    b_start: usize,
+    matches: &mut [Option<usize>],
+) {
+    if a.is_empty() || b.is_empty() {
+        return;
+    }
+    if a.len() == 1 {
+        if let Some(j) = b.iter().position(|line| *line == a[0]) {
+            matches[a_start] = Some(b_start + j);
+        }
+        return;
+    }
+
+    // Split `b` where the LCS of the first half of `a` with its head plus
+    // that of the second half with its tail is longest.
+    let mid = a.len() / 2;
+    let head = lcs_lengths(a[..mid].iter(), b.iter());
+    let tail = lcs_lengths(a[mid..].iter().rev(), b.iter().rev());
+    let split = (0..=b.len())
+        .max_by_key(|&k| (head[k] + tail[b.len() - k], std::cmp::Reverse(k)))
+        .unwrap_or(0);
+
+    hirschberg(&a[..mid], &b[..split], a_start, b_start, matches);
+    hirschberg(&a[mid..], &b[split..], a_start + mid, b_start + split, matches);
+}
+
+/// `lengths[j]`: LCS length of all of `a` with the first `j` items of `b`,
+/// keeping a single row.
+fn lcs_lengths<'a, A, B>(a: A, b: B) -> Vec<u32>
+where
+    A: Iterator<Item = &'a &'a str>,
+    B: Iterator<Item = &'a &'a str> + Clone,
+{
+    let mut lengths = vec![0u32; b.clone().count() + 1];
+
+    for line in a {
+        // `diagonal` is the previous row's value at `j - 1`.
+        let mut diagonal = 0;
+        for (j, other) in b.clone().enumerate() {
+            let above = lengths[j + 1];
+            lengths[j + 1] = if line == other {
+                diagonal + 1
+            } else {
+                above.max(lengths[j])
+            };
+            diagonal = above;
+        }
+    }
+
+    lengths
+}
+
+/// Whether `text` still has conflict markers left by `merge_text`.
+pub fn has_conflicts(text: &str) -> bool {
+    text.lines().any(|line| line == CONFLICT_START || line == CONFLICT_END)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vector(counts: &[(&str, u64)]) -> VersionVector {
+        let mut vector = VersionVector::default();
+        for (replica, count) in counts {
+            for _ in 0..*count {
+                vector.increment(replica);
+            }
+        }
+        vector
+    }
+
+    /// Quadratic-space LCS length, to check `matching_lines` against.
+    fn lcs_len(a: &[&str], b: &[&str]) -> usize {
+        let mut table = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                table[i][j] = if a[i] == b[j] {
+                    table[i + 1][j + 1] + 1
+                } else {
+                    table[i + 1][j].max(table[i][j + 1])
+                };
+            }
+        }
+        table[0][0]
+    }
+
+    #[test]
+    fn version_vectors_order_by_inclusion() {
+        let base = vector(&[("a", 1)]);
+        let ours = vector(&[("a", 2)]);
+        let theirs = vector(&[("a", 1), ("b", 1)]);
+
+        assert!(base < ours && base < theirs);
+        assert_eq!(ours.partial_cmp(&theirs), None);
+        assert_eq!(base.partial_cmp(&vector(&[("a", 1), ("b", 0)])), Some(Ordering::Equal));
+
+        let mut merged = ours.clone();
+        merged.merge(&theirs);
+        assert_eq!(merged, vector(&[("a", 2), ("b", 1)]));
+        assert!(merged > ours && merged > theirs);
+        assert_eq!(merged.get("c"), 0);
+    }
+
+    #[test]
+    fn merges_changes_from_both_sides() {
+        let base = "title\none\ntwo\nthree\nfour\n";
+        let ours = "title\nONE\ntwo\nthree\nfour\n";
+        let theirs = "title\none\ntwo\nthree\nFOUR\nfive\n";
+
+        let merged = merge_text(base, ours, theirs);
+        assert_eq!(merged.text, "title\nONE\ntwo\nthree\nFOUR\nfive\n");
+        assert_eq!(merged.conflicts, 0);
+        assert!(!has_conflicts(&merged.text));
+
+        // The same change on both sides is taken once.
+        let merged = merge_text(base, ours, ours);
+        assert_eq!(merged.text, ours);
+        assert_eq!(merged.conflicts, 0);
+
+        // Deleting on one side, inserting at the start on the other.
+        let deleted = "title\none\nthree\nfour\n";
+        let merged = merge_text(base, deleted, &format!("intro\n{}", base));
+        assert_eq!(merged.text, "intro\ntitle\none\nthree\nfour\n");
+    }
+
+    #[test]
+    fn marks_conflicting_regions() {
+        let base = "a\nb\nc\n";
+        let merged = merge_text(base, "a\nours\nc\n", "a\ntheirs\nc\n");
+
+        assert_eq!(merged.conflicts, 1);
+        assert_eq!(
+            merged.text,
+            "a\n<<<<<<< ours\nours\n=======\ntheirs\n>>>>>>> theirs\nc\n"
+        );
+        assert!(has_conflicts(&merged.text));
+
+        // Without a trailing newline the marker still starts a line.
+        let merged = merge_text("x", "y", "z");
+        assert_eq!(merged.text, "<<<<<<< ours\ny\n=======\nz\n>>>>>>> theirs\n");
+    }
+
+    #[test]
+    fn matching_lines_is_a_longest_common_subsequence() {
+        // Small pseudo-random texts over a tiny alphabet, so lines repeat.
+        let mut seed = 0x2545_f491_u32;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed
+        };
+        let lines = ["a\n", "b\n", "c\n", "d\n"];
+
+        for _ in 0..200 {
+            let base: Vec<&str> = (0..next() % 12).map(|_| lines[next() as usize % 4]).collect();
+            let other: Vec<&str> = (0..next() % 12).map(|_| lines[next() as usize % 4]).collect();
+            let matches = matching_lines(&base, &other);
+
+            let matched: Vec<(usize, usize)> = matches
+                .iter()
+                .enumerate()
+                .filter_map(|(i, j)| j.map(|j| (i, j)))
+                .collect();
+            assert!(matched.iter().all(|&(i, j)| base[i] == other[j]));
+            assert!(matched.windows(2).all(|w| w[0].1 < w[1].1));
+            assert_eq!(matched.len(), lcs_len(&base, &other), "{:?} {:?}", base, other);
+        }
+    }
+
+    #[test]
+    fn merges_large_notes() {
+        let base: String = (0..20_000).map(|i| format!("line {}\n", i)).collect();
+        let ours = base.replacen("line 10\n", "line ten\n", 1);
+        let theirs = base.replacen("line 19990\n", "line 19990 and more\n", 1);
+
+        let merged = merge_text(&base, &ours, &theirs);
+        assert_eq!(merged.conflicts, 0);
+        assert!(merged.text.contains("line ten\n") && merged.text.contains("and more\n"));
+    }
+}